@@ -0,0 +1,92 @@
+use crate::buffermanager::BufferManager;
+use crate::concurrencymanager::LockTable;
+use crate::filemanager::FileManager;
+use crate::logmanager::LogManager;
+use crate::metadata::{CatalogError, MetadataMgr};
+use crate::transaction::Transaction;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The databases attached to another, by the name queries qualify their
+/// tables with.
+pub type Attachments = Arc<RwLock<HashMap<String, Attachment>>>;
+
+/// Another database directory opened read-only, so that queries can read its
+/// tables as `name.table`. It has its own buffer pool, lock table and
+/// catalog, and nothing done through it changes its files: transactions on
+/// it write no log records, and it is not recovered when opened.
+pub struct Attachment {
+    file_manager: Arc<Mutex<FileManager>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    buffer_manager: Arc<BufferManager>,
+    lock_table: Arc<LockTable>,
+    metadata_mgr: Arc<MetadataMgr>,
+}
+
+impl Attachment {
+    /// Opens the database in `directory`, which must have been created with
+    /// blocks of `block_size` bytes.
+    pub fn open<P: AsRef<Path>>(
+        directory: P,
+        block_size: usize,
+        buffer_count: i32,
+        log_file: &str,
+    ) -> Result<Attachment, CatalogError> {
+        let directory = directory.as_ref();
+        let no_database = || CatalogError::NoDatabase(directory.display().to_string());
+        if !directory.join(log_file).is_file() {
+            return Err(no_database());
+        }
+        let file_manager = Arc::new(Mutex::new(FileManager::read_only(
+            directory.to_path_buf(),
+            block_size,
+        )?));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder(log_file.to_string(), file_manager.clone()).build()?,
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_count,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(Transaction::read_only(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            lock_table.clone(),
+        )));
+        let metadata_mgr = MetadataMgr::read_only(tx.clone());
+        tx.borrow_mut().commit()?;
+        Ok(Attachment {
+            file_manager,
+            log_manager,
+            buffer_manager,
+            lock_table,
+            metadata_mgr: Arc::new(metadata_mgr?),
+        })
+    }
+
+    /// Starts a read-only transaction on the attached database.
+    pub fn begin(&self) -> Rc<RefCell<Transaction>> {
+        Rc::new(RefCell::new(Transaction::read_only(
+            self.file_manager.clone(),
+            self.log_manager.clone(),
+            self.buffer_manager.clone(),
+            self.lock_table.clone(),
+        )))
+    }
+
+    pub fn metadata_mgr(&self) -> Arc<MetadataMgr> {
+        self.metadata_mgr.clone()
+    }
+}
+
+/// Splits a table name qualified with the name of an attached database, as
+/// in `otherdb.table`, into its two parts.
+pub fn split_qualified(table_name: &str) -> Option<(&str, &str)> {
+    table_name.split_once('.')
+}
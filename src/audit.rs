@@ -0,0 +1,237 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What a statement does, judged by the keyword it starts with, for
+/// choosing which statements an audit log records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatementKind {
+    /// `select`, `explain` and `check table`.
+    Query,
+    Insert,
+    Update,
+    Delete,
+    /// `create` of a table, view or index.
+    Ddl,
+    /// `grant` and `revoke`.
+    Grant,
+    /// Anything else, which can only have failed to parse.
+    Other,
+}
+
+impl StatementKind {
+    pub fn of(sql: &str) -> StatementKind {
+        let keyword = sql
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .find(|word| !word.is_empty())
+            .unwrap_or("")
+            .to_lowercase();
+        match keyword.as_str() {
+            "select" | "explain" | "check" => StatementKind::Query,
+            "insert" => StatementKind::Insert,
+            "update" => StatementKind::Update,
+            "delete" => StatementKind::Delete,
+            "create" => StatementKind::Ddl,
+            "grant" | "revoke" => StatementKind::Grant,
+            _ => StatementKind::Other,
+        }
+    }
+}
+
+impl Display for StatementKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            StatementKind::Query => "query",
+            StatementKind::Insert => "insert",
+            StatementKind::Update => "update",
+            StatementKind::Delete => "delete",
+            StatementKind::Ddl => "ddl",
+            StatementKind::Grant => "grant",
+            StatementKind::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where an audit log is written and which statements go into it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// The file records are appended to, in the database directory.
+    pub file_name: String,
+    /// The size in bytes past which the file is rotated: it is renamed to
+    /// `<file_name>.1`, the older files moving up one number.
+    pub max_file_size: u64,
+    /// Rotated files kept besides the current one; older ones are deleted.
+    pub max_files: usize,
+    /// The kinds of statement recorded, or `None` for every statement.
+    pub kinds: Option<Vec<StatementKind>>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            file_name: "audit.log".to_string(),
+            max_file_size: 1 << 20,
+            max_files: 4,
+            kinds: None,
+        }
+    }
+}
+
+/// One executed statement. A query counts the rows read from its result
+/// and is recorded once the result set is dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    /// The session user, or `None` for an unrestricted session.
+    pub user: Option<String>,
+    pub tx_num: i32,
+    pub kind: StatementKind,
+    pub duration: Duration,
+    /// Rows affected or returned, or the error the statement failed with.
+    pub result: Result<usize, String>,
+    pub sql: String,
+}
+
+// One line per record, its fields separated by tabs; line breaks and tabs
+// inside the statement are written as spaces.
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let millis = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t",
+            millis,
+            self.user.as_deref().unwrap_or("-"),
+            self.tx_num,
+            self.kind,
+            self.duration.as_micros()
+        )?;
+        match &self.result {
+            Ok(rows) => write!(f, "{} rows", rows)?,
+            Err(err) => write!(f, "error: {}", err.replace(['\n', '\t'], " "))?,
+        }
+        write!(f, "\t{}", self.sql.replace(['\n', '\r', '\t'], " "))
+    }
+}
+
+/// Appends `AuditRecord`s to a file, rotating it once it grows too big.
+pub struct AuditLog {
+    path: PathBuf,
+    config: AuditConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(directory: &Path, config: AuditConfig) -> std::io::Result<AuditLog> {
+        let path = directory.join(&config.file_name);
+        let file = Self::open_file(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            config,
+            file,
+            size,
+        })
+    }
+
+    pub fn records(&self, kind: StatementKind) -> bool {
+        self.config
+            .kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+    }
+
+    pub fn record(&mut self, record: &AuditRecord) -> std::io::Result<()> {
+        if !self.records(record.kind) {
+            return Ok(());
+        }
+        let line = format!("{}\n", record);
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.config.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.config.max_files).rev() {
+                if rotated(n).exists() {
+                    std::fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn open_file(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_audit_log_rotation() {
+        let tmp_dir = TempDir::new("test_audit_log_rotation").expect("failed to create temp dir");
+        let config = AuditConfig {
+            max_file_size: 200,
+            max_files: 2,
+            kinds: Some(vec![StatementKind::Insert, StatementKind::Grant]),
+            ..AuditConfig::default()
+        };
+        let mut log = AuditLog::open(tmp_dir.path(), config).unwrap();
+        let record = |n: usize, sql: &str| AuditRecord {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(n as u64),
+            user: n.is_multiple_of(2).then(|| "alice".to_string()),
+            tx_num: n as i32,
+            kind: StatementKind::of(sql),
+            duration: Duration::from_micros(15),
+            result: Ok(1),
+            sql: sql.to_string(),
+        };
+        assert_eq!(
+            record(2, "insert into t (a)\nvalues (1)").to_string(),
+            "2\talice\t2\tinsert\t15\t1 rows\tinsert into t (a) values (1)"
+        );
+
+        // Queries are filtered out; every record is about 60 bytes, so each
+        // file holds three.
+        for n in 0..10 {
+            log.record(&record(n, "select a from t")).unwrap();
+            log.record(&record(n, "insert into t (a) values (1)"))
+                .unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(tmp_dir.path().join(name)).unwrap();
+        let lines = |name: &str| read(name).lines().count();
+        assert_eq!(
+            (
+                lines("audit.log"),
+                lines("audit.log.1"),
+                lines("audit.log.2")
+            ),
+            (1, 3, 3)
+        );
+        assert!(!tmp_dir.path().join("audit.log.3").exists());
+        assert!(read("audit.log").starts_with("9\t"));
+        assert!(read("audit.log.2").starts_with("3\t"));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
@@ -1,28 +1,87 @@
+use crate::error::DbError;
 use crate::filemanager::{BlockId, FileManager, Page};
 use crate::logmanager::LogManager;
-use std::cell::RefCell;
-use std::ops::DerefMut;
-use std::rc::Rc;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
 use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferEventKind {
+    Pin,
+    Unpin,
+    Flush,
+    /// The buffer was reassigned, dropping the block it held.
+    Evict,
+    /// The block was read ahead of its first pin.
+    Prefetch,
+}
+
+/// Something that happened to a block in the buffer pool, kept by the
+/// buffer trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferEvent {
+    pub kind: BufferEventKind,
+    pub file_name: String,
+    pub block_num: usize,
+    pub txn: Option<i32>,
+    pub thread: ThreadId,
+    pub timestamp: SystemTime,
+}
+
+impl Display for BufferEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let millis = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        write!(
+            f,
+            "{} {:?} {:?} {}:{}",
+            millis, self.thread, self.kind, self.file_name, self.block_num
+        )?;
+        if let Some(txn) = self.txn {
+            write!(f, " txn {}", txn)?;
+        }
+        Ok(())
+    }
+}
+
+// The most recent buffer events, oldest first, dropping the oldest once
+// `capacity` is reached.
+struct BufferTrace {
+    events: VecDeque<BufferEvent>,
+    capacity: usize,
+}
 
 pub struct Buffer {
-    file_manager: Rc<RefCell<FileManager>>,
-    log_manager: Rc<RefCell<LogManager>>,
+    file_manager: Arc<Mutex<FileManager>>,
+    log_manager: Arc<Mutex<LogManager>>,
     block_id: Option<BlockId>,
-    contents: Rc<RefCell<Page>>,
+    contents: Arc<RwLock<Page>>,
     pins: AtomicI32,
+    // Whether the page has changes the disk does not have yet.
+    dirty: bool,
     txn: Option<i32>,
-    lsn: Option<usize>,
+    // The newest log record of a change to the buffer, which has to be on
+    // disk before the buffer is.
+    lsn: Option<i64>,
 }
 
 impl Buffer {
     pub fn new(
-        file_manager: Rc<RefCell<FileManager>>,
-        log_manager: Rc<RefCell<LogManager>>,
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
     ) -> Buffer {
-        let fm_blk_size = { file_manager.borrow_mut().block_size() };
+        let fm_blk_size = { file_manager.lock().unwrap().block_size() };
 
-        let page = Rc::new(RefCell::new(
+        let page = Arc::new(RwLock::new(
             Page::builder()
                 .block_size(fm_blk_size)
                 .with_buffer()
@@ -34,14 +93,14 @@ impl Buffer {
             block_id: None,
             contents: page,
             pins: AtomicI32::new(0),
+            dirty: false,
             txn: None,
             lsn: None,
         }
     }
 
-    pub fn contents(&self) -> Rc<RefCell<Page>> {
-        let page = self.contents.clone();
-        page
+    pub fn contents(&self) -> Arc<RwLock<Page>> {
+        self.contents.clone()
     }
 
     pub fn block_id(&self) -> &Option<BlockId> {
@@ -52,15 +111,52 @@ impl Buffer {
         self.pins.load(Ordering::Relaxed) > 0
     }
 
-    pub fn set_modified(&mut self, txn: i32, lsn: usize) {
+    pub fn pin_count(&self) -> usize {
+        self.pins.load(Ordering::Relaxed).max(0) as usize
+    }
+
+    pub fn set_modified(&mut self, txn: i32, lsn: i64) {
+        self.dirty = true;
         self.txn = Some(txn);
-        self.lsn = Some(lsn);
+        if lsn >= 0 {
+            self.lsn = Some(lsn);
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
     }
 
+    /// The transaction whose changes make the buffer dirty, if it is.
     pub fn modifying_txn(&self) -> Option<i32> {
         self.txn
     }
 
+    pub fn lsn(&self) -> Option<i64> {
+        self.lsn
+    }
+
+    pub fn mark_flushed(&mut self) {
+        self.dirty = false;
+        self.txn = None;
+    }
+
+    /// Repurposes the buffer for `block_id`, writing out whatever it held
+    /// before and reading the new block's contents from disk.
+    pub fn assign_to_block(&mut self, block_id: &BlockId) -> Result<(), DbError> {
+        self.flush()?;
+        self.lsn = None;
+        // A buffer whose read failed holds no block rather than a stale one.
+        self.block_id = None;
+        self.file_manager
+            .lock()
+            .unwrap()
+            .read(block_id, &mut self.contents.write().unwrap())?;
+        self.block_id = Some(block_id.clone());
+        self.pins.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn pin(&self) {
         self.pins.fetch_add(1, Ordering::Relaxed);
     }
@@ -69,139 +165,1149 @@ impl Buffer {
         self.pins.fetch_sub(1, Ordering::Relaxed);
     }
 
-    fn flush(&mut self) {
-        if let Some(txn) = self.txn {
-            self.log_manager.borrow_mut().flush();
-            match self.block_id() {
-                None => {
-                    log::warn!("no block id provided")
-                }
-                Some(blid) => {
-                    let page_clone = self.contents.clone();
-                    {
-                        let mut page_borrow = page_clone.borrow_mut();
-                        let page = page_borrow.deref_mut();
-                        self.file_manager
-                            .borrow_mut()
-                            .write(blid, page)
-                            .expect("could not write to file manager");
-                        if txn == 1 {
-                            self.txn = None;
-                        } else {
-                            self.txn = Some(txn - 1);
-                        }
-                    }
-                }
+    // Write-ahead: the log is flushed up to the buffer's last change first,
+    // and no further.
+    fn flush(&mut self) -> Result<(), DbError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(lsn) = self.lsn {
+            self.log_manager.lock().unwrap().flush_lsn(lsn)?;
+        }
+        match self.block_id() {
+            None => {
+                log::warn!("no block id provided")
+            }
+            Some(blid) => {
+                let mut page = self.contents.write().unwrap();
+                self.file_manager.lock().unwrap().write(blid, &mut page)?;
+                self.dirty = false;
+                self.txn = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Buffers a maintenance scan reads through so that it does not evict the
+/// working set from the main pool.
+pub const MAINTENANCE_RING_SIZE: usize = 2;
+
+// A few private buffers a transaction reads blocks into, reused in turn.
+struct BufferRing {
+    buffers: Vec<Arc<RwLock<Buffer>>>,
+    next: usize,
+    // The size asked for, over every partition.
+    size: usize,
+}
+
+impl BufferRing {
+    fn contains(&self, buffer: &Arc<RwLock<Buffer>>) -> bool {
+        self.buffers
+            .iter()
+            .any(|ring_buffer| Arc::ptr_eq(ring_buffer, buffer))
+    }
+}
+
+/// Chooses which buffer of the pool to reassign when a block that is not
+/// resident is pinned. Buffers are known by their position in the pool; the
+/// policy is told when each is pinned and when its last pin is released.
+pub trait ReplacementPolicy: Send {
+    /// A policy of the same kind in its initial state, for another partition
+    /// of the pool.
+    fn new_policy(&self) -> Box<dyn ReplacementPolicy>;
+    fn pinned(&mut self, _buffer: usize) {}
+    fn unpinned(&mut self, _buffer: usize) {}
+    /// The buffer now holds another block.
+    fn assigned(&mut self, _buffer: usize) {}
+    /// The buffer was just read ahead of a pin. It ranks as if it had just
+    /// been pinned and released, unless the policy knows better.
+    fn prefetched(&mut self, buffer: usize) {
+        self.pinned(buffer);
+        self.unpinned(buffer);
+    }
+    /// One of the buffers not in `pinned`, or `None` if every buffer is.
+    fn victim(&mut self, pinned: &[bool]) -> Option<usize>;
+}
+
+/// Reassigns the buffer whose last pin was released longest ago, so blocks
+/// that are read again and again stay in the pool. A buffer that was never
+/// used goes first.
+#[derive(Default)]
+pub struct LruPolicy {
+    last_unpinned: HashMap<usize, u64>,
+    clock: u64,
+}
+
+impl LruPolicy {
+    pub fn new() -> LruPolicy {
+        Self::default()
+    }
+}
+
+impl ReplacementPolicy for LruPolicy {
+    fn new_policy(&self) -> Box<dyn ReplacementPolicy> {
+        Box::new(LruPolicy::new())
+    }
+
+    fn unpinned(&mut self, buffer: usize) {
+        self.clock += 1;
+        self.last_unpinned.insert(buffer, self.clock);
+    }
+
+    fn victim(&mut self, pinned: &[bool]) -> Option<usize> {
+        (0..pinned.len())
+            .filter(|buffer| !pinned[*buffer])
+            .min_by_key(|buffer| self.last_unpinned.get(buffer).copied().unwrap_or(0))
+    }
+}
+
+/// Second chance replacement: a hand sweeps the pool, and each pin sets the
+/// buffer's reference bit. The hand clears the bits it passes and stops at
+/// the first unpinned buffer whose bit is already clear. Only a bit per
+/// buffer is kept, where `LruPolicy` orders every release.
+#[derive(Default)]
+pub struct ClockPolicy {
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl ClockPolicy {
+    pub fn new() -> ClockPolicy {
+        Self::default()
+    }
+}
+
+impl ReplacementPolicy for ClockPolicy {
+    fn new_policy(&self) -> Box<dyn ReplacementPolicy> {
+        Box::new(ClockPolicy::new())
+    }
+
+    fn pinned(&mut self, buffer: usize) {
+        if buffer >= self.referenced.len() {
+            self.referenced.resize(buffer + 1, false);
+        }
+        self.referenced[buffer] = true;
+    }
+
+    fn victim(&mut self, pinned: &[bool]) -> Option<usize> {
+        let size = pinned.len();
+        self.referenced.resize(size, false);
+        // Two turns clear every bit, so an unpinned buffer is found by then.
+        for _ in 0..2 * size {
+            let buffer = self.hand;
+            self.hand = (self.hand + 1) % size;
+            if pinned[buffer] {
+                continue;
             }
+            if !self.referenced[buffer] {
+                return Some(buffer);
+            }
+            self.referenced[buffer] = false;
+        }
+        None
+    }
+}
+
+/// LRU-K: reassigns the buffer whose `k`th most recent pin is oldest, so a
+/// block read once by a sequential scan goes before blocks that are pinned
+/// again and again, such as catalog and index blocks. Buffers pinned fewer
+/// than `k` times go first, the one released longest ago first among them.
+///
+/// A pin counts only when the buffer was not pinned already, so the pins a
+/// scan takes on a block it is still reading are one reference. A block's
+/// history is forgotten once its buffer is reassigned, and a prefetched
+/// block has no pins to its name until it is read.
+pub struct LruKPolicy {
+    k: usize,
+    history: HashMap<usize, PinHistory>,
+    clock: u64,
+}
+
+#[derive(Default)]
+struct PinHistory {
+    // Most recent first, at most `k` of them.
+    pins: VecDeque<u64>,
+    last_unpinned: u64,
+    pinned: bool,
+}
+
+impl LruKPolicy {
+    /// `k` is at least 1; with 1, the buffer pinned least recently goes
+    /// first.
+    pub fn new(k: usize) -> LruKPolicy {
+        LruKPolicy {
+            k: k.max(1),
+            history: HashMap::new(),
+            clock: 0,
         }
     }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl Default for LruKPolicy {
+    fn default() -> Self {
+        LruKPolicy::new(2)
+    }
+}
+
+impl ReplacementPolicy for LruKPolicy {
+    fn new_policy(&self) -> Box<dyn ReplacementPolicy> {
+        Box::new(LruKPolicy::new(self.k))
+    }
+
+    fn pinned(&mut self, buffer: usize) {
+        let now = self.tick();
+        let history = self.history.entry(buffer).or_default();
+        if !history.pinned {
+            history.pins.push_front(now);
+            history.pins.truncate(self.k);
+            history.pinned = true;
+        }
+    }
+
+    fn unpinned(&mut self, buffer: usize) {
+        let now = self.tick();
+        let history = self.history.entry(buffer).or_default();
+        history.pinned = false;
+        history.last_unpinned = now;
+    }
+
+    fn assigned(&mut self, buffer: usize) {
+        self.history.remove(&buffer);
+    }
+
+    fn prefetched(&mut self, buffer: usize) {
+        self.unpinned(buffer);
+    }
+
+    fn victim(&mut self, pinned: &[bool]) -> Option<usize> {
+        (0..pinned.len())
+            .filter(|buffer| !pinned[*buffer])
+            .min_by_key(|buffer| match self.history.get(buffer) {
+                None => (false, 0),
+                Some(history) => match history.pins.get(self.k - 1) {
+                    Some(kth_pin) => (true, *kth_pin),
+                    None => (false, history.last_unpinned),
+                },
+            })
+    }
+}
+
+/// The replacement policy of a database's buffer pool, as chosen when the
+/// database is opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Reassigns the buffer released longest ago.
+    #[default]
+    Lru,
+    /// Second chance replacement, which keeps a bit per buffer.
+    Clock,
+    /// LRU-K with the given `k`, which keeps blocks read once by a scan from
+    /// pushing out blocks that are used again and again.
+    LruK(usize),
+}
+
+impl BufferPolicy {
+    fn policy(&self) -> Box<dyn ReplacementPolicy> {
+        match self {
+            BufferPolicy::Lru => Box::new(LruPolicy::new()),
+            BufferPolicy::Clock => Box::new(ClockPolicy::new()),
+            BufferPolicy::LruK(k) => Box::new(LruKPolicy::new(*k)),
+        }
+    }
+}
+
+/// A pin that gave up before a buffer came free for its block.
+#[derive(Debug)]
+pub struct BufferAbortError {
+    block_id: BlockId,
+    waited: Duration,
+    attempts: u32,
+}
+
+impl BufferAbortError {
+    pub(crate) fn new(block_id: &BlockId, waited: Duration, attempts: u32) -> BufferAbortError {
+        BufferAbortError {
+            block_id: block_id.clone(),
+            waited,
+            attempts,
+        }
+    }
+
+    /// How long the pin waited before giving up.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// How many times the pin looked for a free buffer, the first try
+    /// included.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+impl Display for BufferAbortError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timed out waiting for a buffer for {} after {} ms and {} attempts",
+            self.block_id,
+            self.waited.as_millis(),
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for BufferAbortError {}
+
+/// What the buffer pool has done since it was created or its counters were
+/// last reset, and the pins held on it now. Pins through a buffer ring count
+/// like pins in the pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    /// Pins that found their block already in a buffer.
+    pub hits: u64,
+    /// Pins that read their block from disk.
+    pub misses: u64,
+    /// Blocks that left a buffer to make room for another.
+    pub evictions: u64,
+    /// Modified blocks written to disk, whether on eviction or by a flush.
+    pub flushes: u64,
+    /// Pins that had to wait for a buffer to come free.
+    pub pin_waits: u64,
+    /// Blocks read into the pool by a prefetch, before anyone pinned them.
+    pub prefetches: u64,
+    /// Buffers pinned now.
+    pub pinned_buffers: usize,
+    /// Pins held now, over all buffers.
+    pub pins: usize,
+    /// Pins waiting now for a buffer to come free.
+    pub waiters: usize,
+}
+
+impl BufferStats {
+    /// The share of pins that found their block in a buffer, or `None`
+    /// before the first pin.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let pins = self.hits + self.misses;
+        (pins > 0).then(|| self.hits as f64 / pins as f64)
+    }
 }
 
+/// The buffer pool, shared by every transaction and every thread. The pool
+/// may be split into partitions, each holding its share of the buffers and
+/// the blocks that hash to it, so that pins of different blocks do not wait
+/// on one another. A partition's bookkeeping sits behind its own mutex,
+/// which is always taken before the lock of any single buffer; a caller
+/// waiting for a buffer to come free sleeps on a condition variable instead
+/// of holding on to it.
 pub struct BufferManager {
-    file_manager: Rc<RefCell<FileManager>>,
-    log_manager: Rc<RefCell<LogManager>>,
-    buffer_pool: Vec<Rc<RefCell<Buffer>>>,
-    buff_n_available: AtomicI32,
+    file_manager: Arc<Mutex<FileManager>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    partitions: Vec<Partition>,
+    // Where prefetches go while a `Prefetcher` runs.
+    prefetcher: Mutex<Option<Sender<Vec<BlockId>>>>,
+}
+
+struct Partition {
+    pool: Mutex<BufferPool>,
+    // Signalled whenever a buffer of the partition comes free, or the waiter
+    // at the front of its queue changes.
+    freed: Condvar,
+}
+
+struct BufferPool {
+    buffer_pool: Vec<Arc<RwLock<Buffer>>>,
+    buff_n_available: i32,
+    // Tickets of callers waiting for an unpinned buffer, oldest first. Only the
+    // waiter at the front may claim a freed buffer so the pool is handed out in
+    // FIFO order instead of to whoever retries first.
+    waiters: VecDeque<u64>,
+    next_ticket: u64,
+    trace: Option<BufferTrace>,
+    rings: HashMap<i32, BufferRing>,
+    max_wait: Duration,
+    max_retries: Option<u32>,
+    policy: Box<dyn ReplacementPolicy>,
+    // Where each resident block is in `buffer_pool`, so that pinning one
+    // does not search the pool.
+    page_table: HashMap<BlockId, usize>,
+    // Only the counters are kept up to date; the pins are counted when the
+    // stats are asked for.
+    stats: BufferStats,
 }
 
 impl BufferManager {
-    const MAX_TIME: u128 = 1000;
+    /// How long a pin waits for a free buffer unless told otherwise.
+    pub const MAX_TIME: Duration = Duration::from_millis(1000);
 
-    fn new(
-        file_manager: Rc<RefCell<FileManager>>,
-        log_manager: Rc<RefCell<LogManager>>,
+    /// A pool of `buff_n` buffers that reassigns the least recently used
+    /// one.
+    pub fn new(
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
         buff_n: i32,
     ) -> BufferManager {
-        let mut buffer_pool = vec![];
-        for _ in 0..buff_n {
-            buffer_pool.push(Rc::new(RefCell::new(Buffer::new(
-                file_manager.clone(),
-                log_manager.clone(),
-            ))));
-        }
-        let buff_n_available = AtomicI32::new(buff_n);
+        Self::builder(file_manager, log_manager, buff_n).build()
+    }
+
+    pub fn builder(
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
+        buff_n: i32,
+    ) -> BufferManagerBuilder {
+        BufferManagerBuilder::new(file_manager, log_manager, buff_n)
+    }
+
+    fn from_builder(builder: BufferManagerBuilder) -> BufferManager {
+        let BufferManagerBuilder {
+            file_manager,
+            log_manager,
+            buff_n,
+            policy,
+            max_wait,
+            max_retries,
+            partitions,
+        } = builder;
+        // Every partition has at least one buffer; the first ones take the
+        // buffers that do not divide evenly.
+        let partition_n = partitions.clamp(1, buff_n.max(1) as usize);
+        let partitions = (0..partition_n)
+            .map(|i| {
+                let size =
+                    buff_n as usize / partition_n + usize::from(i < buff_n as usize % partition_n);
+                let buffer_pool = (0..size)
+                    .map(|_| {
+                        Arc::new(RwLock::new(Buffer::new(
+                            file_manager.clone(),
+                            log_manager.clone(),
+                        )))
+                    })
+                    .collect();
+                Partition {
+                    pool: Mutex::new(BufferPool {
+                        buffer_pool,
+                        buff_n_available: size as i32,
+                        waiters: VecDeque::new(),
+                        next_ticket: 0,
+                        trace: None,
+                        rings: HashMap::new(),
+                        max_wait,
+                        max_retries,
+                        policy: policy.new_policy(),
+                        page_table: HashMap::new(),
+                        stats: BufferStats::default(),
+                    }),
+                    freed: Condvar::new(),
+                }
+            })
+            .collect();
 
         BufferManager {
             file_manager,
             log_manager,
-            buffer_pool,
-            buff_n_available,
+            partitions,
+            prefetcher: Mutex::new(None),
         }
     }
-    pub fn pin(&mut self, block_id: &BlockId) -> Option<Rc<RefCell<Buffer>>> {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
+
+    #[cfg(test)]
+    pub fn partition_count(&self) -> usize {
+        self.partitions.len()
+    }
+
+    fn partition(&self, block_id: &BlockId) -> &Partition {
+        let mut hasher = DefaultHasher::new();
+        block_id.hash(&mut hasher);
+        &self.partitions[hasher.finish() as usize % self.partitions.len()]
+    }
+
+    fn pool(&self, block_id: &BlockId) -> MutexGuard<'_, BufferPool> {
+        self.partition(block_id).pool.lock().unwrap()
+    }
+
+    // Every partition locked, in order, for what looks at the whole pool.
+    fn pools(&self) -> Vec<MutexGuard<'_, BufferPool>> {
+        self.partitions
+            .iter()
+            .map(|partition| partition.pool.lock().unwrap())
+            .collect()
+    }
+
+    /// From now on, blocks `txn` pins that are not already in the pool are
+    /// read into `size` private buffers instead, leaving the pool as it was.
+    /// A size of zero goes back to the pool. Ring buffers are only read: a
+    /// block is moved into the pool before it is changed. Each partition
+    /// gets its share of the ring, and at least one buffer.
+    pub fn set_buffer_ring(&self, txn: i32, size: usize) {
+        let share = size.div_ceil(self.partitions.len());
+        for mut pool in self.pools() {
+            if size == 0 {
+                pool.rings.remove(&txn);
+                continue;
+            }
+            let buffers = (0..share)
+                .map(|_| {
+                    Arc::new(RwLock::new(Buffer::new(
+                        self.file_manager.clone(),
+                        self.log_manager.clone(),
+                    )))
+                })
+                .collect();
+            pool.rings.insert(
+                txn,
+                BufferRing {
+                    buffers,
+                    next: 0,
+                    size,
+                },
+            );
+        }
+    }
+
+    pub fn buffer_ring_size(&self, txn: i32) -> usize {
+        self.partitions[0]
+            .pool
+            .lock()
             .unwrap()
-            .as_millis();
+            .rings
+            .get(&txn)
+            .map_or(0, |ring| ring.size)
+    }
+
+    /// Whether `buffer` is one of the ring buffers of `txn`.
+    pub fn in_buffer_ring(&self, buffer: &Arc<RwLock<Buffer>>, txn: i32) -> bool {
+        let block_id = buffer.read().unwrap().block_id().clone();
+        block_id.is_some_and(|block_id| self.pool(&block_id).in_buffer_ring(buffer, txn))
+    }
+
+    /// How long a pin waits for a buffer to come free before giving up with
+    /// `DbError::BufferAbort`. Defaults to one second.
+    pub fn set_max_wait(&self, max_wait: Duration) {
+        for mut pool in self.pools() {
+            pool.max_wait = max_wait;
+        }
+    }
+
+    /// How many times a waiting pin looks for a free buffer again after its
+    /// first try, each time it is woken, before giving up even if it has
+    /// time left. `Some(0)` fails at once when the pool is full; `None`, the
+    /// default, retries until the wait runs out.
+    pub fn set_max_retries(&self, max_retries: Option<u32>) {
+        for mut pool in self.pools() {
+            pool.max_retries = max_retries;
+        }
+    }
+
+    /// Starts keeping the last `capacity` pin, unpin, flush and evict events
+    /// of each partition. A capacity of zero turns the trace off.
+    pub fn set_trace_capacity(&self, capacity: usize) {
+        for mut pool in self.pools() {
+            pool.trace = (capacity > 0).then(|| BufferTrace {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+            });
+        }
+    }
+
+    /// The traced events of every partition, oldest first.
+    pub fn trace(&self) -> Vec<BufferEvent> {
+        let mut events: Vec<BufferEvent> = self
+            .pools()
+            .iter()
+            .filter_map(|pool| pool.trace.as_ref())
+            .flat_map(|trace| trace.events.iter().cloned())
+            .collect();
+        // Stable, so that one partition's events keep their order.
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let mut stats = BufferStats::default();
+        for pool in self.pools() {
+            stats.hits += pool.stats.hits;
+            stats.misses += pool.stats.misses;
+            stats.evictions += pool.stats.evictions;
+            stats.flushes += pool.stats.flushes;
+            stats.pin_waits += pool.stats.pin_waits;
+            stats.prefetches += pool.stats.prefetches;
+            stats.waiters += pool.waiters.len();
+            let ring_buffers = pool.rings.values().flat_map(|ring| ring.buffers.iter());
+            for buffer in pool.buffer_pool.iter().chain(ring_buffers) {
+                let pins = buffer.read().unwrap().pin_count();
+                if pins > 0 {
+                    stats.pinned_buffers += 1;
+                    stats.pins += pins;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Sets the counters back to zero, to measure a workload on its own.
+    pub fn reset_stats(&self) {
+        for mut pool in self.pools() {
+            pool.stats = BufferStats::default();
+        }
+    }
+
+    /// Records a pin that a transaction satisfied from a buffer it already
+    /// holds, without asking the buffer manager for it.
+    pub fn note_repin(&self, block_id: &BlockId, txn: i32) {
+        self.pool(block_id)
+            .record(BufferEventKind::Pin, block_id, Some(txn));
+    }
+
+    pub fn pin(&self, block_id: &BlockId, txn: i32) -> Result<Arc<RwLock<Buffer>>, DbError> {
+        {
+            let mut pool = self.pool(block_id);
+            if pool.rings.contains_key(&txn) && pool.find_buffer(block_id).is_none() {
+                let buffer = pool.pin_in_ring(block_id, txn)?.ok_or_else(|| {
+                    DbError::BufferAbort(BufferAbortError::new(block_id, Duration::ZERO, 1))
+                })?;
+                pool.record(BufferEventKind::Pin, block_id, Some(txn));
+                return Ok(buffer);
+            }
+        }
+        self.pin_in_pool(block_id, txn)
+    }
+
+    /// Pins the block in the main pool even if `txn` reads through a ring.
+    pub fn pin_in_pool(
+        &self,
+        block_id: &BlockId,
+        txn: i32,
+    ) -> Result<Arc<RwLock<Buffer>>, DbError> {
+        let started = Instant::now();
+        let partition = self.partition(block_id);
+        let mut pool = partition.pool.lock().unwrap();
+        let ticket = pool.enqueue_waiter();
+        let mut attempts = 0;
         loop {
-            if let Some(buffer) = self.try_pin(block_id) {
-                return Some(buffer);
-            } else {
-                if self.waiting_too_long(timestamp) {
-                    return None;
+            attempts += 1;
+            let pinned = match pool.try_pin(ticket, block_id) {
+                Ok(pinned) => pinned,
+                Err(err) => {
+                    pool.dequeue_waiter(ticket);
+                    partition.freed.notify_all();
+                    return Err(err);
+                }
+            };
+            if let Some(buffer) = pinned {
+                pool.record(BufferEventKind::Pin, block_id, Some(txn));
+                // The next waiter in line may be able to go now.
+                partition.freed.notify_all();
+                return Ok(buffer);
+            }
+            let waited = started.elapsed();
+            let may_retry = pool.max_retries.is_none_or(|max| attempts <= max);
+            if waited >= pool.max_wait || !may_retry {
+                pool.dequeue_waiter(ticket);
+                partition.freed.notify_all();
+                // Usually some earlier pin was never released; the trace
+                // shows which.
+                if let Some(trace) = pool.trace.as_ref() {
+                    log::warn!("gave up pinning {:?}, recent buffer events:", block_id);
+                    for event in trace.events.iter() {
+                        log::warn!("  {}", event);
+                    }
                 }
+                return Err(DbError::BufferAbort(BufferAbortError::new(
+                    block_id, waited, attempts,
+                )));
+            }
+            if attempts == 1 {
+                pool.stats.pin_waits += 1;
+            }
+            let remaining = pool.max_wait - waited;
+            pool = partition.freed.wait_timeout(pool, remaining).unwrap().0;
+        }
+    }
+
+    /// Reads `block_ids` into unpinned buffers of the pool, so that pinning
+    /// them later finds them there. The blocks are handed to the
+    /// `Prefetcher` if one runs, and read before returning otherwise. A
+    /// prefetch is only a hint and never waits: blocks already resident are
+    /// skipped, and it stops at the first block with no free buffer, or as
+    /// soon as a pin is waiting for one.
+    pub fn prefetch(&self, block_ids: &[BlockId]) {
+        let prefetcher = self.prefetcher.lock().unwrap().clone();
+        if let Some(prefetcher) = prefetcher {
+            if prefetcher.send(block_ids.to_vec()).is_ok() {
+                return;
+            }
+        }
+        self.read_ahead(block_ids);
+    }
+
+    /// Whether a `Prefetcher` reads prefetched blocks in the background.
+    pub fn prefetching(&self) -> bool {
+        self.prefetcher.lock().unwrap().is_some()
+    }
+
+    fn read_ahead(&self, block_ids: &[BlockId]) {
+        // A prefetch does not push out the blocks it has just read.
+        let mut read = Vec::new();
+        for block_id in block_ids {
+            let partition = self.partition(block_id);
+            let mut pool = partition.pool.lock().unwrap();
+            if pool.find_buffer(block_id).is_some() {
+                continue;
             }
+            if !pool.waiters.is_empty() {
+                return;
+            }
+            let Some(pos) = pool.find_unpinned_buffer() else {
+                return;
+            };
+            let buffer = (partition as *const Partition, pos);
+            if read.contains(&buffer) {
+                return;
+            }
+            read.push(buffer);
+            if let Err(err) = pool.assign(pos, block_id) {
+                log::warn!("failed to prefetch {:?}: {}", block_id, err);
+                return;
+            }
+            // Ranked so that it is not the next buffer to go.
+            pool.policy.prefetched(pos);
+            pool.stats.prefetches += 1;
+            pool.record(BufferEventKind::Prefetch, block_id, None);
         }
     }
 
-    pub fn unpin(&mut self, buffer: &mut Buffer) {
-        buffer.unpin();
-        if !buffer.pinned() {
-            self.buff_n_available.fetch_add(1, Ordering::Relaxed);
+    pub fn unpin(&self, buffer: &Arc<RwLock<Buffer>>, txn: i32) {
+        // A pinned buffer keeps its block, so it can be read before the
+        // partition is locked.
+        let partition = match buffer.read().unwrap().block_id() {
+            Some(block_id) => self.partition(block_id),
+            None => &self.partitions[0],
+        };
+        let mut pool = partition.pool.lock().unwrap();
+        let buf = buffer.read().unwrap();
+        buf.unpin();
+        if !buf.pinned() && !pool.in_buffer_ring(buffer, txn) {
+            pool.buff_n_available += 1;
+            if let Some(pos) = pool.position(buffer) {
+                pool.policy.unpinned(pos);
+            }
+            partition.freed.notify_all();
+        }
+        if let Some(block_id) = buf.block_id().clone() {
+            pool.record(BufferEventKind::Unpin, &block_id, Some(txn));
         }
     }
 
     pub fn available_buffers(&self) -> i32 {
-        self.buff_n_available.load(Ordering::Relaxed)
+        self.pools().iter().map(|pool| pool.buff_n_available).sum()
+    }
+
+    /// Flushes every buffer modified by `txn_num`. Buffers holding adjacent
+    /// blocks of the same file are written together, in block order, with one
+    /// write per run so the flush turns into sequential IO.
+    pub fn flush_all_buffers(&self, txn_num: i32) -> Result<(), DbError> {
+        self.flush_where(|buffer| buffer.modifying_txn() == Some(txn_num))
     }
 
-    pub fn flush_all_buffers(&mut self, txn_num: i32) {
-        for buffer in self.buffer_pool.iter() {
-            if buffer
-                .borrow_mut()
-                .modifying_txn()
-                .is_some_and(|txn| txn == txn_num)
-            {
-                buffer.borrow_mut().flush();
+    /// Flushes every modified buffer that nobody has pinned when the flush
+    /// starts. Each is written as it stands once its buffer is locked, after
+    /// the log records behind it, as for any flush.
+    pub fn flush_unpinned(&self) -> Result<(), DbError> {
+        self.flush_where(|buffer| !buffer.pinned())
+    }
+
+    /// Writes every modified buffer, whichever transaction changed it, and
+    /// syncs the data files, as a checkpoint needs before the log records behind them can go.
+    pub fn flush_all(&self) -> Result<(), DbError> {
+        self.flush_where(|_| true)?;
+        self.file_manager.lock().unwrap().sync_all()
+    }
+
+    // The partitions are only locked while the dirty buffers are collected,
+    // so pins go on while the blocks are written. Each buffer is then
+    // write-locked, in block order, until its run is on disk; one that was
+    // reassigned or flushed in between, or that `selected` no longer picks,
+    // is left out. A buffer pinned in between may be in the middle of a
+    // change whose LSN it does not carry yet.
+    fn flush_where(&self, selected: impl Fn(&Buffer) -> bool) -> Result<(), DbError> {
+        // Each dirty block with the partition it is in.
+        let mut dirty: Vec<(BlockId, Arc<RwLock<Buffer>>, usize)> = Vec::new();
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let mut pool = partition.pool.lock().unwrap();
+            let mut found = Vec::new();
+            for buffer in pool.buffer_pool.iter() {
+                let buf = buffer.read().unwrap();
+                if !buf.is_dirty() || !selected(&buf) {
+                    continue;
+                }
+                match buf.block_id() {
+                    None => log::warn!("no block id provided"),
+                    Some(blid) => found.push((blid.clone(), buffer.clone(), i)),
+                }
+            }
+            for (block_id, buffer, _) in found.iter() {
+                let txn = buffer.read().unwrap().modifying_txn();
+                pool.record(BufferEventKind::Flush, block_id, txn);
             }
+            dirty.extend(found);
         }
+        dirty.sort_by(|(a, _, _), (b, _, _)| {
+            a.file_name()
+                .cmp(&b.file_name())
+                .then(a.block_num().cmp(&b.block_num()))
+        });
+
+        let mut flushes = vec![0; self.partitions.len()];
+        let mut run: Vec<(RwLockWriteGuard<'_, Buffer>, usize)> = Vec::new();
+        for (block_id, buffer, i) in dirty.iter() {
+            let buf = buffer.write().unwrap();
+            if !buf.is_dirty() || buf.block_id().as_ref() != Some(block_id) || !selected(&buf) {
+                continue;
+            }
+            let follows = run.last().is_some_and(|(last, _)| {
+                last.block_id().as_ref().is_some_and(|last| {
+                    last.file_name() == block_id.file_name()
+                        && last.block_num() + 1 == block_id.block_num()
+                })
+            });
+            if !follows {
+                self.write_run(&mut run, &mut flushes)?;
+            }
+            run.push((buf, *i));
+        }
+        self.write_run(&mut run, &mut flushes)?;
+        for (partition, flushes) in self.partitions.iter().zip(flushes) {
+            if flushes > 0 {
+                partition.pool.lock().unwrap().stats.flushes += flushes;
+            }
+        }
+        Ok(())
     }
 
-    fn waiting_too_long(&mut self, start_time: u128) -> bool {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
+    // Writes the buffers of adjacent blocks with one write, after the log
+    // records behind them, and leaves `run` empty.
+    fn write_run(
+        &self,
+        run: &mut Vec<(RwLockWriteGuard<'_, Buffer>, usize)>,
+        flushes: &mut [u64],
+    ) -> Result<(), DbError> {
+        let Some(block_id) = run.first().and_then(|(buf, _)| buf.block_id().clone()) else {
+            return Ok(());
+        };
+        if let Some(lsn) = run.iter().filter_map(|(buf, _)| buf.lsn()).max() {
+            self.log_manager.lock().unwrap().flush_lsn(lsn)?;
+        }
+        let contents: Vec<Arc<RwLock<Page>>> = run.iter().map(|(buf, _)| buf.contents()).collect();
+        let borrowed: Vec<_> = contents.iter().map(|page| page.read().unwrap()).collect();
+        let pages: Vec<&Page> = borrowed.iter().map(|page| page.deref()).collect();
+        self.file_manager
+            .lock()
             .unwrap()
-            .as_millis()
-            - start_time
-            > Self::MAX_TIME
+            .write_blocks(&block_id, &pages)?;
+        for (mut buf, i) in run.drain(..) {
+            buf.mark_flushed();
+            flushes[i] += 1;
+        }
+        Ok(())
     }
+}
 
-    fn try_pin(&mut self, block_id: &BlockId) -> Option<Rc<RefCell<Buffer>>> {
-        if let Some(buffer) = self.find_buffer(block_id) {
-            Some(buffer)
-        } else {
-            self.find_unpinned_buffer()
+/// Configures a `BufferManager`. Without changes it reassigns the least
+/// recently used buffer and a pin waits up to a second for one, retrying
+/// whenever a buffer comes free.
+pub struct BufferManagerBuilder {
+    file_manager: Arc<Mutex<FileManager>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    buff_n: i32,
+    policy: Box<dyn ReplacementPolicy>,
+    max_wait: Duration,
+    max_retries: Option<u32>,
+    partitions: usize,
+}
+
+impl BufferManagerBuilder {
+    pub fn new(
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
+        buff_n: i32,
+    ) -> Self {
+        Self {
+            file_manager,
+            log_manager,
+            buff_n,
+            policy: BufferPolicy::Lru.policy(),
+            max_wait: BufferManager::MAX_TIME,
+            max_retries: None,
+            partitions: 1,
         }
     }
 
-    fn find_buffer(&mut self, block_id: &BlockId) -> Option<Rc<RefCell<Buffer>>> {
-        for buffer in self.buffer_pool.clone() {
-            if let Some(blid) = buffer.clone().borrow_mut().block_id() {
-                if blid == block_id {
-                    return Some(buffer);
+    pub fn policy(mut self, policy: BufferPolicy) -> Self {
+        self.policy = policy.policy();
+        self
+    }
+
+    /// See `BufferManager::set_max_wait`.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// See `BufferManager::set_max_retries`.
+    pub fn max_retries(mut self, max_retries: Option<u32>) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Splits the pool into `partitions` parts, at most one per buffer. A
+    /// block always goes into the partition its id hashes to, and waits
+    /// only for that partition's buffers to come free, so a partition can
+    /// run out while others still have room. `policy` is followed within
+    /// each partition.
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions;
+        self
+    }
+
+    pub fn build(self) -> BufferManager {
+        BufferManager::from_builder(self)
+    }
+}
+
+/// A thread that flushes the dirty, unpinned buffers of the pool every
+/// `interval`, so that the writes are spread out instead of piling up when a
+/// checkpoint or an eviction needs the buffers clean. It stops when dropped.
+pub struct BackgroundWriter {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    pub fn start(buffer_manager: Arc<BufferManager>, interval: Duration) -> BackgroundWriter {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            // Dropping the sender ends the wait early.
+            while stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                if let Err(err) = buffer_manager.flush_unpinned() {
+                    log::warn!("background writer failed to flush buffers: {}", err);
                 }
             }
+        });
+        BackgroundWriter {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("background writer panicked");
+            }
         }
-        None
     }
+}
 
-    fn find_unpinned_buffer(&mut self) -> Option<Rc<RefCell<Buffer>>> {
-        for buffer in self.buffer_pool.clone() {
-            if !buffer.borrow_mut().pinned() {
-                return Some(buffer);
+/// A thread that reads the blocks handed to `BufferManager::prefetch`, so
+/// that the reads overlap with whatever the caller does next. It stops when
+/// dropped, and prefetches are read by their caller again.
+pub struct Prefetcher {
+    buffer_manager: Arc<BufferManager>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    pub fn start(buffer_manager: Arc<BufferManager>) -> Prefetcher {
+        let (blocks, requested) = mpsc::channel::<Vec<BlockId>>();
+        *buffer_manager.prefetcher.lock().unwrap() = Some(blocks);
+        let reader = buffer_manager.clone();
+        // The buffer manager holds the only sender, so the loop ends once
+        // `drop` takes it away.
+        let thread = std::thread::spawn(move || {
+            while let Ok(block_ids) = requested.recv() {
+                reader.read_ahead(&block_ids);
             }
+        });
+        Prefetcher {
+            buffer_manager,
+            thread: Some(thread),
         }
-        None
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.buffer_manager.prefetcher.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("prefetcher panicked");
+            }
+        }
+    }
+}
+
+impl BufferPool {
+    fn record(&mut self, kind: BufferEventKind, block_id: &BlockId, txn: Option<i32>) {
+        let Some(trace) = self.trace.as_mut() else {
+            return;
+        };
+        if trace.events.len() == trace.capacity {
+            trace.events.pop_front();
+        }
+        trace.events.push_back(BufferEvent {
+            kind,
+            file_name: block_id.file_name(),
+            block_num: block_id.block_num(),
+            txn,
+            thread: std::thread::current().id(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    fn in_buffer_ring(&self, buffer: &Arc<RwLock<Buffer>>, txn: i32) -> bool {
+        self.rings
+            .get(&txn)
+            .is_some_and(|ring| ring.contains(buffer))
+    }
+
+    fn enqueue_waiter(&mut self) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push_back(ticket);
+        ticket
+    }
+
+    fn dequeue_waiter(&mut self, ticket: u64) {
+        self.waiters.retain(|waiter| *waiter != ticket);
+    }
+
+    fn try_pin(
+        &mut self,
+        ticket: u64,
+        block_id: &BlockId,
+    ) -> Result<Option<Arc<RwLock<Buffer>>>, DbError> {
+        // A block that is already resident does not consume a free buffer, so
+        // its waiter does not need to wait for its turn in the queue.
+        let pos = match self.find_buffer(block_id) {
+            Some(pos) => {
+                self.stats.hits += 1;
+                pos
+            }
+            None => {
+                if self.waiters.front() != Some(&ticket) {
+                    return Ok(None);
+                }
+                let Some(pos) = self.find_unpinned_buffer() else {
+                    return Ok(None);
+                };
+                self.assign(pos, block_id)?;
+                self.stats.misses += 1;
+                pos
+            }
+        };
+        self.dequeue_waiter(ticket);
+        let buffer = self.buffer_pool[pos].clone();
+        let buf = buffer.read().unwrap();
+        if !buf.pinned() {
+            self.buff_n_available -= 1;
+        }
+        buf.pin();
+        drop(buf);
+        self.policy.pinned(pos);
+        Ok(Some(buffer))
+    }
+
+    // Reassigns the unpinned buffer at `pos` to the block, writing out the
+    // block it held first if that was modified.
+    fn assign(&mut self, pos: usize, block_id: &BlockId) -> Result<(), DbError> {
+        let buffer = self.buffer_pool[pos].clone();
+        let evicted = buffer.read().unwrap().block_id().clone();
+        let was_dirty = buffer.read().unwrap().is_dirty();
+        if let Some(evicted) = evicted.as_ref() {
+            let txn = buffer.read().unwrap().modifying_txn();
+            self.record(BufferEventKind::Evict, evicted, txn);
+            self.page_table.remove(evicted);
+        }
+        let assigned = buffer.write().unwrap().assign_to_block(block_id);
+        // A buffer whose old contents could not be written out still holds
+        // its old block.
+        if let Some(resident) = buffer.read().unwrap().block_id() {
+            self.page_table.insert(resident.clone(), pos);
+        }
+        assigned?;
+        self.policy.assigned(pos);
+        self.stats.evictions += evicted.is_some() as u64;
+        self.stats.flushes += was_dirty as u64;
+        Ok(())
+    }
+
+    fn find_buffer(&self, block_id: &BlockId) -> Option<usize> {
+        self.page_table.get(block_id).copied()
+    }
+
+    fn position(&self, buffer: &Arc<RwLock<Buffer>>) -> Option<usize> {
+        self.buffer_pool
+            .iter()
+            .position(|pool_buffer| Arc::ptr_eq(pool_buffer, buffer))
+    }
+
+    // The ring's buffer for the block if it has one, or else the next
+    // unpinned ring buffer, reassigned to it.
+    fn pin_in_ring(
+        &mut self,
+        block_id: &BlockId,
+        txn: i32,
+    ) -> Result<Option<Arc<RwLock<Buffer>>>, DbError> {
+        let Some(ring) = self.rings.get_mut(&txn) else {
+            return Ok(None);
+        };
+        let resident = ring
+            .buffers
+            .iter()
+            .find(|buffer| buffer.read().unwrap().block_id().as_ref() == Some(block_id))
+            .cloned();
+        let buffer = match resident {
+            Some(buffer) => {
+                self.stats.hits += 1;
+                buffer
+            }
+            None => {
+                let size = ring.buffers.len();
+                let Some(pos) = (0..size)
+                    .map(|offset| (ring.next + offset) % size)
+                    .find(|pos| !ring.buffers[*pos].read().unwrap().pinned())
+                else {
+                    return Ok(None);
+                };
+                ring.next = (pos + 1) % size;
+                let buffer = ring.buffers[pos].clone();
+                let evicted = buffer.read().unwrap().block_id().is_some();
+                buffer.write().unwrap().assign_to_block(block_id)?;
+                self.stats.misses += 1;
+                self.stats.evictions += evicted as u64;
+                buffer
+            }
+        };
+        buffer.read().unwrap().pin();
+        Ok(Some(buffer))
+    }
+
+    fn find_unpinned_buffer(&mut self) -> Option<usize> {
+        let pinned: Vec<bool> = self
+            .buffer_pool
+            .iter()
+            .map(|buffer| buffer.read().unwrap().pinned())
+            .collect();
+        self.policy.victim(&pinned)
     }
 }
 
@@ -213,16 +1319,17 @@ mod buffer_tests {
     #[test]
     fn test_buffer() {
         let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
-        let file_manager = Rc::new(RefCell::new(FileManager::new(
-            tmp_dir.path().to_owned(),
-            TEST_BLOCK_SIZE,
-        )));
-        let log_manager = Rc::new(RefCell::new(
-            LogManager::builder("log.wal".to_string(), file_manager.clone()).build(),
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
         ));
 
         let buffer = Buffer::new(file_manager.clone(), log_manager.clone());
-        assert_eq!(buffer.pinned(), false);
+        assert!(!buffer.pinned());
         assert_eq!(buffer.txn, None);
         assert_eq!(buffer.lsn, None);
 
@@ -233,22 +1340,893 @@ mod buffer_tests {
 #[cfg(test)]
 mod buffer_manager_tests {
     use super::*;
+    use crate::concurrencymanager::LockTable;
+    use crate::logrecord::SetIntLogRecord;
+    use crate::transaction::Transaction;
     use tempdir::TempDir;
     const TEST_BLOCK_SIZE: usize = 16;
+
+    // The pool of a buffer manager that is not partitioned.
+    fn only_pool(buffer_manager: &BufferManager) -> MutexGuard<'_, BufferPool> {
+        assert_eq!(buffer_manager.partition_count(), 1);
+        buffer_manager.partitions[0].pool.lock().unwrap()
+    }
+
     #[test]
     fn test_buffer_manager() {
         let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
-        let file_manager = Rc::new(RefCell::new(FileManager::new(
-            tmp_dir.path().to_owned(),
-            TEST_BLOCK_SIZE,
-        )));
-        let log_manager = Rc::new(RefCell::new(
-            LogManager::builder("log.wal".to_string(), file_manager.clone()).build(),
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
         ));
 
-        let mut buffer_manager = BufferManager::new(file_manager, log_manager, 5);
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 5);
         assert_eq!(buffer_manager.available_buffers(), 5);
-        let maybe_buffer = buffer_manager.find_buffer(&BlockId::new("test", 1));
+        let maybe_buffer = only_pool(&buffer_manager).find_buffer(&BlockId::new("test", 1));
         assert!(maybe_buffer.is_none());
     }
+
+    #[test]
+    fn test_buffer_manager_pin_timeout() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+
+        for _ in 0..2 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 1);
+        let buffer = buffer_manager.pin(&BlockId::new("test", 0), 1);
+        assert!(buffer.is_ok());
+        assert_eq!(buffer_manager.available_buffers(), 0);
+        assert_eq!(buffer_manager.stats().waiters, 0);
+
+        // The pool is exhausted so this waits out the timeout and gives up its
+        // place in the queue.
+        buffer_manager.set_max_wait(Duration::from_millis(50));
+        let started = Instant::now();
+        let Err(DbError::BufferAbort(err)) = buffer_manager.pin(&BlockId::new("test", 1), 1) else {
+            panic!("pin did not time out");
+        };
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(50) && waited < BufferManager::MAX_TIME);
+        assert!(err.waited() >= Duration::from_millis(50));
+        assert_eq!(&err.block_id, &BlockId::new("test", 1));
+        assert_eq!(buffer_manager.stats().waiters, 0);
+
+        // Without retries the pin fails at once, however long it may wait.
+        buffer_manager.set_max_wait(BufferManager::MAX_TIME);
+        buffer_manager.set_max_retries(Some(0));
+        let started = Instant::now();
+        let Err(DbError::BufferAbort(err)) = buffer_manager.pin(&BlockId::new("test", 1), 1) else {
+            panic!("pin did not give up");
+        };
+        assert!(started.elapsed() < BufferManager::MAX_TIME);
+        assert_eq!(err.attempts(), 1);
+        buffer_manager.set_max_retries(None);
+
+        buffer_manager.unpin(&buffer.unwrap(), 1);
+        assert_eq!(buffer_manager.available_buffers(), 1);
+        assert!(buffer_manager.pin(&BlockId::new("test", 1), 1).is_ok());
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_unpin_wakes_waiter() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..2 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = Arc::new(
+            BufferManager::builder(file_manager, log_manager, 1)
+                .max_wait(Duration::from_secs(60))
+                .build(),
+        );
+        let buffer = buffer_manager.pin(&BlockId::new("test", 0), 1).unwrap();
+        let waiter = {
+            let buffer_manager = buffer_manager.clone();
+            std::thread::spawn(move || {
+                let started = Instant::now();
+                let buffer = buffer_manager.pin(&BlockId::new("test", 1), 2).unwrap();
+                buffer_manager.unpin(&buffer, 2);
+                started.elapsed()
+            })
+        };
+        while buffer_manager.stats().waiters == 0 {
+            std::thread::yield_now();
+        }
+
+        // The waiter sleeps until the buffer comes free rather than waiting
+        // out its timeout.
+        buffer_manager.unpin(&buffer, 1);
+        assert!(waiter.join().unwrap() < Duration::from_secs(60));
+        assert_eq!(buffer_manager.available_buffers(), 1);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_trace() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..2 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 1);
+        let buffer = buffer_manager.pin(&BlockId::new("test", 0), 1).unwrap();
+        assert!(buffer_manager.trace().is_empty());
+
+        buffer_manager.set_trace_capacity(4);
+        buffer.write().unwrap().set_modified(1, -1);
+        buffer_manager.flush_all_buffers(1).unwrap();
+        buffer_manager.unpin(&buffer, 1);
+        let buffer = buffer_manager.pin(&BlockId::new("test", 1), 2).unwrap();
+        buffer_manager.note_repin(&BlockId::new("test", 1), 2);
+        let kinds: Vec<BufferEventKind> = buffer_manager
+            .trace()
+            .iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                BufferEventKind::Unpin,
+                BufferEventKind::Evict,
+                BufferEventKind::Pin,
+                BufferEventKind::Pin
+            ]
+        );
+        let trace = buffer_manager.trace();
+        assert_eq!((trace[1].block_num, trace[1].txn), (0, None));
+        assert_eq!((trace[2].block_num, trace[2].txn), (1, Some(2)));
+        assert_eq!(trace[2].thread, std::thread::current().id());
+        assert!(trace[2].to_string().ends_with("Pin test:1 txn 2"));
+
+        buffer_manager.set_trace_capacity(0);
+        buffer_manager.unpin(&buffer, 2);
+        assert!(buffer_manager.trace().is_empty());
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_prefetch() {
+        let tmp_dir =
+            TempDir::new("test_buffer_manager_prefetch").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..6 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+        let buffer_manager = Arc::new(BufferManager::new(file_manager, log_manager, 3));
+        let resident = |block_num| {
+            only_pool(&buffer_manager)
+                .find_buffer(&BlockId::new("test", block_num))
+                .is_some()
+        };
+        let blocks = |block_nums: &[usize]| -> Vec<BlockId> {
+            block_nums
+                .iter()
+                .map(|block_num| BlockId::new("test", *block_num))
+                .collect()
+        };
+
+        // Resident blocks are skipped, and prefetched ones are pinned
+        // without a read.
+        let first = buffer_manager.pin(&BlockId::new("test", 0), 1).unwrap();
+        buffer_manager.prefetch(&blocks(&[0, 1]));
+        assert!(resident(1));
+        assert_eq!(buffer_manager.available_buffers(), 2);
+        let second = buffer_manager.pin(&BlockId::new("test", 1), 1).unwrap();
+        let stats = buffer_manager.stats();
+        assert_eq!((stats.prefetches, stats.hits, stats.misses), (1, 1, 1));
+
+        // A prefetch stops when it runs out of unpinned buffers.
+        buffer_manager.prefetch(&blocks(&[2, 3]));
+        assert!(resident(2) && !resident(3));
+        let third = buffer_manager.pin(&BlockId::new("test", 2), 1).unwrap();
+        buffer_manager.prefetch(&blocks(&[4]));
+        assert!(!resident(4));
+        for buffer in [&first, &second, &third] {
+            buffer_manager.unpin(buffer, 1);
+        }
+
+        // With a prefetcher the blocks are read on its thread; stopping it
+        // finishes what was asked for first.
+        assert!(!buffer_manager.prefetching());
+        let prefetcher = Prefetcher::start(buffer_manager.clone());
+        assert!(buffer_manager.prefetching());
+        buffer_manager.prefetch(&blocks(&[4, 5]));
+        drop(prefetcher);
+        assert!(!buffer_manager.prefetching());
+        assert!(resident(4) && resident(5));
+        assert_eq!(buffer_manager.stats().prefetches, 4);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_evicts_least_recently_used() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..5 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 3);
+        let buffers: Vec<_> = (0..3)
+            .map(|block_num| {
+                buffer_manager
+                    .pin(&BlockId::new("test", block_num), 1)
+                    .unwrap()
+            })
+            .collect();
+        for block_num in [1, 0, 2] {
+            buffer_manager.unpin(&buffers[block_num], 1);
+        }
+        // Block 0 is used again, which makes block 2 the next to go after
+        // block 1.
+        let buffer = buffer_manager.pin(&BlockId::new("test", 0), 1).unwrap();
+        buffer_manager.unpin(&buffer, 1);
+        let resident = |block_num| {
+            only_pool(&buffer_manager)
+                .find_buffer(&BlockId::new("test", block_num))
+                .is_some()
+        };
+        let buffer = buffer_manager.pin(&BlockId::new("test", 3), 1).unwrap();
+        assert!(!resident(1) && resident(0) && resident(2));
+        buffer_manager.unpin(&buffer, 1);
+        buffer_manager.pin(&BlockId::new("test", 4), 1).unwrap();
+        assert!(!resident(2) && resident(0) && resident(3));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_page_table() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..6 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 3);
+        for block_num in [0, 1, 2, 0, 3, 4, 1, 5, 0, 2] {
+            let block_id = BlockId::new("test", block_num);
+            let buffer = buffer_manager.pin(&block_id, 1).unwrap();
+            // A resident block is found where it was loaded.
+            let again = buffer_manager.pin(&block_id, 1).unwrap();
+            assert!(Arc::ptr_eq(&buffer, &again));
+            buffer_manager.unpin(&again, 1);
+            buffer_manager.unpin(&buffer, 1);
+        }
+        let pool = only_pool(&buffer_manager);
+        assert_eq!(pool.page_table.len(), 3);
+        for (block_id, pos) in pool.page_table.iter() {
+            let buffer = pool.buffer_pool[*pos].read().unwrap();
+            assert_eq!(buffer.block_id().as_ref(), Some(block_id));
+        }
+        drop(pool);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_stats() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..4 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 2);
+        buffer_manager.set_max_wait(Duration::from_millis(10));
+        assert_eq!(buffer_manager.stats().hit_ratio(), None);
+        let block = |block_num| BlockId::new("test", block_num);
+        let first = buffer_manager.pin(&block(0), 1).unwrap();
+        let again = buffer_manager.pin(&block(0), 1).unwrap();
+        let second = buffer_manager.pin(&block(1), 1).unwrap();
+        second.write().unwrap().set_modified(1, -1);
+        let stats = buffer_manager.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 2, 0));
+        assert_eq!((stats.pinned_buffers, stats.pins), (2, 3));
+        assert_eq!(stats.hit_ratio(), Some(1.0 / 3.0));
+
+        // Every buffer is pinned, so the next pin waits and gives up.
+        assert!(buffer_manager.pin(&block(2), 1).is_err());
+        assert_eq!(buffer_manager.stats().pin_waits, 1);
+
+        // Reassigning the modified buffer writes it out first.
+        buffer_manager.unpin(&second, 1);
+        let third = buffer_manager.pin(&block(2), 1).unwrap();
+        let stats = buffer_manager.stats();
+        assert_eq!((stats.misses, stats.evictions, stats.flushes), (3, 1, 1));
+
+        buffer_manager.reset_stats();
+        let stats = buffer_manager.stats();
+        assert_eq!((stats.hits, stats.misses, stats.pin_waits), (0, 0, 0));
+        assert_eq!((stats.pinned_buffers, stats.pins), (2, 3));
+        for buffer in [first, again, third] {
+            buffer_manager.unpin(&buffer, 1);
+        }
+        assert_eq!(buffer_manager.stats().pins, 0);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_background_writer() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..2 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = Arc::new(BufferManager::new(file_manager.clone(), log_manager, 3));
+        let buffers: Vec<_> = (0..2)
+            .map(|block_num| {
+                let buffer = buffer_manager
+                    .pin(&BlockId::new("test", block_num), 1)
+                    .unwrap();
+                let mut buf = buffer.write().unwrap();
+                buf.contents()
+                    .write()
+                    .unwrap()
+                    .set_int(0, Some(block_num as i32 + 7))
+                    .unwrap();
+                buf.set_modified(1, -1);
+                drop(buf);
+                buffer
+            })
+            .collect();
+        buffer_manager.unpin(&buffers[0], 1);
+
+        let writer = BackgroundWriter::start(buffer_manager.clone(), Duration::from_millis(5));
+        let started = Instant::now();
+        while buffers[0].read().unwrap().is_dirty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        drop(writer);
+        // Only the unpinned buffer was written.
+        assert!(buffers[1].read().unwrap().is_dirty());
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        for (block_num, expected) in [(0, 7), (1, 0)] {
+            file_manager
+                .lock()
+                .unwrap()
+                .read(&BlockId::new("test", block_num), &mut page)
+                .unwrap();
+            assert_eq!(page.get_int(0), Some(expected));
+        }
+        buffer_manager.unpin(&buffers[1], 1);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_flush_unpinned_races_set_int() {
+        let tmp_dir = TempDir::new("test_flush_race").expect("failed to create temp dir");
+        let block_size = 400;
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), block_size).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(
+            BufferManager::builder(file_manager.clone(), log_manager.clone(), 4)
+                .partitions(2)
+                .build(),
+        );
+        // A block for each partition: the flusher collects `changed` from
+        // the first, then waits on `held` in the second.
+        let partition = |block_id: &BlockId| {
+            let partition = buffer_manager.partition(block_id);
+            (0..2)
+                .position(|i| std::ptr::eq(&buffer_manager.partitions[i], partition))
+                .unwrap()
+        };
+        let mut blocks = [None, None];
+        while blocks.iter().any(Option::is_none) {
+            let block_id = file_manager.lock().unwrap().append("test").unwrap();
+            blocks[partition(&block_id)].get_or_insert(block_id);
+        }
+        let [Some(changed), Some(held)] = blocks else {
+            unreachable!()
+        };
+
+        // A committed change leaves `changed` dirty and unpinned, with its
+        // log record on disk.
+        let mut tx = Transaction::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            Arc::new(LockTable::new()),
+        )
+        .unwrap();
+        tx.pin(&changed).unwrap();
+        tx.set_int(&changed, 0, Some(1), true).unwrap();
+        tx.unpin(&changed);
+        tx.commit().unwrap();
+        buffer_manager.set_trace_capacity(16);
+
+        let held = buffer_manager.pin(&held, 1).unwrap();
+        let guard = held.write().unwrap();
+        let flusher = {
+            let buffer_manager = buffer_manager.clone();
+            std::thread::spawn(move || buffer_manager.flush_unpinned())
+        };
+        let collected = || {
+            let pool = buffer_manager.partitions[0].pool.lock().unwrap();
+            pool.trace.as_ref().unwrap().events.iter().any(|event| {
+                event.kind == BufferEventKind::Flush && event.block_num == changed.block_num()
+            })
+        };
+        while !collected() {
+            std::thread::yield_now();
+        }
+
+        // A set_int caught between changing the page and giving the buffer
+        // the LSN of its record, which is not on disk yet.
+        let buffer = buffer_manager.pin(&changed, 2).unwrap();
+        let lsn = SetIntLogRecord::write_to_log_record(log_manager.clone(), 2, &changed, 0, 1, 2)
+            .unwrap();
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_int(0, Some(2)).unwrap();
+        drop(guard);
+        flusher.join().unwrap().unwrap();
+        buffer.write().unwrap().set_modified(2, lsn);
+
+        // The pinned buffer was left alone rather than written ahead of
+        // its log record.
+        let mut page = Page::builder().block_size(block_size).with_buffer().build();
+        file_manager
+            .lock()
+            .unwrap()
+            .read(&changed, &mut page)
+            .unwrap();
+        assert_eq!(page.get_int(0), Some(0));
+        assert!(buffer.read().unwrap().is_dirty());
+        buffer_manager.unpin(&buffer, 2);
+        buffer_manager.unpin(&held, 1);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_clock_policy() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..5 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::builder(file_manager, log_manager, 3)
+            .policy(BufferPolicy::Clock)
+            .build();
+        for block_num in 0..3 {
+            let buffer = buffer_manager
+                .pin(&BlockId::new("test", block_num), 1)
+                .unwrap();
+            buffer_manager.unpin(&buffer, 1);
+        }
+        let resident = |block_num| {
+            only_pool(&buffer_manager)
+                .find_buffer(&BlockId::new("test", block_num))
+                .is_some()
+        };
+        // Every bit is set, so the hand goes round once clearing them and
+        // takes the first buffer.
+        let buffer = buffer_manager.pin(&BlockId::new("test", 3), 1).unwrap();
+        assert!(!resident(0) && resident(1) && resident(2));
+        buffer_manager.unpin(&buffer, 1);
+
+        // Block 1 is pinned again, which gives it a second chance over
+        // block 2.
+        let buffer = buffer_manager.pin(&BlockId::new("test", 1), 1).unwrap();
+        buffer_manager.unpin(&buffer, 1);
+        buffer_manager.pin(&BlockId::new("test", 4), 1).unwrap();
+        assert!(resident(1) && !resident(2) && resident(3));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_lru_k_policy() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..8 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::builder(file_manager, log_manager, 3)
+            .policy(BufferPolicy::LruK(2))
+            .build();
+        let touch = |block_num| {
+            let buffer = buffer_manager
+                .pin(&BlockId::new("test", block_num), 1)
+                .unwrap();
+            buffer_manager.unpin(&buffer, 1);
+        };
+        let resident = |block_num| {
+            only_pool(&buffer_manager)
+                .find_buffer(&BlockId::new("test", block_num))
+                .is_some()
+        };
+        // Block 0 is pinned twice, block 1 twice while it is still pinned,
+        // which is one reference.
+        touch(0);
+        touch(0);
+        let first = buffer_manager.pin(&BlockId::new("test", 1), 1).unwrap();
+        let second = buffer_manager.pin(&BlockId::new("test", 1), 1).unwrap();
+        buffer_manager.unpin(&first, 1);
+        buffer_manager.unpin(&second, 1);
+
+        // A scan of the other blocks cycles through the two other buffers
+        // and leaves block 0 alone.
+        for block_num in 2..8 {
+            touch(block_num);
+        }
+        assert!(resident(0) && !resident(1) && resident(6) && resident(7));
+
+        // Once the scanned blocks are pinned twice too, block 0 has the
+        // oldest second pin.
+        touch(6);
+        touch(7);
+        touch(1);
+        assert!(!resident(0) && resident(1));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_waiters_are_fifo() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+
+        for _ in 0..4 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 2);
+        let first = buffer_manager.pin(&BlockId::new("test", 0), 1).unwrap();
+        let second = buffer_manager.pin(&BlockId::new("test", 1), 1).unwrap();
+        assert_eq!(buffer_manager.available_buffers(), 0);
+
+        let slow = only_pool(&buffer_manager).enqueue_waiter();
+        let fast = only_pool(&buffer_manager).enqueue_waiter();
+        assert_eq!(buffer_manager.stats().waiters, 2);
+        assert!(only_pool(&buffer_manager)
+            .try_pin(slow, &BlockId::new("test", 2))
+            .unwrap()
+            .is_none());
+
+        // A buffer frees up but the later waiter cannot jump the queue no matter
+        // how often it retries.
+        buffer_manager.unpin(&first, 1);
+        for _ in 0..10 {
+            assert!(only_pool(&buffer_manager)
+                .try_pin(fast, &BlockId::new("test", 3))
+                .unwrap()
+                .is_none());
+        }
+        assert!(only_pool(&buffer_manager)
+            .try_pin(slow, &BlockId::new("test", 2))
+            .unwrap()
+            .is_some());
+        assert_eq!(buffer_manager.stats().waiters, 1);
+
+        // Each freed buffer wakes exactly one waiter, which is now the later one.
+        buffer_manager.unpin(&second, 1);
+        assert!(only_pool(&buffer_manager)
+            .try_pin(fast, &BlockId::new("test", 3))
+            .unwrap()
+            .is_some());
+        assert_eq!(buffer_manager.stats().waiters, 0);
+        assert_eq!(buffer_manager.available_buffers(), 0);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_ring() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..6 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+        let buffer_manager = BufferManager::new(file_manager, log_manager, 3);
+        let hot = BlockId::new("test", 0);
+        let buffer = buffer_manager.pin(&hot, 1).unwrap();
+        buffer_manager.unpin(&buffer, 1);
+
+        // A scan through a ring of two buffers leaves the pool alone, but
+        // uses a block the pool already holds.
+        buffer_manager.set_buffer_ring(2, 2);
+        assert_eq!(buffer_manager.buffer_ring_size(2), 2);
+        for block_num in 0..6 {
+            let buffer = buffer_manager
+                .pin(&BlockId::new("test", block_num), 2)
+                .unwrap();
+            assert_eq!(buffer_manager.in_buffer_ring(&buffer, 2), block_num > 0);
+            buffer_manager.unpin(&buffer, 2);
+        }
+        assert_eq!(buffer_manager.available_buffers(), 3);
+        assert!(only_pool(&buffer_manager).find_buffer(&hot).is_some());
+        assert!(only_pool(&buffer_manager)
+            .find_buffer(&BlockId::new("test", 5))
+            .is_none());
+
+        // Every ring buffer pinned leaves nothing for another block.
+        let first = buffer_manager.pin(&BlockId::new("test", 1), 2).unwrap();
+        let second = buffer_manager.pin(&BlockId::new("test", 2), 2).unwrap();
+        assert!(buffer_manager.pin(&BlockId::new("test", 3), 2).is_err());
+        buffer_manager.unpin(&first, 2);
+        buffer_manager.unpin(&second, 2);
+
+        let buffer = buffer_manager
+            .pin_in_pool(&BlockId::new("test", 4), 2)
+            .unwrap();
+        assert!(!buffer_manager.in_buffer_ring(&buffer, 2));
+        buffer_manager.set_buffer_ring(2, 0);
+        assert_eq!(buffer_manager.buffer_ring_size(2), 0);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_flush_coalesces_adjacent_blocks() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..4 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+        file_manager.lock().unwrap().append("other").unwrap();
+
+        let buffer_manager = BufferManager::new(file_manager.clone(), log_manager, 5);
+        // Dirty blocks handed out of order, with a gap at block 2 and a block
+        // from another file in between.
+        let blocks = [
+            (BlockId::new("test", 3), 1, "D"),
+            (BlockId::new("other", 0), 1, "X"),
+            (BlockId::new("test", 0), 1, "A"),
+            (BlockId::new("test", 1), 1, "B"),
+            (BlockId::new("test", 2), 2, "C"),
+        ];
+        for (buffer, (blid, txn, val)) in only_pool(&buffer_manager)
+            .buffer_pool
+            .iter()
+            .zip(blocks.iter())
+        {
+            let mut buf = buffer.write().unwrap();
+            buf.block_id = Some(blid.clone());
+            buf.contents()
+                .write()
+                .unwrap()
+                .set_bytes(0, Some(val.as_bytes()))
+                .unwrap();
+            buf.set_modified(*txn, -1);
+        }
+
+        buffer_manager.flush_all_buffers(1).unwrap();
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        for (blid, txn, val) in blocks.iter() {
+            file_manager
+                .lock()
+                .unwrap()
+                .read(blid, &mut page)
+                .expect("failed to read file");
+            let expected: &[u8] = if *txn == 1 { val.as_bytes() } else { b"" };
+            assert_eq!(
+                page.get_bytes(0),
+                Some(expected.to_vec().into_boxed_slice())
+            );
+        }
+        for buffer in only_pool(&buffer_manager).buffer_pool.iter() {
+            let buf = buffer.read().unwrap();
+            let txn = blocks
+                .iter()
+                .find(|(blid, _, _)| buf.block_id().as_ref() == Some(blid))
+                .map(|(_, txn, _)| *txn);
+            assert_eq!(buf.modifying_txn().is_some(), txn == Some(2));
+        }
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_manager_partitions() {
+        let tmp_dir =
+            TempDir::new("test_buffer_manager_partitions").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        for _ in 0..40 {
+            file_manager.lock().unwrap().append("test").unwrap();
+        }
+        let few = BufferManager::builder(file_manager.clone(), log_manager.clone(), 3)
+            .partitions(8)
+            .build();
+        assert_eq!(few.partition_count(), 3);
+        let buffer_manager = Arc::new(
+            BufferManager::builder(file_manager.clone(), log_manager, 9)
+                .partitions(4)
+                .max_retries(Some(0))
+                .build(),
+        );
+        assert_eq!(buffer_manager.partition_count(), 4);
+        assert_eq!(buffer_manager.available_buffers(), 9);
+        let sizes: Vec<usize> = buffer_manager
+            .pools()
+            .iter()
+            .map(|pool| pool.buffer_pool.len())
+            .collect();
+        assert_eq!(sizes, vec![3, 2, 2, 2]);
+
+        // Threads working on blocks of their own change them all.
+        let threads: Vec<_> = (0..4)
+            .map(|txn| {
+                let buffer_manager = buffer_manager.clone();
+                std::thread::spawn(move || {
+                    for block_num in (txn as usize..40).step_by(4) {
+                        let block_id = BlockId::new("test", block_num);
+                        let buffer = buffer_manager.pin(&block_id, txn).unwrap();
+                        let mut buf = buffer.write().unwrap();
+                        buf.contents()
+                            .write()
+                            .unwrap()
+                            .set_int(0, Some(block_num as i32))
+                            .unwrap();
+                        buf.set_modified(txn, -1);
+                        drop(buf);
+                        buffer_manager.unpin(&buffer, txn);
+                        buffer_manager.flush_all_buffers(txn).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        for block_num in 0..40 {
+            file_manager
+                .lock()
+                .unwrap()
+                .read(&BlockId::new("test", block_num), &mut page)
+                .unwrap();
+            assert_eq!(page.get_int(0), Some(block_num as i32));
+        }
+        let stats = buffer_manager.stats();
+        assert_eq!((stats.misses, stats.flushes), (40, 40));
+
+        // A partition that is full turns pins away while others have room.
+        let in_partition = |pos: usize| {
+            (0..40)
+                .map(|block_num| BlockId::new("test", block_num))
+                .filter(|block_id| {
+                    std::ptr::eq(
+                        buffer_manager.partition(block_id),
+                        &buffer_manager.partitions[pos],
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let (full, other) = (in_partition(1), in_partition(2));
+        let pinned: Vec<_> = full[..2]
+            .iter()
+            .map(|block_id| buffer_manager.pin(block_id, 1).unwrap())
+            .collect();
+        assert!(matches!(
+            buffer_manager.pin(&full[2], 1),
+            Err(DbError::BufferAbort(_))
+        ));
+        let buffer = buffer_manager.pin(&other[0], 1).unwrap();
+        assert_eq!(buffer_manager.available_buffers(), 6);
+        for buffer in pinned.iter().chain([&buffer]) {
+            buffer_manager.unpin(buffer, 1);
+        }
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
 }
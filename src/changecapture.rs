@@ -0,0 +1,151 @@
+use crate::query::Constant;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A committed change to one row. `before` is empty for inserts and `after`
+/// is empty for deletes; otherwise each holds every field of the row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowChange {
+    pub commit_lsn: i64,
+    pub tx_num: i32,
+    pub table_name: String,
+    pub op: ChangeOp,
+    pub before: Vec<(String, Constant)>,
+    pub after: Vec<(String, Constant)>,
+}
+
+type Queue = Arc<Mutex<VecDeque<RowChange>>>;
+
+/// Holds the row changes of running transactions until they finish, then
+/// hands the changes of committed ones to every subscriber in commit order.
+#[derive(Default)]
+pub struct ChangeCapture {
+    pending: HashMap<i32, Vec<RowChange>>,
+    subscribers: Vec<Weak<Mutex<VecDeque<RowChange>>>>,
+}
+
+impl ChangeCapture {
+    pub fn new() -> ChangeCapture {
+        ChangeCapture {
+            pending: HashMap::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// A stream of the changes committed from now on.
+    pub fn subscribe(&mut self) -> ChangeStream {
+        let queue: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        self.subscribers.push(Arc::downgrade(&queue));
+        ChangeStream { queue }
+    }
+
+    pub fn record(
+        &mut self,
+        tx_num: i32,
+        table_name: &str,
+        op: ChangeOp,
+        before: Vec<(String, Constant)>,
+        after: Vec<(String, Constant)>,
+    ) {
+        self.pending.entry(tx_num).or_default().push(RowChange {
+            commit_lsn: 0,
+            tx_num,
+            table_name: table_name.to_string(),
+            op,
+            before,
+            after,
+        });
+    }
+
+    /// Publishes the changes of a transaction whose commit record is at
+    /// `commit_lsn`.
+    pub fn commit(&mut self, tx_num: i32, commit_lsn: i64) {
+        let Some(changes) = self.pending.remove(&tx_num) else {
+            return;
+        };
+        // Streams that were dropped stop receiving changes.
+        self.subscribers
+            .retain(|subscriber| subscriber.strong_count() > 0);
+        for subscriber in self.subscribers.iter().filter_map(Weak::upgrade) {
+            let mut queue = subscriber.lock().unwrap();
+            for change in changes.iter() {
+                queue.push_back(RowChange {
+                    commit_lsn,
+                    ..change.clone()
+                });
+            }
+        }
+    }
+
+    pub fn rollback(&mut self, tx_num: i32) {
+        self.pending.remove(&tx_num);
+    }
+
+    /// How many changes the transaction has recorded so far.
+    pub fn pending(&self, tx_num: i32) -> usize {
+        self.pending.get(&tx_num).map_or(0, Vec::len)
+    }
+
+    /// Forgets the changes the transaction recorded after its first `count`,
+    /// which a rollback to a savepoint has undone.
+    pub fn rollback_to(&mut self, tx_num: i32, count: usize) {
+        if let Some(changes) = self.pending.get_mut(&tx_num) {
+            changes.truncate(count);
+        }
+    }
+}
+
+/// Committed row changes in commit order. The iterator returns `None` once
+/// it has caught up, and yields more after later commits.
+pub struct ChangeStream {
+    queue: Queue,
+}
+
+impl Iterator for ChangeStream {
+    type Item = RowChange;
+
+    fn next(&mut self) -> Option<RowChange> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_capture() {
+        let mut capture = ChangeCapture::new();
+        let mut early = capture.subscribe();
+        let row = |a: i32| vec![("a".to_string(), Constant::Int(a))];
+
+        capture.record(1, "t", ChangeOp::Insert, Vec::new(), row(1));
+        capture.record(2, "t", ChangeOp::Insert, Vec::new(), row(2));
+        capture.record(1, "t", ChangeOp::Update, row(1), row(3));
+        let mut late = capture.subscribe();
+        capture.rollback(2);
+        assert!(early.next().is_none());
+
+        capture.commit(1, 40);
+        let changes: Vec<RowChange> = early.by_ref().collect();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].op, ChangeOp::Insert);
+        assert_eq!(changes[1].before, row(1));
+        assert_eq!(changes[1].after, row(3));
+        assert!(changes.iter().all(|change| change.commit_lsn == 40));
+        assert_eq!(late.by_ref().count(), 2);
+
+        drop(late);
+        capture.record(3, "t", ChangeOp::Delete, row(3), Vec::new());
+        capture.commit(3, 41);
+        assert_eq!(capture.subscribers.len(), 1);
+        assert_eq!(early.next().map(|change| change.commit_lsn), Some(41));
+    }
+}
@@ -0,0 +1,482 @@
+use crate::filemanager::BlockId;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockAbortReason {
+    Timeout,
+    Deadlock,
+}
+
+#[derive(Debug)]
+pub struct LockAbortError {
+    block_id: BlockId,
+    reason: LockAbortReason,
+}
+
+impl LockAbortError {
+    pub(crate) fn new(block_id: &BlockId, reason: LockAbortReason) -> LockAbortError {
+        LockAbortError {
+            block_id: block_id.clone(),
+            reason,
+        }
+    }
+
+    pub fn reason(&self) -> LockAbortReason {
+        self.reason
+    }
+}
+
+impl Display for LockAbortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            LockAbortReason::Timeout => {
+                write!(f, "timed out waiting for lock on {}", self.block_id)
+            }
+            LockAbortReason::Deadlock => {
+                write!(f, "deadlock detected waiting for lock on {}", self.block_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LockAbortError {}
+
+/// Lock table shared by every transaction, and by every thread running one.
+/// A positive value is the number of shared locks held on a block and -1
+/// means the block is exclusively locked.
+///
+/// Transactions that have to wait for a lock are recorded in a wait-for graph,
+/// and a request that would close a cycle in it is aborted straight away
+/// instead of waiting out `MAX_TIME`. A waiting transaction sleeps until a
+/// lock is released rather than holding on to the table.
+///
+/// In audit mode, which is on by default in debug builds, every read and
+/// write of a block is checked against the table and panics if the
+/// transaction does not hold the lock it needs.
+pub struct LockTable {
+    state: Mutex<LockState>,
+    released: Condvar,
+    audit: AtomicBool,
+}
+
+#[derive(Default)]
+struct LockState {
+    locks: HashMap<BlockId, i32>,
+    holders: HashMap<BlockId, HashSet<i32>>,
+    waits_for: HashMap<i32, HashSet<i32>>,
+}
+
+impl LockTable {
+    const MAX_TIME: u128 = 1000;
+
+    pub fn new() -> LockTable {
+        LockTable {
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+            audit: AtomicBool::new(cfg!(debug_assertions)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, LockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn set_audit(&self, audit: bool) {
+        self.audit.store(audit, Ordering::Relaxed);
+    }
+
+    pub fn audit(&self) -> bool {
+        self.audit.load(Ordering::Relaxed)
+    }
+
+    /// Whether `tx` holds a shared or exclusive lock on the block.
+    pub fn holds_lock(&self, tx: i32, block_id: &BlockId) -> bool {
+        self.state().holds_lock(tx, block_id)
+    }
+
+    pub fn holds_x_lock(&self, tx: i32, block_id: &BlockId) -> bool {
+        let state = self.state();
+        state.has_x_lock(block_id) && state.holds_lock(tx, block_id)
+    }
+
+    pub fn s_lock(&self, tx: i32, block_id: &BlockId) -> Result<(), LockAbortError> {
+        let mut state = self.wait_while(tx, block_id, |state| state.has_x_lock(block_id))?;
+        let val = state.lock_val(block_id);
+        state.locks.insert(block_id.clone(), val + 1);
+        state
+            .holders
+            .entry(block_id.clone())
+            .or_default()
+            .insert(tx);
+        Ok(())
+    }
+
+    /// Takes the exclusive lock on a block. The caller is expected to already
+    /// hold a shared lock on it, so only other shared locks are waited on.
+    pub fn x_lock(&self, tx: i32, block_id: &BlockId) -> Result<(), LockAbortError> {
+        let mut state = self.wait_while(tx, block_id, |state| state.has_other_s_locks(block_id))?;
+        state.locks.insert(block_id.clone(), -1);
+        state
+            .holders
+            .entry(block_id.clone())
+            .or_default()
+            .insert(tx);
+        Ok(())
+    }
+
+    /// Turns the exclusive lock `tx` holds on a block back into a shared
+    /// one.
+    pub fn downgrade(&self, tx: i32, block_id: &BlockId) {
+        let mut state = self.state();
+        if state.has_x_lock(block_id) && state.holds_lock(tx, block_id) {
+            state.locks.insert(block_id.clone(), 1);
+            self.released.notify_all();
+        }
+    }
+
+    pub fn unlock(&self, tx: i32, block_id: &BlockId) {
+        let mut state = self.state();
+        let val = state.lock_val(block_id);
+        if val > 1 {
+            state.locks.insert(block_id.clone(), val - 1);
+        } else {
+            state.locks.remove(block_id);
+        }
+        if let Some(holders) = state.holders.get_mut(block_id) {
+            holders.remove(&tx);
+            if holders.is_empty() {
+                state.holders.remove(block_id);
+            }
+        }
+        // The holders of other locks may be waiting on this one, so the
+        // wait-for graph changes too.
+        self.released.notify_all();
+    }
+
+    // Sleeps until `blocked` no longer holds, giving up on a deadlock or
+    // once `MAX_TIME` has passed, and returns the table still locked.
+    fn wait_while(
+        &self,
+        tx: i32,
+        block_id: &BlockId,
+        blocked: impl Fn(&LockState) -> bool,
+    ) -> Result<MutexGuard<'_, LockState>, LockAbortError> {
+        let timestamp = Self::now();
+        let mut state = self.state();
+        while blocked(&state) {
+            state.wait_for(tx, block_id)?;
+            let waited = Self::now() - timestamp;
+            if waited > Self::MAX_TIME {
+                state.waits_for.remove(&tx);
+                return Err(LockAbortError::new(block_id, LockAbortReason::Timeout));
+            }
+            let remaining = std::time::Duration::from_millis((Self::MAX_TIME - waited) as u64);
+            state = self.released.wait_timeout(state, remaining).unwrap().0;
+        }
+        state.waits_for.remove(&tx);
+        Ok(state)
+    }
+
+    fn now() -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockState {
+    fn holds_lock(&self, tx: i32, block_id: &BlockId) -> bool {
+        self.lock_val(block_id) != 0
+            && self
+                .holders
+                .get(block_id)
+                .is_some_and(|holders| holders.contains(&tx))
+    }
+
+    // Records that `tx` is waiting on every other holder of `block_id`, and
+    // fails if one of those holders is already waiting on `tx`, directly or
+    // through other transactions.
+    fn wait_for(&mut self, tx: i32, block_id: &BlockId) -> Result<(), LockAbortError> {
+        let holders: HashSet<i32> = self
+            .holders
+            .get(block_id)
+            .map(|holders| holders.iter().copied().filter(|h| *h != tx).collect())
+            .unwrap_or_default();
+        if holders.iter().any(|holder| self.reaches(*holder, tx)) {
+            self.waits_for.remove(&tx);
+            return Err(LockAbortError::new(block_id, LockAbortReason::Deadlock));
+        }
+        self.waits_for.insert(tx, holders);
+        Ok(())
+    }
+
+    fn reaches(&self, from: i32, to: i32) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(tx) = stack.pop() {
+            if tx == to {
+                return true;
+            }
+            if visited.insert(tx) {
+                if let Some(next) = self.waits_for.get(&tx) {
+                    stack.extend(next.iter().copied());
+                }
+            }
+        }
+        false
+    }
+
+    fn has_x_lock(&self, block_id: &BlockId) -> bool {
+        self.lock_val(block_id) < 0
+    }
+
+    fn has_other_s_locks(&self, block_id: &BlockId) -> bool {
+        self.lock_val(block_id) > 1
+    }
+
+    fn lock_val(&self, block_id: &BlockId) -> i32 {
+        self.locks.get(block_id).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LockType {
+    Shared,
+    Exclusive,
+}
+
+/// The locks a transaction held at some point, which it can go back to.
+#[derive(Clone, Debug)]
+pub struct HeldLocks(HashMap<BlockId, LockType>);
+
+/// Per-transaction view of the lock table that remembers which locks the
+/// transaction holds so they can all be released at commit or rollback.
+pub struct ConcurrencyManager {
+    tx_n: i32,
+    lock_table: Arc<LockTable>,
+    locks: HashMap<BlockId, LockType>,
+}
+
+impl ConcurrencyManager {
+    pub fn new(tx_n: i32, lock_table: Arc<LockTable>) -> ConcurrencyManager {
+        ConcurrencyManager {
+            tx_n,
+            lock_table,
+            locks: HashMap::new(),
+        }
+    }
+
+    pub fn acquire_s_lock(&mut self, block_id: &BlockId) -> Result<(), LockAbortError> {
+        if !self.locks.contains_key(block_id) {
+            self.lock_table.s_lock(self.tx_n, block_id)?;
+            self.locks.insert(block_id.clone(), LockType::Shared);
+        }
+        Ok(())
+    }
+
+    pub fn acquire_x_lock(&mut self, block_id: &BlockId) -> Result<(), LockAbortError> {
+        if !self.has_x_lock(block_id) {
+            self.acquire_s_lock(block_id)?;
+            self.lock_table.x_lock(self.tx_n, block_id)?;
+            self.locks.insert(block_id.clone(), LockType::Exclusive);
+        }
+        Ok(())
+    }
+
+    pub fn held_locks(&self) -> HeldLocks {
+        HeldLocks(self.locks.clone())
+    }
+
+    /// Releases the locks taken since `held`, and downgrades those that
+    /// were only shared then. This is only safe once everything done under
+    /// them has been undone, as no other transaction can have seen it.
+    pub fn release_since(&mut self, held: &HeldLocks) {
+        let lock_table = &self.lock_table;
+        self.locks.retain(
+            |block_id, lock_type| match (held.0.get(block_id), *lock_type) {
+                (None, _) => {
+                    lock_table.unlock(self.tx_n, block_id);
+                    false
+                }
+                (Some(LockType::Shared), LockType::Exclusive) => {
+                    lock_table.downgrade(self.tx_n, block_id);
+                    *lock_type = LockType::Shared;
+                    true
+                }
+                (Some(_), _) => true,
+            },
+        );
+    }
+
+    pub fn release(&mut self) {
+        for block_id in self.locks.keys() {
+            self.lock_table.unlock(self.tx_n, block_id);
+        }
+        self.locks.clear();
+    }
+
+    /// Panics in audit mode if the transaction may not read the block.
+    pub fn audit_read(&self, block_id: &BlockId) {
+        let lock_table = &self.lock_table;
+        if lock_table.audit() && !lock_table.holds_lock(self.tx_n, block_id) {
+            panic!(
+                "transaction {} read {:?} without holding a lock on it",
+                self.tx_n, block_id
+            );
+        }
+    }
+
+    /// Panics in audit mode if the transaction may not write the block.
+    pub fn audit_write(&self, block_id: &BlockId) {
+        let lock_table = &self.lock_table;
+        if lock_table.audit() && !lock_table.holds_x_lock(self.tx_n, block_id) {
+            panic!(
+                "transaction {} wrote {:?} without holding an exclusive lock on it",
+                self.tx_n, block_id
+            );
+        }
+    }
+
+    fn has_x_lock(&self, block_id: &BlockId) -> bool {
+        self.locks.get(block_id) == Some(&LockType::Exclusive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_table() {
+        let lock_table = LockTable::new();
+        let blid = BlockId::new("test", 0);
+        assert!(lock_table.s_lock(1, &blid).is_ok());
+        assert!(lock_table.s_lock(2, &blid).is_ok());
+        assert_eq!(lock_table.state().lock_val(&blid), 2);
+        lock_table.unlock(2, &blid);
+        assert_eq!(lock_table.state().lock_val(&blid), 1);
+        assert!(lock_table.x_lock(1, &blid).is_ok());
+        assert!(lock_table.state().has_x_lock(&blid));
+        lock_table.unlock(1, &blid);
+        assert_eq!(lock_table.state().lock_val(&blid), 0);
+        assert!(lock_table.state().holders.is_empty());
+    }
+
+    #[test]
+    fn test_concurrency_manager() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm_a = ConcurrencyManager::new(1, lock_table.clone());
+        let mut cm_b = ConcurrencyManager::new(2, lock_table.clone());
+        let blid = BlockId::new("test", 0);
+        let blid2 = BlockId::new("test", 1);
+
+        // Shared locks are compatible, and taking one twice is a no-op.
+        assert!(cm_a.acquire_s_lock(&blid).is_ok());
+        assert!(cm_a.acquire_s_lock(&blid).is_ok());
+        assert!(cm_b.acquire_s_lock(&blid).is_ok());
+        assert_eq!(lock_table.state().lock_val(&blid), 2);
+
+        // b cannot upgrade while a still shares the block.
+        let err = cm_b.acquire_x_lock(&blid).unwrap_err();
+        assert!(err.block_id == blid);
+        assert_eq!(err.reason(), LockAbortReason::Timeout);
+
+        cm_a.release();
+        assert!(cm_b.acquire_x_lock(&blid).is_ok());
+        assert!(cm_b.acquire_x_lock(&blid2).is_ok());
+        assert!(cm_a.acquire_s_lock(&blid).is_err());
+
+        // Releasing drops every lock the transaction held.
+        cm_b.release();
+        assert_eq!(lock_table.state().lock_val(&blid), 0);
+        assert_eq!(lock_table.state().lock_val(&blid2), 0);
+        assert!(cm_a.acquire_x_lock(&blid).is_ok());
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm_a = ConcurrencyManager::new(1, lock_table.clone());
+        let mut cm_b = ConcurrencyManager::new(2, lock_table.clone());
+        let blid = BlockId::new("test", 0);
+        let blid2 = BlockId::new("test", 1);
+        assert!(cm_a.acquire_x_lock(&blid).is_ok());
+        assert!(cm_b.acquire_x_lock(&blid2).is_ok());
+
+        // b is stuck waiting on a's block, so a waiting on b's block closes
+        // the cycle and is aborted without waiting out the timeout.
+        assert!(lock_table.state().wait_for(2, &blid).is_ok());
+        let timestamp = LockTable::now();
+        let err = cm_a.acquire_s_lock(&blid2).unwrap_err();
+        assert_eq!(err.reason(), LockAbortReason::Deadlock);
+        assert!(err.block_id == blid2);
+        assert!(LockTable::now() - timestamp < LockTable::MAX_TIME);
+        assert!(!lock_table.state().waits_for.contains_key(&1));
+
+        // Once a gives up its locks, b is no longer part of a cycle.
+        cm_a.release();
+        assert!(cm_b.acquire_x_lock(&blid).is_ok());
+        assert!(!lock_table.state().waits_for.contains_key(&2));
+    }
+
+    #[test]
+    fn test_wait_for_graph() {
+        let lock_table = LockTable::new();
+        let blids: Vec<BlockId> = (0..3).map(|n| BlockId::new("test", n)).collect();
+        for (tx, blid) in blids.iter().enumerate() {
+            assert!(lock_table.s_lock(tx as i32, blid).is_ok());
+            assert!(lock_table.x_lock(tx as i32, blid).is_ok());
+        }
+        assert!(lock_table.state().wait_for(0, &blids[1]).is_ok());
+        assert!(lock_table.state().wait_for(1, &blids[2]).is_ok());
+        assert!(lock_table.state().reaches(0, 2));
+        assert!(!lock_table.state().reaches(2, 0));
+        let err = lock_table.state().wait_for(2, &blids[0]).unwrap_err();
+        assert_eq!(err.reason(), LockAbortReason::Deadlock);
+    }
+
+    #[test]
+    fn test_audit_mode() {
+        let lock_table = Arc::new(LockTable::new());
+        lock_table.set_audit(true);
+        let mut cm = ConcurrencyManager::new(1, lock_table.clone());
+        let blid = BlockId::new("test", 0);
+        assert!(cm.acquire_s_lock(&blid).is_ok());
+        cm.audit_read(&blid);
+        assert!(lock_table.holds_lock(1, &blid));
+        assert!(!lock_table.holds_x_lock(1, &blid));
+        assert!(!lock_table.holds_lock(2, &blid));
+        assert!(cm.acquire_x_lock(&blid).is_ok());
+        cm.audit_write(&blid);
+
+        // Locks dropped behind the manager's back are caught on the next
+        // access.
+        lock_table.unlock(1, &blid);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cm.audit_read(&blid)));
+        assert!(result.is_err());
+        lock_table.set_audit(false);
+        cm.audit_read(&blid);
+    }
+
+    #[test]
+    #[should_panic(expected = "without holding an exclusive lock")]
+    fn test_audit_write_needs_x_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        lock_table.set_audit(true);
+        let mut cm = ConcurrencyManager::new(1, lock_table);
+        let blid = BlockId::new("test", 0);
+        assert!(cm.acquire_s_lock(&blid).is_ok());
+        cm.audit_write(&blid);
+    }
+}
@@ -0,0 +1,3167 @@
+use crate::attach::{split_qualified, Attachment, Attachments};
+use crate::audit::{AuditConfig, AuditLog, AuditRecord, StatementKind};
+use crate::buffermanager::{
+    BackgroundWriter, BufferEvent, BufferManager, BufferPolicy, BufferStats, Prefetcher,
+};
+use crate::changecapture::{ChangeCapture, ChangeStream};
+use crate::concurrencymanager::LockTable;
+use crate::error::DbError;
+use crate::filemanager::{ChecksumMismatch, FileManager, SyncMode};
+use crate::logmanager::LogManager;
+use crate::metadata::{CatalogError, MetadataMgr, TempCatalog};
+use crate::parse::{Parser, QueryCmd};
+use crate::plan::{BasicQueryPlanner, BasicUpdatePlanner, Limit, PlanError, Planner};
+use crate::query::{Constant, Scan, UpdateScan};
+use crate::recordmanager::{
+    Schema, TableScan, BIGINT, BLOB, BOOLEAN, CHAR, DATE, DOUBLE, INTEGER, VARCHAR,
+};
+use crate::recoverymanager::RecoveryManager;
+use crate::resultcache::{CachedScan, ResultCache};
+use crate::transaction::Transaction;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Settings a database is opened with by `SimpleDB::with_options`. The
+/// defaults are those `SimpleDB::new` uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub block_size: usize,
+    pub buffer_count: i32,
+    /// Whether a new database checksums its blocks, and what a read does
+    /// when one does not match. See `SimpleDB::with_checksums`.
+    pub checksums: Option<ChecksumMismatch>,
+    /// How the buffer pool picks a buffer to reassign.
+    pub buffer_policy: BufferPolicy,
+    /// Parts the buffer pool is split into, each behind its own lock, so
+    /// that sessions pinning different blocks do not wait on one another.
+    /// A block can only use the buffers of its part, so more parts need
+    /// more buffers. At most one part per buffer is made.
+    pub buffer_partitions: usize,
+    /// How long a statement waits for a free buffer before it fails with
+    /// `DbError::BufferAbort`. See `SimpleDB::set_buffer_wait`.
+    pub buffer_wait: Duration,
+    /// How many times a statement waiting for a buffer tries again before
+    /// it fails. See `SimpleDB::set_buffer_retries`.
+    pub buffer_retries: Option<u32>,
+    /// Whether every read and write of a block checks that its transaction
+    /// holds the lock it needs, and panics if not. It catches locking bugs
+    /// at the cost of a lock table lookup per access, and is on by default
+    /// in debug builds only.
+    pub lock_audit: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            block_size: SimpleDB::BLOCK_SIZE,
+            buffer_count: SimpleDB::BUFFER_COUNT,
+            checksums: None,
+            buffer_policy: BufferPolicy::default(),
+            buffer_partitions: 1,
+            buffer_wait: BufferManager::MAX_TIME,
+            buffer_retries: None,
+            lock_audit: cfg!(debug_assertions),
+        }
+    }
+}
+
+/// An embedded database stored in one directory. Applications talk to it
+/// through a `Connection`. Threads can share the database, each working
+/// through connections of its own.
+pub struct SimpleDB {
+    file_manager: Arc<Mutex<FileManager>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    buffer_manager: Arc<BufferManager>,
+    lock_table: Arc<LockTable>,
+    metadata_mgr: Arc<MetadataMgr>,
+    planner: Planner,
+    changes: Arc<Mutex<ChangeCapture>>,
+    result_cache: Mutex<Option<ResultCache>>,
+    sort_workers: AtomicUsize,
+    background_writer: Mutex<Option<BackgroundWriter>>,
+    prefetcher: Mutex<Option<Prefetcher>>,
+    audit_log: Mutex<Option<AuditLog>>,
+    attachments: Attachments,
+}
+
+// Fails the build should a field stop the database being shared between
+// threads.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SimpleDB>();
+};
+
+impl SimpleDB {
+    pub const BLOCK_SIZE: usize = 400;
+    pub const BUFFER_COUNT: i32 = 8;
+    pub const LOG_FILE: &'static str = "simpledb.log";
+    /// The log size past which a checkpoint truncates it, unless changed with
+    /// `set_max_wal_size`.
+    pub const MAX_WAL_SIZE: usize = 1 << 20;
+    /// Rows the retention task deletes in one transaction, so that it never
+    /// holds its locks for long.
+    pub const RETENTION_BATCH: usize = 100;
+
+    pub fn new<P: AsRef<Path>>(directory: P) -> Result<SimpleDB, CatalogError> {
+        Self::with_options(directory, OpenOptions::default())
+    }
+
+    /// Opens the database in `directory`, creating it if the directory is
+    /// missing or empty. An existing database is recovered before use, and its
+    /// catalog is upgraded if an older version of the crate wrote it.
+    pub fn with_params<P: AsRef<Path>>(
+        directory: P,
+        block_size: usize,
+        buffer_count: i32,
+    ) -> Result<SimpleDB, CatalogError> {
+        Self::with_options(
+            directory,
+            OpenOptions {
+                block_size,
+                buffer_count,
+                ..OpenOptions::default()
+            },
+        )
+    }
+
+    /// Like `with_params`, but every block written carries a checksum that is
+    /// verified when it is read back, so torn and corrupted pages are caught
+    /// with `on_mismatch`. Checksums can only be turned on for a new
+    /// database; one created without them is opened as is.
+    pub fn with_checksums<P: AsRef<Path>>(
+        directory: P,
+        block_size: usize,
+        buffer_count: i32,
+        on_mismatch: ChecksumMismatch,
+    ) -> Result<SimpleDB, CatalogError> {
+        Self::with_options(
+            directory,
+            OpenOptions {
+                block_size,
+                buffer_count,
+                checksums: Some(on_mismatch),
+                ..OpenOptions::default()
+            },
+        )
+    }
+
+    /// Opens the database in `directory` as `with_params` does, with the
+    /// settings in `options`.
+    pub fn with_options<P: AsRef<Path>>(
+        directory: P,
+        options: OpenOptions,
+    ) -> Result<SimpleDB, CatalogError> {
+        std::fs::create_dir_all(directory.as_ref()).map_err(DbError::from)?;
+        let mut file_manager =
+            FileManager::new(directory.as_ref().to_path_buf(), options.block_size)?;
+        if let Some(on_mismatch) = options.checksums {
+            file_manager.with_checksums(on_mismatch)?;
+        }
+        let file_manager = Arc::new(Mutex::new(file_manager));
+        let is_new = file_manager.lock().unwrap().is_new();
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder(Self::LOG_FILE.to_string(), file_manager.clone())
+                .max_wal_size(Self::MAX_WAL_SIZE)
+                .build()?,
+        ));
+        let buffer_manager = Arc::new(
+            BufferManager::builder(
+                file_manager.clone(),
+                log_manager.clone(),
+                options.buffer_count,
+            )
+            .policy(options.buffer_policy)
+            .partitions(options.buffer_partitions)
+            .max_wait(options.buffer_wait)
+            .max_retries(options.buffer_retries)
+            .build(),
+        );
+        let lock_table = Arc::new(LockTable::new());
+        lock_table.set_audit(options.lock_audit);
+
+        let tx = Rc::new(RefCell::new(Transaction::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            lock_table.clone(),
+        )?));
+        if !is_new {
+            tx.borrow_mut().recover()?;
+        }
+        let metadata_mgr = match MetadataMgr::new(is_new, tx.clone()) {
+            Ok(metadata_mgr) => Arc::new(metadata_mgr),
+            Err(err) => {
+                tx.borrow_mut().rollback()?;
+                return Err(err);
+            }
+        };
+        tx.borrow_mut().commit()?;
+
+        let changes = Arc::new(Mutex::new(ChangeCapture::new()));
+        let attachments = Attachments::default();
+        let planner = Planner::new(
+            Box::new(BasicQueryPlanner::with_attachments(
+                metadata_mgr.clone(),
+                attachments.clone(),
+            )),
+            Box::new(BasicUpdatePlanner::with_change_capture(
+                metadata_mgr.clone(),
+                changes.clone(),
+            )),
+        );
+        Ok(SimpleDB {
+            file_manager,
+            log_manager,
+            buffer_manager,
+            lock_table,
+            metadata_mgr,
+            planner,
+            changes,
+            result_cache: Mutex::new(None),
+            sort_workers: AtomicUsize::new(1),
+            background_writer: Mutex::new(None),
+            prefetcher: Mutex::new(None),
+            audit_log: Mutex::new(None),
+            attachments,
+        })
+    }
+
+    /// Opens an unrestricted session, which may read and change any table
+    /// and grant privileges to others.
+    pub fn connect(&self) -> Connection<'_> {
+        self.connect_with(None)
+    }
+
+    /// Opens a session for `user`, which may only use the tables it has
+    /// been granted privileges on, directly or through its roles, and may
+    /// not change the schema apart from creating temporary tables.
+    pub fn connect_as(&self, user: &str) -> Connection<'_> {
+        self.connect_with(Some(user.to_lowercase()))
+    }
+
+    fn connect_with(&self, user: Option<String>) -> Connection<'_> {
+        let temp_catalog = Rc::new(RefCell::new(TempCatalog::new()));
+        Connection {
+            db: self,
+            tx: RefCell::new(None),
+            temp_catalog,
+            counters: RefCell::new(HashMap::new()),
+            limits: Cell::new(ResourceLimits::default()),
+            user,
+            explicit: Cell::new(false),
+        }
+    }
+
+    /// Row changes made through SQL updates, delivered once their
+    /// transaction commits.
+    pub fn subscribe_changes(&self) -> ChangeStream {
+        self.changes.lock().unwrap().subscribe()
+    }
+
+    /// How writes reach stable storage. The database starts with
+    /// `SyncMode::Off`, where a power failure can lose recent commits.
+    pub fn set_sync_mode(&self, sync_mode: SyncMode) {
+        self.file_manager.lock().unwrap().with_sync_mode(sync_mode);
+    }
+
+    /// Once the log grows past `max_wal_size` bytes, the database flushes
+    /// its buffers and truncates the log as soon as no transaction has
+    /// uncommitted updates. `None` lets the log grow without limit.
+    pub fn set_max_wal_size(&self, max_wal_size: Option<usize>) {
+        self.log_manager
+            .lock()
+            .unwrap()
+            .set_max_wal_size(max_wal_size);
+    }
+
+    /// Keeps the last `capacity` pin, unpin, flush and evict events of the
+    /// buffer pool, for tracking down pins that are never released. The
+    /// events are logged when a pin gives up waiting for a buffer. A capacity
+    /// of zero turns tracing off.
+    pub fn set_buffer_trace(&self, capacity: usize) {
+        self.buffer_manager.set_trace_capacity(capacity);
+    }
+
+    /// How long a statement waits for a free buffer before it fails with
+    /// `DbError::BufferAbort`. Waiting statements sleep until another
+    /// transaction unpins a buffer, so a longer wait costs no CPU.
+    pub fn set_buffer_wait(&self, max_wait: Duration) {
+        self.buffer_manager.set_max_wait(max_wait);
+    }
+
+    /// How many times a statement waiting for a buffer tries again before
+    /// it fails, however much of its wait is left. `None` retries until the
+    /// wait runs out.
+    pub fn set_buffer_retries(&self, max_retries: Option<u32>) {
+        self.buffer_manager.set_max_retries(max_retries);
+    }
+
+    /// Starts a thread that writes dirty, unpinned buffers out every
+    /// `interval`, or stops it with `None`. Without one, buffers are only
+    /// written when they are evicted, at checkpoints and on rollback.
+    pub fn set_background_writer(&self, interval: Option<Duration>) {
+        // The old writer is stopped before a new one starts.
+        let mut writer = self.background_writer.lock().unwrap();
+        writer.take();
+        *writer =
+            interval.map(|interval| BackgroundWriter::start(self.buffer_manager.clone(), interval));
+    }
+
+    /// Starts or stops a thread that reads blocks ahead of the scans that
+    /// need them: while it runs, a table scan asks for the next block of
+    /// its table each time it moves to one.
+    pub fn set_prefetching(&self, enabled: bool) {
+        let mut prefetcher = self.prefetcher.lock().unwrap();
+        prefetcher.take();
+        if enabled {
+            *prefetcher = Some(Prefetcher::start(self.buffer_manager.clone()));
+        }
+    }
+
+    /// Records every statement run through a connection from now on, with
+    /// its user, time, duration, result and transaction, in a file of the
+    /// database directory; `None` stops recording. Statements that fail are
+    /// recorded with their error.
+    pub fn set_audit_log(&self, config: Option<AuditConfig>) -> Result<(), DbError> {
+        let mut audit_log = self.audit_log.lock().unwrap();
+        audit_log.take();
+        if let Some(config) = config {
+            let directory = self.file_manager.lock().unwrap().directory().to_path_buf();
+            *audit_log = Some(AuditLog::open(&directory, config)?);
+        }
+        Ok(())
+    }
+
+    /// Opens the database in `directory` read-only under `name`, so that
+    /// queries can read its tables as `name.table`, joined with this
+    /// database's own. Nothing is ever written to its files: it is not
+    /// recovered, so it should have been shut down cleanly, and its catalog
+    /// must be of the current version. It must use this database's block
+    /// size. Attaching a name again replaces the database it had.
+    pub fn attach<P: AsRef<Path>>(&self, name: &str, directory: P) -> Result<(), CatalogError> {
+        let block_size = self.file_manager.lock().unwrap().block_size();
+        let attachment =
+            Attachment::open(directory, block_size, Self::BUFFER_COUNT, Self::LOG_FILE)?;
+        self.attachments
+            .write()
+            .unwrap()
+            .insert(name.to_lowercase(), attachment);
+        Ok(())
+    }
+
+    /// Detaches the database attached under `name`. Queries already running
+    /// finish reading it. Returns whether one was attached.
+    pub fn detach(&self, name: &str) -> bool {
+        self.attachments
+            .write()
+            .unwrap()
+            .remove(&name.to_lowercase())
+            .is_some()
+    }
+
+    pub fn buffer_trace(&self) -> Vec<BufferEvent> {
+        self.buffer_manager.trace()
+    }
+
+    /// Hits, misses, evictions, flushes and waits of the buffer pool, for
+    /// judging whether it is big enough, and the pins held on it now.
+    pub fn buffer_stats(&self) -> BufferStats {
+        self.buffer_manager.stats()
+    }
+
+    pub fn reset_buffer_stats(&self) {
+        self.buffer_manager.reset_stats();
+    }
+
+    /// Keeps the results of the last `capacity` queries, answering a query
+    /// whose SQL text matches one of them without running it again. A result
+    /// is dropped once a commit changes a table it read. Queries that read a
+    /// temporary table or an attached database, and explained queries, are
+    /// not cached. A capacity of zero turns the cache off.
+    pub fn set_result_cache(&self, capacity: usize) {
+        *self.result_cache.lock().unwrap() =
+            (capacity > 0).then(|| ResultCache::new(capacity, self.subscribe_changes()));
+    }
+
+    /// Lets sorts started by later transactions sort their input with up to
+    /// `workers` threads. With one worker, the default, sorts run entirely on
+    /// the calling thread.
+    pub fn set_sort_workers(&self, workers: usize) {
+        self.sort_workers.store(workers.max(1), Ordering::Relaxed);
+    }
+
+    /// Flushes every buffer and writes a checkpoint record, so that recovery
+    /// stops reading the log there. If a transaction has uncommitted updates
+    /// the checkpoint waits for it to finish; returns whether it ran now.
+    pub fn checkpoint(&self) -> Result<bool, DbError> {
+        RecoveryManager::checkpoint(&self.log_manager, &self.buffer_manager)
+    }
+
+    /// Declares that rows of `table_name` whose int field `field_name`, in
+    /// seconds since the Unix epoch, is more than `days` days old are to be
+    /// deleted. The policy replaces any the table had, and is applied by
+    /// `run_retention`.
+    pub fn set_retention(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        days: i32,
+    ) -> Result<(), PlanError> {
+        let (table_name, field_name) = (table_name.to_lowercase(), field_name.to_lowercase());
+        let tx = self.new_tx()?;
+        let metadata_mgr = &self.metadata_mgr;
+        let result = match metadata_mgr.get_layout(&table_name, tx.clone()) {
+            None => Err(PlanError::UnknownTable(table_name.clone())),
+            Some(layout) => match layout.schema().field_type(&field_name) {
+                None => Err(PlanError::UnknownField(field_name.clone())),
+                Some(INTEGER) => Ok(()),
+                Some(_) => Err(PlanError::TypeMismatch(field_name.clone())),
+            },
+        };
+        match result {
+            Ok(()) => {
+                let set = metadata_mgr
+                    .set_retention(&table_name, &field_name, days, tx.clone())
+                    .and_then(|()| tx.borrow_mut().commit());
+                if let Err(err) = set {
+                    tx.borrow_mut().rollback()?;
+                    return Err(PlanError::Storage(err));
+                }
+            }
+            Err(_) => tx.borrow_mut().rollback()?,
+        }
+        result
+    }
+
+    /// The maintenance task behind retention policies, meant to be called
+    /// periodically by the application. Expired rows are deleted in
+    /// transactions of at most `RETENTION_BATCH` rows. Returns the number of
+    /// rows deleted.
+    pub fn run_retention(&self) -> Result<usize, PlanError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.run_retention_at(i32::try_from(now).unwrap_or(i32::MAX))
+    }
+
+    /// `run_retention` with the current time, in seconds since the Unix
+    /// epoch, given by the caller.
+    pub fn run_retention_at(&self, now: i32) -> Result<usize, PlanError> {
+        let conn = self.connect();
+        let policies = self.metadata_mgr.retention_policies(conn.tx()?);
+        let mut total = 0;
+        for policy in policies {
+            loop {
+                let result = self
+                    .planner
+                    .execute_expire(
+                        &policy,
+                        policy.cutoff(now),
+                        Self::RETENTION_BATCH,
+                        conn.tx()?,
+                    )
+                    .and_then(|count| {
+                        conn.commit()?;
+                        Ok(count)
+                    });
+                match result {
+                    Ok(count) => {
+                        total += count;
+                        if count < Self::RETENTION_BATCH {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        conn.abort();
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    // The stored tables a query reads, with views expanded, or `None` if its
+    // result cannot be cached.
+    fn query_tables(&self, sql: &str, tx: Rc<RefCell<Transaction>>) -> Option<Vec<String>> {
+        let QueryCmd::Select(data) = Parser::new(sql).ok()?.query_cmd().ok()? else {
+            return None;
+        };
+        let metadata_mgr = &self.metadata_mgr;
+        let mut tables = Vec::new();
+        let mut pending = data.tables().to_vec();
+        while let Some(table_name) = pending.pop() {
+            if split_qualified(&table_name).is_some()
+                || metadata_mgr.is_temp_table(&table_name, tx.clone())
+            {
+                return None;
+            }
+            match metadata_mgr.get_view_def(&table_name, tx.clone()) {
+                Some(view_def) => {
+                    pending.extend_from_slice(Parser::new(&view_def).ok()?.query().ok()?.tables())
+                }
+                None => tables.push(table_name),
+            }
+        }
+        Some(tables)
+    }
+
+    pub(crate) fn new_tx(&self) -> Result<Rc<RefCell<Transaction>>, DbError> {
+        let mut tx = Transaction::new(
+            self.file_manager.clone(),
+            self.log_manager.clone(),
+            self.buffer_manager.clone(),
+            self.lock_table.clone(),
+        )?;
+        tx.set_sort_workers(self.sort_workers.load(Ordering::Relaxed));
+        Ok(Rc::new(RefCell::new(tx)))
+    }
+
+    pub(crate) fn metadata_mgr(&self) -> Arc<MetadataMgr> {
+        self.metadata_mgr.clone()
+    }
+}
+
+/// Committed changes may still be only in the buffer pool and the log, so a
+/// database that is shut down cleanly writes them out first.
+impl Drop for SimpleDB {
+    fn drop(&mut self) {
+        if let Err(err) = RecoveryManager::checkpoint(&self.log_manager, &self.buffer_manager) {
+            log::warn!("failed to checkpoint on shutdown: {}", err);
+        }
+    }
+}
+
+/// Several databases open in one process, each under a name. Every database
+/// keeps its own directory, log, lock table and buffer pool, so work on one
+/// never waits on or evicts the pages of another. Connections are opened by
+/// database name.
+#[derive(Default)]
+pub struct Engine {
+    databases: HashMap<String, SimpleDB>,
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Self::default()
+    }
+
+    /// Opens the database in `directory` under `name`, as `SimpleDB::new`
+    /// does. A name that is already open keeps the database it has.
+    pub fn open<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        directory: P,
+    ) -> Result<&SimpleDB, CatalogError> {
+        let name = name.to_lowercase();
+        if !self.databases.contains_key(&name) {
+            let db = SimpleDB::new(directory)?;
+            self.databases.insert(name.clone(), db);
+        }
+        Ok(&self.databases[&name])
+    }
+
+    /// Adds a database opened some other way, such as with `with_params`,
+    /// under `name`, returning the one it replaces.
+    pub fn insert(&mut self, name: &str, db: SimpleDB) -> Option<SimpleDB> {
+        self.databases.insert(name.to_lowercase(), db)
+    }
+
+    /// Takes the database out of the engine. Dropping it shuts it down.
+    pub fn remove(&mut self, name: &str) -> Option<SimpleDB> {
+        self.databases.remove(&name.to_lowercase())
+    }
+
+    pub fn database(&self, name: &str) -> Option<&SimpleDB> {
+        self.databases.get(&name.to_lowercase())
+    }
+
+    /// A connection to the database open under `name`, if there is one.
+    pub fn connect(&self, name: &str) -> Option<Connection<'_>> {
+        self.database(name).map(SimpleDB::connect)
+    }
+
+    /// The names of the open databases, in order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.databases.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+/// Caps on what one statement of a connection may use, so that a runaway
+/// query cannot starve the rest of the database. `None` leaves a resource
+/// unlimited, which is the default for all of them.
+///
+/// Temporary space and pinned buffers are counted over the statement's
+/// transaction. Queries are checked as they are opened and before each row
+/// is returned; updates are checked once they have run, and rolled back if
+/// they went over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Blocks of temporary tables, such as sort runs, a statement may write.
+    pub max_temp_blocks: Option<usize>,
+    /// Buffers a statement may hold pinned at the same time.
+    pub max_pinned_buffers: Option<usize>,
+    /// Rows a query may return.
+    pub max_result_rows: Option<usize>,
+    /// Time a statement may run for.
+    pub max_runtime: Option<Duration>,
+}
+
+impl ResourceLimits {
+    // The first limit the transaction has gone past since `started`.
+    fn check(&self, tx: &Transaction, started: Instant) -> Result<(), PlanError> {
+        if self
+            .max_temp_blocks
+            .is_some_and(|max| tx.temp_blocks() > max)
+        {
+            return Err(PlanError::LimitExceeded(Limit::TempSpace));
+        }
+        if self
+            .max_pinned_buffers
+            .is_some_and(|max| tx.peak_pinned() > max)
+        {
+            return Err(PlanError::LimitExceeded(Limit::PinnedBuffers));
+        }
+        if self.max_runtime.is_some_and(|max| started.elapsed() > max) {
+            return Err(PlanError::LimitExceeded(Limit::Runtime));
+        }
+        Ok(())
+    }
+}
+
+/// A session with the database. Work happens inside the connection's current
+/// transaction, which begins with the first statement after a commit or
+/// rollback. Each statement commits on its own unless `begin` started a
+/// transaction for it to join. Dropping the connection commits, or rolls
+/// back if the commit fails, and removes its temporary tables.
+pub struct Connection<'db> {
+    db: &'db SimpleDB,
+    tx: RefCell<Option<Rc<RefCell<Transaction>>>>,
+    temp_catalog: Rc<RefCell<TempCatalog>>,
+    counters: RefCell<HashMap<String, i32>>,
+    limits: Cell<ResourceLimits>,
+    user: Option<String>,
+    // Whether `begin` started the current transaction, which statements then
+    // leave for the caller to end.
+    explicit: Cell<bool>,
+}
+
+impl<'db> Connection<'db> {
+    pub fn create_statement(&self) -> Statement<'_> {
+        Statement { connection: self }
+    }
+
+    /// Begins a transaction that the statements which follow run in, until
+    /// `commit` or `rollback` ends it. A statement that fails still rolls
+    /// the whole transaction back.
+    pub fn begin(&self) -> Result<(), DbError> {
+        self.tx()?;
+        self.explicit.set(true);
+        Ok(())
+    }
+
+    /// Commits the current transaction. One whose commit fails, as on a full
+    /// disk, is left for the caller to roll back.
+    pub fn commit(&self) -> Result<(), DbError> {
+        let Some(tx) = self.tx.borrow().clone() else {
+            return Ok(());
+        };
+        let tx_num = tx.borrow().transaction_n();
+        let lsn = tx.borrow_mut().commit()?;
+        self.db.changes.lock().unwrap().commit(tx_num, lsn);
+        self.tx.borrow_mut().take();
+        self.explicit.set(false);
+        Ok(())
+    }
+
+    /// Rolls back the current transaction. Should the rollback fail part way,
+    /// the transaction still ends, and recovery undoes the rest of its
+    /// changes when the database is next opened.
+    pub fn rollback(&self) -> Result<(), DbError> {
+        let Some(tx) = self.tx.borrow_mut().take() else {
+            return Ok(());
+        };
+        self.explicit.set(false);
+        let tx_num = tx.borrow().transaction_n();
+        let result = tx.borrow_mut().rollback();
+        self.db.changes.lock().unwrap().rollback(tx_num);
+        result
+    }
+
+    /// Marks the current point of the transaction so that `rollback_to` can
+    /// undo what comes after it. The transaction stays open until `commit` or
+    /// `rollback`, as one from `begin` does.
+    pub fn savepoint(&self) -> Result<Savepoint, DbError> {
+        self.begin()?;
+        let tx = self.tx()?;
+        let tx = tx.borrow();
+        Ok(Savepoint {
+            tx_num: tx.transaction_n(),
+            changes: self.db.changes.lock().unwrap().pending(tx.transaction_n()),
+            savepoint: tx.savepoint(),
+        })
+    }
+
+    /// Undoes every change made since `savepoint` and releases the locks
+    /// taken since, keeping the transaction and the work done before it.
+    /// Should the rollback fail part way, the whole transaction is rolled
+    /// back.
+    ///
+    /// Panics if the transaction the savepoint was taken in has ended.
+    pub fn rollback_to(&self, savepoint: &Savepoint) -> Result<(), DbError> {
+        let tx = self
+            .tx
+            .borrow()
+            .clone()
+            .filter(|tx| tx.borrow().transaction_n() == savepoint.tx_num)
+            .expect("savepoint is from a transaction that has ended");
+        if let Err(err) = tx.borrow_mut().rollback_to_savepoint(&savepoint.savepoint) {
+            self.abort();
+            return Err(err);
+        }
+        self.db
+            .changes
+            .lock()
+            .unwrap()
+            .rollback_to(savepoint.tx_num, savepoint.changes);
+        Ok(())
+    }
+
+    // Commits the transaction a statement ran in, unless it belongs to one
+    // that `begin` started.
+    fn end_statement(&self) -> Result<(), DbError> {
+        if self.explicit.get() {
+            return Ok(());
+        }
+        self.commit()
+    }
+
+    // Rolls back after a statement failed with an error of its own, which is
+    // the one reported; a rollback that fails as well is only logged.
+    fn abort(&self) {
+        if let Err(err) = self.rollback() {
+            log::warn!("failed to roll back transaction: {}", err);
+        }
+    }
+
+    pub fn close(self) {}
+
+    /// Sets the limits every later statement of this connection runs under.
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        self.limits.set(limits);
+    }
+
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits.get()
+    }
+
+    /// Advances the connection's counter `name` and returns its new value;
+    /// a new counter starts at 1. Counters live only as long as the
+    /// connection and are neither logged nor shared, so bulk imports can
+    /// draw keys from them without contending with anyone.
+    pub fn nextval_local(&self, name: &str) -> i32 {
+        let mut counters = self.counters.borrow_mut();
+        let counter = counters.entry(name.to_lowercase()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// The value `nextval_local` last returned for `name`.
+    pub fn currval_local(&self, name: &str) -> Option<i32> {
+        self.counters.borrow().get(&name.to_lowercase()).copied()
+    }
+
+    /// Sets the counter so that the next `nextval_local` returns `value + 1`.
+    pub fn setval_local(&self, name: &str, value: i32) {
+        self.counters
+            .borrow_mut()
+            .insert(name.to_lowercase(), value);
+    }
+
+    // The current transaction, begun now if the connection has none.
+    // Temporary tables are not logged, so neither a commit nor a rollback
+    // touches them; the next transaction sees them as they are.
+    fn tx(&self) -> Result<Rc<RefCell<Transaction>>, DbError> {
+        if let Some(tx) = self.tx.borrow().as_ref() {
+            return Ok(tx.clone());
+        }
+        let tx = self.db.new_tx()?;
+        tx.borrow_mut().set_temp_catalog(self.temp_catalog.clone());
+        tx.borrow_mut().set_user(self.user.clone());
+        *self.tx.borrow_mut() = Some(tx.clone());
+        Ok(tx)
+    }
+
+    // Fails with the error a scan of the current transaction ran into, such
+    // as a lock it could not get, if one did since the last check.
+    fn check_reads(&self) -> Result<(), DbError> {
+        let read_error = self
+            .tx
+            .borrow()
+            .as_ref()
+            .and_then(|tx| tx.borrow_mut().take_read_error());
+        read_error.map_or(Ok(()), Err)
+    }
+
+    // A read that failed can make a statement look wrong, as a table whose
+    // catalog entry could not be read looks missing, so the statement fails
+    // with the read's error instead.
+    fn read_failure(&self, err: PlanError) -> PlanError {
+        match self.check_reads() {
+            Err(read_err) => PlanError::Storage(read_err),
+            Ok(()) => err,
+        }
+    }
+
+    // What the audit log needs to know about a statement that starts now,
+    // or `None` if the statement is not to be recorded. A transaction that
+    // cannot begin, as on a full disk, fails the statement unrecorded.
+    fn start_audit(&self, sql: &str) -> Result<Option<PendingAudit>, DbError> {
+        let kind = StatementKind::of(sql);
+        if !self
+            .db
+            .audit_log
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|log| log.records(kind))
+        {
+            return Ok(None);
+        }
+        Ok(Some(PendingAudit {
+            sql: sql.to_string(),
+            kind,
+            tx_num: self.tx()?.borrow().transaction_n(),
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+        }))
+    }
+
+    fn audit(&self, pending: Option<PendingAudit>, result: Result<usize, String>) {
+        let Some(pending) = pending else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: pending.timestamp,
+            user: self.user.clone(),
+            tx_num: pending.tx_num,
+            kind: pending.kind,
+            duration: pending.started.elapsed(),
+            result,
+            sql: pending.sql,
+        };
+        if let Some(audit_log) = self.db.audit_log.lock().unwrap().as_mut() {
+            if let Err(err) = audit_log.record(&record) {
+                log::warn!("failed to write audit record: {}", err);
+            }
+        }
+    }
+}
+
+struct PendingAudit {
+    sql: String,
+    kind: StatementKind,
+    tx_num: i32,
+    timestamp: SystemTime,
+    started: Instant,
+}
+
+/// A point in a connection's transaction, from `Connection::savepoint`.
+pub struct Savepoint {
+    tx_num: i32,
+    // Row changes the transaction had recorded for change streams.
+    changes: usize,
+    savepoint: crate::transaction::Savepoint,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            log::warn!("failed to commit on close: {}", err);
+            self.abort();
+        }
+        // Written out first, so that a buffer evicted later does not bring
+        // a removed file back.
+        if let Err(err) = self.db.buffer_manager.flush_all() {
+            log::warn!("failed to flush buffers: {}", err);
+        }
+        for heap_name in self.temp_catalog.borrow().heap_names() {
+            if let Err(err) = self
+                .db
+                .file_manager
+                .lock()
+                .unwrap()
+                .delete(&format!("{}.tbl", heap_name))
+            {
+                log::warn!("failed to remove temporary table {}: {}", heap_name, err);
+            }
+        }
+    }
+}
+
+pub struct Statement<'a> {
+    connection: &'a Connection<'a>,
+}
+
+impl<'a> Statement<'a> {
+    /// Plans and opens a query, or reads it from the result cache if one is
+    /// set. The transaction is rolled back if the query cannot be planned or
+    /// the disk is too full to open it.
+    pub fn execute_query(&self, sql: &str) -> Result<ResultSet<'a>, PlanError> {
+        let audit = self.connection.start_audit(sql)?;
+        match self.open_query(sql) {
+            Ok(mut result_set) => {
+                result_set.audit = audit;
+                Ok(result_set)
+            }
+            Err(err) => {
+                self.connection.audit(audit, Err(err.to_string()));
+                Err(err)
+            }
+        }
+    }
+
+    fn open_query(&self, sql: &str) -> Result<ResultSet<'a>, PlanError> {
+        let started = Instant::now();
+        let db = self.connection.db;
+        // Cached results skip planning, and with it the privilege checks, so
+        // only unrestricted sessions use the cache.
+        let tables = if self.connection.user.is_none() && db.result_cache.lock().unwrap().is_some()
+        {
+            db.query_tables(sql, self.connection.tx()?)
+        } else {
+            None
+        };
+        if tables.is_some() {
+            if let Some(scan) = db
+                .result_cache
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|cache| cache.get(sql))
+            {
+                return Ok(ResultSet {
+                    connection: self.connection,
+                    schema: scan.schema().clone(),
+                    scan: Box::new(scan),
+                    started,
+                    rows: 0,
+                    exceeded: None,
+                    failed: None,
+                    audit: None,
+                });
+            }
+        }
+        let plan = db.planner.create_query_plan(sql, self.connection.tx()?);
+        match plan {
+            Ok(plan) => {
+                let schema = plan.schema().clone();
+                let limits = self.connection.limits();
+                // Opening may already have sorted or materialized the input
+                // into temporary tables.
+                let mut scan = match plan.open() {
+                    Ok(scan) => scan,
+                    Err(err) => {
+                        self.connection.abort();
+                        return Err(PlanError::Storage(err));
+                    }
+                };
+                if let Err(err) = self.connection.check_reads() {
+                    scan.close();
+                    self.connection.abort();
+                    return Err(PlanError::Storage(err));
+                }
+                let checked = limits.check(&self.connection.tx()?.borrow(), started);
+                if let Err(err) = checked {
+                    scan.close();
+                    self.connection.abort();
+                    return Err(err);
+                }
+                // A result worth caching is read in full now.
+                if let Some(tables) = tables {
+                    let mut rows = Vec::new();
+                    while scan.next() {
+                        rows.push(
+                            schema
+                                .fields()
+                                .iter()
+                                .map(|field_name| scan.get_val(field_name))
+                                .collect(),
+                        );
+                        let checked = self
+                            .connection
+                            .check_reads()
+                            .map_err(PlanError::from)
+                            .and_then(|()| limits.check(&self.connection.tx()?.borrow(), started));
+                        if let Err(err) = checked {
+                            scan.close();
+                            self.connection.abort();
+                            return Err(err);
+                        }
+                    }
+                    scan.close();
+                    scan = match db.result_cache.lock().unwrap().as_mut() {
+                        Some(cache) => Box::new(cache.insert(sql, schema.clone(), tables, rows)),
+                        // The cache was turned off while the rows were read.
+                        None => Box::new(CachedScan::new(schema.clone(), Arc::new(rows))),
+                    };
+                }
+                Ok(ResultSet {
+                    connection: self.connection,
+                    schema,
+                    scan,
+                    started,
+                    rows: 0,
+                    exceeded: None,
+                    failed: None,
+                    audit: None,
+                })
+            }
+            Err(err) => {
+                let err = self.connection.read_failure(err);
+                self.connection.abort();
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs an update and commits it, or rolls it back if it fails or went
+    /// past a resource limit. Returns the number of records affected. Inside
+    /// a transaction from `Connection::begin`, the update is left for the
+    /// transaction's commit.
+    ///
+    /// A disk that fills up while the update runs or commits fails it with
+    /// `PlanError::Storage` and rolls it back; the database keeps serving
+    /// reads and takes updates again once there is room.
+    pub fn execute_update(&self, sql: &str) -> Result<usize, PlanError> {
+        let audit = self.connection.start_audit(sql)?;
+        let started = Instant::now();
+        let result = (|| {
+            let count = self
+                .connection
+                .db
+                .planner
+                .execute_update(sql, self.connection.tx()?)?;
+            self.connection
+                .limits()
+                .check(&self.connection.tx()?.borrow(), started)?;
+            self.connection.end_statement()?;
+            Ok(count)
+        })()
+        .map_err(|err| self.connection.read_failure(err));
+        if result.is_err() {
+            self.connection.abort();
+        }
+        self.connection
+            .audit(audit, result.as_ref().copied().map_err(ToString::to_string));
+        result
+    }
+}
+
+/// The records of a query. Field names are case insensitive. Dropping the
+/// result set closes its scan and commits the connection's transaction,
+/// unless `Connection::begin` started it.
+pub struct ResultSet<'a> {
+    connection: &'a Connection<'a>,
+    schema: Schema,
+    scan: Box<dyn Scan>,
+    started: Instant,
+    rows: usize,
+    exceeded: Option<Limit>,
+    // The error a read of the scan failed with, once it has been returned.
+    failed: Option<String>,
+    audit: Option<PendingAudit>,
+}
+
+impl ResultSet<'_> {
+    // Named after JDBC's ResultSet.next rather than Iterator::next; a result
+    // set is read in place instead of yielding owned rows.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        self.try_next().unwrap_or(false)
+    }
+
+    /// Like `next`, but tells a result that ended because it went past one
+    /// of the connection's resource limits from one that ran out of rows.
+    /// Once a limit is hit, every later call fails with it.
+    ///
+    /// Reading writes nothing of its own: sorts and materializations write
+    /// their temporary tables when `execute_query` opens the query, and report
+    /// a full disk there. A read that fails, as on a lock that cannot be
+    /// granted, fails the call and rolls the transaction back, and the result
+    /// ends there. A block that cannot be pinned while reading, as when every
+    /// buffer stays pinned, still panics.
+    pub fn try_next(&mut self) -> Result<bool, PlanError> {
+        if let Some(limit) = self.exceeded {
+            return Err(PlanError::LimitExceeded(limit));
+        }
+        if self.failed.is_some() {
+            return Ok(false);
+        }
+        let limits = self.connection.limits();
+        let found = self.scan.next();
+        if let Err(err) = self.connection.check_reads() {
+            self.failed = Some(err.to_string());
+            self.connection.abort();
+            return Err(PlanError::Storage(err));
+        }
+        let result = if !found {
+            Ok(false)
+        } else if limits.max_result_rows.is_some_and(|max| self.rows >= max) {
+            Err(PlanError::LimitExceeded(Limit::ResultRows))
+        } else {
+            self.rows += 1;
+            self.connection
+                .tx()
+                .map_err(PlanError::from)
+                .and_then(|tx| limits.check(&tx.borrow(), self.started))
+                .map(|()| true)
+        };
+        if let Err(PlanError::LimitExceeded(limit)) = result {
+            self.exceeded = Some(limit);
+        }
+        result
+    }
+
+    pub fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.scan.get_int(&field_name.to_lowercase())
+    }
+
+    pub fn get_string(&self, field_name: &str) -> Option<String> {
+        self.scan.get_string(&field_name.to_lowercase())
+    }
+
+    /// Like `get_string`, but borrowed from the result when it is held in
+    /// memory, as a cached result is.
+    pub fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.scan.get_str(&field_name.to_lowercase())
+    }
+
+    /// Calls `f` with the value of the field borrowed for the length of the
+    /// call, straight from the buffer's page when the scan reads one, so
+    /// that not even a plain select copies it.
+    pub fn with_str<R>(&self, field_name: &str, f: impl FnOnce(Option<&str>) -> R) -> R {
+        let mut f = Some(f);
+        let mut result = None;
+        self.scan.with_str(&field_name.to_lowercase(), &mut |val| {
+            result = f.take().map(|f| f(val));
+        });
+        result.expect("scan did not call back with the value")
+    }
+
+    /// The value of a bigint column, or of an int column widened to one.
+    pub fn get_long(&self, field_name: &str) -> Option<i64> {
+        self.get_val(field_name)?.as_long()
+    }
+
+    /// The value of a double column, or of an int or bigint column
+    /// converted to one.
+    pub fn get_double(&self, field_name: &str) -> Option<f64> {
+        self.get_val(field_name)?.as_double()
+    }
+
+    pub fn get_bool(&self, field_name: &str) -> Option<bool> {
+        self.get_val(field_name)?.as_bool()
+    }
+
+    /// The value of a date column, in days since 1970-01-01.
+    pub fn get_date(&self, field_name: &str) -> Option<i32> {
+        self.get_val(field_name)?.as_date()
+    }
+
+    pub fn get_blob(&self, field_name: &str) -> Option<Vec<u8>> {
+        match self.get_val(field_name)? {
+            Constant::Blob(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.scan.get_val(&field_name.to_lowercase())
+    }
+
+    pub fn fields(&self) -> &[String] {
+        self.schema.fields()
+    }
+
+    pub fn metadata(&self) -> ResultSetMetaData {
+        ResultSetMetaData::new(&self.schema)
+    }
+
+    pub fn close(self) {}
+}
+
+impl Drop for ResultSet<'_> {
+    fn drop(&mut self) {
+        self.scan.close();
+        let result = match (self.exceeded, self.failed.take()) {
+            (Some(limit), _) => Err(PlanError::LimitExceeded(limit).to_string()),
+            (None, Some(message)) => Err(message),
+            (None, None) => Ok(self.rows),
+        };
+        self.connection.audit(self.audit.take(), result);
+        if let Err(err) = self.connection.end_statement() {
+            log::warn!("failed to commit after a query: {}", err);
+            self.connection.abort();
+        }
+    }
+}
+
+/// The columns of a `ResultSet`, in order, as JDBC's `ResultSetMetaData`
+/// describes them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResultSetMetaData {
+    columns: Vec<ColumnMetaData>,
+}
+
+impl ResultSetMetaData {
+    fn new(schema: &Schema) -> ResultSetMetaData {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field_name| {
+                let column_type = schema.field_type(field_name).unwrap_or(VARCHAR);
+                let length = schema.length(field_name).unwrap_or(0);
+                let (type_name, precision, display_size) = match column_type {
+                    INTEGER => ("int", 10, 11),
+                    BIGINT => ("bigint", 19, 20),
+                    DOUBLE => ("double", 15, 24),
+                    BOOLEAN => ("boolean", 1, 5),
+                    DATE => ("date", 10, 10),
+                    BLOB => ("blob", 0, 0),
+                    CHAR => ("char", length, length),
+                    _ => ("varchar", length, length),
+                };
+                ColumnMetaData {
+                    name: field_name.clone(),
+                    column_type,
+                    type_name,
+                    precision,
+                    display_size,
+                    nullable: schema.nullable(field_name).unwrap_or(true),
+                    table_name: schema.table_name(field_name).map(str::to_string),
+                }
+            })
+            .collect();
+        ResultSetMetaData { columns }
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn columns(&self) -> &[ColumnMetaData] {
+        &self.columns
+    }
+
+    /// The column named `name`, in any case.
+    pub fn column(&self, name: &str) -> Option<&ColumnMetaData> {
+        let name = name.to_lowercase();
+        self.columns.iter().find(|column| column.name == name)
+    }
+}
+
+/// One column of a result.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnMetaData {
+    pub name: String,
+    /// The type's `java.sql.Types` code, such as 4 for ints and 12 for
+    /// varchars.
+    pub column_type: i32,
+    /// The name of the type: int, varchar, bigint, double, boolean, date or
+    /// blob.
+    pub type_name: &'static str,
+    /// The declared length of a varchar, or the decimal digits the other
+    /// types can hold; zero for blobs, which have no bound.
+    pub precision: usize,
+    /// The widest a value is when written out as text, sign included; zero
+    /// for blobs.
+    pub display_size: usize,
+    /// False only for columns known never to be null: stored fields with no
+    /// null bit, and counts.
+    pub nullable: bool,
+    /// The stored table the values come from, or `None` for computed
+    /// columns.
+    pub table_name: Option<String>,
+}
+
+/// A table definition written in Rust instead of SQL, for example
+/// `Table::create("users").int("id").varchar("name", 32).execute(&conn)`.
+/// Names are lowercased, as the SQL parser does.
+pub struct Table {
+    table_name: String,
+    schema: Schema,
+}
+
+impl Table {
+    pub fn create(table_name: &str) -> Table {
+        Table {
+            table_name: table_name.to_lowercase(),
+            schema: Schema::new(),
+        }
+    }
+
+    pub fn int(mut self, field_name: &str) -> Table {
+        self.schema.add_int_field(&field_name.to_lowercase());
+        self
+    }
+
+    pub fn varchar(mut self, field_name: &str, length: usize) -> Table {
+        self.schema
+            .add_string_field(&field_name.to_lowercase(), length);
+        self
+    }
+
+    pub fn char(mut self, field_name: &str, length: usize) -> Table {
+        self.schema
+            .add_char_field(&field_name.to_lowercase(), length);
+        self
+    }
+
+    pub fn bigint(mut self, field_name: &str) -> Table {
+        self.schema.add_long_field(&field_name.to_lowercase());
+        self
+    }
+
+    pub fn double(mut self, field_name: &str) -> Table {
+        self.schema.add_double_field(&field_name.to_lowercase());
+        self
+    }
+
+    pub fn boolean(mut self, field_name: &str) -> Table {
+        self.schema.add_bool_field(&field_name.to_lowercase());
+        self
+    }
+
+    pub fn date(mut self, field_name: &str) -> Table {
+        self.schema.add_date_field(&field_name.to_lowercase());
+        self
+    }
+
+    pub fn blob(mut self, field_name: &str) -> Table {
+        self.schema.add_blob_field(&field_name.to_lowercase());
+        self
+    }
+
+    /// Adds the table to the catalog and commits, like the equivalent
+    /// `create table` statement.
+    pub fn execute(self, connection: &Connection) -> Result<(), PlanError> {
+        let tx = connection.tx()?;
+        let metadata_mgr = &connection.db.metadata_mgr;
+        if metadata_mgr
+            .get_layout(&self.table_name, tx.clone())
+            .is_some()
+        {
+            connection.abort();
+            return Err(PlanError::TableExists(self.table_name));
+        }
+        let created = metadata_mgr
+            .create_table(&self.table_name, &self.schema, tx)
+            .and_then(|()| connection.end_statement());
+        if let Err(err) = created {
+            connection.abort();
+            return Err(PlanError::Storage(err));
+        }
+        Ok(())
+    }
+}
+
+enum ColumnGenerator {
+    Sequence,
+    RandomInt(i32),
+    RandomString(usize),
+}
+
+/// Creates a table and fills it with generated rows, for tests and demos,
+/// for example
+/// `Generator::create("users", 1000).sequence("id").random_string("name", 12).execute(&conn)`.
+/// Rows are written straight into the table rather than through `insert`
+/// statements, so they are not reported to change streams. The same seed
+/// always produces the same rows.
+pub struct Generator {
+    table_name: String,
+    rows: usize,
+    columns: Vec<(String, ColumnGenerator)>,
+    seed: u64,
+}
+
+impl Generator {
+    pub fn create(table_name: &str, rows: usize) -> Generator {
+        Generator {
+            table_name: table_name.to_lowercase(),
+            rows,
+            columns: Vec::new(),
+            seed: 0,
+        }
+    }
+
+    /// An int field numbering the rows from 1.
+    pub fn sequence(mut self, field_name: &str) -> Generator {
+        self.columns
+            .push((field_name.to_lowercase(), ColumnGenerator::Sequence));
+        self
+    }
+
+    /// An int field holding random values from 0 up to but excluding `bound`.
+    pub fn random_int(mut self, field_name: &str, bound: i32) -> Generator {
+        self.columns.push((
+            field_name.to_lowercase(),
+            ColumnGenerator::RandomInt(bound.max(1)),
+        ));
+        self
+    }
+
+    /// A varchar field holding random lowercase strings of exactly `length`
+    /// characters.
+    pub fn random_string(mut self, field_name: &str, length: usize) -> Generator {
+        self.columns.push((
+            field_name.to_lowercase(),
+            ColumnGenerator::RandomString(length),
+        ));
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Generator {
+        self.seed = seed;
+        self
+    }
+
+    /// Creates the table, writes the rows and commits. Returns the number of
+    /// rows written.
+    pub fn execute(self, connection: &Connection) -> Result<usize, PlanError> {
+        let tx = connection.tx()?;
+        let metadata_mgr = &connection.db.metadata_mgr;
+        if metadata_mgr
+            .get_layout(&self.table_name, tx.clone())
+            .is_some()
+        {
+            connection.abort();
+            return Err(PlanError::TableExists(self.table_name));
+        }
+        let mut schema = Schema::new();
+        for (field_name, column) in self.columns.iter() {
+            match column {
+                ColumnGenerator::Sequence | ColumnGenerator::RandomInt(_) => {
+                    schema.add_int_field(field_name)
+                }
+                ColumnGenerator::RandomString(length) => {
+                    schema.add_string_field(field_name, *length)
+                }
+            }
+        }
+        let mut state = self.seed;
+        let write_rows = || -> Result<(), DbError> {
+            metadata_mgr.create_table(&self.table_name, &schema, tx.clone())?;
+            let layout = metadata_mgr
+                .get_layout(&self.table_name, tx.clone())
+                .expect("table was just created");
+            let mut scan = TableScan::new(tx, &self.table_name, layout);
+            for row in 0..self.rows {
+                scan.insert()?;
+                for (field_name, column) in self.columns.iter() {
+                    match column {
+                        ColumnGenerator::Sequence => scan.set_int(field_name, row as i32 + 1),
+                        ColumnGenerator::RandomInt(bound) => {
+                            let val = next_random(&mut state) % *bound as u64;
+                            scan.set_int(field_name, val as i32)
+                        }
+                        ColumnGenerator::RandomString(length) => {
+                            let val: String = (0..*length)
+                                .map(|_| (b'a' + (next_random(&mut state) % 26) as u8) as char)
+                                .collect();
+                            scan.set_string(field_name, val)
+                        }
+                    }?;
+                }
+            }
+            scan.close();
+            connection.commit()
+        };
+        if let Err(err) = write_rows() {
+            connection.abort();
+            return Err(PlanError::Storage(err));
+        }
+        Ok(self.rows)
+    }
+}
+
+// SplitMix64: small, fast and good enough for test data.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changecapture::{ChangeOp, RowChange};
+    use crate::filemanager::BlockId;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_simpledb_round_trip() {
+        let tmp_dir = TempDir::new("test_simpledb").expect("failed to create temp dir");
+        let directory = tmp_dir.path().join("studentdb");
+        {
+            let db = SimpleDB::new(&directory).unwrap();
+            db.set_sync_mode(SyncMode::OnCommit);
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table student (sid int, sname varchar(10))")
+                .unwrap();
+            for (sid, sname) in [(1, "joe"), (2, "amy"), (3, "max")] {
+                let sql = format!(
+                    "insert into student (sid, sname) values ({}, '{}')",
+                    sid, sname
+                );
+                assert_eq!(stmt.execute_update(&sql).unwrap(), 1);
+            }
+            assert!(stmt.execute_query("select nosuch from student").is_err());
+            conn.close();
+        }
+
+        // Reopening finds the table and its rows.
+        let db = SimpleDB::new(&directory).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        let mut rs = stmt
+            .execute_query("select SNAME from student where sid = 2")
+            .unwrap();
+        assert_eq!(rs.fields(), &["sname".to_string()]);
+        assert!(rs.next());
+        assert_eq!(rs.get_string("SName"), Some("amy".to_string()));
+        assert_eq!(rs.get_int("sid"), None);
+        assert!(!rs.next());
+        rs.close();
+
+        assert_eq!(
+            stmt.execute_update("delete from student where sid = 1")
+                .unwrap(),
+            1
+        );
+        let mut rs = stmt.execute_query("select sid from student").unwrap();
+        let mut sids = Vec::new();
+        while rs.next() {
+            sids.push(rs.get_int("sid").unwrap());
+        }
+        rs.close();
+        assert_eq!(sids, vec![2, 3]);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_policies() {
+        for buffer_policy in [
+            BufferPolicy::Lru,
+            BufferPolicy::Clock,
+            BufferPolicy::LruK(2),
+        ] {
+            let tmp_dir = TempDir::new("test_buffer_policies").expect("failed to create temp dir");
+            let options = OpenOptions {
+                buffer_policy,
+                ..OpenOptions::default()
+            };
+            let db = SimpleDB::with_options(tmp_dir.path(), options).unwrap();
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table t (a int, b varchar(20))")
+                .unwrap();
+            for a in 0..200 {
+                let sql = format!("insert into t (a, b) values ({}, 'row{}')", a, a);
+                stmt.execute_update(&sql).unwrap();
+            }
+            // Far more blocks than buffers, so every pass replaces them.
+            for _ in 0..2 {
+                let mut rs = stmt.execute_query("select a, b from t").unwrap();
+                let mut total = 0;
+                while rs.next() {
+                    let a = rs.get_int("a").unwrap();
+                    assert_eq!(rs.get_string("b"), Some(format!("row{}", a)));
+                    total += a;
+                }
+                rs.close();
+                assert_eq!(total, 19_900, "{:?}", buffer_policy);
+            }
+            conn.close();
+            tmp_dir.close().expect("failed to remove temp dir");
+        }
+    }
+
+    #[test]
+    fn test_buffer_partitions() {
+        let tmp_dir = TempDir::new("test_buffer_partitions").expect("failed to create temp dir");
+        let options = OpenOptions {
+            buffer_count: 16,
+            buffer_partitions: 4,
+            ..OpenOptions::default()
+        };
+        let db = SimpleDB::with_options(tmp_dir.path(), options).unwrap();
+        assert_eq!(db.buffer_manager.partition_count(), 4);
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("create index a_idx on t (a)").unwrap();
+        for a in 0..300 {
+            stmt.execute_update(&format!("insert into t (a) values ({})", a))
+                .unwrap();
+        }
+        let mut rs = stmt.execute_query("select a from t where a = 123").unwrap();
+        assert!(rs.next());
+        assert!(!rs.next());
+        rs.close();
+        let mut rs = stmt.execute_query("select count(a) from t").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("countofa"), Some(300));
+        rs.close();
+        conn.close();
+        drop(db);
+
+        // More parts than buffers leaves one buffer a part.
+        let options = OpenOptions {
+            buffer_partitions: 100,
+            ..OpenOptions::default()
+        };
+        let db = SimpleDB::with_options(tmp_dir.path(), options).unwrap();
+        assert_eq!(
+            db.buffer_manager.partition_count(),
+            SimpleDB::BUFFER_COUNT as usize
+        );
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_buffer_retries() {
+        let tmp_dir = TempDir::new("test_buffer_retries").expect("failed to create temp dir");
+        let options = OpenOptions {
+            buffer_wait: Duration::from_secs(60),
+            buffer_retries: Some(1),
+            ..OpenOptions::default()
+        };
+        let db = SimpleDB::with_options(tmp_dir.path(), options).unwrap();
+        let buffer_manager = db.buffer_manager.clone();
+        let count = SimpleDB::BUFFER_COUNT as usize;
+        for _ in 0..count + 2 {
+            db.file_manager.lock().unwrap().append("t").unwrap();
+        }
+        let buffers: Vec<_> = (0..count)
+            .map(|block_num| {
+                buffer_manager
+                    .pin(&BlockId::new("t", block_num), 1)
+                    .unwrap()
+            })
+            .collect();
+        let waiter = |block_num: usize, txn: i32| {
+            let waiting = buffer_manager.stats().waiters;
+            let thread = {
+                let buffer_manager = buffer_manager.clone();
+                std::thread::spawn(move || {
+                    let started = Instant::now();
+                    let pinned = buffer_manager.pin(&BlockId::new("t", block_num), txn);
+                    (pinned, started.elapsed())
+                })
+            };
+            while buffer_manager.stats().waiters == waiting {
+                std::thread::yield_now();
+            }
+            thread
+        };
+        let first = waiter(count, 2);
+        let second = waiter(count + 1, 3);
+
+        // The freed buffer goes to the first waiter. The second is woken
+        // too, finds nothing, and has no retries left, so it gives up long
+        // before its wait is over.
+        buffer_manager.unpin(&buffers[0], 1);
+        let (pinned, _) = first.join().unwrap();
+        buffer_manager.unpin(&pinned.unwrap(), 2);
+        let (pinned, waited) = second.join().unwrap();
+        let Err(DbError::BufferAbort(err)) = pinned else {
+            panic!("pin did not give up");
+        };
+        assert_eq!(err.attempts(), 2);
+        assert!(waited < Duration::from_secs(60));
+        for buffer in buffers.iter().skip(1) {
+            buffer_manager.unpin(buffer, 1);
+        }
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_lock_audit_option() {
+        let tmp_dir = TempDir::new("test_lock_audit_option").expect("failed to create temp dir");
+        for lock_audit in [true, false] {
+            let options = OpenOptions {
+                lock_audit,
+                ..OpenOptions::default()
+            };
+            let db = SimpleDB::with_options(tmp_dir.path(), options).unwrap();
+            assert_eq!(db.lock_table.audit(), lock_audit);
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            if lock_audit {
+                stmt.execute_update("create table t (a int)").unwrap();
+            }
+            stmt.execute_update("insert into t (a) values (1)").unwrap();
+            let mut rs = stmt.execute_query("select a from t").unwrap();
+            let mut rows = 0;
+            while rs.next() {
+                rows += 1;
+            }
+            rs.close();
+            assert_eq!(rows, if lock_audit { 1 } else { 2 });
+        }
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_simpledb_checksums() {
+        let tmp_dir = TempDir::new("test_simpledb").expect("failed to create temp dir");
+        {
+            let db = SimpleDB::with_checksums(
+                tmp_dir.path(),
+                SimpleDB::BLOCK_SIZE,
+                SimpleDB::BUFFER_COUNT,
+                ChecksumMismatch::Error,
+            )
+            .unwrap();
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table t (a int)").unwrap();
+            for a in 0..100 {
+                stmt.execute_update(&format!("insert into t (a) values ({})", a))
+                    .unwrap();
+            }
+        }
+
+        // Blocks written with checksums read back once the database reopens.
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        let mut total = 0;
+        while rs.next() {
+            total += rs.get_int("a").unwrap();
+        }
+        rs.close();
+        assert_eq!(total, 4950);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_table_builder() {
+        let tmp_dir = TempDir::new("test_table_builder").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        Table::create("Users")
+            .int("ID")
+            .varchar("name", 8)
+            .execute(&conn)
+            .unwrap();
+        assert!(matches!(
+            Table::create("users").int("id").execute(&conn),
+            Err(PlanError::TableExists(_))
+        ));
+
+        let stmt = conn.create_statement();
+        stmt.execute_update("insert into users (id, name) values (7, 'ada')")
+            .unwrap();
+        assert!(matches!(
+            stmt.execute_update("insert into users (id, name) values (8, 'too long name')"),
+            Err(PlanError::ValueTooLong(_))
+        ));
+        let mut rs = stmt.execute_query("select id, name from users").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("id"), Some(7));
+        assert_eq!(rs.get_string("name"), Some("ada".to_string()));
+        assert!(!rs.next());
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_generator() {
+        let tmp_dir = TempDir::new("test_generator").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let generator = |table_name: &str| {
+            Generator::create(table_name, 300)
+                .sequence("id")
+                .random_int("score", 10)
+                .random_string("name", 12)
+                .seed(7)
+        };
+        assert_eq!(generator("a").execute(&conn).unwrap(), 300);
+        generator("b").execute(&conn).unwrap();
+        assert!(matches!(
+            generator("a").execute(&conn),
+            Err(PlanError::TableExists(_))
+        ));
+
+        let stmt = conn.create_statement();
+        let mut rs = stmt
+            .execute_query("select count(id), min(id), max(id), max(score) from a")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("countofid"), Some(300));
+        assert_eq!(rs.get_int("minofid"), Some(1));
+        assert_eq!(rs.get_int("maxofid"), Some(300));
+        assert!(rs.get_int("maxofscore").unwrap() < 10);
+        rs.close();
+        let mut rs = stmt
+            .execute_query("select count(id), max(score) from a where id = 0")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("countofid"), Some(0));
+        assert_eq!(rs.get_int("maxofscore"), None);
+        assert!(!rs.next());
+        rs.close();
+        let mut rs = stmt
+            .execute_query(
+                "select approx_count_distinct(score), approx_count_distinct(name) from a",
+            )
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("approx_count_distinctofscore"), Some(10));
+        let names = rs.get_int("approx_count_distinctofname").unwrap();
+        assert!((285..=315).contains(&names), "estimated {} names", names);
+        rs.close();
+
+        // The same seed produces the same rows.
+        let rows = |table_name: &str| {
+            let sql = format!("select id, score, name from {}", table_name);
+            let mut rs = stmt.execute_query(&sql).unwrap();
+            let mut rows = Vec::new();
+            while rs.next() {
+                let name = rs.get_string("name").unwrap();
+                assert_eq!(name.len(), 12);
+                assert!(name.chars().all(|c| c.is_ascii_lowercase()));
+                rows.push((rs.get_int("id"), rs.get_int("score"), name));
+            }
+            rows
+        };
+        let rows_a = rows("a");
+        assert_eq!(rows_a.len(), 300);
+        assert_eq!(rows_a, rows("b"));
+        assert!(rows_a.windows(2).any(|pair| pair[0].2 != pair[1].2));
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_change_stream() {
+        let tmp_dir = TempDir::new("test_change_stream").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let mut changes = db.subscribe_changes();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int, b varchar(5))")
+            .unwrap();
+        stmt.execute_update("insert into t (a, b) values (1, 'x')")
+            .unwrap();
+        stmt.execute_update("update t set a = 2 where b = 'x'")
+            .unwrap();
+        assert!(stmt
+            .execute_update("update t set b = 'too long' where a = 2")
+            .is_err());
+        stmt.execute_update("delete from t where a = 2").unwrap();
+
+        let changes: Vec<RowChange> = changes.by_ref().collect();
+        let ops: Vec<ChangeOp> = changes.iter().map(|change| change.op).collect();
+        assert_eq!(
+            ops,
+            vec![ChangeOp::Insert, ChangeOp::Update, ChangeOp::Delete]
+        );
+        assert!(changes.iter().all(|change| change.table_name == "t"));
+        assert!(changes
+            .windows(2)
+            .all(|pair| pair[0].commit_lsn < pair[1].commit_lsn));
+        let row = |a: i32| {
+            vec![
+                ("a".to_string(), Constant::Int(a)),
+                ("b".to_string(), Constant::String("x".to_string())),
+            ]
+        };
+        assert_eq!(changes[0].after, row(1));
+        assert_eq!(changes[1].before, row(1));
+        assert_eq!(changes[1].after, row(2));
+        assert_eq!(changes[2].before, row(2));
+        assert!(changes[2].after.is_empty());
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let tmp_dir = TempDir::new("test_savepoints").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("create table u (a int)").unwrap();
+        let mut changes = db.subscribe_changes();
+
+        conn.begin().unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+        let savepoint = conn.savepoint().unwrap();
+        stmt.execute_update("insert into t (a) values (2)").unwrap();
+        stmt.execute_update("update t set a = 10 where a = 1")
+            .unwrap();
+        stmt.execute_update("insert into u (a) values (1)").unwrap();
+        conn.rollback_to(&savepoint).unwrap();
+
+        // The locks on u went with the insert, so another transaction can
+        // read it while this one is still open.
+        let other = db.connect();
+        let mut rs = other
+            .create_statement()
+            .execute_query("select a from u")
+            .unwrap();
+        assert!(!rs.try_next().unwrap());
+        rs.close();
+        other.close();
+
+        stmt.execute_update("insert into t (a) values (3)").unwrap();
+        conn.commit().unwrap();
+
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        let mut values = Vec::new();
+        while rs.next() {
+            values.push(rs.get_int("a").unwrap());
+        }
+        rs.close();
+        values.sort();
+        assert_eq!(values, vec![1, 3]);
+        let inserted: Vec<Constant> = changes
+            .by_ref()
+            .map(|change| {
+                assert_eq!(change.op, ChangeOp::Insert);
+                change.after[0].1.clone()
+            })
+            .collect();
+        assert_eq!(inserted, vec![Constant::Int(1), Constant::Int(3)]);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_threads() {
+        let tmp_dir = TempDir::new("test_threads").expect("failed to create temp dir");
+        let db = Arc::new(SimpleDB::new(tmp_dir.path()).unwrap());
+        let conn = db.connect();
+        conn.create_statement()
+            .execute_update("create table t (a int)")
+            .unwrap();
+        conn.close();
+
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let conn = db.connect();
+                    let stmt = conn.create_statement();
+                    for i in 0..10 {
+                        let a = thread * 10 + i;
+                        // Inserts into the same block can deadlock; the
+                        // one picked to give way is run again.
+                        loop {
+                            conn.begin().unwrap();
+                            let inserted = stmt
+                                .execute_update(&format!("insert into t (a) values ({})", a))
+                                .and_then(|_| {
+                                    let mut rs = stmt.execute_query("select a from t")?;
+                                    let mut seen = false;
+                                    while rs.try_next()? {
+                                        seen |= rs.get_int("a") == Some(a);
+                                    }
+                                    assert!(seen);
+                                    Ok(())
+                                })
+                                .and_then(|()| Ok(conn.commit()?));
+                            match inserted {
+                                Ok(()) => break,
+                                Err(PlanError::Storage(DbError::LockAbort(_))) => {
+                                    conn.rollback().unwrap()
+                                }
+                                Err(err) => panic!("insert failed: {}", err),
+                            }
+                        }
+                    }
+                    conn.close();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let conn = db.connect();
+        let mut rs = conn
+            .create_statement()
+            .execute_query("select a from t")
+            .unwrap();
+        let mut values = Vec::new();
+        while rs.next() {
+            values.push(rs.get_int("a").unwrap());
+        }
+        rs.close();
+        values.sort();
+        assert_eq!(values, (0..40).collect::<Vec<_>>());
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_retention() {
+        let tmp_dir = TempDir::new("test_retention").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let mut changes = db.subscribe_changes();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table events (id int, ts int, kind varchar(5))")
+            .unwrap();
+        stmt.execute_update("create index events_id on events (id)")
+            .unwrap();
+        assert!(matches!(
+            db.set_retention("events", "kind", 1),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            db.set_retention("missing", "ts", 1),
+            Err(PlanError::UnknownTable(_))
+        ));
+        db.set_retention("Events", "TS", 2).unwrap();
+
+        // Rows a day apart, the oldest 250 days before `now`.
+        let day = 24 * 60 * 60;
+        let now = 1_000 * day;
+        for id in 0..250 {
+            let sql = format!(
+                "insert into events (id, ts, kind) values ({}, {}, 'x')",
+                id,
+                now - (250 - id) * day
+            );
+            stmt.execute_update(&sql).unwrap();
+        }
+        let _ = changes.by_ref().count();
+
+        // Rows more than two days old go, in batches.
+        assert_eq!(db.run_retention_at(now).unwrap(), 248);
+        assert_eq!(changes.by_ref().count(), 248);
+        let mut rs = stmt.execute_query("select id from events").unwrap();
+        let mut ids = Vec::new();
+        while rs.next() {
+            ids.push(rs.get_int("id").unwrap());
+        }
+        rs.close();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![248, 249]);
+        let mut rs = stmt
+            .execute_query("select ts from events where id = 10")
+            .unwrap();
+        assert!(!rs.next());
+        rs.close();
+        assert_eq!(db.run_retention_at(now).unwrap(), 0);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let tmp_dir = TempDir::new("test_result_cache").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        db.set_result_cache(4);
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("create view v as select a from t")
+            .unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+        let cached = || db.result_cache.lock().unwrap().as_ref().unwrap().len();
+        let read = |sql: &str| {
+            let mut rs = stmt.execute_query(sql).unwrap();
+            let mut vals = Vec::new();
+            while rs.next() {
+                vals.push(rs.get_int("a").unwrap());
+            }
+            rs.close();
+            vals.sort_unstable();
+            vals
+        };
+
+        assert_eq!(read("select a from t"), vec![1]);
+        assert_eq!(read("select a from v"), vec![1]);
+        assert_eq!(cached(), 2);
+        assert_eq!(read("select a from t"), vec![1]);
+        assert_eq!(cached(), 2);
+
+        // A commit to t drops both results, including the one read through
+        // the view.
+        stmt.execute_update("insert into t (a) values (2)").unwrap();
+        assert_eq!(read("select a from v"), vec![1, 2]);
+        assert_eq!(cached(), 1);
+        assert_eq!(read("select a from t"), vec![1, 2]);
+        assert_eq!(cached(), 2);
+
+        // Temporary tables are private to a connection, so their results
+        // are not cached.
+        stmt.execute_update("create temp table s (a int)").unwrap();
+        assert!(read("select a from s").is_empty());
+        assert_eq!(cached(), 2);
+        conn.close();
+
+        db.set_result_cache(0);
+        assert!(db.result_cache.lock().unwrap().is_none());
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_local_counters() {
+        let tmp_dir = TempDir::new("test_local_counters").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (id int)").unwrap();
+        assert_eq!(conn.currval_local("ids"), None);
+        for _ in 0..3 {
+            let sql = format!("insert into t (id) values ({})", conn.nextval_local("ids"));
+            stmt.execute_update(&sql).unwrap();
+        }
+        assert_eq!(conn.currval_local("IDS"), Some(3));
+        conn.rollback().unwrap();
+        assert_eq!(conn.nextval_local("ids"), 4);
+
+        // Each connection counts on its own.
+        let other = db.connect();
+        assert_eq!(other.nextval_local("ids"), 1);
+        other.setval_local("ids", 100);
+        assert_eq!(other.nextval_local("ids"), 101);
+        other.close();
+        assert_eq!(conn.nextval_local("ids"), 5);
+
+        let mut rs = stmt.execute_query("select id from t").unwrap();
+        let mut ids = Vec::new();
+        while rs.next() {
+            ids.push(rs.get_int("id").unwrap());
+        }
+        rs.close();
+        assert_eq!(ids, vec![1, 2, 3]);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_temp_table() {
+        let tmp_dir = TempDir::new("test_temp_table").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        assert!(matches!(
+            stmt.execute_update("create temp table t (a int)"),
+            Err(PlanError::TableExists(_))
+        ));
+        stmt.execute_update("create temp table scratch (a int)")
+            .unwrap();
+        for a in [1, 2] {
+            let sql = format!("insert into scratch (a) values ({})", a);
+            stmt.execute_update(&sql).unwrap();
+        }
+        stmt.execute_update("delete from scratch where a = 1")
+            .unwrap();
+        let mut rs = stmt.execute_query("select a from scratch").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("a"), Some(2));
+        assert!(!rs.next());
+        rs.close();
+        assert!(!db
+            .metadata_mgr()
+            .table_names(conn.tx().unwrap())
+            .contains(&"scratch".to_string()));
+
+        // Another connection neither sees the table nor clashes with it.
+        let other = db.connect();
+        let other_stmt = other.create_statement();
+        assert!(other_stmt.execute_query("select a from scratch").is_err());
+        other_stmt
+            .execute_update("create temp table scratch (b int)")
+            .unwrap();
+        other.close();
+
+        let temp_files = || {
+            std::fs::read_dir(tmp_dir.path())
+                .unwrap()
+                .flatten()
+                .filter(|file| file.file_name().to_string_lossy().starts_with("temp_s"))
+                .count()
+        };
+        assert_eq!(temp_files(), 1);
+        conn.close();
+        assert_eq!(temp_files(), 0);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_null_values() {
+        let tmp_dir = TempDir::new("test_null_values").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int, b varchar(5))")
+            .unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+        stmt.execute_update("insert into t (a, b) values (2, 'x')")
+            .unwrap();
+
+        let mut rs = stmt
+            .execute_query("select a, b from t where b is null")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("a"), Some(1));
+        assert_eq!(rs.get_string("b"), None);
+        assert!(!rs.next());
+        rs.close();
+
+        let mut rs = stmt
+            .execute_query("select a from t where b is not null")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("a"), Some(2));
+        assert!(!rs.next());
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let tmp_dir = TempDir::new("test_resource_limits").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        for a in [3, 1, 2, 5, 4] {
+            let sql = format!("insert into t (a) values ({})", a);
+            stmt.execute_update(&sql).unwrap();
+        }
+
+        conn.set_limits(ResourceLimits {
+            max_result_rows: Some(3),
+            ..ResourceLimits::default()
+        });
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        for _ in 0..3 {
+            assert!(rs.try_next().unwrap());
+        }
+        assert!(matches!(
+            rs.try_next(),
+            Err(PlanError::LimitExceeded(Limit::ResultRows))
+        ));
+        assert!(rs.try_next().is_err());
+        assert!(!rs.next());
+        rs.close();
+
+        // A result that fits exactly ends normally.
+        conn.set_limits(ResourceLimits {
+            max_result_rows: Some(5),
+            ..ResourceLimits::default()
+        });
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        let mut count = 0;
+        while rs.try_next().unwrap() {
+            count += 1;
+        }
+        assert_eq!(count, 5);
+        rs.close();
+
+        conn.set_limits(ResourceLimits {
+            max_pinned_buffers: Some(0),
+            ..ResourceLimits::default()
+        });
+        assert!(matches!(
+            stmt.execute_query("select a from t"),
+            Err(PlanError::LimitExceeded(Limit::PinnedBuffers))
+        ));
+
+        // Grouping sorts its input into temporary tables.
+        conn.set_limits(ResourceLimits {
+            max_temp_blocks: Some(0),
+            ..ResourceLimits::default()
+        });
+        assert!(matches!(
+            stmt.execute_query("select a, count(a) from t group by a"),
+            Err(PlanError::LimitExceeded(Limit::TempSpace))
+        ));
+        assert!(stmt.execute_query("select a from t").is_ok());
+
+        // An update that runs too long is rolled back.
+        conn.set_limits(ResourceLimits {
+            max_runtime: Some(Duration::ZERO),
+            ..ResourceLimits::default()
+        });
+        assert!(matches!(
+            stmt.execute_update("insert into t (a) values (6)"),
+            Err(PlanError::LimitExceeded(Limit::Runtime))
+        ));
+
+        conn.set_limits(ResourceLimits::default());
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        let mut count = 0;
+        while rs.next() {
+            count += 1;
+        }
+        assert_eq!(count, 5);
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let tmp_dir = TempDir::new("test_disk_full").expect("failed to create temp dir");
+        let count_rows = |stmt: &Statement| {
+            let mut rs = stmt.execute_query("select a from t").unwrap();
+            let mut count = 0;
+            while rs.next() {
+                count += 1;
+            }
+            rs.close();
+            count
+        };
+        let inserted = {
+            let db = SimpleDB::new(tmp_dir.path()).unwrap();
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table t (a int, b varchar(100))")
+                .unwrap();
+
+            // Inserts fill what room the last blocks have left, then fail.
+            db.file_manager.lock().unwrap().limit_growth(Some(0));
+            let mut inserted = 0;
+            let err = loop {
+                let sql = format!(
+                    "insert into t (a, b) values ({}, '{}')",
+                    inserted,
+                    "x".repeat(100)
+                );
+                match stmt.execute_update(&sql) {
+                    Ok(_) => inserted += 1,
+                    Err(err) => break err,
+                }
+                assert!(inserted < 1000, "the disk never filled up");
+            };
+            assert!(matches!(err, PlanError::Storage(DbError::DiskFull)));
+            assert_eq!(count_rows(&stmt), inserted);
+
+            db.file_manager.lock().unwrap().limit_growth(None);
+            stmt.execute_update("insert into t (a, b) values (-1, 'after')")
+                .unwrap();
+            assert_eq!(count_rows(&stmt), inserted + 1);
+
+            // Grouping sorts into temporary tables, which cannot grow either.
+            db.file_manager.lock().unwrap().limit_growth(Some(0));
+            let err = stmt
+                .execute_query("select a, count(b) from t group by a")
+                .err();
+            assert!(matches!(err, Some(PlanError::Storage(DbError::DiskFull))));
+            db.file_manager.lock().unwrap().limit_growth(None);
+            assert_eq!(count_rows(&stmt), inserted + 1);
+            inserted + 1
+        };
+
+        // Whatever was logged around the failure recovers cleanly.
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        assert_eq!(count_rows(&conn.create_statement()), inserted);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_commit_on_full_disk() {
+        let tmp_dir = TempDir::new("test_commit_on_full_disk").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+        let count_ones = || {
+            let mut rs = stmt.execute_query("select a from t where a = 1").unwrap();
+            let mut count = 0;
+            while rs.next() {
+                count += 1;
+            }
+            rs.close();
+            count
+        };
+
+        // Changes the row in the connection's transaction, then fills the
+        // log's last block with transactions that start and commit, until
+        // one has no room for its start record, which leaves none for a
+        // commit record either.
+        let change_row = |conn: &Connection| {
+            let tx = conn.tx().unwrap();
+            let layout = db.metadata_mgr.get_layout("t", tx.clone()).unwrap();
+            let mut scan = TableScan::new(tx, "t", layout);
+            assert!(scan.next());
+            scan.set_int("a", 2).unwrap();
+            scan.close();
+            db.file_manager.lock().unwrap().limit_growth(Some(0));
+            loop {
+                let lsn = db.log_manager.lock().unwrap().latest_lsn();
+                let tx = db.new_tx().unwrap();
+                let started = db.log_manager.lock().unwrap().latest_lsn() > lsn;
+                tx.borrow_mut().commit().unwrap();
+                if !started {
+                    break;
+                }
+            }
+        };
+
+        change_row(&conn);
+        assert!(matches!(conn.commit(), Err(DbError::DiskFull)));
+        conn.rollback().unwrap();
+        db.file_manager.lock().unwrap().limit_growth(None);
+        assert_eq!(count_ones(), 1);
+
+        // A connection dropped with such a commit rolls back.
+        let other = db.connect();
+        change_row(&other);
+        drop(other);
+        db.file_manager.lock().unwrap().limit_growth(None);
+        assert_eq!(count_ones(), 1);
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let tmp_dir = TempDir::new("test_lock_timeout").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+
+        // Another transaction holds the table's only block exclusively.
+        let writer = db.new_tx().unwrap();
+        let block_id = BlockId::new("t.tbl", 0);
+        writer.borrow_mut().pin(&block_id).unwrap();
+        let flag = writer.borrow_mut().get_int(&block_id, 0).unwrap();
+        writer
+            .borrow_mut()
+            .set_int(&block_id, 0, flag, true)
+            .unwrap();
+
+        let read = stmt
+            .execute_query("select a from t")
+            .and_then(|mut rs| rs.try_next());
+        assert!(matches!(
+            read,
+            Err(PlanError::Storage(DbError::LockAbort(_)))
+        ));
+        let updated = stmt.execute_update("update t set a = 2");
+        assert!(matches!(
+            updated,
+            Err(PlanError::Storage(DbError::LockAbort(_)))
+        ));
+
+        writer.borrow_mut().rollback().unwrap();
+        let mut rs = stmt.execute_query("select a from t").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("a"), Some(1));
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_engine() {
+        let sales_dir = TempDir::new("test_engine_sales").expect("failed to create temp dir");
+        let audit_dir = TempDir::new("test_engine_audit").expect("failed to create temp dir");
+        let mut engine = Engine::new();
+        engine.open("sales", sales_dir.path()).unwrap();
+        engine.open("Audit", audit_dir.path()).unwrap();
+        assert_eq!(engine.names(), vec!["audit", "sales"]);
+        assert!(engine.connect("nosuch").is_none());
+
+        // The same table name in each database holds its own rows.
+        for (name, rows) in [("sales", 3), ("audit", 1)] {
+            let conn = engine.connect(name).unwrap();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table t (a int)").unwrap();
+            for a in 0..rows {
+                let sql = format!("insert into t (a) values ({})", a);
+                stmt.execute_update(&sql).unwrap();
+            }
+        }
+        for (name, rows) in [("SALES", 3), ("audit", 1)] {
+            let conn = engine.connect(name).unwrap();
+            let mut rs = conn
+                .create_statement()
+                .execute_query("select a from t")
+                .unwrap();
+            let mut count = 0;
+            while rs.next() {
+                count += 1;
+            }
+            assert_eq!(count, rows);
+        }
+
+        // A database taken out of the engine opens again with its data.
+        drop(engine.remove("sales"));
+        assert!(engine.connect("sales").is_none());
+        engine.open("sales", sales_dir.path()).unwrap();
+        let conn = engine.connect("sales").unwrap();
+        let mut rs = conn
+            .create_statement()
+            .execute_query("select a from t")
+            .unwrap();
+        assert!(rs.next());
+        rs.close();
+        conn.close();
+        drop(engine);
+        sales_dir.close().expect("failed to remove temp dir");
+        audit_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_attach() {
+        let main_dir = TempDir::new("test_attach_main").expect("failed to create temp dir");
+        let other_dir = TempDir::new("test_attach_other").expect("failed to create temp dir");
+        {
+            let other = SimpleDB::new(other_dir.path()).unwrap();
+            let conn = other.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table dept (did int, dname varchar(10))")
+                .unwrap();
+            stmt.execute_update("create index dept_did on dept (did)")
+                .unwrap();
+            stmt.execute_update("create table empty (x int)").unwrap();
+            for (did, dname) in [(1, "sales"), (2, "audit")] {
+                let sql = format!(
+                    "insert into dept (did, dname) values ({}, '{}')",
+                    did, dname
+                );
+                stmt.execute_update(&sql).unwrap();
+            }
+        }
+        let snapshot = || {
+            let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(other_dir.path())
+                .unwrap()
+                .flatten()
+                .map(|file| {
+                    let name = file.file_name().into_string().unwrap();
+                    (name, std::fs::read(file.path()).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let before = snapshot();
+
+        let db = SimpleDB::new(main_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table emp (ename varchar(10), edept int)")
+            .unwrap();
+        stmt.execute_update("insert into emp (ename, edept) values ('ann', 2)")
+            .unwrap();
+        assert!(matches!(
+            stmt.execute_query("select dname from other.dept"),
+            Err(PlanError::UnknownTable(_))
+        ));
+
+        db.attach("Other", other_dir.path()).unwrap();
+        let mut rs = stmt
+            .execute_query("select ename, dname from emp, other.dept where edept = did")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_string("ename"), Some("ann".to_string()));
+        assert_eq!(rs.get_string("dname"), Some("audit".to_string()));
+        assert!(!rs.next());
+        rs.close();
+        let mut rs = stmt.execute_query("select x from other.empty").unwrap();
+        assert!(!rs.next());
+        rs.close();
+        assert!(matches!(
+            stmt.execute_query("select dname from other.nosuch"),
+            Err(PlanError::UnknownTable(_))
+        ));
+
+        // Attached tables can be copied from, but not written to.
+        stmt.execute_update("create table dept (did int, dname varchar(10))")
+            .unwrap();
+        let mut rs = stmt
+            .execute_query("select did, dname from other.dept")
+            .unwrap();
+        let mut rows = Vec::new();
+        while rs.next() {
+            rows.push((rs.get_int("did").unwrap(), rs.get_string("dname").unwrap()));
+        }
+        rs.close();
+        for (did, dname) in rows {
+            let sql = format!(
+                "insert into dept (did, dname) values ({}, '{}')",
+                did, dname
+            );
+            stmt.execute_update(&sql).unwrap();
+        }
+        let mut rs = stmt.execute_query("select did from dept").unwrap();
+        let mut count = 0;
+        while rs.next() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        rs.close();
+        assert!(stmt
+            .execute_update("insert into other.dept (did, dname) values (3, 'x')")
+            .is_err());
+
+        assert!(db.detach("other"));
+        assert!(!db.detach("other"));
+        assert!(matches!(
+            db.attach("nothing", main_dir.path().join("nothing")),
+            Err(CatalogError::NoDatabase(_))
+        ));
+        conn.close();
+        drop(db);
+        assert!(snapshot() == before);
+        main_dir.close().expect("failed to remove temp dir");
+        other_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_privileges() {
+        let tmp_dir = TempDir::new("test_privileges").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let admin = db.connect();
+        let stmt = admin.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        stmt.execute_update("insert into t (a) values (1)").unwrap();
+        admin.commit().unwrap();
+
+        let alice = db.connect_as("Alice");
+        let alice_stmt = alice.create_statement();
+        let denied = |result: Result<usize, PlanError>| matches!(result, Err(PlanError::PermissionDenied { user, .. }) if user == "alice");
+        assert!(matches!(
+            alice_stmt.execute_query("select a from t"),
+            Err(PlanError::PermissionDenied { .. })
+        ));
+        assert!(denied(
+            alice_stmt.execute_update("grant select on t to alice")
+        ));
+        assert!(denied(alice_stmt.execute_update("create table u (b int)")));
+
+        // Privileges reach alice through a role granted to alice.
+        stmt.execute_update("grant select, delete on t to analyst")
+            .unwrap();
+        stmt.execute_update("grant analyst to alice").unwrap();
+        admin.commit().unwrap();
+        let mut rs = alice_stmt.execute_query("select a from t").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_int("a"), Some(1));
+        rs.close();
+        assert!(denied(
+            alice_stmt.execute_update("insert into t (a) values (2)")
+        ));
+        assert!(denied(alice_stmt.execute_update("update t set a = 2")));
+        assert_eq!(
+            alice_stmt
+                .execute_update("delete from t where a = 5")
+                .unwrap(),
+            0
+        );
+        alice_stmt
+            .execute_update("create temp table scratch (b int)")
+            .unwrap();
+        alice_stmt
+            .execute_update("insert into scratch (b) values (3)")
+            .unwrap();
+
+        assert!(matches!(
+            stmt.execute_update("grant select on nosuch to alice"),
+            Err(PlanError::UnknownTable(_))
+        ));
+        stmt.execute_update("revoke analyst from alice").unwrap();
+        admin.commit().unwrap();
+        alice.commit().unwrap();
+        assert!(matches!(
+            alice_stmt.execute_query("select a from t"),
+            Err(PlanError::PermissionDenied { .. })
+        ));
+        stmt.execute_update("grant insert on t to alice").unwrap();
+        admin.commit().unwrap();
+        assert_eq!(
+            alice_stmt
+                .execute_update("insert into t (a) values (2)")
+                .unwrap(),
+            1
+        );
+        alice.close();
+        admin.close();
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_prefetching() {
+        let tmp_dir = TempDir::new("test_prefetching").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int, b varchar(20))")
+            .unwrap();
+        stmt.execute_update("create table u (c int)").unwrap();
+        for a in 0..400 {
+            let sql = format!("insert into t (a, b) values ({}, 'row {}')", a, a);
+            stmt.execute_update(&sql).unwrap();
+        }
+        for c in [7, 150, 399] {
+            let sql = format!("insert into u (c) values ({})", c);
+            stmt.execute_update(&sql).unwrap();
+        }
+        conn.commit().unwrap();
+
+        // Scans see the same rows while blocks are read ahead of them,
+        // including a join that scans one table once per row of the other.
+        db.set_prefetching(true);
+        let mut rs = stmt.execute_query("select a, b from t").unwrap();
+        let mut sum = 0;
+        while rs.next() {
+            let a = rs.get_int("a").unwrap();
+            assert_eq!(rs.get_string("b"), Some(format!("row {}", a)));
+            sum += a;
+        }
+        rs.close();
+        assert_eq!(sum, (0..400).sum::<i32>());
+        let mut rs = stmt
+            .execute_query("select a from u, t where a = c")
+            .unwrap();
+        let mut joined = Vec::new();
+        while rs.next() {
+            joined.push(rs.get_int("a").unwrap());
+        }
+        rs.close();
+        assert_eq!(joined, vec![7, 150, 399]);
+        db.set_prefetching(false);
+        conn.close();
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let tmp_dir = TempDir::new("test_audit_log").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        db.set_audit_log(Some(AuditConfig::default())).unwrap();
+        let admin = db.connect();
+        let stmt = admin.create_statement();
+        stmt.execute_update("create table t (a int)").unwrap();
+        for a in 0..3 {
+            let sql = format!("insert into t (a) values ({})", a);
+            stmt.execute_update(&sql).unwrap();
+        }
+        let tx_num = admin.tx().unwrap().borrow().transaction_n();
+        let mut rs = stmt.execute_query("select a from t where a = 1").unwrap();
+        while rs.next() {}
+        rs.close();
+        let alice = db.connect_as("alice");
+        assert!(alice
+            .create_statement()
+            .execute_query("select a from t")
+            .is_err());
+        assert!(stmt
+            .execute_update("insert into nosuch (a) values (1)")
+            .is_err());
+
+        // Only changes are recorded from here on.
+        db.set_audit_log(Some(AuditConfig {
+            kinds: Some(vec![StatementKind::Insert, StatementKind::Delete]),
+            ..AuditConfig::default()
+        }))
+        .unwrap();
+        stmt.execute_query("select a from t").unwrap().close();
+        stmt.execute_update("delete from t where a = 2").unwrap();
+        db.set_audit_log(None).unwrap();
+        stmt.execute_update("delete from t").unwrap();
+
+        let log = std::fs::read_to_string(tmp_dir.path().join("audit.log")).unwrap();
+        let records: Vec<Vec<&str>> = log.lines().map(|line| line.split('\t').collect()).collect();
+        let summary: Vec<(&str, &str, &str)> = records
+            .iter()
+            .map(|fields| (fields[1], fields[3], fields[5]))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("-", "ddl", "0 rows"),
+                ("-", "insert", "1 rows"),
+                ("-", "insert", "1 rows"),
+                ("-", "insert", "1 rows"),
+                ("-", "query", "1 rows"),
+                ("alice", "query", "error: user alice may not select from t"),
+                ("-", "insert", "error: table nosuch does not exist"),
+                ("-", "delete", "1 rows"),
+            ]
+        );
+        assert_eq!(records[4][2], tx_num.to_string());
+        assert_eq!(records[4][6], "select a from t where a = 1");
+        assert!(records
+            .iter()
+            .all(|fields| fields[4].parse::<u64>().is_ok()));
+        alice.close();
+        admin.close();
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_result_set_metadata() {
+        let tmp_dir = TempDir::new("test_result_set_metadata").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        db.set_result_cache(4);
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table emp (id int, name varchar(12), did int)")
+            .unwrap();
+        stmt.execute_update("create table dept (did2 int, dname varchar(8))")
+            .unwrap();
+        stmt.execute_update("insert into emp (id, name, did) values (1, 'ann', 7)")
+            .unwrap();
+
+        let sql = "select name, dname, id from emp, dept where did = did2";
+        let rs = stmt.execute_query(sql).unwrap();
+        let metadata = rs.metadata();
+        rs.close();
+        assert_eq!(metadata.column_count(), 3);
+        assert_eq!(
+            metadata.columns()[0],
+            ColumnMetaData {
+                name: "name".to_string(),
+                column_type: VARCHAR,
+                type_name: "varchar",
+                precision: 12,
+                display_size: 12,
+                nullable: true,
+                table_name: Some("emp".to_string()),
+            }
+        );
+        let dname = metadata.column("DNAME").unwrap();
+        assert_eq!(
+            (dname.precision, dname.table_name.as_deref()),
+            (8, Some("dept"))
+        );
+        let id = metadata.column("id").unwrap();
+        assert_eq!(
+            (id.column_type, id.type_name, id.precision, id.display_size),
+            (INTEGER, "int", 10, 11)
+        );
+        // A cached result describes its columns the same way.
+        let rs = stmt.execute_query(sql).unwrap();
+        assert_eq!(rs.metadata(), metadata);
+        rs.close();
+
+        // Computed columns come from no table, and counts are never null.
+        let rs = stmt
+            .execute_query("select did, count(id), max(name) from emp group by did")
+            .unwrap();
+        let metadata = rs.metadata();
+        rs.close();
+        let summary: Vec<(&str, bool, Option<&str>)> = metadata
+            .columns()
+            .iter()
+            .map(|column| {
+                (
+                    column.name.as_str(),
+                    column.nullable,
+                    column.table_name.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("did", true, Some("emp")),
+                ("countofid", false, None),
+                ("maxofname", true, None),
+            ]
+        );
+        assert_eq!(metadata.column("maxofname").unwrap().precision, 12);
+        conn.close();
+        drop(db);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_char_fields() {
+        let tmp_dir = TempDir::new("test_char_fields").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table codes (code char(4), label varchar(6))")
+            .unwrap();
+        Table::create("tags").char("tag", 6).execute(&conn).unwrap();
+        stmt.execute_update("create index code_idx on codes (code)")
+            .unwrap();
+        for (code, label) in [("ab", "ab"), ("abc ", "abc "), ("abcd", "abcd\0")] {
+            let sql = format!(
+                "insert into codes (code, label) values ('{}', '{}')",
+                code, label
+            );
+            stmt.execute_update(&sql).unwrap();
+        }
+        stmt.execute_update("insert into tags (tag) values ('abc')")
+            .unwrap();
+        // Trailing spaces a char field has no room for are dropped; other
+        // characters are not.
+        stmt.execute_update("insert into codes (code) values ('wxyz  ')")
+            .unwrap();
+        assert!(matches!(
+            stmt.execute_update("insert into codes (code) values ('vwxyz')"),
+            Err(PlanError::ValueTooLong(_))
+        ));
+
+        let query = |sql: &str| {
+            let mut rs = stmt.execute_query(sql).unwrap();
+            let mut rows = Vec::new();
+            while rs.next() {
+                rows.push((rs.get_string("code"), rs.get_string("label")));
+            }
+            rs.close();
+            rows
+        };
+        let row = |code: &str, label: &str| (Some(code.to_string()), Some(label.to_string()));
+        // Char values come back padded, varchar values exactly as stored.
+        assert_eq!(
+            query("select code, label from codes where code = 'ab'"),
+            vec![row("ab  ", "ab")]
+        );
+        assert_eq!(
+            query("select code, label from codes where code = 'abcd'"),
+            vec![row("abcd", "abcd\0")]
+        );
+        assert_eq!(
+            query("select code, label from codes where label = 'abc'"),
+            vec![]
+        );
+        assert_eq!(
+            query("select code, label from codes where code = 'abc'"),
+            vec![row("abc ", "abc ")]
+        );
+        // A char field ignores trailing spaces against another field.
+        let mut rs = stmt
+            .execute_query("select label, tag from codes, tags where tag = label")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_string("label"), Some("abc ".to_string()));
+        assert_eq!(rs.get_string("tag"), Some("abc   ".to_string()));
+        assert!(!rs.next());
+        let metadata = rs.metadata();
+        rs.close();
+        let tag = metadata.column("tag").unwrap();
+        assert_eq!(
+            (tag.column_type, tag.type_name, tag.precision),
+            (CHAR, "char", 6)
+        );
+
+        assert_eq!(
+            stmt.execute_update("update codes set label = 'x' where code = 'wxyz'")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            stmt.execute_update("delete from codes where code = 'ab  '")
+                .unwrap(),
+            1
+        );
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_typed_columns() {
+        let tmp_dir = TempDir::new("test_typed_columns").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update(
+            "create table m (id bigint, score double, ok boolean, born date, photo blob)",
+        )
+        .unwrap();
+        Table::create("n")
+            .bigint("l")
+            .double("d")
+            .boolean("b")
+            .date("dt")
+            .blob("bl")
+            .execute(&conn)
+            .unwrap();
+        stmt.execute_update("create index id_idx on m (id)")
+            .unwrap();
+        assert!(matches!(
+            stmt.execute_update("create index photo_idx on m (photo)"),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        stmt.execute_update(
+            "insert into m (id, score, ok, born, photo) \
+             values (3000000000, 1.5, true, date '2024-02-29', x'00ff')",
+        )
+        .unwrap();
+        // Ints are stored in bigint and double fields as those types.
+        stmt.execute_update(
+            "insert into m (id, score, ok, born, photo) \
+             values (7, 2, false, date '1969-12-31', x'')",
+        )
+        .unwrap();
+        for sql in [
+            "insert into m (id) values ('x')",
+            "insert into m (score) values (true)",
+            "insert into m (ok) values (1)",
+            "insert into m (born) values ('2024-01-01')",
+            "insert into m (photo) values (1)",
+        ] {
+            assert!(
+                matches!(stmt.execute_update(sql), Err(PlanError::TypeMismatch(_))),
+                "{}",
+                sql
+            );
+        }
+        assert!(matches!(
+            stmt.execute_update("insert into m (born) values (date '2023-02-29')"),
+            Err(PlanError::BadSyntax(_))
+        ));
+
+        let mut rs = stmt
+            .execute_query("select id, score, ok, born, photo from m where id = 3000000000")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_long("id"), Some(3_000_000_000));
+        assert_eq!(rs.get_double("score"), Some(1.5));
+        assert_eq!(rs.get_bool("ok"), Some(true));
+        assert_eq!(rs.get_date("born"), Some(19_782));
+        assert_eq!(rs.get_blob("photo"), Some(vec![0, 255]));
+        assert!(!rs.next());
+        let metadata = rs.metadata();
+        rs.close();
+        let types: Vec<&str> = metadata.columns().iter().map(|c| c.type_name).collect();
+        assert_eq!(types, ["bigint", "double", "boolean", "date", "blob"]);
+
+        // An int compares with a bigint or double field as that type, and
+        // the index on the bigint field finds it.
+        let mut rs = stmt
+            .execute_query("select id, score, born, photo from m where id = 7 and score = 2")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_val("id"), Some(Constant::Long(7)));
+        assert_eq!(rs.get_val("score"), Some(Constant::Double(2.0)));
+        assert_eq!(rs.get_date("born"), Some(-1));
+        assert_eq!(rs.get_blob("photo"), Some(Vec::new()));
+        assert!(!rs.next());
+        rs.close();
+
+        assert_eq!(
+            stmt.execute_update("update m set score = 2.5e-1 where ok = false")
+                .unwrap(),
+            1
+        );
+        let mut rs = stmt
+            .execute_query("select max(born), min(score) from m")
+            .unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_val("maxofborn"), Some(Constant::Date(19_782)));
+        assert_eq!(rs.get_double("minofscore"), Some(0.25));
+        rs.close();
+
+        // Defaults and view definitions are kept as text and read back.
+        stmt.execute_update(
+            "create table d (x double default 0.5, day date default date '2000-01-01', \
+             b boolean default true)",
+        )
+        .unwrap();
+        stmt.execute_update("insert into d (x) values (1)").unwrap();
+        stmt.execute_update("create view v as select id from m where born = date '2024-02-29'")
+            .unwrap();
+        let mut rs = stmt.execute_query("select x, day, b from d").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_double("x"), Some(1.0));
+        assert_eq!(rs.get_date("day"), Some(10_957));
+        assert_eq!(rs.get_bool("b"), Some(true));
+        rs.close();
+        let mut rs = stmt.execute_query("select id from v").unwrap();
+        assert!(rs.next());
+        assert_eq!(rs.get_long("id"), Some(3_000_000_000));
+        assert!(!rs.next());
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_borrowed_strings() {
+        let tmp_dir = TempDir::new("test_borrowed_strings").expect("failed to create temp dir");
+        let db = SimpleDB::new(tmp_dir.path()).unwrap();
+        let conn = db.connect();
+        let stmt = conn.create_statement();
+        stmt.execute_update("create table t (a int, b varchar(8))")
+            .unwrap();
+        stmt.execute_update("insert into t (a, b) values (1, 'one')")
+            .unwrap();
+
+        // get_str copies values read from a page out, as the page can't be
+        // lent past its lock; with_str lends them for the length of the call.
+        // A cached result lends its own either way.
+        let sql = "select a, b from t";
+        let mut rs = stmt.execute_query(sql).unwrap();
+        assert!(rs.next());
+        assert!(matches!(rs.get_str("b"), Some(Cow::Owned(b)) if b == "one"));
+        assert!(rs.with_str("B", |b| b == Some("one")));
+        assert!(rs.with_str("missing", |b| b.is_none()));
+        rs.close();
+        db.set_result_cache(4);
+        let mut rs = stmt.execute_query(sql).unwrap();
+        assert!(rs.next());
+        assert!(matches!(rs.get_str("B"), Some(Cow::Borrowed("one"))));
+        assert_eq!(rs.get_string("b"), Some("one".to_string()));
+        rs.close();
+
+        let mut rs = stmt
+            .execute_query("select b, count(a) from t group by b")
+            .unwrap();
+        assert!(rs.next());
+        assert!(matches!(rs.get_str("b"), Some(Cow::Borrowed("one"))));
+        rs.close();
+        conn.close();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let tmp_dir = TempDir::new("test_open_errors").expect("failed to create temp dir");
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"not a database").unwrap();
+        assert!(matches!(
+            SimpleDB::new(&file),
+            Err(CatalogError::Storage(DbError::Io(_)))
+        ));
+        assert!(matches!(
+            FileManager::read_only(file.clone(), SimpleDB::BLOCK_SIZE),
+            Err(DbError::Io(_))
+        ));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
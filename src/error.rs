@@ -0,0 +1,86 @@
+use crate::buffermanager::BufferAbortError;
+use crate::concurrencymanager::LockAbortError;
+use std::fmt::Display;
+
+/// A failure in the storage layers: the file, log and buffer managers, the
+/// pages they move around and the locks transactions take on them.
+#[derive(Debug)]
+pub enum DbError {
+    /// The operating system failed a read, write or sync.
+    Io(std::io::Error),
+    /// No buffer came free for a block before the pin gave up.
+    BufferAbort(BufferAbortError),
+    /// A lock could not be granted.
+    LockAbort(LockAbortError),
+    /// What was read back is not what was written, such as a block whose
+    /// checksum does not match.
+    Corruption(String),
+    /// A value does not fit in a page at the offset it was written to.
+    OutOfBounds { offset: usize, len: usize },
+    /// A page read or written as a block is not the size of one.
+    PageSizeMismatch { block_size: usize, page_size: usize },
+    /// A file could not grow because the disk is full.
+    DiskFull,
+    /// A change was attempted on a database opened read-only.
+    ReadOnly,
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Io(err) => write!(f, "{}", err),
+            DbError::BufferAbort(err) => write!(f, "{}", err),
+            DbError::LockAbort(err) => write!(f, "{}", err),
+            DbError::Corruption(message) => write!(f, "{}", message),
+            DbError::OutOfBounds { offset, len } => {
+                write!(
+                    f,
+                    "{} bytes at offset {} do not fit in the page",
+                    len, offset
+                )
+            }
+            DbError::PageSizeMismatch {
+                block_size,
+                page_size,
+            } => write!(
+                f,
+                "a page of {} bytes does not fit a block of {} bytes",
+                page_size, block_size
+            ),
+            DbError::DiskFull => write!(f, "no space left on the disk"),
+            DbError::ReadOnly => write!(f, "the database is read-only"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(err) => Some(err),
+            DbError::BufferAbort(err) => Some(err),
+            DbError::LockAbort(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::StorageFull {
+            return DbError::DiskFull;
+        }
+        DbError::Io(err)
+    }
+}
+
+impl From<BufferAbortError> for DbError {
+    fn from(err: BufferAbortError) -> Self {
+        DbError::BufferAbort(err)
+    }
+}
+
+impl From<LockAbortError> for DbError {
+    fn from(err: LockAbortError) -> Self {
+        DbError::LockAbort(err)
+    }
+}
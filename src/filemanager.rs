@@ -1,10 +1,13 @@
+use crate::error::DbError;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+#[derive(Clone, Debug)]
 pub(crate) struct BlockId {
     file_name: String,
     block_num: usize,
@@ -46,16 +49,18 @@ impl Display for BlockId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}",
-            format!(
-                "[file {}, block number {}]",
-                self.file_name(),
-                self.block_num()
-            )
+            "[file {}, block number {}]",
+            self.file_name(),
+            self.block_num()
         )
     }
 }
 
+/// A block's worth of bytes in memory. Offsets are byte offsets into the
+/// page; ints are stored big endian in four bytes, longs and doubles in eight,
+/// and byte strings as a four byte length followed by the bytes themselves.
+/// Booleans and dates are stored like ints.
+#[derive(Clone)]
 pub(crate) struct Page {
     block_size: usize,
     byte_buffer: Vec<u8>,
@@ -66,92 +71,125 @@ impl Page {
         PageBuilder::new()
     }
 
-    pub fn block_size(&self) -> usize {
-        self.block_size
-    }
-
+    /// The int at `offset`, or `None` if it runs past the end of the page.
     pub fn get_int(&self, offset: usize) -> Option<i32> {
-        let buf_size = size_of::<i32>();
-        if (offset + size_of::<i32>()) > self.block_size {
-            return None;
-        }
-        let bytes = &self.byte_buffer[offset..offset + buf_size];
+        let bytes = self
+            .byte_buffer
+            .get(offset..offset.checked_add(size_of::<i32>())?)?;
         Some(i32::from_be_bytes(bytes.try_into().unwrap()))
     }
 
+    /// The length prefixed bytes at `offset`, or `None` if the length is
+    /// negative or the bytes run past the end of the page.
     pub fn get_bytes(&self, offset: usize) -> Option<Box<[u8]>> {
-        let rec_len = self.get_int(offset)? as usize;
-        let bytes = match self
-            .byte_buffer
-            .get((offset + size_of::<i32>())..(offset + size_of::<i32>()) + rec_len)
-        {
-            Some(bytes) => bytes,
-            None => return None,
-        };
-
-        Some(bytes.into())
+        self.bytes_at(offset).map(Box::from)
     }
 
+    /// The string at `offset`, exactly as it was set, or `None` if its bytes
+    /// are missing or are not UTF-8. Padding is up to the caller: a char
+    /// field's values are stored already padded with spaces.
     pub fn get_string(&self, offset: usize) -> Option<String> {
-        let rec_len = self.get_int(offset)? as usize;
-        let bytes = match self
-            .byte_buffer
-            .get(offset..(offset + size_of::<i32>()) + rec_len)
-        {
-            Some(bytes) => bytes,
-            None => return None,
-        };
+        self.get_str(offset).map(Cow::into_owned)
+    }
 
-        Some(
-            String::from_utf8(bytes.to_vec())
-                .ok()
-                .unwrap()
-                .trim_end_matches('\0')
-                .to_string(),
-        )
+    /// Like `get_string`, but borrowed from the page.
+    pub fn get_str(&self, offset: usize) -> Option<Cow<'_, str>> {
+        std::str::from_utf8(self.bytes_at(offset)?)
+            .ok()
+            .map(Cow::Borrowed)
     }
 
-    pub fn set_int(&mut self, offset: usize, val: Option<i32>) {
+    // The length prefixed bytes at `offset`, where they lie in the page.
+    fn bytes_at(&self, offset: usize) -> Option<&[u8]> {
+        let rec_len = usize::try_from(self.get_int(offset)?).ok()?;
+        let data_offset = offset + size_of::<i32>();
+        self.byte_buffer
+            .get(data_offset..data_offset.checked_add(rec_len)?)
+    }
+
+    pub fn set_int(&mut self, offset: usize, val: Option<i32>) -> Result<(), DbError> {
         if let Some(val) = val {
-            let mut bytes = val.to_be_bytes().to_vec();
-            self.byte_buffer[offset..offset + bytes.len()].copy_from_slice(bytes.as_mut_slice());
+            let bytes = val.to_be_bytes();
+            self.slice_mut(offset, bytes.len())?.copy_from_slice(&bytes);
         }
+        Ok(())
     }
 
-    pub fn set_bytes(&mut self, offset: usize, bytes: Option<&[u8]>) {
+    pub fn set_bytes(&mut self, offset: usize, bytes: Option<&[u8]>) -> Result<(), DbError> {
         let len_aligned_offset = offset;
         if let Some(bytes) = bytes {
-            if bytes.len() > self.block_size + offset {
-                panic!("out of range");
-            }
             let data_aligned_offset = offset + size_of::<i32>();
-            self.set_int(len_aligned_offset, Some(bytes.len() as i32));
-
-            self.byte_buffer[data_aligned_offset..data_aligned_offset + bytes.len()]
+            // Checked before the length is written so a failed call leaves
+            // the page as it was.
+            self.slice_mut(len_aligned_offset, size_of::<i32>() + bytes.len())?;
+            self.set_int(len_aligned_offset, Some(bytes.len() as i32))?;
+            self.slice_mut(data_aligned_offset, bytes.len())?
                 .copy_from_slice(bytes);
         }
+        Ok(())
+    }
+
+    pub fn set_string(&mut self, offset: usize, val: Option<String>) -> Result<(), DbError> {
+        match val {
+            Some(val) => self.set_bytes(offset, Some(val.as_bytes())),
+            None => Ok(()),
+        }
+    }
+
+    /// The i64 at `offset`, or `None` if it runs past the end of the page.
+    pub fn get_long(&self, offset: usize) -> Option<i64> {
+        let bytes = self
+            .byte_buffer
+            .get(offset..offset.checked_add(size_of::<i64>())?)?;
+        Some(i64::from_be_bytes(bytes.try_into().unwrap()))
     }
 
-    pub fn set_string(&mut self, offset: usize, val: Option<String>) {
+    pub fn set_long(&mut self, offset: usize, val: Option<i64>) -> Result<(), DbError> {
         if let Some(val) = val {
-            self.byte_buffer[offset..offset + val.len()].copy_from_slice(val.as_bytes());
+            let bytes = val.to_be_bytes();
+            self.slice_mut(offset, bytes.len())?.copy_from_slice(&bytes);
         }
+        Ok(())
+    }
+
+    /// A double is stored as the bits of its IEEE 754 representation, so it
+    /// reads back exactly as it was written, NaNs included.
+    pub fn get_double(&self, offset: usize) -> Option<f64> {
+        self.get_long(offset)
+            .map(|bits| f64::from_bits(bits as u64))
+    }
+
+    pub fn set_double(&mut self, offset: usize, val: Option<f64>) -> Result<(), DbError> {
+        self.set_long(offset, val.map(|val| val.to_bits() as i64))
+    }
+
+    /// A boolean is stored as the int 0 or 1; any other int reads as true.
+    fn slice_mut(&mut self, offset: usize, len: usize) -> Result<&mut [u8], DbError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.byte_buffer.get_mut(offset..end))
+            .ok_or(DbError::OutOfBounds { offset, len })
     }
 
     pub fn flush(&mut self) {
-        self.byte_buffer = vec![0; self.block_size as usize];
+        self.byte_buffer = vec![0; self.block_size];
     }
 
+    /// The bytes `set_string` takes to store `s`: its length prefix plus its
+    /// UTF-8 encoding.
     pub fn max_len(s: &str) -> usize {
-        size_of::<i32>() + s.as_bytes().len()
+        size_of::<i32>() + s.len()
     }
 
+    /// The page's contents.
     pub fn bytes(&self) -> &[u8] {
         &self.byte_buffer
     }
 
+    /// The number of bytes in the page, which for a log page may differ
+    /// from the block size it was built with.
     pub fn size(&self) -> usize {
-        todo!()
+        self.byte_buffer.len()
     }
 }
 
@@ -193,33 +231,153 @@ impl PageBuilder {
     }
 }
 
-struct FileManagerStats {
-    blocks_read: u64,
-    blocks_write: u64,
+/// How hard a `FileManager` works to get writes onto stable storage. The log
+/// and the data files can use different modes by giving each its own
+/// `FileManager`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Every write is followed by `fsync` of the file's data.
+    Full,
+    /// Only the points recovery depends on are synced: the log whenever it
+    /// is flushed, which every commit does, and the data files at a
+    /// checkpoint. Recovery redoes committed changes lost from the rest.
+    OnCommit,
+    /// Writes are left in the OS page cache until it flushes them.
+    Off,
 }
 
-impl FileManagerStats {
-    pub fn new() -> FileManagerStats {
-        FileManagerStats {
-            blocks_read: 0,
-            blocks_write: 0,
+/// What a read does with a block whose stored checksum does not match its
+/// contents, which is how a torn or otherwise corrupted write shows up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumMismatch {
+    /// The read fails with an `InvalidData` error.
+    Error,
+    /// The mismatch is logged and the block is returned as read.
+    Warn,
+}
+
+// CRC-32 (IEEE) lookup table, built at compile time.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
         }
+        table[n] = crc;
+        n += 1;
     }
+    table
+};
 
-    pub fn blocks_read(&self) -> u64 {
-        self.blocks_read
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+// Reads all of `buf` from `offset` of `file`, returning how many bytes were
+// read. The offset is given with every call instead of being taken from the
+// handle's cursor, so a read does not depend on where an earlier read or
+// write left it.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)?;
+    Ok(buf.len())
+}
+
+// Windows has no call that leaves the cursor alone; `seek_read` moves it,
+// which no other read or write relies on.
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::io::ErrorKind;
+    use std::os::windows::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.seek_read(&mut buf[read..], offset + read as u64) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
     }
+    Ok(read)
+}
 
-    pub fn blocks_write(&self) -> u64 {
-        self.blocks_write
+// Writes all of `buf` at `offset` of `file`, returning how many bytes were
+// written; see `read_at`.
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)?;
+    Ok(buf.len())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+    use std::io::ErrorKind;
+    use std::os::windows::fs::FileExt;
+    let mut written = 0;
+    while written < buf.len() {
+        match file.seek_write(&buf[written..], offset + written as u64) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
     }
+    Ok(written)
+}
+
+// One bit per block of a file, set when the block has been freed and can be
+// handed out again by `FileManager::append`.
+struct FreeBlockMap {
+    bits: Vec<u8>,
+}
 
-    pub fn set_blocks_read(&mut self, count: u64) {
-        self.blocks_read = count;
+impl FreeBlockMap {
+    pub fn new(bits: Vec<u8>) -> FreeBlockMap {
+        FreeBlockMap { bits }
     }
 
-    pub fn set_blocks_write(&mut self, count: u64) {
-        self.blocks_write = count;
+    pub fn is_free(&self, block_num: usize) -> bool {
+        self.bits
+            .get(block_num / 8)
+            .is_some_and(|byte| byte & (1 << (block_num % 8)) != 0)
+    }
+
+    pub fn set_free(&mut self, block_num: usize, free: bool) {
+        let idx = block_num / 8;
+        if idx >= self.bits.len() {
+            self.bits.resize(idx + 1, 0);
+        }
+        if free {
+            self.bits[idx] |= 1 << (block_num % 8);
+        } else {
+            self.bits[idx] &= !(1 << (block_num % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn first_free(&self) -> Option<usize> {
+        let idx = self.bits.iter().position(|byte| *byte != 0)?;
+        Some(idx * 8 + self.bits[idx].trailing_zeros() as usize)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bits
     }
 }
 
@@ -228,126 +386,398 @@ pub(crate) struct FileManager {
     block_size: usize,
     is_new: bool,
     open_file: HashMap<String, File>,
-    stats: Option<FileManagerStats>,
+    free_blocks: HashMap<String, FreeBlockMap>,
+    sync_mode: SyncMode,
+    checksums: Option<ChecksumMismatch>,
+    growth_limit: Option<usize>,
+    read_only: bool,
 }
 
 impl FileManager {
-    pub fn new(db_directory: PathBuf, block_size: usize) -> FileManager {
-        if !db_directory.is_dir() {
-            panic!("Database directory is not a directory!");
-        }
-        let is_new = std::fs::exists(&db_directory).unwrap_or(false);
-        let files = std::fs::read_dir(&db_directory).expect("failed to read directory");
+    const FREE_MAP_SUFFIX: &'static str = ".free";
+    // Present in the directory of a database whose blocks carry checksums.
+    const CHECKSUM_MARKER: &'static str = "checksums";
+    const CHECKSUM_SIZE: usize = size_of::<u32>();
+
+    pub fn new(db_directory: PathBuf, block_size: usize) -> Result<FileManager, DbError> {
+        Self::check_directory(&db_directory)?;
+        let files = std::fs::read_dir(&db_directory)?;
 
         // Remove all temp files on startup
-        for file in files {
-            if let Ok(file) = file {
-                if !file.file_name().into_string().unwrap().starts_with("temp") {
-                    continue;
-                } else {
-                    std::fs::remove_file(file.path()).expect("failed to remove file");
-                }
+        for file in files.flatten() {
+            if !file.file_name().to_string_lossy().starts_with("temp") {
+                continue;
+            } else {
+                std::fs::remove_file(file.path())?;
             }
         }
+        Self::open(db_directory, block_size, false)
+    }
+
+    /// Opens the files in `db_directory` for reading only. Anything that
+    /// would change them, temporary files included, fails with
+    /// `DbError::ReadOnly`, and a file that does not exist reads as empty.
+    pub fn read_only(db_directory: PathBuf, block_size: usize) -> Result<FileManager, DbError> {
+        Self::check_directory(&db_directory)?;
+        Self::open(db_directory, block_size, true)
+    }
 
-        FileManager {
+    fn check_directory(db_directory: &Path) -> Result<(), DbError> {
+        if !db_directory.is_dir() {
+            return Err(DbError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", db_directory.display()),
+            )));
+        }
+        Ok(())
+    }
+
+    fn open(
+        db_directory: PathBuf,
+        block_size: usize,
+        read_only: bool,
+    ) -> Result<FileManager, DbError> {
+        // A directory with nothing left in it holds no database yet.
+        let is_new = std::fs::read_dir(&db_directory)?.next().is_none();
+        let checksums = db_directory
+            .join(Self::CHECKSUM_MARKER)
+            .exists()
+            .then_some(ChecksumMismatch::Error);
+
+        Ok(FileManager {
             db_directory,
             block_size,
             is_new,
             open_file: HashMap::new(),
-            stats: None,
+            free_blocks: HashMap::new(),
+            sync_mode: SyncMode::Off,
+            checksums,
+            growth_limit: None,
+            read_only,
+        })
+    }
+
+    pub fn with_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    /// Stores a CRC-32 of every block in the four bytes following it on disk
+    /// and verifies it whenever the block is read back. The layout is fixed
+    /// when the database is created, so a new database turns checksums on for
+    /// good while one created without them keeps its layout and only warns.
+    pub fn with_checksums(&mut self, on_mismatch: ChecksumMismatch) -> Result<(), DbError> {
+        if self.checksums.is_none() && !self.is_new {
+            log::warn!(
+                "{} was created without block checksums",
+                self.db_directory.display()
+            );
+            return Ok(());
+        }
+        if self.checksums.is_none() {
+            std::fs::write(self.db_directory.join(Self::CHECKSUM_MARKER), [])?;
         }
+        self.checksums = Some(on_mismatch);
+        Ok(())
+    }
+
+    /// Lets at most `blocks` more blocks be added to the end of files before
+    /// `append` fails as if the disk were full, so tests can see how the
+    /// layers above cope. Reusing a freed block does not count. `None` lifts
+    /// the limit.
+    #[cfg(test)]
+    pub fn limit_growth(&mut self, blocks: Option<usize>) {
+        self.growth_limit = blocks;
     }
 
-    pub fn with_stats(&mut self) {
-        self.stats = Some(FileManagerStats::new());
+    /// Syncs the writes made so far to `file_name` under `OnCommit`. Under
+    /// `Full` they already are, and under `Off` they are never synced.
+    pub fn sync_file(&mut self, file_name: &str) -> Result<(), DbError> {
+        if self.sync_mode != SyncMode::OnCommit {
+            return Ok(());
+        }
+        self.open_file(self.db_directory.join(file_name))?
+            .sync_data()?;
+        Ok(())
     }
 
-    pub fn read(&mut self, block_id: &BlockId, page: &mut Page) -> Result<(), std::io::Error> {
-        let mut file = self.open_file(self.db_directory.join(&block_id.file_name()));
-        let seek_n = (page.block_size() * (block_id.block_num())) as u64;
-        match file.seek(std::io::SeekFrom::Start(seek_n)) {
-            Ok(_) => {}
-            Err(err) => {
-                return Err(err);
+    /// Like `sync_file` for every file written through this manager.
+    pub fn sync_all(&mut self) -> Result<(), DbError> {
+        if self.sync_mode != SyncMode::OnCommit {
+            return Ok(());
+        }
+        self.open_file.values().try_for_each(File::sync_data)?;
+        Ok(())
+    }
+
+    /// Reads the block into `page`, returning the number of bytes read from
+    /// the disk, its checksum included.
+    pub fn read(&mut self, block_id: &BlockId, page: &mut Page) -> Result<usize, DbError> {
+        self.check_page_size(page)?;
+        let slot_size = self.slot_size();
+        let offset = (slot_size * block_id.block_num()) as u64;
+        let Some(on_mismatch) = self.checksums else {
+            let file = self.open_file(self.db_directory.join(block_id.file_name()))?;
+            return Ok(read_at(file, page.byte_buffer.as_mut_slice(), offset)?);
+        };
+
+        let mut bytes = vec![0; slot_size];
+        let file = self.open_file(self.db_directory.join(block_id.file_name()))?;
+        let read = read_at(file, bytes.as_mut_slice(), offset)?;
+        let (data, stored) = bytes.split_at(self.block_size);
+        let stored = u32::from_be_bytes(stored.try_into().unwrap());
+        // A block that was never written is all zeroes, checksum included.
+        let never_written = stored == 0 && data.iter().all(|byte| *byte == 0);
+        if !never_written && stored != crc32(data) {
+            let message = format!("checksum mismatch in block {}", block_id);
+            match on_mismatch {
+                ChecksumMismatch::Error => return Err(DbError::Corruption(message)),
+                ChecksumMismatch::Warn => log::warn!("{}", message),
             }
         }
+        page.byte_buffer.copy_from_slice(data);
 
-        file.read(page.byte_buffer.as_mut_slice())?;
+        Ok(read)
+    }
 
-        Ok(())
+    /// Writes `page` to the block, returning the number of bytes written to
+    /// the disk, its checksum included.
+    pub fn write(&mut self, block_id: &BlockId, page: &mut Page) -> Result<usize, DbError> {
+        self.check_writable()?;
+        self.check_page_size(page)?;
+        let bytes = self.on_disk(page.bytes());
+        let offset = (self.slot_size() * block_id.block_num()) as u64;
+        let sync_mode = self.sync_mode;
+        let file = self.open_file(self.db_directory.join(block_id.file_name()))?;
+        let written = write_at(file, bytes.as_slice(), offset)?;
+        Self::sync(file, sync_mode)?;
+
+        Ok(written)
     }
 
-    pub fn write(&mut self, block_id: &BlockId, page: &mut Page) -> Result<(), std::io::Error> {
-        let mut file = self.open_file(self.db_directory.join(&block_id.file_name()));
-        let seek_n = (page.block_size() * (block_id.block_num())) as u64;
-        file.seek(std::io::SeekFrom::Start(seek_n))
-            .expect("seek error while reading file");
-        file.write(page.byte_buffer.as_mut_slice())?;
+    /// Writes `pages` to consecutive blocks of one file starting at `block_id`
+    /// with a single positioned write, returning the number of bytes written.
+    pub fn write_blocks(&mut self, block_id: &BlockId, pages: &[&Page]) -> Result<usize, DbError> {
+        self.check_writable()?;
+        let mut bytes = Vec::with_capacity(pages.len() * self.slot_size());
+        for page in pages {
+            self.check_page_size(page)?;
+            bytes.extend(self.on_disk(page.bytes()));
+        }
+        let offset = (self.slot_size() * block_id.block_num()) as u64;
+        let sync_mode = self.sync_mode;
+        let file = self.open_file(self.db_directory.join(block_id.file_name()))?;
+        let written = write_at(file, bytes.as_slice(), offset)?;
+        Self::sync(file, sync_mode)?;
 
-        Ok(())
+        Ok(written)
+    }
+
+    /// Returns a zeroed block of `file_name`, reusing a previously freed block
+    /// before extending the file.
+    pub fn append(&mut self, file_name: &str) -> Result<BlockId, DbError> {
+        self.check_writable()?;
+        if let Some(block_id) = self.first_free(file_name) {
+            self.set_free(&block_id, false)?;
+            self.clear(&block_id)?;
+            return Ok(block_id);
+        }
+        if let Some(blocks) = self.growth_limit {
+            if blocks == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into());
+            }
+            self.growth_limit = Some(blocks - 1);
+        }
+
+        let slot_size = self.slot_size();
+        let file = self.open_file(self.db_directory.join(file_name))?;
+        let block_id = BlockId::new(file_name, file.metadata()?.len() as usize / slot_size);
+        self.clear(&block_id)?;
+        Ok(block_id)
     }
 
-    pub fn append(&mut self, file_name: &str) -> BlockId {
-        let path = self.db_directory.join(&file_name);
-        let mut file = self.open_file(path);
-        let metadata = file.metadata().expect("failed to get metadata");
-        let block_number = metadata.len() as usize / self.block_size;
+    /// Puts a block on its file's free block map or takes it off again. The
+    /// map is written at once; a transaction logs the change first.
+    pub fn set_free(&mut self, block_id: &BlockId, free: bool) -> Result<(), DbError> {
+        self.check_writable()?;
+        let file_name = block_id.file_name();
+        self.free_block_map(&file_name)
+            .set_free(block_id.block_num(), free);
+        self.save_free_block_map(&file_name)
+    }
 
-        file.seek(std::io::SeekFrom::End(
-            (self.block_size * block_number) as i64,
-        ))
-        .expect("seek error");
-        let bytes = vec![0; self.block_size as usize];
-        file.write(bytes.as_slice()).expect("failed to write file");
+    /// The freed block of `file_name` that `append` will hand out next.
+    pub fn first_free(&mut self, file_name: &str) -> Option<BlockId> {
+        let block_number = self.free_block_map(file_name).first_free()?;
+        Some(BlockId::new(file_name, block_number))
+    }
 
-        BlockId::new(&file_name, block_number)
+    /// Zeroes a block, extending its file if the block lies at the end.
+    pub fn clear(&mut self, block_id: &BlockId) -> Result<(), DbError> {
+        self.check_writable()?;
+        let slot_size = self.slot_size();
+        let sync_mode = self.sync_mode;
+        let file = self.open_file(self.db_directory.join(block_id.file_name()))?;
+        let bytes = vec![0; slot_size];
+        write_at(
+            file,
+            bytes.as_slice(),
+            (slot_size * block_id.block_num()) as u64,
+        )?;
+        Self::sync(file, sync_mode)
+    }
+
+    pub fn is_free(&mut self, block_id: &BlockId) -> bool {
+        self.free_block_map(&block_id.file_name())
+            .is_free(block_id.block_num())
+    }
+
+    /// The number of blocks of `file_name` waiting in its free block map to
+    /// be handed out again.
+    pub fn free_block_count(&mut self, file_name: &str) -> usize {
+        self.free_block_map(file_name).count()
+    }
+
+    /// Cuts `file_name` down to its first `blocks` blocks.
+    pub fn truncate(&mut self, file_name: &str, blocks: usize) -> Result<(), DbError> {
+        self.check_writable()?;
+        let length = (self.slot_size() * blocks) as u64;
+        let sync_mode = self.sync_mode;
+        let file = self.open_file(self.db_directory.join(file_name))?;
+        file.set_len(length)?;
+        Self::sync(file, sync_mode)
+    }
+
+    /// Removes `file_name` and its free block map from the disk.
+    pub fn delete(&mut self, file_name: &str) -> Result<(), DbError> {
+        self.check_writable()?;
+        let path = self.db_directory.join(file_name);
+        self.open_file.remove(path.to_str().unwrap());
+        self.free_blocks.remove(file_name);
+        let free_map = self
+            .db_directory
+            .join(format!("{}{}", file_name, Self::FREE_MAP_SUFFIX));
+        self.open_file.remove(free_map.to_str().unwrap());
+        if free_map.exists() {
+            std::fs::remove_file(free_map)?;
+        }
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
     }
 
     pub fn is_new(&self) -> bool {
         self.is_new
     }
 
-    pub fn length(&self, file_name: &str) -> Option<usize> {
-        let file = self.open_file.get(file_name);
-        if let Some(file) = file {
-            Some(
-                file.metadata()
-                    .expect("could not get metadata from file")
-                    .len() as usize,
-            )
-        } else {
-            None
+    /// The number of bytes of blocks in `file_name`, not counting the
+    /// checksums stored alongside them.
+    pub fn length(&mut self, file_name: &str) -> Result<usize, DbError> {
+        let path = self.db_directory.join(file_name);
+        if self.read_only && !path.exists() {
+            return Ok(0);
         }
+        let slot_size = self.slot_size();
+        let block_size = self.block_size;
+        let length = self.open_file(path)?.metadata()?.len() as usize;
+        Ok(length / slot_size * block_size + length % slot_size)
     }
 
     pub fn block_size(&self) -> usize {
-        self.block_size as usize
+        self.block_size
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.db_directory
+    }
+
+    // How many bytes a block takes up on disk.
+    fn slot_size(&self) -> usize {
+        match self.checksums {
+            Some(_) => self.block_size + Self::CHECKSUM_SIZE,
+            None => self.block_size,
+        }
+    }
+
+    // The bytes written to disk for a block holding `data`.
+    fn on_disk(&self, data: &[u8]) -> Vec<u8> {
+        let mut bytes = data.to_vec();
+        if self.checksums.is_some() {
+            bytes.extend_from_slice(&crc32(data).to_be_bytes());
+        }
+        bytes
+    }
+
+    fn free_block_map(&mut self, file_name: &str) -> &mut FreeBlockMap {
+        let path = self
+            .db_directory
+            .join(format!("{}{}", file_name, Self::FREE_MAP_SUFFIX));
+        self.free_blocks
+            .entry(file_name.to_string())
+            .or_insert_with(|| FreeBlockMap::new(std::fs::read(path).unwrap_or_default()))
+    }
+
+    fn save_free_block_map(&mut self, file_name: &str) -> Result<(), DbError> {
+        let path = self
+            .db_directory
+            .join(format!("{}{}", file_name, Self::FREE_MAP_SUFFIX));
+        let bits = self.free_block_map(file_name).bytes().to_vec();
+        let sync_mode = self.sync_mode;
+        let file = self.open_file(path)?;
+        file.set_len(bits.len() as u64)?;
+        write_at(file, bits.as_slice(), 0)?;
+        Self::sync(file, sync_mode)
     }
 
-    fn open_file(&mut self, file_name: PathBuf) -> File {
+    // Blocks are found by the manager's block size, so a page of any other
+    // size would be read from or written over the wrong bytes.
+    fn check_page_size(&self, page: &Page) -> Result<(), DbError> {
+        if page.size() != self.block_size {
+            return Err(DbError::PageSizeMismatch {
+                block_size: self.block_size,
+                page_size: page.size(),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), DbError> {
+        match self.read_only {
+            true => Err(DbError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    fn sync(file: &File, sync_mode: SyncMode) -> Result<(), DbError> {
+        match sync_mode {
+            SyncMode::Full => Ok(file.sync_data()?),
+            SyncMode::OnCommit | SyncMode::Off => Ok(()),
+        }
+    }
+
+    // Every block of a file goes through the one handle opened here, so reads
+    // and writes never duplicate the underlying file descriptor.
+    fn open_file(&mut self, file_name: PathBuf) -> Result<&mut File, DbError> {
         let filename = file_name.to_str().unwrap().to_string();
-        match self.open_file.get(filename.as_str()) {
-            None => {
-                let file = OpenOptions::new()
+        let writable = !self.read_only;
+        match self.open_file.entry(filename) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(
+                OpenOptions::new()
                     .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(file_name)
-                    .expect("failed to open file");
-                self.open_file.insert(
-                    filename.clone(),
-                    file.try_clone().expect("failed to clone file"),
-                );
-                file
-            }
-            Some(file) => file.try_clone().expect("failed to clone file"),
+                    .write(writable)
+                    .create(writable)
+                    .truncate(false)
+                    .open(file_name)?,
+            )),
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Seek};
     use tempdir::TempDir;
     const TEST_BLOCK_SIZE: usize = 16;
     #[test]
@@ -365,19 +795,32 @@ mod tests {
             .block_size(TEST_BLOCK_SIZE)
             .with_buffer()
             .build();
-        assert_eq!(page.block_size(), TEST_BLOCK_SIZE);
+        assert_eq!(page.bytes().len(), TEST_BLOCK_SIZE);
         assert_eq!(page.get_int(0), Some(0));
-        page.set_int(0, Some(65));
+        page.set_int(0, Some(65)).unwrap();
         assert_eq!(page.get_int(0), Some(65));
         assert_eq!(page.get_bytes(0), None);
-        page.set_bytes(1, Some(b"B"));
+        page.set_bytes(1, Some(b"B")).unwrap();
         let expected = vec![].into_boxed_slice();
         assert_eq!(page.get_bytes(0), Some(expected));
 
+        page.set_string(5, Some("AB".to_string())).unwrap();
+        assert_eq!(page.get_string(5), Some("AB".to_string()));
+        assert!(matches!(page.get_str(5), Some(Cow::Borrowed("AB"))));
+        assert_eq!(page.get_bytes(5), Some(b"AB".to_vec().into_boxed_slice()));
+
         assert_eq!(page.get_bytes(60), None);
         assert_eq!(page.get_int(60), None);
         assert_eq!(page.get_string(60), None);
 
+        // Writes past the end of the page fail and leave it unchanged.
+        assert!(matches!(
+            page.set_int(TEST_BLOCK_SIZE - 2, Some(1)),
+            Err(DbError::OutOfBounds { offset: 14, len: 4 })
+        ));
+        assert!(page.set_string(5, Some("TOO LONG".to_string())).is_err());
+        assert_eq!(page.get_string(5), Some("AB".to_string()));
+
         let page2 = Page::builder()
             .block_size(TEST_BLOCK_SIZE)
             .with_log_buffer(vec![0; TEST_BLOCK_SIZE * 4])
@@ -385,27 +828,58 @@ mod tests {
 
         let v = page2.get_int(0);
         assert_eq!(v, Some(0));
+        assert_eq!(page2.size(), TEST_BLOCK_SIZE * 4);
+        assert_eq!(page2.get_int(TEST_BLOCK_SIZE * 4 - 4), Some(0));
+
+        // Offsets are plain byte offsets, and a string takes its length
+        // prefix plus its bytes.
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        page.set_int(7, Some(-3)).unwrap();
+        assert_eq!(page.get_int(7), Some(-3));
+        assert_eq!(&page.bytes()[7..11], &(-3i32).to_be_bytes());
+        assert_eq!(Page::max_len("é"), 6);
+        page.set_int(0, Some(-1)).unwrap();
+        assert_eq!(page.get_bytes(0), None);
+        page.set_bytes(0, Some(&[0xff, 0xfe])).unwrap();
+        assert_eq!(page.get_string(0), None);
+        assert_eq!(page.get_int(usize::MAX), None);
+
+        page.set_long(2, Some(-(1 << 40))).unwrap();
+        assert_eq!(page.get_long(2), Some(-(1 << 40)));
+        page.set_double(8, Some(-2.5)).unwrap();
+        assert_eq!(page.get_double(8), Some(-2.5));
+        page.set_double(8, Some(f64::NAN)).unwrap();
+        assert!(page.get_double(8).unwrap().is_nan());
+        assert_eq!(page.get_long(TEST_BLOCK_SIZE - 4), None);
+        assert!(page.set_long(TEST_BLOCK_SIZE - 4, Some(1)).is_err());
     }
 
     #[test]
     fn test_file_manager() {
         let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
-        let mut file_manager = FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE);
-        assert_eq!(file_manager.is_new(), true);
-        let blid = file_manager.append(&String::from("test.block"));
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        assert!(file_manager.is_new());
+        let blid = file_manager.append(&String::from("test.block")).unwrap();
         assert_eq!(blid.block_num(), 0);
 
         let mut page = Page::builder()
             .block_size(TEST_BLOCK_SIZE)
             .with_buffer()
             .build();
-        assert_eq!(page.block_size(), 16);
+        assert_eq!(page.bytes().len(), 16);
         assert_eq!(page.get_bytes(0), Some(b"".to_vec().into_boxed_slice()));
-        page.set_bytes(0, Some(b"B"));
+        page.set_bytes(0, Some(b"B")).unwrap();
         assert_eq!(page.get_bytes(0), Some(b"B".to_vec().into_boxed_slice()));
-        file_manager
-            .write(&blid, &mut page)
-            .expect("failed to write file");
+        assert_eq!(
+            file_manager
+                .write(&blid, &mut page)
+                .expect("failed to write file"),
+            TEST_BLOCK_SIZE
+        );
 
         let mut page2 = Page::builder()
             .block_size(TEST_BLOCK_SIZE)
@@ -417,16 +891,16 @@ mod tests {
             .expect("failed to read file");
         assert_eq!(page2.get_bytes(0), Some(b"B".to_vec().into_boxed_slice()));
 
-        let blid2 = file_manager.append(&String::from("test.block"));
+        let blid2 = file_manager.append(&String::from("test.block")).unwrap();
         assert_eq!(blid2.block_num, 1);
 
         let mut page3 = Page::builder()
             .block_size(TEST_BLOCK_SIZE)
             .with_buffer()
             .build();
-        assert_eq!(page3.block_size(), 16);
+        assert_eq!(page3.bytes().len(), 16);
         assert_eq!(page3.get_bytes(0), Some(b"".to_vec().into_boxed_slice()));
-        page3.set_bytes(0, Some(b"FOO"));
+        page3.set_bytes(0, Some(b"FOO")).unwrap();
         assert_eq!(page3.get_bytes(0), Some(b"FOO".to_vec().into_boxed_slice()));
         file_manager
             .write(&blid2, &mut page3)
@@ -453,8 +927,308 @@ mod tests {
             buf,
             vec![
                 0, 0, 0, 1, 66, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 70, 79, 79, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+                0, 0, 0, 0, 0, 0
             ]
         );
     }
+
+    #[test]
+    fn test_file_manager_write_blocks() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        let mut pages = Vec::new();
+        for val in ["A", "B", "C"] {
+            file_manager.append("test.block").unwrap();
+            let mut page = Page::builder()
+                .block_size(TEST_BLOCK_SIZE)
+                .with_buffer()
+                .build();
+            page.set_bytes(0, Some(val.as_bytes())).unwrap();
+            pages.push(page);
+        }
+        let pages: Vec<&Page> = pages.iter().collect();
+        file_manager
+            .write_blocks(&BlockId::new("test.block", 1), &pages[1..])
+            .expect("failed to write file");
+
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        for (n, expected) in [(0, b"".as_slice()), (1, b"B"), (2, b"C")] {
+            file_manager
+                .read(&BlockId::new("test.block", n), &mut page)
+                .expect("failed to read file");
+            assert_eq!(
+                page.get_bytes(0),
+                Some(expected.to_vec().into_boxed_slice())
+            );
+        }
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_file_manager_page_size() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        let blid = file_manager.append("test.block").unwrap();
+        file_manager.append("test.block").unwrap();
+
+        // A page of any other size is refused rather than read from or
+        // written over the wrong offset, and the file is left alone.
+        for size in [TEST_BLOCK_SIZE / 4, TEST_BLOCK_SIZE * 2] {
+            let mut page = Page::builder().block_size(size).with_buffer().build();
+            page.set_int(0, Some(7)).unwrap();
+            assert!(matches!(
+                file_manager.write(&blid, &mut page),
+                Err(DbError::PageSizeMismatch {
+                    block_size: TEST_BLOCK_SIZE,
+                    page_size,
+                }) if page_size == size
+            ));
+            assert!(matches!(
+                file_manager.write_blocks(&blid, &[&page]),
+                Err(DbError::PageSizeMismatch { .. })
+            ));
+            assert!(matches!(
+                file_manager.read(&blid, &mut page),
+                Err(DbError::PageSizeMismatch { .. })
+            ));
+        }
+        assert_eq!(
+            file_manager.length("test.block").unwrap(),
+            2 * TEST_BLOCK_SIZE
+        );
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        file_manager.read(&blid, &mut page).unwrap();
+        assert_eq!(page.get_int(0), Some(0));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_free_block_map() {
+        let mut map = FreeBlockMap::new(Vec::new());
+        assert_eq!(map.first_free(), None);
+        assert!(!map.is_free(11));
+        map.set_free(11, true);
+        map.set_free(3, true);
+        assert!(map.is_free(11));
+        assert_eq!(map.first_free(), Some(3));
+        map.set_free(3, false);
+        assert_eq!(map.first_free(), Some(11));
+        assert_eq!(map.bytes(), &[0, 8]);
+    }
+
+    #[test]
+    fn test_file_manager_reuses_freed_blocks() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        for n in 0..3 {
+            assert_eq!(file_manager.append("test.block").unwrap().block_num(), n);
+        }
+
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        page.set_bytes(0, Some(b"OLD")).unwrap();
+        let freed = BlockId::new("test.block", 1);
+        file_manager
+            .write(&freed, &mut page)
+            .expect("failed to write file");
+        file_manager.set_free(&freed, true).unwrap();
+        assert!(file_manager.is_free(&freed));
+
+        // The freed block comes back zeroed before the file grows again.
+        let blid = file_manager.append("test.block").unwrap();
+        assert!(blid == freed);
+        assert!(!file_manager.is_free(&freed));
+        file_manager
+            .read(&blid, &mut page)
+            .expect("failed to read file");
+        assert_eq!(page.get_bytes(0), Some(b"".to_vec().into_boxed_slice()));
+        assert_eq!(file_manager.append("test.block").unwrap().block_num(), 3);
+
+        // Freed blocks are remembered across restarts.
+        file_manager
+            .set_free(&BlockId::new("test.block", 2), true)
+            .unwrap();
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        assert!(file_manager.is_free(&BlockId::new("test.block", 2)));
+        assert_eq!(file_manager.append("test.block").unwrap().block_num(), 2);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_file_manager_positioned_io() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        let blid = file_manager.append("test.block").unwrap();
+        let blid2 = file_manager.append("test.block").unwrap();
+
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        page.set_bytes(0, Some(b"A")).unwrap();
+        file_manager
+            .write(&blid2, &mut page)
+            .expect("failed to write file");
+        page.set_bytes(0, Some(b"B")).unwrap();
+        file_manager
+            .write(&blid, &mut page)
+            .expect("failed to write file");
+
+        // Moving the shared cursor must not affect where blocks are read from.
+        file_manager
+            .open_file(tmp_dir.path().join("test.block"))
+            .unwrap()
+            .seek(std::io::SeekFrom::End(0))
+            .expect("seek error");
+        file_manager
+            .read(&blid2, &mut page)
+            .expect("failed to read file");
+        assert_eq!(page.get_bytes(0), Some(b"A".to_vec().into_boxed_slice()));
+        file_manager
+            .read(&blid, &mut page)
+            .expect("failed to read file");
+        assert_eq!(page.get_bytes(0), Some(b"B".to_vec().into_boxed_slice()));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_file_manager_sync_modes() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        page.set_bytes(0, Some(b"A")).unwrap();
+        for sync_mode in [SyncMode::Full, SyncMode::OnCommit, SyncMode::Off] {
+            file_manager.with_sync_mode(sync_mode);
+            let blid = file_manager.append("test.block").unwrap();
+            file_manager
+                .write(&blid, &mut page)
+                .expect("failed to write file");
+            file_manager
+                .sync_file("test.block")
+                .expect("failed to sync file");
+            file_manager.sync_all().expect("failed to sync files");
+        }
+        assert_eq!(
+            file_manager.length("test.block").unwrap(),
+            3 * TEST_BLOCK_SIZE
+        );
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_file_manager_checksums() {
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        file_manager
+            .with_checksums(ChecksumMismatch::Error)
+            .unwrap();
+        let blid = file_manager.append("test.block").unwrap();
+        let blid2 = file_manager.append("test.block").unwrap();
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        page.set_bytes(0, Some(b"AB")).unwrap();
+        file_manager
+            .write(&blid2, &mut page)
+            .expect("failed to write file");
+        assert_eq!(
+            file_manager.length("test.block").unwrap(),
+            2 * TEST_BLOCK_SIZE
+        );
+        file_manager
+            .read(&blid, &mut page)
+            .expect("failed to read file");
+        assert_eq!(page.get_bytes(0), Some(b"".to_vec().into_boxed_slice()));
+
+        // The database keeps its checksums when it is opened again.
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        // The checksum is read along with the block.
+        assert_eq!(
+            file_manager
+                .read(&blid2, &mut page)
+                .expect("failed to read file"),
+            TEST_BLOCK_SIZE + FileManager::CHECKSUM_SIZE
+        );
+        assert_eq!(page.get_bytes(0), Some(b"AB".to_vec().into_boxed_slice()));
+
+        // Simulate a torn write by changing a byte of the second block.
+        let offset = (TEST_BLOCK_SIZE + FileManager::CHECKSUM_SIZE + 5) as u64;
+        let file = file_manager
+            .open_file(tmp_dir.path().join("test.block"))
+            .unwrap();
+        write_at(file, b"C", offset).expect("failed to write file");
+        assert!(matches!(
+            file_manager.read(&blid2, &mut page),
+            Err(DbError::Corruption(_))
+        ));
+        file_manager.with_checksums(ChecksumMismatch::Warn).unwrap();
+        file_manager
+            .read(&blid2, &mut page)
+            .expect("failed to read file");
+        assert_eq!(page.get_bytes(0), Some(b"AC".to_vec().into_boxed_slice()));
+
+        // An existing database without checksums keeps its layout.
+        let other_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        FileManager::new(other_dir.path().to_owned(), TEST_BLOCK_SIZE)
+            .unwrap()
+            .append("test.block")
+            .unwrap();
+        let mut file_manager =
+            FileManager::new(other_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        file_manager
+            .with_checksums(ChecksumMismatch::Error)
+            .unwrap();
+        assert_eq!(file_manager.length("test.block").unwrap(), TEST_BLOCK_SIZE);
+        other_dir.close().expect("failed to remove temp dir");
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_manager_reuses_file_handle() {
+        use std::os::fd::AsRawFd;
+
+        let tmp_dir = TempDir::new("test_file_manager").expect("failed to create temp dir");
+        let mut file_manager =
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        let blid = file_manager.append("test.block").unwrap();
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+
+        let path = tmp_dir.path().join("test.block");
+        let fd = file_manager.open_file(path.clone()).unwrap().as_raw_fd();
+        for _ in 0..5 {
+            file_manager
+                .write(&blid, &mut page)
+                .expect("failed to write file");
+            file_manager
+                .read(&blid, &mut page)
+                .expect("failed to read file");
+        }
+        assert_eq!(file_manager.open_file(path).unwrap().as_raw_fd(), fd);
+        assert_eq!(file_manager.open_file.len(), 1);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
 }
@@ -0,0 +1,1051 @@
+use crate::error::DbError;
+use crate::filemanager::BlockId;
+use crate::query::{Constant, Scan, UpdateScan};
+use crate::recordmanager::{
+    Layout, Rid, Schema, TableScan, BIGINT, BOOLEAN, CHAR, DATE, DOUBLE, VARCHAR,
+};
+use crate::transaction::Transaction;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Maps values of one field to the records that hold them.
+pub trait Index {
+    /// Positions the index before the first entry with `search_key`.
+    fn before_first(&mut self, search_key: &Constant);
+    fn next(&mut self) -> bool;
+    fn get_data_rid(&self) -> Option<Rid>;
+    fn insert(&mut self, data_val: &Constant, rid: Rid) -> Result<(), DbError>;
+    fn delete(&mut self, data_val: &Constant, rid: Rid) -> Result<(), DbError>;
+    fn close(&mut self);
+}
+
+// A page of a B-tree file: a flag, a record count, and then sorted,
+// fixed-length slots. Directory pages use the flag for their level, and leaf
+// pages for the block number of their overflow chain (-1 when there is none).
+struct BTPage {
+    tx: Rc<RefCell<Transaction>>,
+    block_id: Option<BlockId>,
+    layout: Layout,
+}
+
+impl BTPage {
+    const FLAG_POS: usize = 0;
+    const NUM_RECS_POS: usize = size_of::<i32>();
+    const HEADER_SIZE: usize = 2 * size_of::<i32>();
+
+    fn new(tx: Rc<RefCell<Transaction>>, block_id: BlockId, layout: Layout) -> BTPage {
+        tx.borrow_mut()
+            .pin(&block_id)
+            .expect("failed to pin index block");
+        BTPage {
+            tx,
+            block_id: Some(block_id),
+            layout,
+        }
+    }
+
+    /// The slot of the last record whose value is less than `search_key`, or
+    /// `None` when every record is at least `search_key`.
+    fn find_slot_before(&self, search_key: &Constant) -> Option<usize> {
+        let mut slot = 0;
+        while slot < self.num_recs() && self.get_data_val(slot) < *search_key {
+            slot += 1;
+        }
+        slot.checked_sub(1)
+    }
+
+    fn close(&mut self) {
+        if let Some(block_id) = self.block_id.take() {
+            self.tx.borrow_mut().unpin(&block_id);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.slot_pos(self.num_recs() + 1) >= self.tx.borrow().block_size()
+    }
+
+    /// Moves the records from `split_pos` onwards into a new block with
+    /// `flag`, returning that block.
+    fn split(&self, split_pos: usize, flag: i32) -> Result<BlockId, DbError> {
+        let new_block = self.append_new(flag)?;
+        let mut new_page = BTPage::new(self.tx.clone(), new_block.clone(), self.layout.clone());
+        let moved = self
+            .transfer_recs(split_pos, &new_page)
+            .and_then(|()| new_page.set_flag(flag));
+        new_page.close();
+        moved.map(|()| new_block)
+    }
+
+    fn get_data_val(&self, slot: usize) -> Constant {
+        self.get_val(slot, "dataval")
+    }
+
+    // No overflow chain and no level below, should the read fail.
+    fn get_flag(&self) -> i32 {
+        self.read(-1, |tx, block_id| {
+            tx.get_int(block_id, Self::FLAG_POS)
+                .map(|flag| flag.expect("btree page has a flag"))
+        })
+    }
+
+    fn set_flag(&self, flag: i32) -> Result<(), DbError> {
+        self.tx
+            .borrow_mut()
+            .set_int(self.block(), Self::FLAG_POS as i32, Some(flag), true)
+    }
+
+    fn append_new(&self, flag: i32) -> Result<BlockId, DbError> {
+        let file_name = self.block().file_name();
+        let block_id = self.tx.borrow_mut().append(&file_name)?;
+        self.tx.borrow_mut().pin(&block_id)?;
+        let formatted = self.format(&block_id, flag);
+        self.tx.borrow_mut().unpin(&block_id);
+        formatted.map(|()| block_id)
+    }
+
+    /// Initializes a new block. None of this is logged because the block
+    /// holds nothing worth restoring.
+    fn format(&self, block_id: &BlockId, flag: i32) -> Result<(), DbError> {
+        let mut tx = self.tx.borrow_mut();
+        tx.set_int(block_id, Self::FLAG_POS as i32, Some(flag), false)?;
+        tx.set_int(block_id, Self::NUM_RECS_POS as i32, Some(0), false)?;
+        let record_size = self.layout.slot_size();
+        let mut pos = Self::HEADER_SIZE;
+        while pos + record_size <= tx.block_size() {
+            for field_name in self.layout.schema().fields() {
+                let offset = (pos + self.field_offset(field_name)) as i32;
+                match self.layout.schema().field_type(field_name) {
+                    Some(VARCHAR) | Some(CHAR) => {
+                        tx.set_string(block_id, offset, Some(String::new()), false)
+                    }
+                    _ => tx.set_int(block_id, offset, Some(0), false),
+                }?;
+            }
+            pos += record_size;
+        }
+        Ok(())
+    }
+
+    // Directory pages.
+
+    fn get_child_num(&self, slot: usize) -> usize {
+        self.get_int(slot, "block") as usize
+    }
+
+    fn insert_dir(&self, slot: usize, val: Constant, block_num: usize) -> Result<(), DbError> {
+        self.insert(slot)?;
+        self.set_val(slot, "dataval", val)?;
+        self.set_int(slot, "block", block_num as i32)
+    }
+
+    // Leaf pages.
+
+    fn get_data_rid(&self, slot: usize) -> Rid {
+        Rid::new(
+            self.get_int(slot, "block") as usize,
+            self.get_int(slot, "id") as usize,
+        )
+    }
+
+    fn insert_leaf(&self, slot: usize, val: Constant, rid: Rid) -> Result<(), DbError> {
+        self.insert(slot)?;
+        self.set_val(slot, "dataval", val)?;
+        self.set_int(slot, "block", rid.block_num() as i32)?;
+        self.set_int(slot, "id", rid.slot() as i32)
+    }
+
+    fn delete(&self, slot: usize) -> Result<(), DbError> {
+        for i in slot + 1..self.num_recs() {
+            self.copy_record(i, i - 1)?;
+        }
+        self.set_num_recs(self.num_recs() - 1)
+    }
+
+    // No records, should the read fail.
+    fn num_recs(&self) -> usize {
+        self.read(0, |tx, block_id| {
+            tx.get_int(block_id, Self::NUM_RECS_POS)
+                .map(|num_recs| num_recs.expect("btree page has a record count"))
+        }) as usize
+    }
+
+    fn get_int(&self, slot: usize, field_name: &str) -> i32 {
+        let pos = self.field_pos(slot, field_name);
+        self.read(0, |tx, block_id| {
+            tx.get_int(block_id, pos)
+                .map(|val| val.expect("btree slot is inside the block"))
+        })
+    }
+
+    fn get_val(&self, slot: usize, field_name: &str) -> Constant {
+        let pos = self.field_pos(slot, field_name);
+        match self.layout.schema().field_type(field_name) {
+            Some(VARCHAR) | Some(CHAR) => {
+                Constant::String(self.read(String::new(), |tx, block_id| {
+                    tx.get_string(block_id, pos)
+                        .map(|val| val.expect("btree slot is inside the block"))
+                }))
+            }
+            Some(BIGINT) => Constant::Long(self.read(0, |tx, block_id| {
+                tx.get_long(block_id, pos)
+                    .map(|val| val.expect("btree slot is inside the block"))
+            })),
+            Some(DOUBLE) => Constant::Double(self.read(0.0, |tx, block_id| {
+                tx.get_double(block_id, pos)
+                    .map(|val| val.expect("btree slot is inside the block"))
+            })),
+            Some(BOOLEAN) => Constant::Bool(self.get_int(slot, field_name) != 0),
+            Some(DATE) => Constant::Date(self.get_int(slot, field_name)),
+            _ => Constant::Int(self.get_int(slot, field_name)),
+        }
+    }
+
+    // Index reads cannot fail, so one that does is kept on the transaction
+    // for the statement to fail with, and reads as `failed`, a value that
+    // ends any search through the page.
+    fn read<T>(
+        &self,
+        failed: T,
+        read: impl FnOnce(&mut Transaction, &BlockId) -> Result<T, DbError>,
+    ) -> T {
+        self.tx
+            .borrow_mut()
+            .scan_read(|tx| read(tx, self.block()).map(Some))
+            .unwrap_or(failed)
+    }
+
+    fn set_int(&self, slot: usize, field_name: &str, val: i32) -> Result<(), DbError> {
+        let pos = self.field_pos(slot, field_name) as i32;
+        self.tx
+            .borrow_mut()
+            .set_int(self.block(), pos, Some(val), true)
+    }
+
+    fn set_val(&self, slot: usize, field_name: &str, val: Constant) -> Result<(), DbError> {
+        let pos = self.field_pos(slot, field_name) as i32;
+        let mut tx = self.tx.borrow_mut();
+        match self.layout.schema().padded(field_name, &val) {
+            Constant::Int(val) | Constant::Date(val) => {
+                tx.set_int(self.block(), pos, Some(val), true)
+            }
+            Constant::String(val) => tx.set_string(self.block(), pos, Some(val), true),
+            Constant::Long(val) => tx.set_long(self.block(), pos, Some(val), true),
+            Constant::Double(val) => tx.set_double(self.block(), pos, Some(val), true),
+            Constant::Bool(val) => tx.set_int(self.block(), pos, Some(i32::from(val)), true),
+            // Blob fields cannot be indexed.
+            Constant::Blob(_) => unreachable!("blob values are not indexed"),
+        }
+    }
+
+    fn set_num_recs(&self, n: usize) -> Result<(), DbError> {
+        self.tx.borrow_mut().set_int(
+            self.block(),
+            Self::NUM_RECS_POS as i32,
+            Some(n as i32),
+            true,
+        )
+    }
+
+    fn insert(&self, slot: usize) -> Result<(), DbError> {
+        for i in (slot + 1..=self.num_recs()).rev() {
+            self.copy_record(i - 1, i)?;
+        }
+        self.set_num_recs(self.num_recs() + 1)
+    }
+
+    fn copy_record(&self, from: usize, to: usize) -> Result<(), DbError> {
+        for field_name in self.layout.schema().fields() {
+            self.set_val(to, field_name, self.get_val(from, field_name))?;
+        }
+        Ok(())
+    }
+
+    fn transfer_recs(&self, slot: usize, dest: &BTPage) -> Result<(), DbError> {
+        let mut dest_slot = 0;
+        while slot < self.num_recs() {
+            dest.insert(dest_slot)?;
+            for field_name in self.layout.schema().fields() {
+                dest.set_val(dest_slot, field_name, self.get_val(slot, field_name))?;
+            }
+            self.delete(slot)?;
+            dest_slot += 1;
+        }
+        Ok(())
+    }
+
+    fn field_pos(&self, slot: usize, field_name: &str) -> usize {
+        self.slot_pos(slot) + self.field_offset(field_name)
+    }
+
+    fn field_offset(&self, field_name: &str) -> usize {
+        self.layout
+            .offset(field_name)
+            .unwrap_or_else(|| panic!("field {} is not in the layout", field_name))
+    }
+
+    fn slot_pos(&self, slot: usize) -> usize {
+        Self::HEADER_SIZE + slot * self.layout.slot_size()
+    }
+
+    fn block(&self) -> &BlockId {
+        self.block_id.as_ref().expect("btree page is closed")
+    }
+}
+
+/// A directory entry: the smallest value found in the child block.
+#[derive(Clone, Debug, PartialEq)]
+struct DirEntry {
+    data_val: Constant,
+    block_num: usize,
+}
+
+struct BTreeLeaf {
+    tx: Rc<RefCell<Transaction>>,
+    layout: Layout,
+    search_key: Constant,
+    contents: BTPage,
+    current_slot: Option<usize>,
+    file_name: String,
+}
+
+impl BTreeLeaf {
+    fn new(
+        tx: Rc<RefCell<Transaction>>,
+        block_id: BlockId,
+        layout: Layout,
+        search_key: Constant,
+    ) -> BTreeLeaf {
+        let file_name = block_id.file_name();
+        let contents = BTPage::new(tx.clone(), block_id, layout.clone());
+        let current_slot = contents.find_slot_before(&search_key);
+        BTreeLeaf {
+            tx,
+            layout,
+            search_key,
+            contents,
+            current_slot,
+            file_name,
+        }
+    }
+
+    fn close(&mut self) {
+        self.contents.close();
+    }
+
+    fn next(&mut self) -> bool {
+        loop {
+            let slot = self.current_slot.map_or(0, |slot| slot + 1);
+            self.current_slot = Some(slot);
+            if slot < self.contents.num_recs()
+                && self.contents.get_data_val(slot) == self.search_key
+            {
+                return true;
+            }
+            if !self.try_overflow() {
+                return false;
+            }
+        }
+    }
+
+    fn get_data_rid(&self) -> Option<Rid> {
+        self.current_slot
+            .map(|slot| self.contents.get_data_rid(slot))
+    }
+
+    fn delete(&mut self, rid: Rid) -> Result<(), DbError> {
+        while self.next() {
+            if self.get_data_rid() == Some(rid) {
+                self.contents.delete(self.current_slot.unwrap())?;
+                return self.absorb_overflow();
+            }
+        }
+        Ok(())
+    }
+
+    // A page with an overflow block is never left empty, since an empty page
+    // no longer says which key its chain holds. It takes over the records of
+    // its overflow block instead, and that block is abandoned.
+    fn absorb_overflow(&mut self) -> Result<(), DbError> {
+        let flag = self.contents.get_flag();
+        if self.contents.num_recs() > 0 || flag < 0 {
+            return Ok(());
+        }
+        let overflow_block = BlockId::new(&self.file_name, flag as usize);
+        let mut overflow = BTPage::new(self.tx.clone(), overflow_block, self.layout.clone());
+        let absorbed = overflow
+            .transfer_recs(0, &self.contents)
+            .and_then(|()| self.contents.set_flag(overflow.get_flag()));
+        overflow.close();
+        absorbed
+    }
+
+    /// Inserts an entry for `rid` under the search key. Returns the entry
+    /// the parent directory needs if the leaf had to split.
+    fn insert(&mut self, rid: Rid) -> Result<Option<DirEntry>, DbError> {
+        // A key smaller than everything in an overflowing leaf cannot join
+        // the overflow chain, so the leaf's contents move to a new block and
+        // the key starts this one afresh.
+        if self.contents.get_flag() >= 0 && self.contents.get_data_val(0) > self.search_key {
+            let first_val = self.contents.get_data_val(0);
+            let new_block = self.contents.split(0, self.contents.get_flag())?;
+            self.current_slot = Some(0);
+            self.contents.set_flag(-1)?;
+            self.contents.insert_leaf(0, self.search_key.clone(), rid)?;
+            return Ok(Some(DirEntry {
+                data_val: first_val,
+                block_num: new_block.block_num(),
+            }));
+        }
+
+        let slot = self.current_slot.map_or(0, |slot| slot + 1);
+        self.current_slot = Some(slot);
+        self.contents
+            .insert_leaf(slot, self.search_key.clone(), rid)?;
+        if !self.contents.is_full() {
+            return Ok(None);
+        }
+
+        let first_key = self.contents.get_data_val(0);
+        let last_key = self.contents.get_data_val(self.contents.num_recs() - 1);
+        if last_key == first_key {
+            // Every record has the same key, so everything but the first goes
+            // to an overflow block.
+            let new_block = self.contents.split(1, self.contents.get_flag())?;
+            self.contents.set_flag(new_block.block_num() as i32)?;
+            return Ok(None);
+        }
+
+        let mut split_pos = self.contents.num_recs() / 2;
+        let mut split_key = self.contents.get_data_val(split_pos);
+        if split_key == first_key {
+            while self.contents.get_data_val(split_pos) == split_key {
+                split_pos += 1;
+            }
+            split_key = self.contents.get_data_val(split_pos);
+        } else {
+            while self.contents.get_data_val(split_pos - 1) == split_key {
+                split_pos -= 1;
+            }
+        }
+        let new_block = self.contents.split(split_pos, -1)?;
+        Ok(Some(DirEntry {
+            data_val: split_key,
+            block_num: new_block.block_num(),
+        }))
+    }
+
+    fn try_overflow(&mut self) -> bool {
+        if self.contents.num_recs() == 0 {
+            return false;
+        }
+        let first_key = self.contents.get_data_val(0);
+        let flag = self.contents.get_flag();
+        if self.search_key != first_key || flag < 0 {
+            return false;
+        }
+        self.contents.close();
+        let next_block = BlockId::new(&self.file_name, flag as usize);
+        self.contents = BTPage::new(self.tx.clone(), next_block, self.layout.clone());
+        self.current_slot = None;
+        true
+    }
+}
+
+struct BTreeDir {
+    tx: Rc<RefCell<Transaction>>,
+    layout: Layout,
+    contents: BTPage,
+    file_name: String,
+}
+
+impl BTreeDir {
+    fn new(tx: Rc<RefCell<Transaction>>, block_id: BlockId, layout: Layout) -> BTreeDir {
+        let file_name = block_id.file_name();
+        let contents = BTPage::new(tx.clone(), block_id, layout.clone());
+        BTreeDir {
+            tx,
+            layout,
+            contents,
+            file_name,
+        }
+    }
+
+    fn close(&mut self) {
+        self.contents.close();
+    }
+
+    /// The number of the leaf block that `search_key` belongs in.
+    fn search(&mut self, search_key: &Constant) -> usize {
+        let mut child = self.find_child_block(search_key);
+        while self.contents.get_flag() > 0 {
+            self.contents.close();
+            self.contents = BTPage::new(self.tx.clone(), child, self.layout.clone());
+            child = self.find_child_block(search_key);
+        }
+        child.block_num()
+    }
+
+    /// Splits the root so it can take `entry`, keeping the root at block 0.
+    fn make_new_root(&mut self, entry: DirEntry) -> Result<(), DbError> {
+        let first_val = self.contents.get_data_val(0);
+        let level = self.contents.get_flag();
+        let new_block = self.contents.split(0, level)?;
+        let old_root = DirEntry {
+            data_val: first_val,
+            block_num: new_block.block_num(),
+        };
+        self.insert_entry(old_root)?;
+        self.insert_entry(entry)?;
+        self.contents.set_flag(level + 1)
+    }
+
+    fn insert(&mut self, entry: DirEntry) -> Result<Option<DirEntry>, DbError> {
+        if self.contents.get_flag() == 0 {
+            return self.insert_entry(entry);
+        }
+        let child_block = self.find_child_block(&entry.data_val);
+        let mut child = BTreeDir::new(self.tx.clone(), child_block, self.layout.clone());
+        let new_entry = child.insert(entry);
+        child.close();
+        match new_entry? {
+            Some(entry) => self.insert_entry(entry),
+            None => Ok(None),
+        }
+    }
+
+    fn insert_entry(&mut self, entry: DirEntry) -> Result<Option<DirEntry>, DbError> {
+        let new_slot = self
+            .contents
+            .find_slot_before(&entry.data_val)
+            .map_or(0, |slot| slot + 1);
+        self.contents
+            .insert_dir(new_slot, entry.data_val, entry.block_num)?;
+        if !self.contents.is_full() {
+            return Ok(None);
+        }
+        let level = self.contents.get_flag();
+        let split_pos = self.contents.num_recs() / 2;
+        let split_val = self.contents.get_data_val(split_pos);
+        let new_block = self.contents.split(split_pos, level)?;
+        Ok(Some(DirEntry {
+            data_val: split_val,
+            block_num: new_block.block_num(),
+        }))
+    }
+
+    fn find_child_block(&self, search_key: &Constant) -> BlockId {
+        let slot = match self.contents.find_slot_before(search_key) {
+            Some(slot)
+                if slot + 1 < self.contents.num_recs()
+                    && self.contents.get_data_val(slot + 1) == *search_key =>
+            {
+                slot + 1
+            }
+            Some(slot) => slot,
+            None => 0,
+        };
+        BlockId::new(&self.file_name, self.contents.get_child_num(slot))
+    }
+}
+
+/// A B-tree index stored in two files: `<name>leaf.idx` holds the sorted
+/// (value, rid) entries and `<name>dir.idx` the directory above them, whose
+/// root is always block 0.
+pub struct BTreeIndex {
+    tx: Rc<RefCell<Transaction>>,
+    dir_layout: Layout,
+    leaf_layout: Layout,
+    leaf_file: String,
+    leaf: Option<BTreeLeaf>,
+    root_block: BlockId,
+}
+
+impl BTreeIndex {
+    /// Opens the index, creating its files the first time.
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        index_name: &str,
+        leaf_layout: Layout,
+    ) -> Result<BTreeIndex, DbError> {
+        let [leaf_file, dir_file] = Self::file_names(index_name);
+        if tx.borrow_mut().size(&leaf_file)? == 0 {
+            let block_id = tx.borrow_mut().append(&leaf_file)?;
+            let mut node = BTPage::new(tx.clone(), block_id.clone(), leaf_layout.clone());
+            let formatted = node.format(&block_id, -1);
+            node.close();
+            formatted?;
+        }
+
+        let mut dir_schema = Schema::new();
+        dir_schema.add("block", leaf_layout.schema());
+        dir_schema.add("dataval", leaf_layout.schema());
+        let dir_layout = Layout::new(dir_schema);
+        let root_block = BlockId::new(&dir_file, 0);
+        if tx.borrow_mut().size(&dir_file)? == 0 {
+            tx.borrow_mut().append(&dir_file)?;
+            let mut node = BTPage::new(tx.clone(), root_block.clone(), dir_layout.clone());
+            let min_val = match dir_layout.schema().field_type("dataval") {
+                Some(VARCHAR) | Some(CHAR) => Constant::String(String::new()),
+                Some(BIGINT) => Constant::Long(i64::MIN),
+                Some(DOUBLE) => Constant::Double(f64::NEG_INFINITY),
+                Some(BOOLEAN) => Constant::Bool(false),
+                Some(DATE) => Constant::Date(i32::MIN),
+                _ => Constant::Int(i32::MIN),
+            };
+            let formatted = node
+                .format(&root_block, 0)
+                .and_then(|()| node.insert_dir(0, min_val, 0));
+            node.close();
+            formatted?;
+        }
+
+        Ok(BTreeIndex {
+            tx,
+            dir_layout,
+            leaf_layout,
+            leaf_file,
+            leaf: None,
+            root_block,
+        })
+    }
+
+    /// The leaf file and the directory file of the index `index_name`.
+    pub fn file_names(index_name: &str) -> [String; 2] {
+        [
+            format!("{}leaf.idx", index_name),
+            format!("{}dir.idx", index_name),
+        ]
+    }
+
+    /// Schema of the leaf records of an index on a field of `field_type` and
+    /// declared `length`. Blob fields cannot be indexed.
+    pub fn leaf_layout(field_type: i32, length: usize) -> Layout {
+        let mut schema = Schema::new();
+        schema.add_int_field("block");
+        schema.add_int_field("id");
+        schema.add_field("dataval", field_type, length);
+        Layout::new(schema)
+    }
+
+    /// Every (value, rid) entry of the index, read leaf block by leaf block
+    /// in file order. Blocks left behind by an absorbed overflow chain are
+    /// empty, so nothing is seen twice.
+    pub fn entries(&self) -> Result<Vec<(Constant, Rid)>, DbError> {
+        let mut entries = Vec::new();
+        let blocks = self.tx.borrow_mut().size(&self.leaf_file)?;
+        for block_num in 0..blocks {
+            let block_id = BlockId::new(&self.leaf_file, block_num);
+            let mut page = BTPage::new(self.tx.clone(), block_id, self.leaf_layout.clone());
+            for slot in 0..page.num_recs() {
+                entries.push((page.get_data_val(slot), page.get_data_rid(slot)));
+            }
+            page.close();
+        }
+        Ok(entries)
+    }
+
+    /// Blocks read by one search: one per directory level plus the leaf.
+    pub fn search_cost(num_blocks: usize, records_per_block: usize) -> usize {
+        let mut levels = 0;
+        let mut blocks = num_blocks.max(1);
+        while blocks > 1 && records_per_block > 1 {
+            blocks = blocks.div_ceil(records_per_block);
+            levels += 1;
+        }
+        1 + levels
+    }
+}
+
+impl Index for BTreeIndex {
+    // Keys on a char field are padded like the values stored in it.
+    fn before_first(&mut self, search_key: &Constant) {
+        self.close();
+        let search_key = &self.leaf_layout.schema().padded("dataval", search_key);
+        let mut root = BTreeDir::new(
+            self.tx.clone(),
+            self.root_block.clone(),
+            self.dir_layout.clone(),
+        );
+        let block_num = root.search(search_key);
+        root.close();
+        let leaf_block = BlockId::new(&self.leaf_file, block_num);
+        self.leaf = Some(BTreeLeaf::new(
+            self.tx.clone(),
+            leaf_block,
+            self.leaf_layout.clone(),
+            search_key.clone(),
+        ));
+    }
+
+    fn next(&mut self) -> bool {
+        self.leaf.as_mut().expect("index is not positioned").next()
+    }
+
+    fn get_data_rid(&self) -> Option<Rid> {
+        self.leaf.as_ref()?.get_data_rid()
+    }
+
+    fn insert(&mut self, data_val: &Constant, rid: Rid) -> Result<(), DbError> {
+        self.before_first(data_val);
+        let entry = self
+            .leaf
+            .as_mut()
+            .expect("index is not positioned")
+            .insert(rid);
+        self.close();
+        let Some(entry) = entry? else {
+            return Ok(());
+        };
+        let mut root = BTreeDir::new(
+            self.tx.clone(),
+            self.root_block.clone(),
+            self.dir_layout.clone(),
+        );
+        let inserted = match root.insert(entry) {
+            Ok(Some(new_entry)) => root.make_new_root(new_entry),
+            result => result.map(|_| ()),
+        };
+        root.close();
+        inserted
+    }
+
+    fn delete(&mut self, data_val: &Constant, rid: Rid) -> Result<(), DbError> {
+        self.before_first(data_val);
+        let deleted = self
+            .leaf
+            .as_mut()
+            .expect("index is not positioned")
+            .delete(rid);
+        self.close();
+        deleted
+    }
+
+    fn close(&mut self) {
+        if let Some(mut leaf) = self.leaf.take() {
+            leaf.close();
+        }
+    }
+}
+
+/// The records of a table whose indexed field equals a constant, found
+/// through the index instead of by reading the whole table.
+pub struct IndexSelectScan<I: Index> {
+    table_scan: TableScan,
+    index: I,
+    val: Constant,
+}
+
+impl<I: Index> IndexSelectScan<I> {
+    pub fn new(table_scan: TableScan, index: I, val: Constant) -> IndexSelectScan<I> {
+        let mut scan = IndexSelectScan {
+            table_scan,
+            index,
+            val,
+        };
+        scan.before_first();
+        scan
+    }
+}
+
+impl<I: Index> Scan for IndexSelectScan<I> {
+    fn before_first(&mut self) {
+        self.index.before_first(&self.val);
+    }
+
+    fn next(&mut self) -> bool {
+        if !self.index.next() {
+            return false;
+        }
+        match self.index.get_data_rid() {
+            Some(rid) => {
+                self.table_scan.move_to_rid(rid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.table_scan.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.table_scan.get_string(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        self.table_scan.with_str(field_name, f)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.table_scan.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.table_scan.has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.index.close();
+        self.table_scan.close();
+    }
+}
+
+// Changes go straight to the table. Changing the indexed field would move
+// the entry the scan is on, so callers leave that field alone.
+impl<I: Index> UpdateScan for IndexSelectScan<I> {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError> {
+        self.table_scan.set_int(field_name, val)
+    }
+
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError> {
+        self.table_scan.set_string(field_name, val)
+    }
+
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError> {
+        self.table_scan.set_val(field_name, val)
+    }
+
+    fn insert(&mut self) -> Result<(), DbError> {
+        self.table_scan.insert()
+    }
+
+    fn delete(&mut self) -> Result<(), DbError> {
+        self.table_scan.delete()
+    }
+
+    fn get_rid(&self) -> Option<Rid> {
+        self.table_scan.get_rid()
+    }
+
+    fn move_to_rid(&mut self, rid: Rid) {
+        self.table_scan.move_to_rid(rid);
+    }
+}
+
+/// Joins each record on the left with the records of a table whose indexed
+/// field equals the left record's `join_field`.
+pub struct IndexJoinScan<S: Scan, I: Index> {
+    lhs: S,
+    index: I,
+    join_field: String,
+    rhs: TableScan,
+    lhs_valid: bool,
+}
+
+impl<S: Scan, I: Index> IndexJoinScan<S, I> {
+    pub fn new(lhs: S, index: I, join_field: &str, rhs: TableScan) -> IndexJoinScan<S, I> {
+        let mut scan = IndexJoinScan {
+            lhs,
+            index,
+            join_field: join_field.to_string(),
+            rhs,
+            lhs_valid: false,
+        };
+        scan.before_first();
+        scan
+    }
+
+    fn reset_index(&mut self) {
+        let search_key = self
+            .lhs
+            .get_val(&self.join_field)
+            .expect("join field is on the left");
+        self.index.before_first(&search_key);
+    }
+}
+
+impl<S: Scan, I: Index> Scan for IndexJoinScan<S, I> {
+    fn before_first(&mut self) {
+        self.lhs.before_first();
+        self.lhs_valid = self.lhs.next();
+        if self.lhs_valid {
+            self.reset_index();
+        }
+    }
+
+    fn next(&mut self) -> bool {
+        while self.lhs_valid {
+            if self.index.next() {
+                if let Some(rid) = self.index.get_data_rid() {
+                    self.rhs.move_to_rid(rid);
+                    return true;
+                }
+            }
+            self.lhs_valid = self.lhs.next();
+            if self.lhs_valid {
+                self.reset_index();
+            }
+        }
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        if self.rhs.has_field(field_name) {
+            self.rhs.get_int(field_name)
+        } else {
+            self.lhs.get_int(field_name)
+        }
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        if self.rhs.has_field(field_name) {
+            self.rhs.get_string(field_name)
+        } else {
+            self.lhs.get_string(field_name)
+        }
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        if self.rhs.has_field(field_name) {
+            self.rhs.get_str(field_name)
+        } else {
+            self.lhs.get_str(field_name)
+        }
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        if self.rhs.has_field(field_name) {
+            self.rhs.with_str(field_name, f)
+        } else {
+            self.lhs.with_str(field_name, f)
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        if self.rhs.has_field(field_name) {
+            self.rhs.get_val(field_name)
+        } else {
+            self.lhs.get_val(field_name)
+        }
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.rhs.has_field(field_name) || self.lhs.has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.lhs.close();
+        self.index.close();
+        self.rhs.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use crate::recordmanager::INTEGER;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    fn rids_for(index: &mut BTreeIndex, key: &Constant) -> Vec<Rid> {
+        let mut rids = Vec::new();
+        index.before_first(key);
+        while index.next() {
+            rids.push(index.get_data_rid().unwrap());
+        }
+        index.close();
+        rids.sort_by_key(|rid| (rid.block_num(), rid.slot()));
+        rids
+    }
+
+    #[test]
+    fn test_btree_index() {
+        let tmp_dir = TempDir::new("test_btree_index").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+
+        // 16 byte leaf records put 24 entries in a block, so 600 entries
+        // split leaves and grow the directory, and the 40 copies of key 7
+        // need an overflow chain.
+        let mut index =
+            BTreeIndex::new(tx.clone(), "idx", BTreeIndex::leaf_layout(INTEGER, 0)).unwrap();
+        let mut expected: Vec<Vec<Rid>> = vec![Vec::new(); 100];
+        for i in 0..600 {
+            let key = (i * 37) % 100;
+            let rid = Rid::new(i / 10, i % 10);
+            index.insert(&Constant::Int(key as i32), rid).unwrap();
+            expected[key].push(rid);
+        }
+        for i in 600..640 {
+            let rid = Rid::new(i / 10, i % 10);
+            index.insert(&Constant::Int(7), rid).unwrap();
+            expected[7].push(rid);
+        }
+        assert!(tx.borrow_mut().size("idxleaf.idx").unwrap() > 1);
+        for (key, rids) in expected.iter().enumerate() {
+            assert_eq!(&rids_for(&mut index, &Constant::Int(key as i32)), rids);
+        }
+        assert!(rids_for(&mut index, &Constant::Int(1000)).is_empty());
+
+        for rid in expected[7].drain(..30).collect::<Vec<Rid>>() {
+            index.delete(&Constant::Int(7), rid).unwrap();
+        }
+        let rid = expected[42].remove(0);
+        index.delete(&Constant::Int(42), rid).unwrap();
+        assert_eq!(&rids_for(&mut index, &Constant::Int(7)), &expected[7]);
+        assert_eq!(&rids_for(&mut index, &Constant::Int(42)), &expected[42]);
+        let mut entries = index.entries().unwrap();
+        entries.sort_by_key(|(_, rid)| *rid);
+        let mut expected_entries: Vec<(Constant, Rid)> = expected
+            .iter()
+            .enumerate()
+            .flat_map(|(key, rids)| {
+                rids.iter()
+                    .map(move |rid| (Constant::Int(key as i32), *rid))
+            })
+            .collect();
+        expected_entries.sort_by_key(|(_, rid)| *rid);
+        assert_eq!(entries, expected_entries);
+        tx.borrow_mut().commit().unwrap();
+
+        let mut index =
+            BTreeIndex::new(tx.clone(), "names", BTreeIndex::leaf_layout(VARCHAR, 10)).unwrap();
+        for i in 0..200 {
+            index
+                .insert(&Constant::String(format!("name{}", i % 50)), Rid::new(i, 0))
+                .unwrap();
+        }
+        let rids = rids_for(&mut index, &Constant::String("name3".to_string()));
+        assert_eq!(
+            rids,
+            vec![
+                Rid::new(3, 0),
+                Rid::new(53, 0),
+                Rid::new(103, 0),
+                Rid::new(153, 0)
+            ]
+        );
+        assert!(rids_for(&mut index, &Constant::String(String::new())).is_empty());
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_search_cost() {
+        assert_eq!(BTreeIndex::search_cost(1, 24), 1);
+        assert_eq!(BTreeIndex::search_cost(24, 24), 2);
+        assert_eq!(BTreeIndex::search_cost(25, 24), 3);
+    }
+}
@@ -1,7 +1,37 @@
+mod attach;
+mod audit;
 mod buffermanager;
+mod changecapture;
+mod concurrencymanager;
+mod driver;
+mod error;
 mod filemanager;
+mod index;
 mod logmanager;
 mod logrecord;
+mod materialize;
+mod metadata;
+pub mod migrate;
+mod multibuffer;
+mod parse;
+mod plan;
+mod query;
+mod recordmanager;
 mod recoverymanager;
+mod resultcache;
 mod transaction;
-mod concurrencymanager;
+
+pub use audit::{AuditConfig, AuditRecord, StatementKind};
+pub use buffermanager::{
+    BufferAbortError, BufferEvent, BufferEventKind, BufferPolicy, BufferStats,
+};
+pub use changecapture::{ChangeOp, ChangeStream, RowChange};
+pub use driver::{
+    ColumnMetaData, Connection, Engine, Generator, OpenOptions, ResourceLimits, ResultSet,
+    ResultSetMetaData, Savepoint, SimpleDB, Statement, Table,
+};
+pub use error::DbError;
+pub use filemanager::{ChecksumMismatch, SyncMode};
+pub use metadata::CatalogError;
+pub use plan::{Limit, PlanError};
+pub use query::Constant;
@@ -1,208 +1,310 @@
+use crate::error::DbError;
 use crate::filemanager::{BlockId, FileManager, Page, PageBuilder};
-use std::cell::{RefCell, RefMut};
+use std::collections::HashSet;
 use std::ops::Add;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-pub struct LogIterator<'a> {
-    file_manager: Rc<RefCell<FileManager>>,
+pub struct LogIterator {
+    file_manager: Arc<Mutex<FileManager>>,
     log_page: Page,
-    block_id: &'a BlockId,
+    block_id: BlockId,
     current_offset: i32,
 }
 
 impl LogIterator {
-    pub fn new(fm: Rc<RefCell<FileManager>>, blk: &BlockId) -> Self {
-        let fm_mut = fm.borrow_mut();
+    pub fn new(fm: Arc<Mutex<FileManager>>, blk: &BlockId) -> Result<Self, DbError> {
+        let fm_mut = fm.lock().unwrap();
         let b = vec![0; fm_mut.block_size()];
         let mut p = Page::builder()
             .block_size(fm_mut.block_size())
             .with_log_buffer(b)
             .build();
-        let current_b = Self::move_to_block(fm_mut, blk, &mut p);
-        Self {
+        let current_b = Self::move_to_block(fm_mut, blk, &mut p)?;
+        Ok(Self {
             file_manager: fm,
             log_page: p,
-            block_id: blk,
+            block_id: blk.clone(),
             current_offset: current_b,
-        }
+        })
     }
 
-    fn move_to_block(mut fm: RefMut<FileManager>, blk: &BlockId, lp: &mut Page) -> i32 {
-        fm.read(blk, lp).expect("could not read block in to page");
-        let boundary = lp.get_int(0).expect("could not read boundary in page");
-        boundary
+    fn move_to_block(
+        mut fm: MutexGuard<FileManager>,
+        blk: &BlockId,
+        lp: &mut Page,
+    ) -> Result<i32, DbError> {
+        fm.read(blk, lp)?;
+        lp.get_int(0)
+            .ok_or_else(|| DbError::Corruption(format!("log block {} has no boundary", blk)))
     }
 }
 
 impl Iterator for LogIterator {
-    type Item = Box<[u8]>;
+    type Item = Result<Box<[u8]>, DbError>;
 
+    /// Returns records newest first. Once a block is used up the iterator
+    /// reads the one before it, until the first block of the log is done.
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_offset >= self.file_manager.borrow_mut().block_size() as i32
-            || self.block_id.borrow_mut().block_num() > 0
-        {
-            return None;
-        }
-        let bytes = self.log_page.get_bytes(self.current_offset as usize);
-        if bytes == None {
-            return None;
+        let block_size = self.file_manager.lock().unwrap().block_size() as i32;
+        while self.current_offset >= block_size {
+            if self.block_id.block_num() == 0 {
+                return None;
+            }
+            self.block_id = BlockId::new(&self.block_id.file_name(), self.block_id.block_num() - 1);
+            self.current_offset = match Self::move_to_block(
+                self.file_manager.lock().unwrap(),
+                &self.block_id,
+                &mut self.log_page,
+            ) {
+                Ok(offset) => offset,
+                Err(err) => return Some(Err(err)),
+            };
         }
+        let bytes = self.log_page.get_bytes(self.current_offset as usize)?;
 
         let total = self
             .current_offset
-            .add((size_of::<i32>() + bytes.as_ref()?.len()) as i32);
+            .add((size_of::<i32>() + bytes.len()) as i32);
         self.current_offset = total;
 
-        bytes
+        Some(Ok(bytes))
     }
 }
 
 pub struct LogManager {
     log_file: String,
-    file_manager: Rc<RefCell<FileManager>>,
+    file_manager: Arc<Mutex<FileManager>>,
     log_page: Page,
     block_id: BlockId,
-    latest_lsn: i32,
-    last_lsn: i32,
+    latest_lsn: i64,
+    last_lsn: i64,
+    max_wal_size: Option<usize>,
+    checkpoint_pending: bool,
+    // Transactions that have logged updates and not yet finished. Their
+    // records are needed to roll them back, so the log cannot be truncated
+    // until this is empty.
+    active_writers: HashSet<i32>,
 }
 
 impl LogManager {
-    pub fn builder(log_file: String, file_manager: Rc<RefCell<FileManager>>) -> LogManagerBuilder {
+    pub fn builder(log_file: String, file_manager: Arc<Mutex<FileManager>>) -> LogManagerBuilder {
         LogManagerBuilder::new(log_file, file_manager)
     }
 
-    pub fn append(&mut self, rec: Vec<u8>) -> i32 {
+    pub fn append(&mut self, rec: Vec<u8>) -> Result<i64, DbError> {
         let reclen = rec.len();
         let bytes_needed = reclen + size_of::<i32>();
-        if let Some(b) = self.log_page.get_int(0) {
-            let boundary;
-            if (b as usize - bytes_needed) < size_of::<i32>() {
-                self.flush();
-                self.block_id = self.append_new_block();
-                boundary = self.log_page.get_int(0).expect("failed to get int");
-            } else {
-                boundary = b;
+        let mut boundary = self.boundary()?;
+        if (boundary as usize) < bytes_needed + size_of::<i32>() {
+            self.flush()?;
+            self.block_id = self.append_new_block()?;
+            if !self.checkpoint_pending && self.over_max_size() {
+                log::info!("log passed its maximum size, scheduling a checkpoint");
+                self.checkpoint_pending = true;
             }
-            let recpos = boundary as usize - bytes_needed;
-            self.log_page.set_bytes(recpos, Some(rec.as_slice()));
-            self.log_page.set_int(0, Some(recpos as i32));
-            self.latest_lsn += 1;
-
-            self.latest_lsn
-        } else {
-            panic!("no page available")
+            boundary = self.boundary()?;
         }
+        let recpos = boundary as usize - bytes_needed;
+        self.log_page.set_bytes(recpos, Some(rec.as_slice()))?;
+        self.log_page.set_int(0, Some(recpos as i32))?;
+        self.latest_lsn += 1;
+
+        Ok(self.latest_lsn)
+    }
+
+    /// The LSN of the newest record appended.
+    pub fn latest_lsn(&self) -> i64 {
+        self.latest_lsn
     }
 
-    pub fn flush(&mut self) {
-        if self.latest_lsn >= self.last_lsn {
-            self.flush_to_file()
+    /// Writes out every record appended so far.
+    pub fn flush(&mut self) -> Result<(), DbError> {
+        self.flush_lsn(self.latest_lsn)
+    }
+
+    /// Makes sure the record with `lsn`, and every one before it, is on
+    /// disk. The tail page is only written if it holds records that are not.
+    pub fn flush_lsn(&mut self, lsn: i64) -> Result<(), DbError> {
+        if lsn > self.last_lsn {
+            self.flush_to_file()?;
         }
+        Ok(())
     }
 
-    pub fn iterator(&self) -> LogIterator {
-        LogIterator::new(self.file_manager.clone(), &self.block_id)
+    /// Limits the log to about `max_wal_size` bytes. Past that a checkpoint
+    /// is scheduled, and runs once no transaction still needs its records.
+    pub fn set_max_wal_size(&mut self, max_wal_size: Option<usize>) {
+        self.max_wal_size = max_wal_size;
+        self.checkpoint_pending |= self.over_max_size();
+    }
+
+    pub fn mark_active(&mut self, tx_number: i32) {
+        self.active_writers.insert(tx_number);
+    }
+
+    pub fn mark_finished(&mut self, tx_number: i32) {
+        self.active_writers.remove(&tx_number);
     }
 
-    fn flush_to_file(&mut self) {
+    /// Schedules a checkpoint to run once no transaction has uncommitted
+    /// updates.
+    pub fn request_checkpoint(&mut self) {
+        self.checkpoint_pending = true;
+    }
+
+    /// Whether a checkpoint has been scheduled and can run now.
+    pub fn checkpoint_due(&self) -> bool {
+        self.checkpoint_pending && self.active_writers.is_empty()
+    }
+
+    pub fn checkpoint_done(&mut self) {
+        self.checkpoint_pending = false;
+    }
+
+    /// Discards every record and starts the log again from one empty block.
+    /// Callers must first flush every buffer, since the records that could
+    /// redo or undo their changes are gone afterwards. LSNs keep counting up.
+    pub fn truncate(&mut self) -> Result<(), DbError> {
         self.file_manager
-            .borrow_mut()
-            .write(&self.block_id, &mut self.log_page)
-            .expect("error writing to log file");
+            .lock()
+            .unwrap()
+            .truncate(&self.log_file, 0)?;
+        self.block_id = self.append_new_block()?;
+        self.last_lsn = self.latest_lsn;
+        Ok(())
+    }
+
+    /// Whether the log has grown past `max_wal_size`.
+    pub fn over_max_size(&self) -> bool {
+        let blocks = self.block_id.block_num() + 1;
+        self.max_wal_size
+            .is_some_and(|max| blocks * self.file_manager.lock().unwrap().block_size() > max)
+    }
+
+    /// Flushes the tail page and iterates over the log from its newest
+    /// record. The iterator reads its own copy of each block, so appends made
+    /// after it was taken are not seen.
+    pub fn iterator(&mut self) -> Result<LogIterator, DbError> {
+        self.flush()?;
+        LogIterator::new(self.file_manager.clone(), &self.block_id)
+    }
+
+    // Once flushed the records must survive a crash, since a commit is
+    // acknowledged and dirty pages are written on the strength of them.
+    fn flush_to_file(&mut self) -> Result<(), DbError> {
+        let mut file_manager = self.file_manager.lock().unwrap();
+        file_manager.write(&self.block_id, &mut self.log_page)?;
+        file_manager.sync_file(&self.log_file)?;
         self.last_lsn = self.latest_lsn;
+        Ok(())
+    }
+
+    fn boundary(&self) -> Result<i32, DbError> {
+        self.log_page.get_int(0).ok_or_else(|| {
+            DbError::Corruption(format!("log block {} has no boundary", self.block_id))
+        })
     }
 
-    fn append_new_block(&mut self) -> BlockId {
-        let blid = self.file_manager.borrow_mut().append(&self.log_file);
+    fn append_new_block(&mut self) -> Result<BlockId, DbError> {
+        let blid = self.file_manager.lock().unwrap().append(&self.log_file)?;
         self.log_page.flush();
-        self.log_page
-            .set_int(0, Some(self.file_manager.borrow().block_size() as i32));
+        self.log_page.set_int(
+            0,
+            Some(self.file_manager.lock().unwrap().block_size() as i32),
+        )?;
         self.file_manager
-            .borrow_mut()
-            .write(&blid, &mut self.log_page)
-            .expect("could not write block id in to log file");
-        blid
+            .lock()
+            .unwrap()
+            .write(&blid, &mut self.log_page)?;
+        Ok(blid)
     }
 }
 
 pub struct LogManagerBuilder {
     log_file: String,
-    file_manager: Rc<RefCell<FileManager>>,
+    file_manager: Arc<Mutex<FileManager>>,
     log_page: Page,
+    max_wal_size: Option<usize>,
 }
 
 impl LogManagerBuilder {
-    pub fn new(log_file: String, file_manager: Rc<RefCell<FileManager>>) -> Self {
+    pub fn new(log_file: String, file_manager: Arc<Mutex<FileManager>>) -> Self {
         let page = PageBuilder::new()
-            .with_log_buffer(vec![0; file_manager.borrow().block_size()])
+            .with_log_buffer(vec![0; file_manager.lock().unwrap().block_size()])
             .build();
         Self {
             log_file,
             file_manager,
             log_page: page,
+            max_wal_size: None,
         }
     }
 
-    pub fn build(mut self) -> LogManager {
+    /// See `LogManager::set_max_wal_size`. The log has no limit by default.
+    pub fn max_wal_size(mut self, max_wal_size: usize) -> Self {
+        self.max_wal_size = Some(max_wal_size);
+        self
+    }
+
+    pub fn build(mut self) -> Result<LogManager, DbError> {
         let fm = self.file_manager.clone();
-        let file_len = {
-            let blid = fm.borrow_mut().length(&self.log_file);
-            blid
-        };
+        let file_len = fm.lock().unwrap().length(&self.log_file)?;
 
         let blid = {
-            if file_len == None {
-                self.append_new_block()
+            if file_len > 0 {
+                let block_size = self.file_manager.lock().unwrap().block_size();
+                let blid = BlockId::new(&self.log_file, file_len / block_size - 1);
+                self.file_manager
+                    .lock()
+                    .unwrap()
+                    .read(&blid, &mut self.log_page)?;
+                blid
             } else {
-                let file_len = file_len.expect("no block in log file");
-                if file_len > 0 {
-                    let blid = BlockId::new(&self.log_file, file_len - 1);
-                    self.file_manager
-                        .borrow_mut()
-                        .read(&blid, &mut self.log_page)
-                        .expect("could not read block id in to page");
-                    blid
-                } else {
-                    self.append_new_block()
-                }
+                self.append_new_block()?
             }
         };
 
-        LogManager {
+        Ok(LogManager {
             log_file: self.log_file,
             file_manager: self.file_manager,
             log_page: self.log_page,
             block_id: blid,
             latest_lsn: 0,
             last_lsn: 0,
-        }
+            max_wal_size: self.max_wal_size,
+            checkpoint_pending: false,
+            active_writers: HashSet::new(),
+        })
     }
 
-    fn append_new_block(&mut self) -> BlockId {
-        let blid = self.file_manager.borrow_mut().append(&self.log_file);
-        self.log_page
-            .set_int(0, Some(self.file_manager.borrow_mut().block_size() as i32));
+    fn append_new_block(&mut self) -> Result<BlockId, DbError> {
+        let blid = self.file_manager.lock().unwrap().append(&self.log_file)?;
+        self.log_page.set_int(
+            0,
+            Some(self.file_manager.lock().unwrap().block_size() as i32),
+        )?;
         self.file_manager
-            .borrow_mut()
-            .write(&blid, &mut self.log_page)
-            .expect("could not write block id in to log file");
-        blid
+            .lock()
+            .unwrap()
+            .write(&blid, &mut self.log_page)?;
+        Ok(blid)
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use std::rc::Rc;
     use tempdir::TempDir;
     const TEST_BLOCK_SIZE: usize = 4 * 8;
     #[test]
     fn test_log_manger_builder() {
         let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
-        let file_manager = Rc::new(RefCell::new(FileManager::new(
-            tmp_dir.path().to_owned(),
-            TEST_BLOCK_SIZE,
-        )));
-        let log_manager = LogManager::builder("log.wal".to_string(), file_manager).build();
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = LogManager::builder("log.wal".to_string(), file_manager)
+            .build()
+            .unwrap();
         assert_eq!(log_manager.block_id.block_num(), 0);
         assert_eq!(log_manager.latest_lsn, 0);
         assert_eq!(log_manager.last_lsn, 0);
@@ -216,11 +318,12 @@ mod tests {
     #[test]
     fn test_log_manger_append() {
         let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
-        let file_manager = Rc::new(RefCell::new(FileManager::new(
-            tmp_dir.path().to_owned(),
-            TEST_BLOCK_SIZE,
-        )));
-        let mut log_manager = LogManager::builder("log.wal".to_string(), file_manager).build();
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let mut log_manager = LogManager::builder("log.wal".to_string(), file_manager)
+            .build()
+            .unwrap();
         assert_eq!(log_manager.block_id.block_num(), 0);
         assert_eq!(log_manager.latest_lsn, 0);
         assert_eq!(log_manager.last_lsn, 0);
@@ -229,44 +332,132 @@ mod tests {
             Some(TEST_BLOCK_SIZE as i32)
         );
 
-        log_manager.append("foo".as_bytes().to_vec());
+        log_manager.append("foo".as_bytes().to_vec()).unwrap();
         assert_eq!(log_manager.latest_lsn, 1);
-        log_manager.append("bar".as_bytes().to_vec());
+        log_manager.append("bar".as_bytes().to_vec()).unwrap();
         assert_eq!(log_manager.latest_lsn, 2);
-        log_manager.append("fizz".as_bytes().to_vec());
+        log_manager.append("fizz".as_bytes().to_vec()).unwrap();
         assert_eq!(log_manager.latest_lsn, 3);
         // This append will flush the log page to disk
-        log_manager.append("buzz".as_bytes().to_vec());
+        log_manager.append("buzz".as_bytes().to_vec()).unwrap();
         assert_eq!(log_manager.latest_lsn, 4);
         tmp_dir.close().expect("failed to remove temp dir");
     }
 
+    #[test]
+    fn test_log_manager_flush_lsn() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let mut log_manager = LogManager::builder("log.wal".to_string(), file_manager)
+            .build()
+            .unwrap();
+        let first = log_manager.append("foo".as_bytes().to_vec()).unwrap();
+        let second = log_manager.append("bar".as_bytes().to_vec()).unwrap();
+        assert_eq!(log_manager.last_lsn, 0);
+
+        // Flushing one record writes the whole tail page.
+        log_manager.flush_lsn(first).unwrap();
+        assert_eq!(log_manager.last_lsn, second);
+        let third = log_manager.append("baz".as_bytes().to_vec()).unwrap();
+        log_manager.flush_lsn(second).unwrap();
+        assert_eq!(log_manager.last_lsn, second);
+        log_manager.flush().unwrap();
+        assert_eq!(log_manager.last_lsn, third);
+
+        // Moving to a new block writes out the old one.
+        log_manager.append("buzz".as_bytes().to_vec()).unwrap();
+        assert_eq!(log_manager.block_id.block_num(), 1);
+        assert_eq!(log_manager.last_lsn, third);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
     #[test]
     fn test_log_iterator() {
         let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
-        let file_manager = Rc::new(RefCell::new(FileManager::new(
-            tmp_dir.path().to_owned(),
-            TEST_BLOCK_SIZE,
-        )));
-        let log_manager = Rc::new(RefCell::new(
-            LogManager::builder("log.wal".to_string(), file_manager.clone()).build(),
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
         ));
         let initial_block_id = {
-            let mut lm = log_manager.borrow_mut();
-            lm.append("foo".as_bytes().to_vec());
-            lm.append("bar".as_bytes().to_vec());
-            lm.flush();
+            let mut lm = log_manager.lock().unwrap();
+            lm.append("foo".as_bytes().to_vec()).unwrap();
+            lm.append("bar".as_bytes().to_vec()).unwrap();
+            lm.flush().unwrap();
             // First block ID
             BlockId::new(&lm.log_file, 0)
         };
 
-        let mut log_iterator = LogIterator::new(file_manager.clone(), &initial_block_id);
+        let mut log_iterator = LogIterator::new(file_manager.clone(), &initial_block_id).unwrap();
         let first = log_iterator.next();
         assert!(first.is_some());
-        assert_eq!(first.unwrap().to_owned().to_vec(), vec![98, 97, 114]);
+        assert_eq!(first.unwrap().unwrap().to_vec(), vec![98, 97, 114]);
         let second = log_iterator.next();
         assert!(second.is_some());
-        assert_eq!(log_iterator.next(), None);
+        assert!(log_iterator.next().is_none());
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_log_iterator_walks_back_through_blocks() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let mut log_manager = LogManager::builder("log.wal".to_string(), file_manager)
+            .build()
+            .unwrap();
+        let records: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 3]).collect();
+        for record in records.iter() {
+            log_manager.append(record.clone()).unwrap();
+        }
+        assert!(log_manager.block_id.block_num() > 1);
+
+        let read: Vec<Vec<u8>> = log_manager
+            .iterator()
+            .unwrap()
+            .map(|record| record.unwrap().to_vec())
+            .collect();
+        let expected: Vec<Vec<u8>> = records.into_iter().rev().collect();
+        assert_eq!(read, expected);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_log_iterator_snapshot() {
+        let tmp_dir = TempDir::new("test_log_manager").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let mut log_manager = LogManager::builder("log.wal".to_string(), file_manager)
+            .build()
+            .unwrap();
+        log_manager.append("foo".as_bytes().to_vec()).unwrap();
+
+        // Records still sitting in the tail page are flushed and visible,
+        // and appends made after the iterator was taken are not.
+        let log_iterator = log_manager.iterator().unwrap();
+        log_manager.append("bar".as_bytes().to_vec()).unwrap();
+        let records: Vec<Box<[u8]>> = log_iterator.map(Result::unwrap).collect();
+        assert_eq!(records, vec![b"foo".to_vec().into_boxed_slice()]);
+
+        let records: Vec<Box<[u8]>> = log_manager
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                b"bar".to_vec().into_boxed_slice(),
+                b"foo".to_vec().into_boxed_slice()
+            ]
+        );
         tmp_dir.close().expect("failed to remove temp dir");
     }
 }
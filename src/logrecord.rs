@@ -1,8 +1,8 @@
+use crate::error::DbError;
 use crate::filemanager::{BlockId, Page};
 use crate::logmanager::LogManager;
 use crate::transaction::Transaction;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 pub const CHECKPOINT: i32 = 0;
 pub const START: i32 = 1;
@@ -10,44 +10,54 @@ pub const COMMIT: i32 = 2;
 pub const ROLLBACK: i32 = 3;
 pub const SETINT: i32 = 4;
 pub const SETSTRING: i32 = 5;
+pub const SETLONG: i32 = 6;
+pub const SETDOUBLE: i32 = 7;
+pub const SETBYTES: i32 = 8;
+pub const SETFREE: i32 = 9;
 
-pub trait LogRecord {
-
+/// Whether records of type `operation` change a block, and so are undone
+/// and redone.
+pub fn is_update(operation: i32) -> bool {
+    matches!(
+        operation,
+        SETINT | SETSTRING | SETLONG | SETDOUBLE | SETBYTES | SETFREE
+    )
+}
 
+pub trait LogRecord {
     fn operation(&self) -> i32;
 
     fn tx_number(&self) -> i32;
 
-    fn undo(&self, txn: &mut Transaction);
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError>;
 
-    fn create_log_record(bytes: Vec<u8>) -> Option<Box<dyn LogRecord>> {
-        let mut page = Page::builder().with_log_buffer(bytes).build();
-        let page_t = page.get_int(0).unwrap();
-        match page_t {
-            CHECKPOINT => Some(Box::new(SetIntLogRecord::new(page))),
-            START => Some(Box::new(SetIntLogRecord::new(page))),
-            COMMIT => Some(Box::new(SetIntLogRecord::new(page))),
-            ROLLBACK => Some(Box::new(SetIntLogRecord::new(page))),
-            SETINT => Some(Box::new(SetIntLogRecord::new(page))),
-            SETSTRING => Some(Box::new(SetStringLogRecord::new(page))),
-            _ => None,
-        }
-    }
+    /// Undoes the change with a logged write, for a transaction that goes
+    /// on after rolling back to a savepoint. Recovery then redoes the undo
+    /// along with the change if the transaction commits.
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError>;
+
+    /// Applies the change again, for a transaction that committed before its
+    /// buffers reached the disk.
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError>;
 }
 
 pub struct LogRecordFactory;
 
 impl LogRecordFactory {
     pub fn create_log_record(bytes: Vec<u8>) -> Option<Box<dyn LogRecord>> {
-        let mut page = Page::builder().with_log_buffer(bytes).build();
+        let page = Page::builder().with_log_buffer(bytes).build();
         let page_t = page.get_int(0).unwrap();
         match page_t {
-            CHECKPOINT => Some(Box::new(SetIntLogRecord::new(page))),
-            START => Some(Box::new(SetIntLogRecord::new(page))),
-            COMMIT => Some(Box::new(SetIntLogRecord::new(page))),
-            ROLLBACK => Some(Box::new(SetIntLogRecord::new(page))),
+            CHECKPOINT => Some(Box::new(CheckpointLogRecord)),
+            START => Some(Box::new(StartLogRecord::new(page))),
+            COMMIT => Some(Box::new(CommitLogRecord::new(page))),
+            ROLLBACK => Some(Box::new(RollbackLogRecord::new(page))),
             SETINT => Some(Box::new(SetIntLogRecord::new(page))),
             SETSTRING => Some(Box::new(SetStringLogRecord::new(page))),
+            SETLONG => Some(Box::new(SetLongLogRecord::new(page))),
+            SETDOUBLE => Some(Box::new(SetDoubleLogRecord::new(page))),
+            SETBYTES => Some(Box::new(SetBytesLogRecord::new(page))),
+            SETFREE => Some(Box::new(SetFreeLogRecord::new(page))),
             _ => None,
         }
     }
@@ -57,7 +67,8 @@ pub struct SetStringLogRecord {
     tx_number: i32,
     offset: i32,
     block_id: BlockId,
-    value: String,
+    old_value: String,
+    new_value: String,
 }
 
 impl SetStringLogRecord {
@@ -66,45 +77,51 @@ impl SetStringLogRecord {
         let tx_number = page.get_int(tx_pos).unwrap();
         let filename_pos = tx_pos + size_of::<i32>();
         let filename = page.get_string(filename_pos).unwrap();
-        let block_pos = Page::max_len(&filename);
+        let block_pos = filename_pos + Page::max_len(&filename);
         let block_num = page.get_int(block_pos).unwrap();
         let block_id = BlockId::new(&filename, block_num as usize);
         let offset_pos = block_pos + size_of::<i32>();
         let offset = page.get_int(offset_pos).unwrap();
         let value_pos = offset_pos + size_of::<i32>();
-        let value = page.get_string(value_pos).unwrap();
+        let old_value = page.get_string(value_pos).unwrap();
+        let new_pos = value_pos + Page::max_len(&old_value);
+        let new_value = page.get_string(new_pos).unwrap();
 
         SetStringLogRecord {
             tx_number,
             offset,
             block_id,
-            value,
+            old_value,
+            new_value,
         }
     }
 
     pub fn write_to_log_record(
-        log_manager: Rc<RefCell<LogManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
         tx_number: i32,
         block_id: &BlockId,
         offset: i32,
-        value: String,
-    ) -> i32 {
+        old_value: String,
+        new_value: String,
+    ) -> Result<i64, DbError> {
         let tx_pos = size_of::<i32>();
         let filename_pos = tx_pos + size_of::<i32>();
-        let block_pos = Page::max_len(block_id.file_name().as_str());
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
         let offset_pos = block_pos + size_of::<i32>();
         let value_pos = offset_pos + size_of::<i32>();
-        let record_len = value_pos + Page::max_len(&value);
+        let new_pos = value_pos + Page::max_len(&old_value);
+        let record_len = new_pos + Page::max_len(&new_value);
         let record = vec![0u8; record_len];
         let mut page = Page::builder().with_log_buffer(record).build();
-        page.set_int(0, Some(SETSTRING));
-        page.set_int(tx_pos, Some(tx_number));
-        page.set_string(filename_pos, Some(block_id.file_name()));
-        page.set_int(block_pos, Some(block_id.block_num() as i32));
-        page.set_int(offset_pos, Some(offset));
-        page.set_string(value_pos, Some(value));
+        page.set_int(0, Some(SETSTRING))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(offset_pos, Some(offset))?;
+        page.set_string(value_pos, Some(old_value))?;
+        page.set_string(new_pos, Some(new_value))?;
         let bb = page.bytes();
-        log_manager.borrow_mut().append(Vec::from(bb))
+        log_manager.lock().unwrap().append(Vec::from(bb))
     }
 }
 impl LogRecord for SetStringLogRecord {
@@ -116,10 +133,40 @@ impl LogRecord for SetStringLogRecord {
         self.tx_number
     }
 
-    fn undo(&self, txn: &mut Transaction) {
-        txn.pin(&self.block_id);
-        txn.set_string(&self.block_id, self.offset, Some(self.value.clone()), false);
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_string(
+            &self.block_id,
+            self.offset,
+            Some(self.old_value.clone()),
+            false,
+        );
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_string(
+            &self.block_id,
+            self.offset,
+            Some(self.old_value.clone()),
+            true,
+        );
         txn.unpin(&self.block_id);
+        result
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_string(
+            &self.block_id,
+            self.offset,
+            Some(self.new_value.clone()),
+            false,
+        );
+        txn.unpin(&self.block_id);
+        result
     }
 }
 
@@ -127,7 +174,8 @@ pub struct SetIntLogRecord {
     tx_number: i32,
     offset: i32,
     block_id: BlockId,
-    value: i32,
+    old_value: i32,
+    new_value: i32,
 }
 
 impl SetIntLogRecord {
@@ -136,45 +184,51 @@ impl SetIntLogRecord {
         let tx_number = page.get_int(tx_pos).unwrap();
         let filename_pos = tx_pos + size_of::<i32>();
         let filename = page.get_string(filename_pos).unwrap();
-        let block_pos = Page::max_len(&filename);
+        let block_pos = filename_pos + Page::max_len(&filename);
         let block_num = page.get_int(block_pos).unwrap();
         let block_id = BlockId::new(&filename, block_num as usize);
         let offset_pos = block_pos + size_of::<i32>();
         let offset = page.get_int(offset_pos).unwrap();
         let value_pos = offset_pos + size_of::<i32>();
-        let value = page.get_int(value_pos).unwrap();
+        let old_value = page.get_int(value_pos).unwrap();
+        let new_pos = value_pos + size_of::<i32>();
+        let new_value = page.get_int(new_pos).unwrap();
 
         SetIntLogRecord {
             tx_number,
             offset,
             block_id,
-            value,
+            old_value,
+            new_value,
         }
     }
 
     pub fn write_to_log_record(
-        log_manager: Rc<RefCell<LogManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
         tx_number: i32,
         block_id: &BlockId,
         offset: i32,
-        value: i32,
-    ) -> i32 {
+        old_value: i32,
+        new_value: i32,
+    ) -> Result<i64, DbError> {
         let tx_pos = size_of::<i32>();
         let filename_pos = tx_pos + size_of::<i32>();
-        let block_pos = Page::max_len(block_id.file_name().as_str());
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
         let offset_pos = block_pos + size_of::<i32>();
         let value_pos = offset_pos + size_of::<i32>();
-        let record_len = value_pos + size_of::<i32>();
+        let new_pos = value_pos + size_of::<i32>();
+        let record_len = new_pos + size_of::<i32>();
         let record = vec![0u8; record_len];
         let mut page = Page::builder().with_log_buffer(record).build();
-        page.set_int(0, Some(SETINT));
-        page.set_int(tx_pos, Some(tx_number));
-        page.set_string(filename_pos, Some(block_id.file_name()));
-        page.set_int(block_pos, Some(block_id.block_num() as i32));
-        page.set_int(offset_pos, Some(offset));
-        page.set_int(value_pos, Some(value));
+        page.set_int(0, Some(SETINT))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(offset_pos, Some(offset))?;
+        page.set_int(value_pos, Some(old_value))?;
+        page.set_int(new_pos, Some(new_value))?;
         let bb = page.bytes();
-        log_manager.borrow_mut().append(Vec::from(bb))
+        log_manager.lock().unwrap().append(Vec::from(bb))
     }
 }
 impl LogRecord for SetIntLogRecord {
@@ -186,10 +240,418 @@ impl LogRecord for SetIntLogRecord {
         self.tx_number
     }
 
-    fn undo(&self, txn: &mut Transaction) {
-        txn.pin(&self.block_id);
-        txn.set_int(&self.block_id, self.offset, Some(self.value.clone()), false);
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_int(&self.block_id, self.offset, Some(self.old_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_int(&self.block_id, self.offset, Some(self.old_value), true);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_int(&self.block_id, self.offset, Some(self.new_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+}
+
+pub struct SetLongLogRecord {
+    tx_number: i32,
+    offset: i32,
+    block_id: BlockId,
+    old_value: i64,
+    new_value: i64,
+}
+
+impl SetLongLogRecord {
+    pub fn new(page: Page) -> SetLongLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let filename = page.get_string(filename_pos).unwrap();
+        let block_pos = filename_pos + Page::max_len(&filename);
+        let block_num = page.get_int(block_pos).unwrap();
+        let block_id = BlockId::new(&filename, block_num as usize);
+        let offset_pos = block_pos + size_of::<i32>();
+        let offset = page.get_int(offset_pos).unwrap();
+        let value_pos = offset_pos + size_of::<i32>();
+        let old_value = page.get_long(value_pos).unwrap();
+        let new_pos = value_pos + size_of::<i64>();
+        let new_value = page.get_long(new_pos).unwrap();
+
+        SetLongLogRecord {
+            tx_number,
+            offset,
+            block_id,
+            old_value,
+            new_value,
+        }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+        block_id: &BlockId,
+        offset: i32,
+        old_value: i64,
+        new_value: i64,
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
+        let offset_pos = block_pos + size_of::<i32>();
+        let value_pos = offset_pos + size_of::<i32>();
+        let new_pos = value_pos + size_of::<i64>();
+        let record_len = new_pos + size_of::<i64>();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(SETLONG))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(offset_pos, Some(offset))?;
+        page.set_long(value_pos, Some(old_value))?;
+        page.set_long(new_pos, Some(new_value))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+impl LogRecord for SetLongLogRecord {
+    fn operation(&self) -> i32 {
+        SETLONG
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
+
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_long(&self.block_id, self.offset, Some(self.old_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_long(&self.block_id, self.offset, Some(self.old_value), true);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_long(&self.block_id, self.offset, Some(self.new_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+}
+
+/// Laid out like a `SetLongLogRecord`, with the values stored as the bits of
+/// the doubles.
+pub struct SetDoubleLogRecord {
+    tx_number: i32,
+    offset: i32,
+    block_id: BlockId,
+    old_value: f64,
+    new_value: f64,
+}
+
+impl SetDoubleLogRecord {
+    pub fn new(page: Page) -> SetDoubleLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let filename = page.get_string(filename_pos).unwrap();
+        let block_pos = filename_pos + Page::max_len(&filename);
+        let block_num = page.get_int(block_pos).unwrap();
+        let block_id = BlockId::new(&filename, block_num as usize);
+        let offset_pos = block_pos + size_of::<i32>();
+        let offset = page.get_int(offset_pos).unwrap();
+        let value_pos = offset_pos + size_of::<i32>();
+        let old_value = page.get_double(value_pos).unwrap();
+        let new_pos = value_pos + size_of::<f64>();
+        let new_value = page.get_double(new_pos).unwrap();
+
+        SetDoubleLogRecord {
+            tx_number,
+            offset,
+            block_id,
+            old_value,
+            new_value,
+        }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+        block_id: &BlockId,
+        offset: i32,
+        old_value: f64,
+        new_value: f64,
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
+        let offset_pos = block_pos + size_of::<i32>();
+        let value_pos = offset_pos + size_of::<i32>();
+        let new_pos = value_pos + size_of::<f64>();
+        let record_len = new_pos + size_of::<f64>();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(SETDOUBLE))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(offset_pos, Some(offset))?;
+        page.set_double(value_pos, Some(old_value))?;
+        page.set_double(new_pos, Some(new_value))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+impl LogRecord for SetDoubleLogRecord {
+    fn operation(&self) -> i32 {
+        SETDOUBLE
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
+
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_double(&self.block_id, self.offset, Some(self.old_value), false);
         txn.unpin(&self.block_id);
+        result
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_double(&self.block_id, self.offset, Some(self.old_value), true);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_double(&self.block_id, self.offset, Some(self.new_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+}
+
+/// A change to a length prefixed run of bytes, such as a chunk of a blob in
+/// an overflow block.
+pub struct SetBytesLogRecord {
+    tx_number: i32,
+    offset: i32,
+    block_id: BlockId,
+    old_value: Vec<u8>,
+    new_value: Vec<u8>,
+}
+
+impl SetBytesLogRecord {
+    pub fn new(page: Page) -> SetBytesLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let filename = page.get_string(filename_pos).unwrap();
+        let block_pos = filename_pos + Page::max_len(&filename);
+        let block_num = page.get_int(block_pos).unwrap();
+        let block_id = BlockId::new(&filename, block_num as usize);
+        let offset_pos = block_pos + size_of::<i32>();
+        let offset = page.get_int(offset_pos).unwrap();
+        let value_pos = offset_pos + size_of::<i32>();
+        let old_value = page.get_bytes(value_pos).unwrap().into_vec();
+        let new_pos = value_pos + size_of::<i32>() + old_value.len();
+        let new_value = page.get_bytes(new_pos).unwrap().into_vec();
+
+        SetBytesLogRecord {
+            tx_number,
+            offset,
+            block_id,
+            old_value,
+            new_value,
+        }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+        block_id: &BlockId,
+        offset: i32,
+        old_value: &[u8],
+        new_value: &[u8],
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
+        let offset_pos = block_pos + size_of::<i32>();
+        let value_pos = offset_pos + size_of::<i32>();
+        let new_pos = value_pos + size_of::<i32>() + old_value.len();
+        let record_len = new_pos + size_of::<i32>() + new_value.len();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(SETBYTES))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(offset_pos, Some(offset))?;
+        page.set_bytes(value_pos, Some(old_value))?;
+        page.set_bytes(new_pos, Some(new_value))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+impl LogRecord for SetBytesLogRecord {
+    fn operation(&self) -> i32 {
+        SETBYTES
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
+
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_bytes(&self.block_id, self.offset, Some(&self.old_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_bytes(&self.block_id, self.offset, Some(&self.old_value), true);
+        txn.unpin(&self.block_id);
+        result
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.pin(&self.block_id)?;
+        let result = txn.set_bytes(&self.block_id, self.offset, Some(&self.new_value), false);
+        txn.unpin(&self.block_id);
+        result
+    }
+}
+
+/// A block going onto or coming off its file's free block map.
+pub struct SetFreeLogRecord {
+    tx_number: i32,
+    block_id: BlockId,
+    was_free: bool,
+    free: bool,
+}
+
+impl SetFreeLogRecord {
+    pub fn new(page: Page) -> SetFreeLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let filename = page.get_string(filename_pos).unwrap();
+        let block_pos = filename_pos + Page::max_len(&filename);
+        let block_num = page.get_int(block_pos).unwrap();
+        let block_id = BlockId::new(&filename, block_num as usize);
+        let old_pos = block_pos + size_of::<i32>();
+        let was_free = page.get_int(old_pos).unwrap() != 0;
+        let new_pos = old_pos + size_of::<i32>();
+        let free = page.get_int(new_pos).unwrap() != 0;
+
+        SetFreeLogRecord {
+            tx_number,
+            block_id,
+            was_free,
+            free,
+        }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+        block_id: &BlockId,
+        was_free: bool,
+        free: bool,
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let filename_pos = tx_pos + size_of::<i32>();
+        let block_pos = filename_pos + Page::max_len(block_id.file_name().as_str());
+        let old_pos = block_pos + size_of::<i32>();
+        let new_pos = old_pos + size_of::<i32>();
+        let record_len = new_pos + size_of::<i32>();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(SETFREE))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        page.set_string(filename_pos, Some(block_id.file_name()))?;
+        page.set_int(block_pos, Some(block_id.block_num() as i32))?;
+        page.set_int(old_pos, Some(was_free as i32))?;
+        page.set_int(new_pos, Some(free as i32))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+
+impl LogRecord for SetFreeLogRecord {
+    fn operation(&self) -> i32 {
+        SETFREE
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
+
+    fn undo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.set_free(&self.block_id, self.was_free, false)
+    }
+
+    fn compensate(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.set_free(&self.block_id, self.was_free, true)
+    }
+
+    fn redo(&self, txn: &mut Transaction) -> Result<(), DbError> {
+        txn.set_free(&self.block_id, self.free, false)
+    }
+}
+
+/// Marks a point at which no transaction had uncommitted changes and every
+/// buffer had been flushed, so recovery need not read further back.
+pub struct CheckpointLogRecord;
+
+impl CheckpointLogRecord {
+    pub fn write_to_log_record(log_manager: Arc<Mutex<LogManager>>) -> Result<i64, DbError> {
+        let record = vec![0u8; size_of::<i32>()];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(CHECKPOINT))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+impl LogRecord for CheckpointLogRecord {
+    fn operation(&self) -> i32 {
+        CHECKPOINT
+    }
+
+    fn tx_number(&self) -> i32 {
+        -1
+    }
+
+    fn undo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn compensate(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn redo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
     }
 }
 
@@ -202,23 +664,21 @@ impl CommitLogRecord {
         let tx_pos = size_of::<i32>();
         let tx_number = page.get_int(tx_pos).unwrap();
 
-        CommitLogRecord {
-            tx_number,
-        }
+        CommitLogRecord { tx_number }
     }
 
     pub fn write_to_log_record(
-        log_manager: Rc<RefCell<LogManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
         tx_number: i32,
-    ) -> i32 {
+    ) -> Result<i64, DbError> {
         let tx_pos = size_of::<i32>();
         let record_len = tx_pos + size_of::<i32>();
         let record = vec![0u8; record_len];
         let mut page = Page::builder().with_log_buffer(record).build();
-        page.set_int(0, Some(COMMIT));
-        page.set_int(tx_pos, Some(tx_number));
+        page.set_int(0, Some(COMMIT))?;
+        page.set_int(tx_pos, Some(tx_number))?;
         let bb = page.bytes();
-        log_manager.borrow_mut().append(Vec::from(bb))
+        log_manager.lock().unwrap().append(Vec::from(bb))
     }
 }
 impl LogRecord for CommitLogRecord {
@@ -230,8 +690,111 @@ impl LogRecord for CommitLogRecord {
         self.tx_number
     }
 
-    fn undo(&self, txn: &mut Transaction) {
-        txn.rollback();
+    fn undo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn compensate(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn redo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+pub struct StartLogRecord {
+    tx_number: i32,
+}
+
+impl StartLogRecord {
+    pub fn new(page: Page) -> StartLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+
+        StartLogRecord { tx_number }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let record_len = tx_pos + size_of::<i32>();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(START))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
     }
 }
+impl LogRecord for StartLogRecord {
+    fn operation(&self) -> i32 {
+        START
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
 
+    fn undo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn compensate(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn redo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+pub struct RollbackLogRecord {
+    tx_number: i32,
+}
+
+impl RollbackLogRecord {
+    pub fn new(page: Page) -> RollbackLogRecord {
+        let tx_pos = size_of::<i32>();
+        let tx_number = page.get_int(tx_pos).unwrap();
+
+        RollbackLogRecord { tx_number }
+    }
+
+    pub fn write_to_log_record(
+        log_manager: Arc<Mutex<LogManager>>,
+        tx_number: i32,
+    ) -> Result<i64, DbError> {
+        let tx_pos = size_of::<i32>();
+        let record_len = tx_pos + size_of::<i32>();
+        let record = vec![0u8; record_len];
+        let mut page = Page::builder().with_log_buffer(record).build();
+        page.set_int(0, Some(ROLLBACK))?;
+        page.set_int(tx_pos, Some(tx_number))?;
+        let bb = page.bytes();
+        log_manager.lock().unwrap().append(Vec::from(bb))
+    }
+}
+impl LogRecord for RollbackLogRecord {
+    fn operation(&self) -> i32 {
+        ROLLBACK
+    }
+
+    fn tx_number(&self) -> i32 {
+        self.tx_number
+    }
+
+    fn undo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn compensate(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn redo(&self, _txn: &mut Transaction) -> Result<(), DbError> {
+        Ok(())
+    }
+}
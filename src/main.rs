@@ -0,0 +1,53 @@
+use simpledb_rs::migrate::migrate;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str =
+    "usage: simpledb migrate --from-block-size N --block-size N <source dir> <target dir>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("migrate") => run_migrate(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_migrate(args: &[String]) -> ExitCode {
+    let mut source_block_size = None;
+    let mut target_block_size = None;
+    let mut dirs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from-block-size" => source_block_size = args.next().and_then(|n| n.parse().ok()),
+            "--block-size" => target_block_size = args.next().and_then(|n| n.parse().ok()),
+            _ => dirs.push(PathBuf::from(arg)),
+        }
+    }
+    let (Some(source_block_size), Some(target_block_size), [source, target]) =
+        (source_block_size, target_block_size, dirs.as_slice())
+    else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    match migrate(source, source_block_size, target, target_block_size) {
+        Ok(summaries) => {
+            for summary in summaries {
+                println!(
+                    "{}: {} rows, checksum {:016x}",
+                    summary.table_name, summary.rows, summary.checksum
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("migration failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,1827 @@
+use crate::error::DbError;
+use crate::parse::{Aggregate, AggregateKind};
+use crate::plan::{Plan, PlanError, PlanNode};
+use crate::query::{Constant, Scan, UpdateScan};
+use crate::recordmanager::{Layout, Rid, Schema, TableScan, INTEGER};
+use crate::transaction::Transaction;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+static NEXT_TABLE_NUM: AtomicUsize = AtomicUsize::new(0);
+
+/// A table that lives only as long as the query that made it. Its file name
+/// starts with `temp`, so the file manager removes it the next time the
+/// database starts.
+pub struct TempTable {
+    tx: Rc<RefCell<Transaction>>,
+    table_name: String,
+    layout: Layout,
+}
+
+impl TempTable {
+    pub fn new(tx: Rc<RefCell<Transaction>>, schema: &Schema) -> TempTable {
+        let table_num = NEXT_TABLE_NUM.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        TempTable {
+            tx,
+            table_name: format!("temp{}", table_num),
+            layout: Layout::new(schema.clone()),
+        }
+    }
+
+    /// A temporary table holding a copy of the output of `plan`.
+    pub fn from_plan(tx: Rc<RefCell<Transaction>>, plan: &dyn Plan) -> Result<TempTable, DbError> {
+        let schema = plan.schema();
+        let temp = TempTable::new(tx, schema);
+        let mut src = plan.open()?;
+        let mut dest = temp.open();
+        let mut has_more = src.next();
+        while has_more {
+            has_more = copy_record(&mut src, &mut dest, schema)?;
+        }
+        src.close();
+        dest.close();
+        Ok(temp)
+    }
+
+    pub fn open(&self) -> TableScan {
+        TableScan::new(self.tx.clone(), &self.table_name, self.layout.clone())
+    }
+
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+}
+
+// Appends the current record of `src` to `dest`, then moves `src` on and
+// reports whether it has another record.
+fn copy_record(src: &mut dyn Scan, dest: &mut TableScan, schema: &Schema) -> Result<bool, DbError> {
+    dest.insert()?;
+    for field_name in schema.fields() {
+        if let Some(val) = src.get_val(field_name) {
+            dest.set_val(field_name, val)?;
+        }
+    }
+    Ok(src.next())
+}
+
+// Blocks a temporary table holding the output of `plan` would take up.
+pub(crate) fn temp_table_blocks(tx: &Rc<RefCell<Transaction>>, plan: &dyn Plan) -> usize {
+    let layout = Layout::new(plan.schema().clone());
+    let records_per_block = tx.borrow().block_size() / layout.slot_size();
+    plan.records_output().div_ceil(records_per_block.max(1))
+}
+
+/// Whether `plan` already comes out sorted on `fields`, in that order.
+pub fn is_sorted_on(plan: &dyn Plan, fields: &[String]) -> bool {
+    plan.sort_order().starts_with(fields)
+}
+
+/// `plan` sorted on `fields`, or `plan` itself if it already is.
+pub fn sorted(
+    tx: Rc<RefCell<Transaction>>,
+    plan: Box<dyn Plan>,
+    fields: Vec<String>,
+) -> Box<dyn Plan> {
+    if is_sorted_on(plan.as_ref(), &fields) {
+        plan
+    } else {
+        Box::new(SortPlan::new(tx, plan, fields))
+    }
+}
+
+/// Orders records by a list of fields, comparing later fields only when the
+/// earlier ones are equal.
+#[derive(Clone)]
+pub struct RecordComparator {
+    fields: Vec<String>,
+}
+
+impl RecordComparator {
+    pub fn new(fields: Vec<String>) -> RecordComparator {
+        RecordComparator { fields }
+    }
+
+    pub fn compare(&self, lhs: &dyn Scan, rhs: &dyn Scan) -> Ordering {
+        for field_name in self.fields.iter() {
+            let order = lhs.get_val(field_name).cmp(&rhs.get_val(field_name));
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+}
+
+/// Sorts the output of a plan with an external merge sort. Opening the plan
+/// splits its records into sorted runs in temporary tables and merges them
+/// pairwise until at most two remain; the scan merges the last two as it
+/// reads.
+///
+/// When the transaction allows more than one sort worker, runs are instead
+/// cut into chunks of `RUN_RECORDS` records that the workers sort in memory
+/// side by side. The merges stay on the calling thread, since the temporary
+/// tables belong to its transaction.
+pub struct SortPlan {
+    tx: Rc<RefCell<Transaction>>,
+    plan: Box<dyn Plan>,
+    comparator: RecordComparator,
+}
+
+impl SortPlan {
+    /// Records one sort worker holds in memory at a time.
+    pub const RUN_RECORDS: usize = 1000;
+
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        plan: Box<dyn Plan>,
+        sort_fields: Vec<String>,
+    ) -> SortPlan {
+        SortPlan {
+            tx,
+            plan,
+            comparator: RecordComparator::new(sort_fields),
+        }
+    }
+
+    // Copies the input into runs, starting a new run whenever a record is
+    // smaller than the one before it.
+    fn split_into_runs(&self, src: &mut dyn Scan) -> Result<Vec<TempTable>, DbError> {
+        let mut runs = Vec::new();
+        src.before_first();
+        if !src.next() {
+            return Ok(runs);
+        }
+        let schema = self.plan.schema();
+        let mut current = TempTable::new(self.tx.clone(), schema);
+        let mut current_scan = current.open();
+        while copy_record(src, &mut current_scan, schema)? {
+            if self.comparator.compare(src, &current_scan) == Ordering::Less {
+                current_scan.close();
+                runs.push(current);
+                current = TempTable::new(self.tx.clone(), schema);
+                current_scan = current.open();
+            }
+        }
+        current_scan.close();
+        runs.push(current);
+        Ok(runs)
+    }
+
+    // Reads the input a batch of chunks at a time, sorts the chunks of a
+    // batch on one thread each and writes every chunk out as a run.
+    fn sort_into_runs(
+        &self,
+        src: &mut dyn Scan,
+        workers: usize,
+    ) -> Result<Vec<TempTable>, DbError> {
+        let schema = self.plan.schema();
+        let keys: Vec<usize> = self
+            .comparator
+            .fields()
+            .iter()
+            .filter_map(|sort_field| schema.fields().iter().position(|field| field == sort_field))
+            .collect();
+        let mut runs = Vec::new();
+        src.before_first();
+        let mut has_more = src.next();
+        while has_more {
+            let mut chunks: Vec<Vec<Vec<Option<Constant>>>> = Vec::new();
+            while has_more && chunks.len() < workers {
+                let mut chunk = Vec::new();
+                while has_more && chunk.len() < Self::RUN_RECORDS {
+                    chunk.push(
+                        schema
+                            .fields()
+                            .iter()
+                            .map(|field_name| src.get_val(field_name))
+                            .collect(),
+                    );
+                    has_more = src.next();
+                }
+                chunks.push(chunk);
+            }
+            std::thread::scope(|scope| {
+                for chunk in chunks.iter_mut() {
+                    let keys = &keys;
+                    scope.spawn(move || {
+                        chunk.sort_by(|lhs, rhs| {
+                            keys.iter()
+                                .map(|&key| lhs[key].cmp(&rhs[key]))
+                                .find(|order| *order != Ordering::Equal)
+                                .unwrap_or(Ordering::Equal)
+                        })
+                    });
+                }
+            });
+            for chunk in chunks {
+                let run = TempTable::new(self.tx.clone(), schema);
+                let mut dest = run.open();
+                for record in chunk {
+                    dest.insert()?;
+                    for (field_name, val) in schema.fields().iter().zip(record) {
+                        if let Some(val) = val {
+                            dest.set_val(field_name, val)?;
+                        }
+                    }
+                }
+                dest.close();
+                runs.push(run);
+            }
+        }
+        Ok(runs)
+    }
+
+    fn do_merge_iteration(&self, runs: Vec<TempTable>) -> Result<Vec<TempTable>, DbError> {
+        let mut result = Vec::new();
+        let mut runs = runs.into_iter();
+        while let Some(first) = runs.next() {
+            match runs.next() {
+                Some(second) => result.push(self.merge_two_runs(&first, &second)?),
+                None => result.push(first),
+            }
+        }
+        Ok(result)
+    }
+
+    fn merge_two_runs(&self, first: &TempTable, second: &TempTable) -> Result<TempTable, DbError> {
+        let schema = self.plan.schema();
+        let result = TempTable::new(self.tx.clone(), schema);
+        let mut src1 = first.open();
+        let mut src2 = second.open();
+        let mut dest = result.open();
+        let mut has_more1 = src1.next();
+        let mut has_more2 = src2.next();
+        while has_more1 && has_more2 {
+            if self.comparator.compare(&src1, &src2) == Ordering::Less {
+                has_more1 = copy_record(&mut src1, &mut dest, schema)?;
+            } else {
+                has_more2 = copy_record(&mut src2, &mut dest, schema)?;
+            }
+        }
+        while has_more1 {
+            has_more1 = copy_record(&mut src1, &mut dest, schema)?;
+        }
+        while has_more2 {
+            has_more2 = copy_record(&mut src2, &mut dest, schema)?;
+        }
+        src1.close();
+        src2.close();
+        dest.close();
+        Ok(result)
+    }
+
+    /// Sorts the input and returns the scan itself rather than a boxed one,
+    /// for operators such as merge join that need to save their position.
+    pub fn open_sort_scan(&self) -> Result<SortScan, DbError> {
+        let mut src = self.plan.open()?;
+        let workers = self.tx.borrow().sort_workers();
+        let mut runs = if workers > 1 {
+            self.sort_into_runs(src.as_mut(), workers)?
+        } else {
+            self.split_into_runs(src.as_mut())?
+        };
+        src.close();
+        while runs.len() > 2 {
+            runs = self.do_merge_iteration(runs)?;
+        }
+        Ok(SortScan::new(runs, self.comparator.clone()))
+    }
+}
+
+impl Plan for SortPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(self.open_sort_scan()?))
+    }
+
+    // Like materialization, only the final read of the sorted output is
+    // counted.
+    fn blocks_accessed(&self) -> usize {
+        temp_table_blocks(&self.tx, self.plan.as_ref())
+    }
+
+    fn records_output(&self) -> usize {
+        self.plan.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        self.plan.distinct_values(field_name)
+    }
+
+    fn schema(&self) -> &Schema {
+        self.plan.schema()
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("sort", self, vec![self.plan.explain()])
+            .detail("fields", self.comparator.fields().join(", "))
+    }
+
+    fn sort_order(&self) -> Vec<String> {
+        self.comparator.fields().to_vec()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Current {
+    None,
+    First,
+    Second,
+}
+
+/// Reads the records of up to two sorted runs in order, merging them as it
+/// goes.
+pub struct SortScan {
+    runs: Vec<TempTable>,
+    scan1: Option<TableScan>,
+    scan2: Option<TableScan>,
+    has_more1: bool,
+    has_more2: bool,
+    current: Current,
+    comparator: RecordComparator,
+    saved_position: Option<SavedPosition>,
+}
+
+#[derive(Clone, Copy)]
+struct SavedPosition {
+    rid1: Option<Rid>,
+    rid2: Option<Rid>,
+    has_more1: bool,
+    has_more2: bool,
+    current: Current,
+}
+
+impl SortScan {
+    fn new(runs: Vec<TempTable>, comparator: RecordComparator) -> SortScan {
+        let scan1 = runs.first().map(TempTable::open);
+        let scan2 = runs.get(1).map(TempTable::open);
+        let mut scan = SortScan {
+            runs,
+            scan1,
+            scan2,
+            has_more1: false,
+            has_more2: false,
+            current: Current::None,
+            comparator,
+            saved_position: None,
+        };
+        scan.before_first();
+        scan
+    }
+
+    /// Remembers the current record so `restore_position` can return to it.
+    pub fn save_position(&mut self) {
+        self.saved_position = Some(SavedPosition {
+            rid1: self.scan1.as_ref().and_then(|scan| scan.get_rid()),
+            rid2: self.scan2.as_ref().and_then(|scan| scan.get_rid()),
+            has_more1: self.has_more1,
+            has_more2: self.has_more2,
+            current: self.current,
+        });
+    }
+
+    pub fn restore_position(&mut self) {
+        let Some(saved) = self.saved_position else {
+            return;
+        };
+        if let (Some(scan), Some(rid)) = (self.scan1.as_mut(), saved.rid1) {
+            scan.move_to_rid(rid);
+        }
+        if let (Some(scan), Some(rid)) = (self.scan2.as_mut(), saved.rid2) {
+            scan.move_to_rid(rid);
+        }
+        self.has_more1 = saved.has_more1;
+        self.has_more2 = saved.has_more2;
+        self.current = saved.current;
+    }
+
+    fn current_scan(&self) -> Option<&TableScan> {
+        match self.current {
+            Current::First => self.scan1.as_ref(),
+            Current::Second => self.scan2.as_ref(),
+            Current::None => None,
+        }
+    }
+}
+
+impl Scan for SortScan {
+    fn before_first(&mut self) {
+        self.current = Current::None;
+        if let Some(scan) = self.scan1.as_mut() {
+            scan.before_first();
+            self.has_more1 = scan.next();
+        }
+        if let Some(scan) = self.scan2.as_mut() {
+            scan.before_first();
+            self.has_more2 = scan.next();
+        }
+    }
+
+    fn next(&mut self) -> bool {
+        match self.current {
+            Current::First => {
+                self.has_more1 = self.scan1.as_mut().is_some_and(|scan| scan.next());
+            }
+            Current::Second => {
+                self.has_more2 = self.scan2.as_mut().is_some_and(|scan| scan.next());
+            }
+            Current::None => {}
+        }
+        self.current = match (self.has_more1, self.has_more2) {
+            (false, false) => return false,
+            (true, true) => {
+                let order = self
+                    .comparator
+                    .compare(self.scan1.as_ref().unwrap(), self.scan2.as_ref().unwrap());
+                if order == Ordering::Less {
+                    Current::First
+                } else {
+                    Current::Second
+                }
+            }
+            (true, false) => Current::First,
+            (false, true) => Current::Second,
+        };
+        true
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.current_scan()?.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.current_scan()?.get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.current_scan()?.get_str(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        match self.current_scan() {
+            Some(scan) => scan.with_str(field_name, f),
+            None => f(None),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.current_scan()?.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.runs
+            .first()
+            .is_some_and(|run| run.layout().schema().has_field(field_name))
+    }
+
+    fn close(&mut self) {
+        if let Some(scan) = self.scan1.as_mut() {
+            scan.close();
+        }
+        if let Some(scan) = self.scan2.as_mut() {
+            scan.close();
+        }
+    }
+}
+
+/// Computes one aggregate over the records of a group. `process_first` starts
+/// a new group at the scan's current record and `process_next` adds each
+/// further record of it. Null values of the aggregated field are skipped.
+pub trait AggregationFn {
+    fn process_first(&mut self, scan: &dyn Scan);
+    fn process_next(&mut self, scan: &dyn Scan);
+    /// Starts a group with no records, the one group of an empty input that
+    /// is not grouped by any field.
+    fn process_empty(&mut self);
+    /// The name the value is read back under, such as `countofsid`.
+    fn field_name(&self) -> &str;
+    /// The aggregate of the group, or `None` (null) if it had no value to
+    /// aggregate.
+    fn value(&self) -> Option<Constant>;
+}
+
+/// The number of non-null values, which is 0 for a group with none.
+pub struct CountFn {
+    field_name: String,
+    source_field: String,
+    count: i32,
+}
+
+impl CountFn {
+    pub fn new(field_name: &str) -> CountFn {
+        CountFn {
+            field_name: format!("countof{}", field_name),
+            source_field: field_name.to_string(),
+            count: 0,
+        }
+    }
+}
+
+impl AggregationFn for CountFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.process_empty();
+        self.process_next(scan);
+    }
+
+    fn process_empty(&mut self) {
+        self.count = 0;
+    }
+
+    fn process_next(&mut self, scan: &dyn Scan) {
+        if scan.get_val(&self.source_field).is_some() {
+            self.count += 1;
+        }
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        Some(Constant::Int(self.count))
+    }
+}
+
+pub struct MaxFn {
+    field_name: String,
+    source_field: String,
+    val: Option<Constant>,
+}
+
+impl MaxFn {
+    pub fn new(field_name: &str) -> MaxFn {
+        MaxFn {
+            field_name: format!("maxof{}", field_name),
+            source_field: field_name.to_string(),
+            val: None,
+        }
+    }
+}
+
+impl AggregationFn for MaxFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.val = scan.get_val(&self.source_field);
+    }
+
+    // `None` orders before any value, so a null never wins.
+    fn process_next(&mut self, scan: &dyn Scan) {
+        let val = scan.get_val(&self.source_field);
+        if val > self.val {
+            self.val = val;
+        }
+    }
+
+    fn process_empty(&mut self) {
+        self.val = None;
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        self.val.clone()
+    }
+}
+
+pub struct MinFn {
+    field_name: String,
+    source_field: String,
+    val: Option<Constant>,
+}
+
+impl MinFn {
+    pub fn new(field_name: &str) -> MinFn {
+        MinFn {
+            field_name: format!("minof{}", field_name),
+            source_field: field_name.to_string(),
+            val: None,
+        }
+    }
+}
+
+impl AggregationFn for MinFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.val = scan.get_val(&self.source_field);
+    }
+
+    fn process_next(&mut self, scan: &dyn Scan) {
+        let Some(val) = scan.get_val(&self.source_field) else {
+            return;
+        };
+        if self.val.as_ref().is_none_or(|min| val < *min) {
+            self.val = Some(val);
+        }
+    }
+
+    fn process_empty(&mut self) {
+        self.val = None;
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        self.val.clone()
+    }
+}
+
+/// Sums in 64 bits and saturates at the bounds of an int when read back, so
+/// an overflowing group yields `i32::MAX` or `i32::MIN` rather than wrapping.
+pub struct SumFn {
+    field_name: String,
+    source_field: String,
+    sum: Option<i64>,
+}
+
+impl SumFn {
+    pub fn new(field_name: &str) -> SumFn {
+        SumFn {
+            field_name: format!("sumof{}", field_name),
+            source_field: field_name.to_string(),
+            sum: None,
+        }
+    }
+}
+
+impl AggregationFn for SumFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.process_empty();
+        self.process_next(scan);
+    }
+
+    fn process_empty(&mut self) {
+        self.sum = None;
+    }
+
+    fn process_next(&mut self, scan: &dyn Scan) {
+        if let Some(val) = scan.get_int(&self.source_field) {
+            self.sum = Some(self.sum.unwrap_or(0) + val as i64);
+        }
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        self.sum
+            .map(|sum| Constant::Int(sum.clamp(i32::MIN as i64, i32::MAX as i64) as i32))
+    }
+}
+
+/// The average of an int field, truncated toward zero.
+pub struct AvgFn {
+    field_name: String,
+    source_field: String,
+    sum: i64,
+    count: i64,
+}
+
+impl AvgFn {
+    pub fn new(field_name: &str) -> AvgFn {
+        AvgFn {
+            field_name: format!("avgof{}", field_name),
+            source_field: field_name.to_string(),
+            sum: 0,
+            count: 0,
+        }
+    }
+}
+
+impl AggregationFn for AvgFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.process_empty();
+        self.process_next(scan);
+    }
+
+    fn process_empty(&mut self) {
+        self.sum = 0;
+        self.count = 0;
+    }
+
+    fn process_next(&mut self, scan: &dyn Scan) {
+        if let Some(val) = scan.get_int(&self.source_field) {
+            self.sum += val as i64;
+            self.count += 1;
+        }
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        (self.count > 0).then(|| Constant::Int((self.sum / self.count) as i32))
+    }
+}
+
+/// A HyperLogLog sketch of the distinct values added to it. Each value's
+/// hash picks a register by its top bits and the register keeps the longest
+/// run of leading zeros seen in the rest. With 4096 registers the estimate is
+/// typically within 2% of the true count, in 4KB of memory however many
+/// values there are.
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    const PRECISION: u32 = 12;
+
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << Self::PRECISION],
+        }
+    }
+
+    pub fn add(&mut self, val: &Constant) {
+        let mut hasher = DefaultHasher::new();
+        val.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - Self::PRECISION)) as usize;
+        let rank = ((hash << Self::PRECISION).leading_zeros() + 1).min(64 - Self::PRECISION + 1);
+        self.registers[register] = self.registers[register].max(rank as u8);
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-(*rank as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        // Small counts leave registers empty, and counting those is the
+        // better estimate.
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            m * (m / empty as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An estimate of the number of distinct non-null values of a field, from a
+/// `HyperLogLog` sketch instead of sorting out duplicates.
+pub struct ApproxCountDistinctFn {
+    field_name: String,
+    source_field: String,
+    sketch: HyperLogLog,
+}
+
+impl ApproxCountDistinctFn {
+    pub fn new(field_name: &str) -> ApproxCountDistinctFn {
+        ApproxCountDistinctFn {
+            field_name: format!("approx_count_distinctof{}", field_name),
+            source_field: field_name.to_string(),
+            sketch: HyperLogLog::new(),
+        }
+    }
+}
+
+impl AggregationFn for ApproxCountDistinctFn {
+    fn process_first(&mut self, scan: &dyn Scan) {
+        self.process_empty();
+        self.process_next(scan);
+    }
+
+    fn process_empty(&mut self) {
+        self.sketch = HyperLogLog::new();
+    }
+
+    fn process_next(&mut self, scan: &dyn Scan) {
+        if let Some(val) = scan.get_val(&self.source_field) {
+            self.sketch.add(&val);
+        }
+    }
+
+    fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn value(&self) -> Option<Constant> {
+        Some(Constant::Int(self.sketch.estimate().round() as i32))
+    }
+}
+
+fn aggregation_fn(aggregate: &Aggregate) -> Box<dyn AggregationFn> {
+    let field_name = aggregate.field_name();
+    match aggregate.kind() {
+        AggregateKind::Count => Box::new(CountFn::new(field_name)),
+        AggregateKind::Max => Box::new(MaxFn::new(field_name)),
+        AggregateKind::Min => Box::new(MinFn::new(field_name)),
+        AggregateKind::Sum => Box::new(SumFn::new(field_name)),
+        AggregateKind::Avg => Box::new(AvgFn::new(field_name)),
+        AggregateKind::ApproxCountDistinct => Box::new(ApproxCountDistinctFn::new(field_name)),
+    }
+}
+
+/// Groups the output of a plan by a list of fields and computes aggregates
+/// over each group. The input is sorted on the group fields first, unless it
+/// already comes out sorted on them in some order, so each group is a run of
+/// adjacent records. With no group fields the whole input is a single group.
+pub struct GroupByPlan {
+    plan: Box<dyn Plan>,
+    group_fields: Vec<String>,
+    aggregates: Vec<Aggregate>,
+    schema: Schema,
+}
+
+impl GroupByPlan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        plan: Box<dyn Plan>,
+        group_fields: Vec<String>,
+        aggregates: Vec<Aggregate>,
+    ) -> Result<GroupByPlan, PlanError> {
+        let mut schema = Schema::new();
+        for field_name in group_fields.iter() {
+            if !plan.schema().has_field(field_name) {
+                return Err(PlanError::UnknownField(field_name.clone()));
+            }
+            schema.add(field_name, plan.schema());
+        }
+        for aggregate in aggregates.iter() {
+            let field_name = aggregate.field_name();
+            let Some(field_type) = plan.schema().field_type(field_name) else {
+                return Err(PlanError::UnknownField(field_name.to_string()));
+            };
+            match aggregate.kind() {
+                AggregateKind::Count | AggregateKind::ApproxCountDistinct => {
+                    schema.add_int_field(&aggregate.output_name());
+                    schema.set_nullable(&aggregate.output_name(), false);
+                }
+                AggregateKind::Sum | AggregateKind::Avg => {
+                    if field_type != INTEGER {
+                        return Err(PlanError::TypeMismatch(field_name.to_string()));
+                    }
+                    schema.add_int_field(&aggregate.output_name());
+                }
+                AggregateKind::Max | AggregateKind::Min => {
+                    let length = plan.schema().length(field_name).unwrap_or(0);
+                    schema.add_field(&aggregate.output_name(), field_type, length);
+                }
+            }
+        }
+        let order = plan.sort_order();
+        let grouped = order.len() >= group_fields.len()
+            && order[..group_fields.len()]
+                .iter()
+                .all(|field_name| group_fields.contains(field_name));
+        let plan: Box<dyn Plan> = if grouped {
+            plan
+        } else {
+            Box::new(SortPlan::new(tx, plan, group_fields.clone()))
+        };
+        Ok(GroupByPlan {
+            plan,
+            group_fields,
+            aggregates,
+            schema,
+        })
+    }
+}
+
+impl Plan for GroupByPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(GroupByScan::new(
+            self.plan.open()?,
+            self.group_fields.clone(),
+            self.aggregates.iter().map(aggregation_fn).collect(),
+        )))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        self.plan.blocks_accessed()
+    }
+
+    // One record per combination of group values, which is at most the
+    // number of input records.
+    fn records_output(&self) -> usize {
+        let groups = self
+            .group_fields
+            .iter()
+            .map(|field_name| self.plan.distinct_values(field_name))
+            .fold(1usize, |groups, values| groups.saturating_mul(values));
+        groups.min(self.plan.records_output().max(1))
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        if self.group_fields.iter().any(|field| field == field_name) {
+            self.plan.distinct_values(field_name)
+        } else {
+            self.records_output()
+        }
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        let aggregates: Vec<String> = self.aggregates.iter().map(Aggregate::to_string).collect();
+        PlanNode::new("group by", self, vec![self.plan.explain()])
+            .detail("fields", self.group_fields.join(", "))
+            .detail("aggregates", aggregates.join(", "))
+    }
+
+    // Groups come out in the order of their input.
+    fn sort_order(&self) -> Vec<String> {
+        let mut order = self.plan.sort_order();
+        order.truncate(self.group_fields.len());
+        order
+    }
+}
+
+/// Reads a scan whose records are ordered by the group fields and returns one
+/// record per group, holding the group values and the aggregates. With no
+/// group fields there is always exactly one record, even for an empty input.
+pub struct GroupByScan {
+    scan: Box<dyn Scan>,
+    group_fields: Vec<String>,
+    agg_fns: Vec<Box<dyn AggregationFn>>,
+    group_vals: Vec<Option<Constant>>,
+    more_groups: bool,
+    empty_group: bool,
+}
+
+impl GroupByScan {
+    pub fn new(
+        scan: Box<dyn Scan>,
+        group_fields: Vec<String>,
+        agg_fns: Vec<Box<dyn AggregationFn>>,
+    ) -> GroupByScan {
+        let mut scan = GroupByScan {
+            scan,
+            group_fields,
+            agg_fns,
+            group_vals: Vec::new(),
+            more_groups: false,
+            empty_group: false,
+        };
+        scan.before_first();
+        scan
+    }
+
+    fn current_group_vals(&self) -> Vec<Option<Constant>> {
+        self.group_fields
+            .iter()
+            .map(|field_name| self.scan.get_val(field_name))
+            .collect()
+    }
+}
+
+impl Scan for GroupByScan {
+    fn before_first(&mut self) {
+        self.scan.before_first();
+        self.more_groups = self.scan.next();
+        self.empty_group = self.group_fields.is_empty() && !self.more_groups;
+    }
+
+    fn next(&mut self) -> bool {
+        if self.empty_group {
+            self.empty_group = false;
+            for agg_fn in self.agg_fns.iter_mut() {
+                agg_fn.process_empty();
+            }
+            return true;
+        }
+        if !self.more_groups {
+            return false;
+        }
+        for agg_fn in self.agg_fns.iter_mut() {
+            agg_fn.process_first(self.scan.as_ref());
+        }
+        self.group_vals = self.current_group_vals();
+        loop {
+            self.more_groups = self.scan.next();
+            if !self.more_groups || self.current_group_vals() != self.group_vals {
+                break;
+            }
+            for agg_fn in self.agg_fns.iter_mut() {
+                agg_fn.process_next(self.scan.as_ref());
+            }
+        }
+        true
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.get_val(field_name)?.as_int()
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.get_str(field_name).map(Cow::into_owned)
+    }
+
+    // Group values are kept for the whole group, so they can be lent.
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        match self
+            .group_fields
+            .iter()
+            .position(|field| field == field_name)
+        {
+            Some(pos) => self
+                .group_vals
+                .get(pos)?
+                .as_ref()?
+                .as_string()
+                .map(Cow::Borrowed),
+            None => self
+                .get_val(field_name)?
+                .as_string()
+                .map(|val| Cow::Owned(val.to_string())),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        if let Some(pos) = self
+            .group_fields
+            .iter()
+            .position(|field| field == field_name)
+        {
+            return self.group_vals.get(pos).cloned().flatten();
+        }
+        self.agg_fns
+            .iter()
+            .find(|agg_fn| agg_fn.field_name() == field_name)
+            .and_then(|agg_fn| agg_fn.value())
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.group_fields.iter().any(|field| field == field_name)
+            || self
+                .agg_fns
+                .iter()
+                .any(|agg_fn| agg_fn.field_name() == field_name)
+    }
+
+    fn close(&mut self) {
+        self.scan.close();
+    }
+}
+
+/// Joins two plans on the equality of a field from each by sorting both on
+/// their join field and merging them. Each input is read once after sorting,
+/// apart from runs of equal join values on the right, which are reread for
+/// every matching record on the left. A left hand side already sorted on its
+/// join field is not sorted again; the right is always sorted, since the
+/// merge saves and restores its position.
+pub struct MergeJoinPlan {
+    lhs: Box<dyn Plan>,
+    rhs: SortPlan,
+    lhs_field: String,
+    rhs_field: String,
+    schema: Schema,
+}
+
+impl MergeJoinPlan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        lhs: Box<dyn Plan>,
+        rhs: Box<dyn Plan>,
+        lhs_field: &str,
+        rhs_field: &str,
+    ) -> MergeJoinPlan {
+        let mut schema = Schema::new();
+        schema.add_all(lhs.schema());
+        schema.add_all(rhs.schema());
+        MergeJoinPlan {
+            lhs: sorted(tx.clone(), lhs, vec![lhs_field.to_string()]),
+            rhs: SortPlan::new(tx, rhs, vec![rhs_field.to_string()]),
+            lhs_field: lhs_field.to_string(),
+            rhs_field: rhs_field.to_string(),
+            schema,
+        }
+    }
+}
+
+impl Plan for MergeJoinPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(MergeJoinScan::new(
+            self.lhs.open()?,
+            self.rhs.open_sort_scan()?,
+            &self.lhs_field,
+            &self.rhs_field,
+        )))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        self.lhs.blocks_accessed() + self.rhs.blocks_accessed()
+    }
+
+    fn records_output(&self) -> usize {
+        let distinct = self
+            .lhs
+            .distinct_values(&self.lhs_field)
+            .max(self.rhs.distinct_values(&self.rhs_field))
+            .max(1);
+        self.lhs.records_output() * self.rhs.records_output() / distinct
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        if self.lhs.schema().has_field(field_name) {
+            self.lhs.distinct_values(field_name)
+        } else {
+            self.rhs.distinct_values(field_name)
+        }
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new(
+            "merge join",
+            self,
+            vec![self.lhs.explain(), self.rhs.explain()],
+        )
+        .detail(
+            "condition",
+            format!("{} = {}", self.lhs_field, self.rhs_field),
+        )
+    }
+
+    // Each left hand record is followed by its matches before the next.
+    fn sort_order(&self) -> Vec<String> {
+        self.lhs.sort_order()
+    }
+}
+
+/// Merges two scans sorted on their join fields. The position of the first
+/// right hand record with the current join value is saved, so the run of
+/// matching records can be read again for the next left hand record with the
+/// same value.
+pub struct MergeJoinScan {
+    lhs: Box<dyn Scan>,
+    rhs: SortScan,
+    lhs_field: String,
+    rhs_field: String,
+    join_val: Option<Constant>,
+}
+
+impl MergeJoinScan {
+    pub fn new(
+        lhs: Box<dyn Scan>,
+        rhs: SortScan,
+        lhs_field: &str,
+        rhs_field: &str,
+    ) -> MergeJoinScan {
+        let mut scan = MergeJoinScan {
+            lhs,
+            rhs,
+            lhs_field: lhs_field.to_string(),
+            rhs_field: rhs_field.to_string(),
+            join_val: None,
+        };
+        scan.before_first();
+        scan
+    }
+}
+
+impl Scan for MergeJoinScan {
+    fn before_first(&mut self) {
+        self.lhs.before_first();
+        self.rhs.before_first();
+        self.join_val = None;
+    }
+
+    fn next(&mut self) -> bool {
+        let mut has_more_rhs = self.rhs.next();
+        if has_more_rhs
+            && self.join_val.is_some()
+            && self.rhs.get_val(&self.rhs_field) == self.join_val
+        {
+            return true;
+        }
+        let mut has_more_lhs = self.lhs.next();
+        if has_more_lhs
+            && self.join_val.is_some()
+            && self.lhs.get_val(&self.lhs_field) == self.join_val
+        {
+            self.rhs.restore_position();
+            return true;
+        }
+        while has_more_lhs && has_more_rhs {
+            let lhs_val = self.lhs.get_val(&self.lhs_field);
+            let rhs_val = self.rhs.get_val(&self.rhs_field);
+            match lhs_val.cmp(&rhs_val) {
+                Ordering::Less => has_more_lhs = self.lhs.next(),
+                Ordering::Greater => has_more_rhs = self.rhs.next(),
+                Ordering::Equal => {
+                    self.rhs.save_position();
+                    self.join_val = rhs_val;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_int(field_name)
+        } else {
+            self.rhs.get_int(field_name)
+        }
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_string(field_name)
+        } else {
+            self.rhs.get_string(field_name)
+        }
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_str(field_name)
+        } else {
+            self.rhs.get_str(field_name)
+        }
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        if self.lhs.has_field(field_name) {
+            self.lhs.with_str(field_name, f)
+        } else {
+            self.rhs.with_str(field_name, f)
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_val(field_name)
+        } else {
+            self.rhs.get_val(field_name)
+        }
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.lhs.has_field(field_name) || self.rhs.has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.lhs.close();
+        self.rhs.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use crate::metadata::MetadataMgr;
+    use crate::plan::TablePlan;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_sort_plan() {
+        let tmp_dir = TempDir::new("test_sort_plan").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        schema.add_string_field("b", 6);
+        metadata_mgr.create_table("t", &schema, tx.clone()).unwrap();
+        let layout = metadata_mgr.get_layout("t", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t", layout);
+        let mut expected = Vec::new();
+        for i in 0..200 {
+            let a = (i * 73) % 31;
+            let b = format!("b{}", (i * 17) % 11);
+            scan.insert().unwrap();
+            scan.set_int("a", a).unwrap();
+            scan.set_string("b", b.clone()).unwrap();
+            expected.push((a, b));
+        }
+        scan.close();
+        expected.sort();
+
+        let sort_plan = SortPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            vec!["a".to_string(), "b".to_string()],
+        );
+        let mut sorted = sort_plan.open().unwrap();
+        let mut rows = Vec::new();
+        while sorted.next() {
+            rows.push((
+                sorted.get_int("a").unwrap(),
+                sorted.get_string("b").unwrap(),
+            ));
+        }
+        sorted.close();
+        assert_eq!(rows, expected);
+
+        let mut sort_scan = sort_plan.open_sort_scan().unwrap();
+        assert!(sort_scan.next());
+        assert!(sort_scan.next());
+        sort_scan.save_position();
+        let saved = sort_scan.get_val("b");
+        assert!(sort_scan.next() && sort_scan.next());
+        sort_scan.restore_position();
+        assert_eq!(sort_scan.get_val("b"), saved);
+        sort_scan.close();
+
+        let group_by = GroupByPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            vec!["b".to_string()],
+            vec![
+                Aggregate::new(AggregateKind::Count, "a"),
+                Aggregate::new(AggregateKind::Max, "a"),
+                Aggregate::new(AggregateKind::Min, "a"),
+                Aggregate::new(AggregateKind::Sum, "a"),
+                Aggregate::new(AggregateKind::Avg, "a"),
+            ],
+        )
+        .unwrap();
+        let mut scan = group_by.open().unwrap();
+        let mut groups = Vec::new();
+        while scan.next() {
+            groups.push(scan.get_string("b").unwrap());
+            let b = groups.last().unwrap().clone();
+            let group: Vec<i32> = expected
+                .iter()
+                .filter(|(_, expected_b)| *expected_b == b)
+                .map(|(a, _)| *a)
+                .collect();
+            let sum: i32 = group.iter().sum();
+            assert_eq!(scan.get_int("countofa"), Some(group.len() as i32));
+            assert_eq!(scan.get_int("maxofa"), group.iter().max().copied());
+            assert_eq!(scan.get_int("minofa"), group.iter().min().copied());
+            assert_eq!(scan.get_int("sumofa"), Some(sum));
+            assert_eq!(scan.get_int("avgofa"), Some(sum / group.len() as i32));
+        }
+        scan.close();
+        assert_eq!(groups.len(), 11);
+        assert!(groups.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Input already sorted on the group field is not sorted again.
+        let sorted_input = SortPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            vec!["b".to_string()],
+        );
+        let group_by = GroupByPlan::new(
+            tx.clone(),
+            Box::new(sorted_input),
+            vec!["b".to_string()],
+            vec![Aggregate::new(AggregateKind::Count, "a")],
+        )
+        .unwrap();
+        let node = group_by.explain();
+        assert_eq!(node.children[0].operator, "sort");
+        assert_eq!(node.children[0].children[0].operator, "table");
+        assert_eq!(group_by.sort_order(), vec!["b".to_string()]);
+        let mut scan = group_by.open().unwrap();
+        let mut count = 0;
+        while scan.next() {
+            count += 1;
+        }
+        scan.close();
+        assert_eq!(count, 11);
+
+        let mut scan = GroupByPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            Vec::new(),
+            vec![Aggregate::new(AggregateKind::Count, "b")],
+        )
+        .unwrap()
+        .open()
+        .unwrap();
+        assert!(scan.next());
+        assert_eq!(scan.get_int("countofb"), Some(200));
+        assert!(!scan.next());
+        scan.close();
+
+        assert!(matches!(
+            GroupByPlan::new(
+                tx.clone(),
+                Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+                Vec::new(),
+                vec![Aggregate::new(AggregateKind::Sum, "b")],
+            ),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_parallel_sort() {
+        let tmp_dir = TempDir::new("test_parallel_sort").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        schema.add_int_field("b");
+        metadata_mgr.create_table("t", &schema, tx.clone()).unwrap();
+        let layout = metadata_mgr.get_layout("t", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t", layout);
+        // Enough records for several chunks, the last one partly full.
+        let mut expected = Vec::new();
+        for i in 0..(SortPlan::RUN_RECORDS as i32 * 5 / 2) {
+            let a = (i * 7919) % 101;
+            scan.insert().unwrap();
+            scan.set_int("a", a).unwrap();
+            scan.set_int("b", i).unwrap();
+            expected.push((a, i));
+        }
+        scan.close();
+        expected.sort();
+
+        tx.borrow_mut().set_sort_workers(2);
+        let sort_plan = SortPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            vec!["a".to_string(), "b".to_string()],
+        );
+        let mut sorted = sort_plan.open().unwrap();
+        let mut rows = Vec::new();
+        while sorted.next() {
+            rows.push((sorted.get_int("a").unwrap(), sorted.get_int("b").unwrap()));
+        }
+        sorted.close();
+        assert_eq!(rows, expected);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_hyper_log_log() {
+        let mut sketch = HyperLogLog::new();
+        assert_eq!(sketch.estimate(), 0.0);
+        for val in [3, 1, 3, 2, 1] {
+            sketch.add(&Constant::Int(val));
+        }
+        assert_eq!(sketch.estimate().round(), 3.0);
+
+        // Large counts are estimated, not counted, and repeats change
+        // nothing.
+        let mut sketch = HyperLogLog::new();
+        for _ in 0..2 {
+            for i in 0..50_000 {
+                sketch.add(&Constant::String(format!("value{}", i)));
+            }
+        }
+        let error = (sketch.estimate() - 50_000.0).abs() / 50_000.0;
+        assert!(error < 0.1, "estimate off by {}", error);
+    }
+
+    #[test]
+    fn test_merge_join() {
+        let tmp_dir = TempDir::new("test_merge_join").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        metadata_mgr
+            .create_table("t1", &schema, tx.clone())
+            .unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("b");
+        schema.add_int_field("c");
+        metadata_mgr
+            .create_table("t2", &schema, tx.clone())
+            .unwrap();
+        let layout = metadata_mgr.get_layout("t1", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t1", layout);
+        for a in [5, 1, 3, 3, 7, 1, 9] {
+            scan.insert().unwrap();
+            scan.set_int("a", a).unwrap();
+        }
+        scan.close();
+        let layout = metadata_mgr.get_layout("t2", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t2", layout);
+        for (c, b) in [3, 1, 8, 3, 5, 3, 0].into_iter().enumerate() {
+            scan.insert().unwrap();
+            scan.set_int("b", b).unwrap();
+            scan.set_int("c", c as i32).unwrap();
+        }
+        scan.close();
+
+        let plan = MergeJoinPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t1", &metadata_mgr).unwrap()),
+            Box::new(TablePlan::new(tx.clone(), "t2", &metadata_mgr).unwrap()),
+            "a",
+            "b",
+        );
+        assert!(plan.schema().has_field("c"));
+        let mut scan = plan.open().unwrap();
+        let mut rows = Vec::new();
+        while scan.next() {
+            assert_eq!(scan.get_int("a"), scan.get_int("b"));
+            rows.push((scan.get_int("a").unwrap(), scan.get_int("c").unwrap()));
+        }
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                (1, 1),
+                (1, 1),
+                (3, 0),
+                (3, 0),
+                (3, 3),
+                (3, 3),
+                (3, 5),
+                (3, 5),
+                (5, 4)
+            ]
+        );
+        scan.before_first();
+        let mut count = 0;
+        while scan.next() {
+            count += 1;
+        }
+        assert_eq!(count, 9);
+        scan.close();
+
+        // A left hand side already sorted on its join field is reused.
+        let plan = MergeJoinPlan::new(
+            tx.clone(),
+            Box::new(SortPlan::new(
+                tx.clone(),
+                Box::new(TablePlan::new(tx.clone(), "t1", &metadata_mgr).unwrap()),
+                vec!["a".to_string()],
+            )),
+            Box::new(TablePlan::new(tx.clone(), "t2", &metadata_mgr).unwrap()),
+            "a",
+            "b",
+        );
+        let node = plan.explain();
+        assert_eq!(node.children[0].operator, "sort");
+        assert_eq!(node.children[0].children[0].operator, "table");
+        assert_eq!(plan.sort_order(), vec!["a".to_string()]);
+        let mut scan = plan.open().unwrap();
+        let mut count = 0;
+        while scan.next() {
+            assert_eq!(scan.get_int("a"), scan.get_int("b"));
+            count += 1;
+        }
+        assert_eq!(count, 9);
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_null_aggregates() {
+        let tmp_dir = TempDir::new("test_null_aggregates").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        schema.add_string_field("b", 6);
+        metadata_mgr.create_table("t", &schema, tx.clone()).unwrap();
+        let layout = metadata_mgr.get_layout("t", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t", layout);
+        for (a, b) in [(None, "x"), (Some(1), "x"), (None, "x"), (Some(4), "x")]
+            .into_iter()
+            .chain([(None, "y"), (None, "y")])
+        {
+            scan.insert().unwrap();
+            if let Some(a) = a {
+                scan.set_int("a", a).unwrap();
+            }
+            scan.set_string("b", b.to_string()).unwrap();
+        }
+        scan.close();
+
+        let mut scan = GroupByPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+            vec!["b".to_string()],
+            vec![
+                Aggregate::new(AggregateKind::Count, "a"),
+                Aggregate::new(AggregateKind::Max, "a"),
+                Aggregate::new(AggregateKind::Min, "a"),
+                Aggregate::new(AggregateKind::Sum, "a"),
+                Aggregate::new(AggregateKind::Avg, "a"),
+                Aggregate::new(AggregateKind::ApproxCountDistinct, "a"),
+            ],
+        )
+        .unwrap()
+        .open()
+        .unwrap();
+        assert!(scan.next());
+        assert_eq!(scan.get_string("b"), Some("x".to_string()));
+        assert_eq!(scan.get_int("countofa"), Some(2));
+        assert_eq!(scan.get_int("maxofa"), Some(4));
+        assert_eq!(scan.get_int("minofa"), Some(1));
+        assert_eq!(scan.get_int("sumofa"), Some(5));
+        assert_eq!(scan.get_int("avgofa"), Some(2));
+        assert_eq!(scan.get_int("approx_count_distinctofa"), Some(2));
+        assert!(scan.next());
+        assert_eq!(scan.get_string("b"), Some("y".to_string()));
+        assert_eq!(scan.get_int("countofa"), Some(0));
+        assert_eq!(scan.get_val("maxofa"), None);
+        assert_eq!(scan.get_val("minofa"), None);
+        assert_eq!(scan.get_val("sumofa"), None);
+        assert_eq!(scan.get_val("avgofa"), None);
+        assert_eq!(scan.get_int("approx_count_distinctofa"), Some(0));
+        assert!(!scan.next());
+        scan.close();
+
+        metadata_mgr.create_table("e", &schema, tx.clone()).unwrap();
+        let mut scan = GroupByPlan::new(
+            tx.clone(),
+            Box::new(TablePlan::new(tx.clone(), "e", &metadata_mgr).unwrap()),
+            Vec::new(),
+            vec![
+                Aggregate::new(AggregateKind::Count, "a"),
+                Aggregate::new(AggregateKind::Max, "a"),
+                Aggregate::new(AggregateKind::Sum, "a"),
+                Aggregate::new(AggregateKind::Avg, "a"),
+            ],
+        )
+        .unwrap()
+        .open()
+        .unwrap();
+        for _ in 0..2 {
+            assert!(scan.next());
+            assert_eq!(scan.get_int("countofa"), Some(0));
+            assert_eq!(scan.get_val("maxofa"), None);
+            assert_eq!(scan.get_val("sumofa"), None);
+            assert_eq!(scan.get_val("avgofa"), None);
+            assert!(!scan.next());
+            scan.before_first();
+        }
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_group_by_aggregates() {
+        let tmp_dir = TempDir::new("test_group_by_aggregates").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        schema.add_string_field("b", 6);
+        metadata_mgr.create_table("t", &schema, tx.clone()).unwrap();
+        let layout = metadata_mgr.get_layout("t", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t", layout);
+        let rows = [
+            (7, "x"),
+            (-3, "x"),
+            (7, "x"),
+            (i32::MAX, "y"),
+            (i32::MAX, "y"),
+            (i32::MIN, "z"),
+            (i32::MIN, "z"),
+        ];
+        for (a, b) in rows {
+            scan.insert().unwrap();
+            scan.set_int("a", a).unwrap();
+            scan.set_string("b", b.to_string()).unwrap();
+        }
+        scan.close();
+
+        let open = |kind: AggregateKind| {
+            GroupByPlan::new(
+                tx.clone(),
+                Box::new(TablePlan::new(tx.clone(), "t", &metadata_mgr).unwrap()),
+                vec!["b".to_string()],
+                vec![Aggregate::new(kind, "a")],
+            )
+            .unwrap()
+            .open()
+            .unwrap()
+        };
+        let cases = [
+            (AggregateKind::Count, "countofa", [3, 2, 2]),
+            (AggregateKind::Max, "maxofa", [7, i32::MAX, i32::MIN]),
+            (AggregateKind::Min, "minofa", [-3, i32::MAX, i32::MIN]),
+            (AggregateKind::Sum, "sumofa", [11, i32::MAX, i32::MIN]),
+            (AggregateKind::Avg, "avgofa", [3, i32::MAX, i32::MIN]),
+            (
+                AggregateKind::ApproxCountDistinct,
+                "approx_count_distinctofa",
+                [2, 1, 1],
+            ),
+        ];
+        for (kind, field_name, expected) in cases {
+            let mut scan = open(kind);
+            let mut values = Vec::new();
+            while scan.next() {
+                values.push(scan.get_int(field_name).unwrap());
+            }
+            scan.close();
+            assert_eq!(values, expected, "{}", field_name);
+        }
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
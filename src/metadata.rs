@@ -0,0 +1,2165 @@
+use crate::buffermanager::MAINTENANCE_RING_SIZE;
+use crate::error::DbError;
+use crate::index::BTreeIndex;
+use crate::parse::{DeleteAction, ForeignKey, Privilege};
+use crate::query::{Constant, Predicate, Scan, UpdateScan};
+use crate::recordmanager::{Layout, Schema, TableScan, BLOB};
+use crate::transaction::Transaction;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Longest table or field name the catalog can store.
+pub const MAX_NAME: usize = 16;
+
+pub const TABLE_CATALOG: &str = "tblcat";
+pub const FIELD_CATALOG: &str = "fldcat";
+pub const INDEX_CATALOG: &str = "idxcat";
+pub const VERSION_CATALOG: &str = "vercat";
+pub const VIEW_CATALOG: &str = "viewcat";
+pub const PARTITION_CATALOG: &str = "partcat";
+pub const RETENTION_CATALOG: &str = "retcat";
+pub const DEFAULT_CATALOG: &str = "defcat";
+pub const SEQUENCE_CATALOG: &str = "seqcat";
+pub const FOREIGN_KEY_CATALOG: &str = "fkcat";
+pub const PRIVILEGE_CATALOG: &str = "privcat";
+pub const ROLE_CATALOG: &str = "rolecat";
+
+/// Longest view definition the catalog can store.
+pub const MAX_VIEWDEF: usize = 100;
+
+/// Longest column default expression the catalog can store.
+pub const MAX_DEFAULT: usize = 40;
+
+/// Number of statistics requests `StatMgr` answers from its cache before it
+/// scans the tables again.
+pub const STAT_REFRESH_INTERVAL: usize = 100;
+
+/// Name the maintenance advisor is queried under, like a view.
+pub const MAINTENANCE_VIEW: &str = "sys_maintenance";
+
+/// Name the storage report is queried under, like a view.
+pub const STORAGE_VIEW: &str = "sys_storage";
+
+/// Percentage of a table's slots that may be dead before the advisor
+/// recommends vacuuming it.
+pub const VACUUM_THRESHOLD: usize = 30;
+
+/// Percentage of dead slots past which the advisor also recommends
+/// rebuilding the table's indexes, whose leaves thin out with the table.
+pub const REINDEX_THRESHOLD: usize = 50;
+
+/// Version of the catalog layout this crate writes. Version 1 catalogs have
+/// only `tblcat` and `fldcat`; version 2 adds `idxcat` and `vercat`,
+/// version 3 adds `viewcat`, version 4 adds `partcat`, version 5 adds
+/// `retcat`, version 6 adds `defcat` and `seqcat`, version 7 adds `fkcat`
+/// and version 8 adds `privcat` and `rolecat`.
+pub const CATALOG_VERSION: i32 = 8;
+
+const CATALOG_TABLES: [&str; 12] = [
+    TABLE_CATALOG,
+    FIELD_CATALOG,
+    INDEX_CATALOG,
+    VERSION_CATALOG,
+    VIEW_CATALOG,
+    PARTITION_CATALOG,
+    RETENTION_CATALOG,
+    DEFAULT_CATALOG,
+    SEQUENCE_CATALOG,
+    FOREIGN_KEY_CATALOG,
+    PRIVILEGE_CATALOG,
+    ROLE_CATALOG,
+];
+
+/// Whether a table belongs to the catalog rather than to the user.
+pub fn is_catalog_table(table_name: &str) -> bool {
+    CATALOG_TABLES.contains(&table_name)
+}
+
+/// Reasons an existing catalog cannot be opened.
+#[derive(Debug)]
+pub enum CatalogError {
+    /// The catalog was written by a newer version of the crate.
+    NewerVersion { found: i32, supported: i32 },
+    /// The catalog tables no longer match the checksum recorded for them.
+    ChecksumMismatch,
+    /// The catalog was written by an older version of the crate and cannot
+    /// be upgraded because the database is opened read-only.
+    OlderVersion { found: i32, supported: i32 },
+    /// The directory holds no database.
+    NoDatabase(String),
+    /// The database files could not be opened or read.
+    Storage(DbError),
+}
+
+impl Display for CatalogError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatalogError::NewerVersion { found, supported } => write!(
+                f,
+                "catalog version {} is newer than the supported version {}",
+                found, supported
+            ),
+            CatalogError::ChecksumMismatch => write!(f, "catalog checksum does not match"),
+            CatalogError::OlderVersion { found, supported } => write!(
+                f,
+                "catalog version {} needs an upgrade to version {}, which a read-only database cannot take",
+                found, supported
+            ),
+            CatalogError::NoDatabase(directory) => write!(f, "no database in {}", directory),
+            CatalogError::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for CatalogError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CatalogError::Storage(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DbError> for CatalogError {
+    fn from(err: DbError) -> Self {
+        CatalogError::Storage(err)
+    }
+}
+
+/// Stores table definitions in the `tblcat` and `fldcat` catalog tables.
+pub struct TableMgr {
+    table_catalog_layout: Layout,
+    field_catalog_layout: Layout,
+}
+
+impl TableMgr {
+    pub fn new(is_new: bool, tx: Rc<RefCell<Transaction>>) -> Result<TableMgr, DbError> {
+        let mut table_catalog_schema = Schema::new();
+        table_catalog_schema.add_string_field("tblname", MAX_NAME);
+        table_catalog_schema.add_int_field("slotsize");
+        let table_catalog_layout = Layout::new(table_catalog_schema.clone());
+
+        let mut field_catalog_schema = Schema::new();
+        field_catalog_schema.add_string_field("tblname", MAX_NAME);
+        field_catalog_schema.add_string_field("fldname", MAX_NAME);
+        field_catalog_schema.add_int_field("type");
+        field_catalog_schema.add_int_field("length");
+        field_catalog_schema.add_int_field("offset");
+        let field_catalog_layout = Layout::new(field_catalog_schema.clone());
+
+        let table_mgr = TableMgr {
+            table_catalog_layout,
+            field_catalog_layout,
+        };
+        if is_new {
+            table_mgr.create_table(TABLE_CATALOG, &table_catalog_schema, tx.clone())?;
+            table_mgr.create_table(FIELD_CATALOG, &field_catalog_schema, tx)?;
+        }
+        Ok(table_mgr)
+    }
+
+    /// Records one row in `tblcat` for the table and one row in `fldcat` for
+    /// each of its fields.
+    pub fn create_table(
+        &self,
+        table_name: &str,
+        schema: &Schema,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let layout = Layout::new(schema.clone());
+
+        let mut table_catalog =
+            TableScan::new(tx.clone(), TABLE_CATALOG, self.table_catalog_layout.clone());
+        table_catalog.insert()?;
+        table_catalog.set_string("tblname", table_name.to_string())?;
+        table_catalog.set_int("slotsize", layout.slot_size() as i32)?;
+        table_catalog.close();
+
+        let mut field_catalog =
+            TableScan::new(tx, FIELD_CATALOG, self.field_catalog_layout.clone());
+        for field_name in schema.fields() {
+            field_catalog.insert()?;
+            field_catalog.set_string("tblname", table_name.to_string())?;
+            field_catalog.set_string("fldname", field_name.clone())?;
+            field_catalog.set_int("type", schema.field_type(field_name).unwrap_or(0))?;
+            field_catalog.set_int("length", schema.length(field_name).unwrap_or(0) as i32)?;
+            field_catalog.set_int("offset", layout.offset(field_name).unwrap_or(0) as i32)?;
+        }
+        field_catalog.close();
+        Ok(())
+    }
+
+    /// Rebuilds a table's layout from the catalog, or `None` if the table was
+    /// never created.
+    pub fn get_layout(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> Option<Layout> {
+        let mut slot_size = None;
+        let mut table_catalog =
+            TableScan::new(tx.clone(), TABLE_CATALOG, self.table_catalog_layout.clone());
+        while table_catalog.next() {
+            if table_catalog.get_string("tblname").as_deref() == Some(table_name) {
+                slot_size = table_catalog.get_int("slotsize");
+                break;
+            }
+        }
+        table_catalog.close();
+        let slot_size = slot_size? as usize;
+
+        let mut schema = Schema::new();
+        let mut offsets = HashMap::new();
+        let mut field_catalog =
+            TableScan::new(tx, FIELD_CATALOG, self.field_catalog_layout.clone());
+        while field_catalog.next() {
+            if field_catalog.get_string("tblname").as_deref() == Some(table_name) {
+                let field_name = field_catalog.get_string("fldname")?;
+                let field_type = field_catalog.get_int("type")?;
+                let length = field_catalog.get_int("length")? as usize;
+                let offset = field_catalog.get_int("offset")? as usize;
+                schema.add_field(&field_name, field_type, length);
+                offsets.insert(field_name, offset);
+            }
+        }
+        field_catalog.close();
+        Some(Layout::from_parts(schema, offsets, slot_size))
+    }
+
+    /// Names of every table in the catalog, including the catalog tables.
+    pub fn table_names(&self, tx: Rc<RefCell<Transaction>>) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut table_catalog =
+            TableScan::new(tx, TABLE_CATALOG, self.table_catalog_layout.clone());
+        while table_catalog.next() {
+            if let Some(name) = table_catalog.get_string("tblname") {
+                names.push(name);
+            }
+        }
+        table_catalog.close();
+        names
+    }
+}
+
+/// Size of a table and the number of distinct values in each of its fields,
+/// as counted by the last scan `StatMgr` made of it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatInfo {
+    blocks: usize,
+    records: usize,
+    // Slots in the table's blocks that hold no record.
+    dead: usize,
+    distinct: HashMap<String, usize>,
+}
+
+impl StatInfo {
+    pub fn blocks_accessed(&self) -> usize {
+        self.blocks
+    }
+
+    pub fn records_output(&self) -> usize {
+        self.records
+    }
+
+    pub fn dead_slots(&self) -> usize {
+        self.dead
+    }
+
+    /// The share of the table's slots that hold no record, as a percentage.
+    pub fn dead_percent(&self) -> usize {
+        match self.records + self.dead {
+            0 => 0,
+            slots => self.dead * 100 / slots,
+        }
+    }
+
+    /// Never less than one, so that it can divide a record count.
+    pub fn distinct_values(&self, field_name: &str) -> usize {
+        self.distinct.get(field_name).copied().unwrap_or(0).max(1)
+    }
+
+    /// Adds the statistics of another part of the same table. Distinct
+    /// values are summed, which is exact for the field the parts are split
+    /// on and an upper bound for the others.
+    pub fn merge(&mut self, other: &StatInfo) {
+        self.blocks += other.blocks;
+        self.records += other.records;
+        self.dead += other.dead;
+        for (field_name, distinct) in other.distinct.iter() {
+            *self.distinct.entry(field_name.clone()).or_insert(0) += distinct;
+        }
+    }
+}
+
+/// Keeps the statistics of each table the planner has asked about. They go
+/// stale as the tables change, so every `STAT_REFRESH_INTERVAL` requests the
+/// cache is dropped and the tables are scanned again. Inserts and deletes are
+/// counted as they happen, so that dead slots show up before the next scan.
+#[derive(Default)]
+pub struct StatMgr {
+    table_stats: Mutex<HashMap<String, StatInfo>>,
+    calls: AtomicUsize,
+}
+
+impl StatMgr {
+    pub fn new() -> StatMgr {
+        StatMgr {
+            table_stats: Mutex::new(HashMap::new()),
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get_stat_info(
+        &self,
+        table_name: &str,
+        layout: &Layout,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> StatInfo {
+        if self.calls.fetch_add(1, Ordering::Relaxed) + 1 > STAT_REFRESH_INTERVAL {
+            self.table_stats.lock().unwrap().clear();
+            self.calls.store(0, Ordering::Relaxed);
+        }
+        if let Some(stat_info) = self.table_stats.lock().unwrap().get(table_name) {
+            return stat_info.clone();
+        }
+        // The table is scanned without holding the cache, so other sessions
+        // can plan in the meantime.
+        let stat_info = Self::calc_table_stats(table_name, layout, tx);
+        self.table_stats
+            .lock()
+            .unwrap()
+            .insert(table_name.to_string(), stat_info.clone());
+        stat_info
+    }
+
+    /// Counts `count` new records in a cached table. Each is taken to fill a
+    /// dead slot while there are any, as inserts reuse them first.
+    pub fn record_inserts(&self, table_name: &str, count: usize) {
+        if let Some(stat_info) = self.table_stats.lock().unwrap().get_mut(table_name) {
+            stat_info.records += count;
+            stat_info.dead -= count.min(stat_info.dead);
+        }
+    }
+
+    /// Moves `count` records of a cached table to its dead slots. A table
+    /// that is not cached is counted from scratch when it is next asked for.
+    pub fn record_deletes(&self, table_name: &str, count: usize) {
+        if let Some(stat_info) = self.table_stats.lock().unwrap().get_mut(table_name) {
+            let count = count.min(stat_info.records);
+            stat_info.records -= count;
+            stat_info.dead += count;
+        }
+    }
+
+    fn calc_table_stats(
+        table_name: &str,
+        layout: &Layout,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> StatInfo {
+        let blocks = tx
+            .borrow_mut()
+            .scan_read(|tx| tx.size(&format!("{}.tbl", table_name)).map(Some))
+            .unwrap_or(0);
+        let slots = blocks * (tx.borrow().block_size() / layout.slot_size());
+        let fields = layout.schema().fields();
+        let mut values: Vec<HashSet<Constant>> = vec![HashSet::new(); fields.len()];
+        let mut records = 0;
+        // Reading every block of the table should not evict the pool.
+        let ring_size = tx.borrow().buffer_ring_size();
+        tx.borrow_mut().set_buffer_ring(MAINTENANCE_RING_SIZE);
+        let mut scan = TableScan::new(tx.clone(), table_name, layout.clone());
+        while scan.next() {
+            records += 1;
+            for (field_name, values) in fields.iter().zip(values.iter_mut()) {
+                if let Some(val) = scan.get_val(field_name) {
+                    values.insert(val);
+                }
+            }
+        }
+        scan.close();
+        tx.borrow_mut().set_buffer_ring(ring_size);
+        let distinct = fields
+            .iter()
+            .cloned()
+            .zip(values.iter().map(HashSet::len))
+            .collect();
+        StatInfo {
+            blocks,
+            records,
+            dead: slots.saturating_sub(records),
+            distinct,
+        }
+    }
+}
+
+/// One recommendation of the maintenance advisor: a table, how many of its
+/// slots are dead and what to do about it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceAdvice {
+    pub table_name: String,
+    pub dead_slots: usize,
+    pub dead_percent: usize,
+    pub action: String,
+}
+
+/// The disk space of one table or index, over all of its files: a table's
+/// heap files and overflow files, or an index's leaf and directory files.
+/// Used blocks are those not waiting in a free block map. Overflow chains
+/// are the blobs a table stores; an index has none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelationStorage {
+    pub relation: String,
+    pub kind: String,
+    pub blocks: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    pub overflow_chains: usize,
+}
+
+/// What the planner needs to know about one index: how to open it and what
+/// using it is expected to cost.
+pub struct IndexInfo {
+    index_name: String,
+    field_name: String,
+    tx: Rc<RefCell<Transaction>>,
+    index_layout: Layout,
+    stat_info: StatInfo,
+}
+
+impl IndexInfo {
+    pub fn new(
+        index_name: &str,
+        field_name: &str,
+        table_layout: &Layout,
+        tx: Rc<RefCell<Transaction>>,
+        stat_info: StatInfo,
+    ) -> IndexInfo {
+        let schema = table_layout.schema();
+        let index_layout = BTreeIndex::leaf_layout(
+            schema.field_type(field_name).unwrap_or(0),
+            schema.length(field_name).unwrap_or(0),
+        );
+        IndexInfo {
+            index_name: index_name.to_string(),
+            field_name: field_name.to_string(),
+            tx,
+            index_layout,
+            stat_info,
+        }
+    }
+
+    pub fn open(&self) -> Result<BTreeIndex, DbError> {
+        BTreeIndex::new(self.tx.clone(), &self.index_name, self.index_layout.clone())
+    }
+
+    /// Blocks read by one lookup of the index.
+    pub fn blocks_accessed(&self) -> usize {
+        let records_per_block = self.tx.borrow().block_size() / self.index_layout.slot_size();
+        let blocks = self.stat_info.records_output() / records_per_block.max(1);
+        BTreeIndex::search_cost(blocks, records_per_block)
+    }
+
+    /// Records one lookup of the index is expected to find.
+    pub fn records_output(&self) -> usize {
+        self.stat_info.records_output() / self.distinct_values(&self.field_name)
+    }
+
+    pub fn distinct_values(&self, field_name: &str) -> usize {
+        if field_name == self.field_name {
+            self.stat_info.distinct_values(field_name)
+        } else {
+            1
+        }
+    }
+
+    pub fn index_name(&self) -> &str {
+        &self.index_name
+    }
+
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+}
+
+/// Stores index definitions in the `idxcat` catalog table.
+pub struct IndexMgr {
+    layout: Layout,
+}
+
+impl IndexMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<IndexMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(INDEX_CATALOG, &schema, tx)?;
+        }
+        Ok(IndexMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("indexname", MAX_NAME);
+        schema.add_string_field("tablename", MAX_NAME);
+        schema.add_string_field("fieldname", MAX_NAME);
+        schema
+    }
+
+    pub fn create_index(
+        &self,
+        index_name: &str,
+        table_name: &str,
+        field_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut index_catalog = TableScan::new(tx, INDEX_CATALOG, self.layout.clone());
+        index_catalog.insert()?;
+        index_catalog.set_string("indexname", index_name.to_string())?;
+        index_catalog.set_string("tablename", table_name.to_string())?;
+        index_catalog.set_string("fieldname", field_name.to_string())?;
+        index_catalog.close();
+        Ok(())
+    }
+
+    /// The indexes on a table, keyed by the field they index.
+    pub fn get_index_info(
+        &self,
+        table_name: &str,
+        table_mgr: &TableMgr,
+        stat_mgr: &StatMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> HashMap<String, IndexInfo> {
+        let mut result = HashMap::new();
+        let Some(table_layout) = table_mgr.get_layout(table_name, tx.clone()) else {
+            return result;
+        };
+        let stat_info = stat_mgr.get_stat_info(table_name, &table_layout, tx.clone());
+
+        let mut index_catalog = TableScan::new(tx.clone(), INDEX_CATALOG, self.layout.clone());
+        while index_catalog.next() {
+            if index_catalog.get_string("tablename").as_deref() != Some(table_name) {
+                continue;
+            }
+            let (Some(index_name), Some(field_name)) = (
+                index_catalog.get_string("indexname"),
+                index_catalog.get_string("fieldname"),
+            ) else {
+                continue;
+            };
+            let info = IndexInfo::new(
+                &index_name,
+                &field_name,
+                &table_layout,
+                tx.clone(),
+                stat_info.clone(),
+            );
+            result.insert(field_name, info);
+        }
+        index_catalog.close();
+        result
+    }
+}
+
+/// Stores view definitions, as the SQL text of their queries, in the
+/// `viewcat` catalog table.
+pub struct ViewMgr {
+    layout: Layout,
+}
+
+impl ViewMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<ViewMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(VIEW_CATALOG, &schema, tx)?;
+        }
+        Ok(ViewMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("viewname", MAX_NAME);
+        schema.add_string_field("viewdef", MAX_VIEWDEF);
+        schema
+    }
+
+    pub fn create_view(
+        &self,
+        view_name: &str,
+        view_def: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut view_catalog = TableScan::new(tx, VIEW_CATALOG, self.layout.clone());
+        view_catalog.insert()?;
+        view_catalog.set_string("viewname", view_name.to_string())?;
+        view_catalog.set_string("viewdef", view_def.to_string())?;
+        view_catalog.close();
+        Ok(())
+    }
+
+    pub fn get_view_def(&self, view_name: &str, tx: Rc<RefCell<Transaction>>) -> Option<String> {
+        let mut view_catalog = TableScan::new(tx, VIEW_CATALOG, self.layout.clone());
+        let mut result = None;
+        while view_catalog.next() {
+            if view_catalog.get_string("viewname").as_deref() == Some(view_name) {
+                result = view_catalog.get_string("viewdef");
+                break;
+            }
+        }
+        view_catalog.close();
+        result
+    }
+
+    pub fn view_names(&self, tx: Rc<RefCell<Transaction>>) -> Vec<String> {
+        let mut view_catalog = TableScan::new(tx, VIEW_CATALOG, self.layout.clone());
+        let mut result = Vec::new();
+        while view_catalog.next() {
+            if let Some(view_name) = view_catalog.get_string("viewname") {
+                result.push(view_name);
+            }
+        }
+        view_catalog.close();
+        result
+    }
+}
+
+/// How a range partitioned table is split. Partition `k` holds the records
+/// whose key is at least `bounds[k - 1]` and less than `bounds[k]`; the first
+/// and last partitions are open ended. Each partition is a heap file of its
+/// own, named after the table and the partition number.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partitioning {
+    table_name: String,
+    field_name: String,
+    bounds: Vec<i32>,
+}
+
+impl Partitioning {
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    pub fn bounds(&self) -> &[i32] {
+        &self.bounds
+    }
+
+    pub fn heap_name(&self, partition: usize) -> String {
+        format!("{}.p{}", self.table_name, partition)
+    }
+
+    pub fn heap_names(&self) -> Vec<String> {
+        (0..=self.bounds.len())
+            .map(|partition| self.heap_name(partition))
+            .collect()
+    }
+
+    /// The partition a record with this key belongs in.
+    pub fn partition_for(&self, key: i32) -> usize {
+        self.bounds.partition_point(|bound| *bound <= key)
+    }
+
+    /// The heaps that can hold records satisfying `predicate`. Only an
+    /// equality between the key and a constant narrows them down.
+    pub fn prune(&self, predicate: &Predicate) -> Vec<String> {
+        match predicate.equates_with_constant(&self.field_name) {
+            Some(Constant::Int(key)) => vec![self.heap_name(self.partition_for(*key))],
+            _ => self.heap_names(),
+        }
+    }
+}
+
+/// Stores the key and bounds of range partitioned tables in the `partcat`
+/// catalog table, one record per bound.
+pub struct PartitionMgr {
+    layout: Layout,
+}
+
+impl PartitionMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<PartitionMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(PARTITION_CATALOG, &schema, tx)?;
+        }
+        Ok(PartitionMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("tblname", MAX_NAME);
+        schema.add_string_field("fldname", MAX_NAME);
+        schema.add_int_field("bound");
+        schema
+    }
+
+    pub fn create_partitioning(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        bounds: &[i32],
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut partition_catalog = TableScan::new(tx, PARTITION_CATALOG, self.layout.clone());
+        for bound in bounds {
+            partition_catalog.insert()?;
+            partition_catalog.set_string("tblname", table_name.to_string())?;
+            partition_catalog.set_string("fldname", field_name.to_string())?;
+            partition_catalog.set_int("bound", *bound)?;
+        }
+        partition_catalog.close();
+        Ok(())
+    }
+
+    pub fn get_partitioning(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Option<Partitioning> {
+        let mut partition_catalog = TableScan::new(tx, PARTITION_CATALOG, self.layout.clone());
+        let mut field_name = None;
+        let mut bounds = Vec::new();
+        while partition_catalog.next() {
+            if partition_catalog.get_string("tblname").as_deref() != Some(table_name) {
+                continue;
+            }
+            field_name = partition_catalog.get_string("fldname");
+            bounds.extend(partition_catalog.get_int("bound"));
+        }
+        partition_catalog.close();
+        bounds.sort_unstable();
+        Some(Partitioning {
+            table_name: table_name.to_string(),
+            field_name: field_name?,
+            bounds,
+        })
+    }
+}
+
+const SECONDS_PER_DAY: i32 = 24 * 60 * 60;
+
+/// Rows of a table whose timestamp field, in seconds since the Unix epoch,
+/// is more than `days` days old are deleted by the retention task.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    table_name: String,
+    field_name: String,
+    days: i32,
+}
+
+impl RetentionPolicy {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    pub fn days(&self) -> i32 {
+        self.days
+    }
+
+    /// Timestamps before this have expired at time `now`.
+    pub fn cutoff(&self, now: i32) -> i32 {
+        now.saturating_sub(self.days.saturating_mul(SECONDS_PER_DAY))
+    }
+}
+
+/// Stores retention policies in the `retcat` catalog table, at most one per
+/// table.
+pub struct RetentionMgr {
+    layout: Layout,
+}
+
+impl RetentionMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<RetentionMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(RETENTION_CATALOG, &schema, tx)?;
+        }
+        Ok(RetentionMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("tblname", MAX_NAME);
+        schema.add_string_field("fldname", MAX_NAME);
+        schema.add_int_field("days");
+        schema
+    }
+
+    /// Replaces the table's policy, if it has one.
+    pub fn set_retention(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        days: i32,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut retention_catalog = TableScan::new(tx, RETENTION_CATALOG, self.layout.clone());
+        while retention_catalog.next() {
+            if retention_catalog.get_string("tblname").as_deref() == Some(table_name) {
+                retention_catalog.delete()?;
+            }
+        }
+        retention_catalog.insert()?;
+        retention_catalog.set_string("tblname", table_name.to_string())?;
+        retention_catalog.set_string("fldname", field_name.to_string())?;
+        retention_catalog.set_int("days", days)?;
+        retention_catalog.close();
+        Ok(())
+    }
+
+    pub fn retention_policies(&self, tx: Rc<RefCell<Transaction>>) -> Vec<RetentionPolicy> {
+        let mut retention_catalog = TableScan::new(tx, RETENTION_CATALOG, self.layout.clone());
+        let mut result = Vec::new();
+        while retention_catalog.next() {
+            if let (Some(table_name), Some(field_name), Some(days)) = (
+                retention_catalog.get_string("tblname"),
+                retention_catalog.get_string("fldname"),
+                retention_catalog.get_int("days"),
+            ) {
+                result.push(RetentionPolicy {
+                    table_name,
+                    field_name,
+                    days,
+                });
+            }
+        }
+        retention_catalog.close();
+        result
+    }
+}
+
+/// Stores column defaults, as the SQL text of their expressions, in the
+/// `defcat` catalog table. The update planner parses and evaluates them for
+/// each inserted record that leaves the column out.
+pub struct DefaultMgr {
+    layout: Layout,
+}
+
+impl DefaultMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<DefaultMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(DEFAULT_CATALOG, &schema, tx)?;
+        }
+        Ok(DefaultMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("tblname", MAX_NAME);
+        schema.add_string_field("fldname", MAX_NAME);
+        schema.add_string_field("defexpr", MAX_DEFAULT);
+        schema
+    }
+
+    pub fn create_default(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        expr: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut default_catalog = TableScan::new(tx, DEFAULT_CATALOG, self.layout.clone());
+        default_catalog.insert()?;
+        default_catalog.set_string("tblname", table_name.to_string())?;
+        default_catalog.set_string("fldname", field_name.to_string())?;
+        default_catalog.set_string("defexpr", expr.to_string())?;
+        default_catalog.close();
+        Ok(())
+    }
+
+    /// The table's fields that have a default, each with its expression.
+    pub fn get_defaults(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Vec<(String, String)> {
+        let mut default_catalog = TableScan::new(tx, DEFAULT_CATALOG, self.layout.clone());
+        let mut result = Vec::new();
+        while default_catalog.next() {
+            if default_catalog.get_string("tblname").as_deref() != Some(table_name) {
+                continue;
+            }
+            if let (Some(field_name), Some(expr)) = (
+                default_catalog.get_string("fldname"),
+                default_catalog.get_string("defexpr"),
+            ) {
+                result.push((field_name, expr));
+            }
+        }
+        default_catalog.close();
+        result
+    }
+}
+
+/// Stores named sequences in the `seqcat` catalog table. A sequence comes
+/// into being the first time a value is drawn from it. Drawing a value is an
+/// ordinary logged update, so a rolled back transaction gives its values
+/// back and two transactions drawing from one sequence wait on each other.
+pub struct SequenceMgr {
+    layout: Layout,
+}
+
+impl SequenceMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<SequenceMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(SEQUENCE_CATALOG, &schema, tx)?;
+        }
+        Ok(SequenceMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("seqname", MAX_NAME);
+        schema.add_int_field("value");
+        schema
+    }
+
+    /// Advances the sequence and returns its new value; a new sequence
+    /// starts at 1.
+    pub fn nextval(
+        &self,
+        sequence_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<i32, DbError> {
+        let mut sequence_catalog = TableScan::new(tx, SEQUENCE_CATALOG, self.layout.clone());
+        let mut value = None;
+        while sequence_catalog.next() {
+            if sequence_catalog.get_string("seqname").as_deref() == Some(sequence_name) {
+                value = sequence_catalog.get_int("value");
+                break;
+            }
+        }
+        let next = match value {
+            Some(value) => value + 1,
+            None => {
+                sequence_catalog.insert()?;
+                sequence_catalog.set_string("seqname", sequence_name.to_string())?;
+                1
+            }
+        };
+        sequence_catalog.set_int("value", next)?;
+        sequence_catalog.close();
+        Ok(next)
+    }
+}
+
+/// Stores the foreign keys declared with `references` in the `fkcat`
+/// catalog table. The update planner looks up the keys that reference a
+/// table when records are deleted from it.
+pub struct ForeignKeyMgr {
+    layout: Layout,
+}
+
+impl ForeignKeyMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<ForeignKeyMgr, DbError> {
+        let schema = Self::catalog_schema();
+        if is_new {
+            table_mgr.create_table(FOREIGN_KEY_CATALOG, &schema, tx)?;
+        }
+        Ok(ForeignKeyMgr {
+            layout: Layout::new(schema),
+        })
+    }
+
+    fn catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("tblname", MAX_NAME);
+        schema.add_string_field("fldname", MAX_NAME);
+        schema.add_string_field("reftblname", MAX_NAME);
+        schema.add_string_field("reffldname", MAX_NAME);
+        schema.add_string_field("ondelete", MAX_NAME);
+        schema
+    }
+
+    pub fn create_foreign_key(
+        &self,
+        key: &ForeignKey,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        let mut key_catalog = TableScan::new(tx, FOREIGN_KEY_CATALOG, self.layout.clone());
+        key_catalog.insert()?;
+        key_catalog.set_string("tblname", key.table_name.clone())?;
+        key_catalog.set_string("fldname", key.field_name.clone())?;
+        key_catalog.set_string("reftblname", key.ref_table.clone())?;
+        key_catalog.set_string("reffldname", key.ref_field.clone())?;
+        key_catalog.set_string("ondelete", key.on_delete.to_string())?;
+        key_catalog.close();
+        Ok(())
+    }
+
+    /// The foreign keys whose values are those of a field of `table_name`.
+    pub fn get_referencing(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Vec<ForeignKey> {
+        let mut key_catalog = TableScan::new(tx, FOREIGN_KEY_CATALOG, self.layout.clone());
+        let mut result = Vec::new();
+        while key_catalog.next() {
+            if key_catalog.get_string("reftblname").as_deref() != Some(table_name) {
+                continue;
+            }
+            if let (Some(key_table), Some(field_name), Some(ref_field), Some(on_delete)) = (
+                key_catalog.get_string("tblname"),
+                key_catalog.get_string("fldname"),
+                key_catalog.get_string("reffldname"),
+                key_catalog
+                    .get_string("ondelete")
+                    .and_then(|name| DeleteAction::from_name(&name)),
+            ) {
+                result.push(ForeignKey {
+                    table_name: key_table,
+                    field_name,
+                    ref_table: table_name.to_string(),
+                    ref_field,
+                    on_delete,
+                });
+            }
+        }
+        key_catalog.close();
+        result
+    }
+}
+
+/// Stores the privileges granted on tables in the `privcat` catalog table
+/// and the role memberships granted in `rolecat`. A user holds what was
+/// granted to it and to every role it is a member of, directly or through
+/// other roles.
+pub struct PrivilegeMgr {
+    privilege_layout: Layout,
+    role_layout: Layout,
+}
+
+impl PrivilegeMgr {
+    pub fn new(
+        is_new: bool,
+        table_mgr: &TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<PrivilegeMgr, DbError> {
+        let privilege_schema = Self::privilege_catalog_schema();
+        let role_schema = Self::role_catalog_schema();
+        if is_new {
+            table_mgr.create_table(PRIVILEGE_CATALOG, &privilege_schema, tx.clone())?;
+            table_mgr.create_table(ROLE_CATALOG, &role_schema, tx)?;
+        }
+        Ok(PrivilegeMgr {
+            privilege_layout: Layout::new(privilege_schema),
+            role_layout: Layout::new(role_schema),
+        })
+    }
+
+    fn privilege_catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("tblname", MAX_NAME);
+        schema.add_string_field("grantee", MAX_NAME);
+        schema.add_string_field("privilege", MAX_NAME);
+        schema
+    }
+
+    fn role_catalog_schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_string_field("rolename", MAX_NAME);
+        schema.add_string_field("member", MAX_NAME);
+        schema
+    }
+
+    /// Grants `privilege` on `table_name` to `grantee`, unless it already
+    /// has it.
+    pub fn grant(
+        &self,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        if self.find_grant(table_name, grantee, privilege, tx.clone()) {
+            return Ok(());
+        }
+        let mut catalog = TableScan::new(tx, PRIVILEGE_CATALOG, self.privilege_layout.clone());
+        catalog.insert()?;
+        catalog.set_string("tblname", table_name.to_string())?;
+        catalog.set_string("grantee", grantee.to_string())?;
+        catalog.set_string("privilege", privilege.to_string())?;
+        catalog.close();
+        Ok(())
+    }
+
+    /// Takes back a privilege granted to `grantee` itself, returning whether
+    /// it had been granted. Privileges held through roles stay.
+    pub fn revoke(
+        &self,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<bool, DbError> {
+        let mut catalog = TableScan::new(tx, PRIVILEGE_CATALOG, self.privilege_layout.clone());
+        let mut revoked = false;
+        while catalog.next() {
+            if Self::is_grant(&catalog, table_name, grantee, privilege) {
+                catalog.delete()?;
+                revoked = true;
+            }
+        }
+        catalog.close();
+        Ok(revoked)
+    }
+
+    /// Makes `member` a member of `role`, unless it already is.
+    pub fn grant_role(
+        &self,
+        role: &str,
+        member: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        if self.roles_of(member, tx.clone()).contains(role) {
+            return Ok(());
+        }
+        let mut catalog = TableScan::new(tx, ROLE_CATALOG, self.role_layout.clone());
+        catalog.insert()?;
+        catalog.set_string("rolename", role.to_string())?;
+        catalog.set_string("member", member.to_string())?;
+        catalog.close();
+        Ok(())
+    }
+
+    /// Ends the membership of `member` in `role`, returning whether it was
+    /// a member.
+    pub fn revoke_role(
+        &self,
+        role: &str,
+        member: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<bool, DbError> {
+        let mut catalog = TableScan::new(tx, ROLE_CATALOG, self.role_layout.clone());
+        let mut revoked = false;
+        while catalog.next() {
+            if catalog.get_string("rolename").as_deref() == Some(role)
+                && catalog.get_string("member").as_deref() == Some(member)
+            {
+                catalog.delete()?;
+                revoked = true;
+            }
+        }
+        catalog.close();
+        Ok(revoked)
+    }
+
+    /// Whether `user` holds `privilege` on `table_name`, itself or through
+    /// a role.
+    pub fn has_privilege(
+        &self,
+        user: &str,
+        table_name: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> bool {
+        let grantees = self.roles_of(user, tx.clone());
+        let mut catalog = TableScan::new(tx, PRIVILEGE_CATALOG, self.privilege_layout.clone());
+        let mut found = false;
+        while !found && catalog.next() {
+            found = catalog
+                .get_string("grantee")
+                .is_some_and(|grantee| grantees.contains(&grantee))
+                && catalog.get_string("tblname").as_deref() == Some(table_name)
+                && catalog.get_string("privilege") == Some(privilege.to_string());
+        }
+        catalog.close();
+        found
+    }
+
+    // `user` and every role it is a member of, however indirectly.
+    fn roles_of(&self, user: &str, tx: Rc<RefCell<Transaction>>) -> HashSet<String> {
+        let mut memberships = Vec::new();
+        let mut catalog = TableScan::new(tx, ROLE_CATALOG, self.role_layout.clone());
+        while catalog.next() {
+            if let (Some(role), Some(member)) =
+                (catalog.get_string("rolename"), catalog.get_string("member"))
+            {
+                memberships.push((role, member));
+            }
+        }
+        catalog.close();
+        let mut roles = HashSet::from([user.to_string()]);
+        let mut pending = vec![user.to_string()];
+        while let Some(name) = pending.pop() {
+            for (role, member) in memberships.iter() {
+                if *member == name && roles.insert(role.clone()) {
+                    pending.push(role.clone());
+                }
+            }
+        }
+        roles
+    }
+
+    fn find_grant(
+        &self,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> bool {
+        let mut catalog = TableScan::new(tx, PRIVILEGE_CATALOG, self.privilege_layout.clone());
+        let mut found = false;
+        while !found && catalog.next() {
+            found = Self::is_grant(&catalog, table_name, grantee, privilege);
+        }
+        catalog.close();
+        found
+    }
+
+    fn is_grant(
+        catalog: &TableScan,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+    ) -> bool {
+        catalog.get_string("tblname").as_deref() == Some(table_name)
+            && catalog.get_string("grantee").as_deref() == Some(grantee)
+            && catalog.get_string("privilege") == Some(privilege.to_string())
+    }
+}
+
+static NEXT_SESSION: AtomicUsize = AtomicUsize::new(0);
+
+/// The temporary tables of one connection. They are never written to the
+/// catalog tables, so no other connection sees them, and their heap files
+/// start with "temp" so that they are removed when the database next opens.
+pub struct TempCatalog {
+    session: usize,
+    layouts: HashMap<String, Layout>,
+}
+
+impl TempCatalog {
+    pub fn new() -> TempCatalog {
+        TempCatalog {
+            session: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            layouts: HashMap::new(),
+        }
+    }
+
+    pub fn heap_name(&self, table_name: &str) -> String {
+        format!("temp_s{}_{}", self.session, table_name)
+    }
+
+    pub fn create_table(&mut self, table_name: &str, schema: &Schema) {
+        self.layouts
+            .insert(table_name.to_string(), Layout::new(schema.clone()));
+    }
+
+    pub fn get_layout(&self, table_name: &str) -> Option<Layout> {
+        self.layouts.get(table_name).cloned()
+    }
+
+    pub fn heap_names(&self) -> Vec<String> {
+        self.layouts
+            .keys()
+            .map(|table_name| self.heap_name(table_name))
+            .collect()
+    }
+}
+
+impl Default for TempCatalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn version_catalog_schema() -> Schema {
+    let mut schema = Schema::new();
+    schema.add_int_field("version");
+    schema.add_int_field("checksum");
+    schema
+}
+
+type CatalogUpgrade = fn(&TableMgr, Rc<RefCell<Transaction>>) -> Result<(), DbError>;
+
+// Each entry upgrades a catalog from the version at its position plus one to
+// the next version.
+const UPGRADES: [CatalogUpgrade; 7] = [
+    upgrade_to_v2,
+    upgrade_to_v3,
+    upgrade_to_v4,
+    upgrade_to_v5,
+    upgrade_to_v6,
+    upgrade_to_v7,
+    upgrade_to_v8,
+];
+
+fn upgrade_to_v2(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(INDEX_CATALOG, &IndexMgr::catalog_schema(), tx.clone())?;
+    table_mgr.create_table(VERSION_CATALOG, &version_catalog_schema(), tx)?;
+    Ok(())
+}
+
+fn upgrade_to_v3(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(VIEW_CATALOG, &ViewMgr::catalog_schema(), tx)
+}
+
+fn upgrade_to_v4(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(PARTITION_CATALOG, &PartitionMgr::catalog_schema(), tx)
+}
+
+fn upgrade_to_v5(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(RETENTION_CATALOG, &RetentionMgr::catalog_schema(), tx)
+}
+
+fn upgrade_to_v6(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(DEFAULT_CATALOG, &DefaultMgr::catalog_schema(), tx.clone())?;
+    table_mgr.create_table(SEQUENCE_CATALOG, &SequenceMgr::catalog_schema(), tx)?;
+    Ok(())
+}
+
+fn upgrade_to_v7(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    table_mgr.create_table(FOREIGN_KEY_CATALOG, &ForeignKeyMgr::catalog_schema(), tx)
+}
+
+fn upgrade_to_v8(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), DbError> {
+    let privilege_schema = PrivilegeMgr::privilege_catalog_schema();
+    table_mgr.create_table(PRIVILEGE_CATALOG, &privilege_schema, tx.clone())?;
+    table_mgr.create_table(ROLE_CATALOG, &PrivilegeMgr::role_catalog_schema(), tx)?;
+    Ok(())
+}
+
+fn upgrade_catalog(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Result<(), CatalogError> {
+    let version = catalog_version(table_mgr, tx.clone())?;
+    if version < CATALOG_VERSION {
+        for upgrade in &UPGRADES[version as usize - 1..] {
+            upgrade(table_mgr, tx.clone())?;
+        }
+        write_version(table_mgr, CATALOG_VERSION, tx)?;
+    }
+    Ok(())
+}
+
+// The version of an existing catalog, refusing one that is newer than this
+// crate or whose checksum does not match.
+fn catalog_version(
+    table_mgr: &TableMgr,
+    tx: Rc<RefCell<Transaction>>,
+) -> Result<i32, CatalogError> {
+    let version = match read_version(table_mgr, tx.clone()) {
+        Some((version, checksum)) => {
+            if version > CATALOG_VERSION {
+                return Err(CatalogError::NewerVersion {
+                    found: version,
+                    supported: CATALOG_VERSION,
+                });
+            }
+            if checksum != catalog_checksum(table_mgr, version, tx.clone()) {
+                return Err(CatalogError::ChecksumMismatch);
+            }
+            version
+        }
+        // Catalogs older than the version table are version 1.
+        None => 1,
+    };
+    Ok(version)
+}
+
+// The version and checksum recorded in `vercat`, or `None` if the catalog
+// predates it.
+fn read_version(table_mgr: &TableMgr, tx: Rc<RefCell<Transaction>>) -> Option<(i32, i32)> {
+    let layout = table_mgr.get_layout(VERSION_CATALOG, tx.clone())?;
+    let mut scan = TableScan::new(tx, VERSION_CATALOG, layout);
+    let mut result = None;
+    if scan.next() {
+        result = scan.get_int("version").zip(scan.get_int("checksum"));
+    }
+    scan.close();
+    result
+}
+
+fn write_version(
+    table_mgr: &TableMgr,
+    version: i32,
+    tx: Rc<RefCell<Transaction>>,
+) -> Result<(), DbError> {
+    let checksum = catalog_checksum(table_mgr, version, tx.clone());
+    let layout = table_mgr
+        .get_layout(VERSION_CATALOG, tx.clone())
+        .expect("version catalog exists");
+    let mut scan = TableScan::new(tx, VERSION_CATALOG, layout);
+    if !scan.next() {
+        scan.insert()?;
+    }
+    scan.set_int("version", version)?;
+    scan.set_int("checksum", checksum)?;
+    scan.close();
+    Ok(())
+}
+
+// FNV-1a over the version and the recorded definitions of the catalog
+// tables. The hash has to stay the same across Rust releases, which rules
+// out the standard library's hashers.
+fn catalog_checksum(table_mgr: &TableMgr, version: i32, tx: Rc<RefCell<Transaction>>) -> i32 {
+    let mut hash: u32 = 0x811c9dc5;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    };
+    feed(&version.to_be_bytes());
+    for table_name in CATALOG_TABLES {
+        let Some(layout) = table_mgr.get_layout(table_name, tx.clone()) else {
+            continue;
+        };
+        feed(table_name.as_bytes());
+        feed(&(layout.slot_size() as i32).to_be_bytes());
+        for field_name in layout.schema().fields() {
+            feed(field_name.as_bytes());
+            feed(
+                &layout
+                    .schema()
+                    .field_type(field_name)
+                    .unwrap_or(0)
+                    .to_be_bytes(),
+            );
+            feed(&(layout.schema().length(field_name).unwrap_or(0) as i32).to_be_bytes());
+            feed(&(layout.offset(field_name).unwrap_or(0) as i32).to_be_bytes());
+        }
+    }
+    hash as i32
+}
+
+/// Single entry point for everything the database knows about its tables.
+pub struct MetadataMgr {
+    table_mgr: TableMgr,
+    index_mgr: IndexMgr,
+    view_mgr: ViewMgr,
+    partition_mgr: PartitionMgr,
+    retention_mgr: RetentionMgr,
+    default_mgr: DefaultMgr,
+    sequence_mgr: SequenceMgr,
+    foreign_key_mgr: ForeignKeyMgr,
+    privilege_mgr: PrivilegeMgr,
+    stat_mgr: StatMgr,
+}
+
+impl MetadataMgr {
+    /// Opens the catalog, creating it for a new database. A catalog written by
+    /// an older version of the crate is upgraded in place within `tx`; one
+    /// written by a newer version is refused.
+    pub fn new(is_new: bool, tx: Rc<RefCell<Transaction>>) -> Result<MetadataMgr, CatalogError> {
+        let table_mgr = TableMgr::new(is_new, tx.clone())?;
+        if is_new {
+            table_mgr.create_table(VERSION_CATALOG, &version_catalog_schema(), tx.clone())?;
+        } else {
+            upgrade_catalog(&table_mgr, tx.clone())?;
+        }
+        let metadata_mgr = Self::with_table_mgr(is_new, table_mgr, tx.clone())?;
+        if is_new {
+            write_version(&metadata_mgr.table_mgr, CATALOG_VERSION, tx)?;
+        }
+        Ok(metadata_mgr)
+    }
+
+    /// Opens the catalog of an existing database without changing it, so
+    /// `tx` may be read-only. A catalog that would need an upgrade is
+    /// refused.
+    pub fn read_only(tx: Rc<RefCell<Transaction>>) -> Result<MetadataMgr, CatalogError> {
+        let table_mgr = TableMgr::new(false, tx.clone())?;
+        let version = catalog_version(&table_mgr, tx.clone())?;
+        if version < CATALOG_VERSION {
+            return Err(CatalogError::OlderVersion {
+                found: version,
+                supported: CATALOG_VERSION,
+            });
+        }
+        Ok(Self::with_table_mgr(false, table_mgr, tx)?)
+    }
+
+    fn with_table_mgr(
+        is_new: bool,
+        table_mgr: TableMgr,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<MetadataMgr, DbError> {
+        let index_mgr = IndexMgr::new(is_new, &table_mgr, tx.clone())?;
+        let view_mgr = ViewMgr::new(is_new, &table_mgr, tx.clone())?;
+        let partition_mgr = PartitionMgr::new(is_new, &table_mgr, tx.clone())?;
+        let retention_mgr = RetentionMgr::new(is_new, &table_mgr, tx.clone())?;
+        let default_mgr = DefaultMgr::new(is_new, &table_mgr, tx.clone())?;
+        let sequence_mgr = SequenceMgr::new(is_new, &table_mgr, tx.clone())?;
+        let foreign_key_mgr = ForeignKeyMgr::new(is_new, &table_mgr, tx.clone())?;
+        let privilege_mgr = PrivilegeMgr::new(is_new, &table_mgr, tx)?;
+        Ok(MetadataMgr {
+            table_mgr,
+            index_mgr,
+            view_mgr,
+            partition_mgr,
+            retention_mgr,
+            default_mgr,
+            sequence_mgr,
+            foreign_key_mgr,
+            privilege_mgr,
+            stat_mgr: StatMgr::new(),
+        })
+    }
+
+    pub fn create_table(
+        &self,
+        table_name: &str,
+        schema: &Schema,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.table_mgr.create_table(table_name, schema, tx)
+    }
+
+    /// Creates a table only the connection running `tx` can see.
+    pub fn create_temp_table(
+        &self,
+        table_name: &str,
+        schema: &Schema,
+        tx: Rc<RefCell<Transaction>>,
+    ) {
+        let temp_catalog = tx.borrow().temp_catalog();
+        temp_catalog.borrow_mut().create_table(table_name, schema);
+    }
+
+    pub fn is_temp_table(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> bool {
+        let temp_catalog = tx.borrow().temp_catalog();
+        let is_temp = temp_catalog.borrow().get_layout(table_name).is_some();
+        is_temp
+    }
+
+    /// A temporary table of the connection running `tx` hides a catalog
+    /// table of the same name.
+    pub fn get_layout(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> Option<Layout> {
+        let temp_catalog = tx.borrow().temp_catalog();
+        let layout = temp_catalog.borrow().get_layout(table_name);
+        layout.or_else(|| self.table_mgr.get_layout(table_name, tx))
+    }
+
+    pub fn table_names(&self, tx: Rc<RefCell<Transaction>>) -> Vec<String> {
+        self.table_mgr.table_names(tx)
+    }
+
+    pub fn create_view(
+        &self,
+        view_name: &str,
+        view_def: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.view_mgr.create_view(view_name, view_def, tx)
+    }
+
+    pub fn get_view_def(&self, view_name: &str, tx: Rc<RefCell<Transaction>>) -> Option<String> {
+        if self.is_temp_table(view_name, tx.clone()) {
+            return None;
+        }
+        self.view_mgr.get_view_def(view_name, tx)
+    }
+
+    pub fn view_names(&self, tx: Rc<RefCell<Transaction>>) -> Vec<String> {
+        self.view_mgr.view_names(tx)
+    }
+
+    pub fn create_partitioning(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        bounds: &[i32],
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.partition_mgr
+            .create_partitioning(table_name, field_name, bounds, tx)
+    }
+
+    pub fn get_partitioning(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Option<Partitioning> {
+        if self.is_temp_table(table_name, tx.clone()) {
+            return None;
+        }
+        self.partition_mgr.get_partitioning(table_name, tx)
+    }
+
+    /// The heap files that hold a table's records: one per partition, the
+    /// connection's own file for a temporary table, or else the table's own
+    /// file.
+    pub fn heap_names(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> Vec<String> {
+        self.scan_heaps(table_name, &Predicate::new(), tx)
+    }
+
+    /// The heap files of a table that can hold records satisfying
+    /// `predicate`.
+    pub fn scan_heaps(
+        &self,
+        table_name: &str,
+        predicate: &Predicate,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Vec<String> {
+        let temp_catalog = tx.borrow().temp_catalog();
+        if temp_catalog.borrow().get_layout(table_name).is_some() {
+            return vec![temp_catalog.borrow().heap_name(table_name)];
+        }
+        match self.get_partitioning(table_name, tx) {
+            Some(partitioning) => partitioning.prune(predicate),
+            None => vec![table_name.to_string()],
+        }
+    }
+
+    pub fn create_index(
+        &self,
+        index_name: &str,
+        table_name: &str,
+        field_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.index_mgr
+            .create_index(index_name, table_name, field_name, tx)
+    }
+
+    pub fn get_index_info(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> HashMap<String, IndexInfo> {
+        if self.is_temp_table(table_name, tx.clone()) {
+            return HashMap::new();
+        }
+        self.index_mgr
+            .get_index_info(table_name, &self.table_mgr, &self.stat_mgr, tx)
+    }
+
+    pub fn get_stat_info(
+        &self,
+        table_name: &str,
+        layout: &Layout,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> StatInfo {
+        self.stat_mgr.get_stat_info(table_name, layout, tx)
+    }
+
+    pub fn record_inserts(&self, table_name: &str, count: usize) {
+        self.stat_mgr.record_inserts(table_name, count);
+    }
+
+    pub fn record_deletes(&self, table_name: &str, count: usize) {
+        self.stat_mgr.record_deletes(table_name, count);
+    }
+
+    /// The disk space of every user table, followed by that of its indexes.
+    pub fn storage_report(&self, tx: Rc<RefCell<Transaction>>) -> Vec<RelationStorage> {
+        let mut table_names = self.table_names(tx.clone());
+        table_names.retain(|table_name| !is_catalog_table(table_name));
+        table_names.sort();
+        let measure = |relation: &str, kind: &str, files: Vec<String>| {
+            let mut tx = tx.borrow_mut();
+            let blocks: usize = files
+                .iter()
+                .map(|file| tx.scan_read(|tx| tx.size(file).map(Some)).unwrap_or(0))
+                .sum();
+            let free_blocks: usize = files
+                .iter()
+                .map(|file| {
+                    tx.scan_read(|tx| tx.free_block_count(file).map(Some))
+                        .unwrap_or(0)
+                })
+                .sum();
+            RelationStorage {
+                relation: relation.to_string(),
+                kind: kind.to_string(),
+                blocks,
+                used_blocks: blocks.saturating_sub(free_blocks),
+                free_blocks,
+                overflow_chains: 0,
+            }
+        };
+        let mut report = Vec::new();
+        for table_name in table_names {
+            let Some(layout) = self.get_layout(&table_name, tx.clone()) else {
+                continue;
+            };
+            let schema = layout.schema();
+            let blob_fields: Vec<&String> = schema
+                .fields()
+                .iter()
+                .filter(|field_name| schema.field_type(field_name) == Some(BLOB))
+                .collect();
+            let heap_names = self.heap_names(&table_name, tx.clone());
+            let mut files = Vec::new();
+            let mut overflow_chains = 0;
+            for heap_name in heap_names.iter() {
+                files.push(format!("{}.tbl", heap_name));
+                if blob_fields.is_empty() {
+                    continue;
+                }
+                files.push(format!("{}.blob", heap_name));
+                // Reading every block of the table should not evict the pool.
+                let ring_size = tx.borrow().buffer_ring_size();
+                tx.borrow_mut().set_buffer_ring(MAINTENANCE_RING_SIZE);
+                let mut scan = TableScan::new(tx.clone(), heap_name, layout.clone());
+                while scan.next() {
+                    overflow_chains += blob_fields
+                        .iter()
+                        .filter(|field_name| scan.has_blob(field_name))
+                        .count();
+                }
+                scan.close();
+                tx.borrow_mut().set_buffer_ring(ring_size);
+            }
+            report.push(RelationStorage {
+                overflow_chains,
+                ..measure(&table_name, "table", files)
+            });
+            let mut index_names: Vec<String> = self
+                .get_index_info(&table_name, tx.clone())
+                .values()
+                .map(|index_info| index_info.index_name().to_string())
+                .collect();
+            index_names.sort();
+            for index_name in index_names {
+                let files = BTreeIndex::file_names(&index_name).to_vec();
+                report.push(measure(&index_name, "index", files));
+            }
+        }
+        report
+    }
+
+    /// What the maintenance advisor recommends for each user table whose
+    /// dead slots have passed a threshold: vacuuming it past
+    /// `VACUUM_THRESHOLD`, and rebuilding its indexes as well past
+    /// `REINDEX_THRESHOLD`.
+    pub fn maintenance_advice(&self, tx: Rc<RefCell<Transaction>>) -> Vec<MaintenanceAdvice> {
+        let mut table_names = self.table_names(tx.clone());
+        table_names.retain(|table_name| !is_catalog_table(table_name));
+        table_names.sort();
+        let mut advice = Vec::new();
+        for table_name in table_names {
+            let Some(layout) = self.get_layout(&table_name, tx.clone()) else {
+                continue;
+            };
+            let mut stat_info = StatInfo::default();
+            for heap_name in self.heap_names(&table_name, tx.clone()) {
+                stat_info.merge(&self.get_stat_info(&heap_name, &layout, tx.clone()));
+            }
+            let dead_percent = stat_info.dead_percent();
+            let mut actions = Vec::new();
+            if dead_percent >= VACUUM_THRESHOLD {
+                actions.push("vacuum");
+            }
+            if dead_percent >= REINDEX_THRESHOLD
+                && !self.get_index_info(&table_name, tx.clone()).is_empty()
+            {
+                actions.push("reindex");
+            }
+            for action in actions {
+                advice.push(MaintenanceAdvice {
+                    table_name: table_name.clone(),
+                    dead_slots: stat_info.dead_slots(),
+                    dead_percent,
+                    action: action.to_string(),
+                });
+            }
+        }
+        advice
+    }
+
+    pub fn set_retention(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        days: i32,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.retention_mgr
+            .set_retention(table_name, field_name, days, tx)
+    }
+
+    pub fn retention_policies(&self, tx: Rc<RefCell<Transaction>>) -> Vec<RetentionPolicy> {
+        self.retention_mgr.retention_policies(tx)
+    }
+
+    pub fn create_default(
+        &self,
+        table_name: &str,
+        field_name: &str,
+        expr: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.default_mgr
+            .create_default(table_name, field_name, expr, tx)
+    }
+
+    pub fn get_defaults(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Vec<(String, String)> {
+        if self.is_temp_table(table_name, tx.clone()) {
+            return Vec::new();
+        }
+        self.default_mgr.get_defaults(table_name, tx)
+    }
+
+    pub fn nextval(
+        &self,
+        sequence_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<i32, DbError> {
+        self.sequence_mgr.nextval(sequence_name, tx)
+    }
+
+    pub fn create_foreign_key(
+        &self,
+        key: &ForeignKey,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.foreign_key_mgr.create_foreign_key(key, tx)
+    }
+
+    /// The foreign keys referencing a table. Temporary tables cannot be
+    /// referenced.
+    pub fn get_referencing(
+        &self,
+        table_name: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Vec<ForeignKey> {
+        if self.is_temp_table(table_name, tx.clone()) {
+            return Vec::new();
+        }
+        self.foreign_key_mgr.get_referencing(table_name, tx)
+    }
+
+    pub fn grant_privilege(
+        &self,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.privilege_mgr.grant(table_name, grantee, privilege, tx)
+    }
+
+    pub fn revoke_privilege(
+        &self,
+        table_name: &str,
+        grantee: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<bool, DbError> {
+        self.privilege_mgr
+            .revoke(table_name, grantee, privilege, tx)
+    }
+
+    pub fn grant_role(
+        &self,
+        role: &str,
+        member: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), DbError> {
+        self.privilege_mgr.grant_role(role, member, tx)
+    }
+
+    pub fn revoke_role(
+        &self,
+        role: &str,
+        member: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<bool, DbError> {
+        self.privilege_mgr.revoke_role(role, member, tx)
+    }
+
+    /// Whether `user` may apply `privilege` to `table_name`. Everyone may
+    /// use the temporary tables of their own connection.
+    pub fn has_privilege(
+        &self,
+        user: &str,
+        table_name: &str,
+        privilege: Privilege,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> bool {
+        self.is_temp_table(table_name, tx.clone())
+            || self
+                .privilege_mgr
+                .has_privilege(user, table_name, privilege, tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use crate::recordmanager::{INTEGER, VARCHAR};
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_metadata_mgr() {
+        let tmp_dir = TempDir::new("test_metadata_mgr").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            )
+            .unwrap(),
+        ));
+
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_string_field("B", 9);
+        metadata_mgr
+            .create_table("mytable", &schema, tx.clone())
+            .unwrap();
+        assert!(metadata_mgr.get_layout("missing", tx.clone()).is_none());
+        tx.borrow_mut().commit().unwrap();
+
+        // A catalog opened later sees the tables created before it.
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(false, tx.clone()).unwrap();
+        let layout = metadata_mgr
+            .get_layout("mytable", tx.clone())
+            .expect("table should be in the catalog");
+        assert_eq!(layout.slot_size(), 21);
+        assert_eq!(
+            layout.schema().fields(),
+            &["A".to_string(), "B".to_string()]
+        );
+        assert_eq!(layout.schema().field_type("A"), Some(INTEGER));
+        assert_eq!(layout.schema().field_type("B"), Some(VARCHAR));
+        assert_eq!(layout.schema().length("B"), Some(9));
+        assert_eq!(layout.offset("B"), Some(8));
+
+        let catalog = metadata_mgr
+            .get_layout("fldcat", tx.clone())
+            .expect("catalog describes itself");
+        assert_eq!(catalog.schema().fields().len(), 5);
+        assert_eq!(
+            metadata_mgr.table_names(tx.clone()),
+            vec![
+                "tblcat".to_string(),
+                "fldcat".to_string(),
+                "vercat".to_string(),
+                "idxcat".to_string(),
+                "viewcat".to_string(),
+                "partcat".to_string(),
+                "retcat".to_string(),
+                "defcat".to_string(),
+                "seqcat".to_string(),
+                "fkcat".to_string(),
+                "privcat".to_string(),
+                "rolecat".to_string(),
+                "mytable".to_string()
+            ]
+        );
+
+        let mut scan = TableScan::new(tx.clone(), "mytable", layout.clone());
+        for (a, b) in [(1, "x"), (1, "y"), (2, "y")] {
+            scan.insert().unwrap();
+            scan.set_int("A", a).unwrap();
+            scan.set_string("B", b.to_string()).unwrap();
+        }
+        scan.close();
+        let stat_info = metadata_mgr.get_stat_info("mytable", &layout, tx.clone());
+        assert_eq!(stat_info.blocks_accessed(), 1);
+        assert_eq!(stat_info.records_output(), 3);
+        assert_eq!(stat_info.distinct_values("A"), 2);
+        assert_eq!(stat_info.distinct_values("B"), 2);
+        assert_eq!(stat_info.distinct_values("missing"), 1);
+
+        metadata_mgr
+            .create_index("mytable_b", "mytable", "B", tx.clone())
+            .unwrap();
+        let indexes = metadata_mgr.get_index_info("mytable", tx.clone());
+        assert_eq!(indexes.len(), 1);
+        assert_eq!(indexes["B"].index_name(), "mytable_b");
+        assert!(metadata_mgr
+            .get_index_info("missing", tx.clone())
+            .is_empty());
+
+        metadata_mgr
+            .create_view("myview", "select A from mytable", tx.clone())
+            .unwrap();
+        assert_eq!(
+            metadata_mgr.get_view_def("myview", tx.clone()).as_deref(),
+            Some("select A from mytable")
+        );
+        assert!(metadata_mgr.get_view_def("mytable", tx.clone()).is_none());
+        assert_eq!(
+            metadata_mgr.view_names(tx.clone()),
+            vec!["myview".to_string()]
+        );
+
+        metadata_mgr
+            .set_retention("mytable", "A", 30, tx.clone())
+            .unwrap();
+        metadata_mgr
+            .set_retention("mytable", "A", 7, tx.clone())
+            .unwrap();
+        let policies = metadata_mgr.retention_policies(tx.clone());
+        assert_eq!(policies.len(), 1);
+        assert_eq!(policies[0].table_name(), "mytable");
+        assert_eq!(policies[0].field_name(), "A");
+        assert_eq!(policies[0].days(), 7);
+        assert_eq!(policies[0].cutoff(8 * 86400), 86400);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_catalog_upgrade() {
+        let tmp_dir = TempDir::new("test_catalog_upgrade").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = || {
+            Rc::new(RefCell::new(
+                Transaction::new(
+                    file_manager.clone(),
+                    log_manager.clone(),
+                    buffer_manager.clone(),
+                    lock_table.clone(),
+                )
+                .unwrap(),
+            ))
+        };
+
+        // A version 1 catalog has only tblcat and fldcat.
+        let tx = new_tx();
+        let table_mgr = TableMgr::new(true, tx.clone()).unwrap();
+        assert!(read_version(&table_mgr, tx.clone()).is_none());
+        tx.borrow_mut().commit().unwrap();
+
+        let tx = new_tx();
+        let metadata_mgr = MetadataMgr::new(false, tx.clone()).unwrap();
+        assert!(metadata_mgr.get_layout(INDEX_CATALOG, tx.clone()).is_some());
+        assert!(metadata_mgr.get_layout(VIEW_CATALOG, tx.clone()).is_some());
+        assert!(metadata_mgr
+            .get_layout(PARTITION_CATALOG, tx.clone())
+            .is_some());
+        assert!(metadata_mgr
+            .get_layout(RETENTION_CATALOG, tx.clone())
+            .is_some());
+        assert!(metadata_mgr
+            .get_layout(SEQUENCE_CATALOG, tx.clone())
+            .is_some());
+        assert!(metadata_mgr
+            .get_layout(FOREIGN_KEY_CATALOG, tx.clone())
+            .is_some());
+        assert!(metadata_mgr
+            .get_layout(PRIVILEGE_CATALOG, tx.clone())
+            .is_some());
+        assert!(metadata_mgr.get_layout(ROLE_CATALOG, tx.clone()).is_some());
+        let (version, _) = read_version(&metadata_mgr.table_mgr, tx.clone()).unwrap();
+        assert_eq!(version, CATALOG_VERSION);
+        tx.borrow_mut().commit().unwrap();
+
+        // An upgraded catalog opens without changes.
+        let tx = new_tx();
+        assert!(MetadataMgr::new(false, tx.clone()).is_ok());
+        tx.borrow_mut().commit().unwrap();
+
+        let set_version_row = |version: i32, checksum: Option<i32>| {
+            let tx = new_tx();
+            let table_mgr = TableMgr::new(false, tx.clone()).unwrap();
+            let layout = table_mgr.get_layout(VERSION_CATALOG, tx.clone()).unwrap();
+            let mut scan = TableScan::new(tx.clone(), VERSION_CATALOG, layout);
+            assert!(scan.next());
+            scan.set_int("version", version).unwrap();
+            if let Some(checksum) = checksum {
+                scan.set_int("checksum", checksum).unwrap();
+            }
+            scan.close();
+            tx.borrow_mut().commit().unwrap();
+        };
+        set_version_row(CATALOG_VERSION + 1, None);
+        let tx = new_tx();
+        assert!(matches!(
+            MetadataMgr::new(false, tx.clone()).err(),
+            Some(CatalogError::NewerVersion {
+                found,
+                supported: CATALOG_VERSION
+            }) if found == CATALOG_VERSION + 1
+        ));
+        tx.borrow_mut().rollback().unwrap();
+
+        set_version_row(CATALOG_VERSION, Some(12345));
+        let tx = new_tx();
+        assert!(matches!(
+            MetadataMgr::new(false, tx.clone()).err(),
+            Some(CatalogError::ChecksumMismatch)
+        ));
+        tx.borrow_mut().rollback().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
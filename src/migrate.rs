@@ -0,0 +1,334 @@
+use crate::buffermanager::MAINTENANCE_RING_SIZE;
+use crate::driver::SimpleDB;
+use crate::error::DbError;
+use crate::index::Index;
+use crate::metadata::{is_catalog_table, CatalogError};
+use crate::query::{Constant, Scan, UpdateScan};
+use crate::recordmanager::{PartitionScan, Schema, TableScan};
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Io(std::io::Error),
+    Catalog(CatalogError),
+    TargetNotEmpty(String),
+    RowCountMismatch {
+        table: String,
+        expected: usize,
+        found: usize,
+    },
+    ChecksumMismatch(String),
+}
+
+impl Display for MigrateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrateError::Io(err) => write!(f, "{}", err),
+            MigrateError::Catalog(err) => write!(f, "{}", err),
+            MigrateError::TargetNotEmpty(dir) => write!(f, "target directory {} is not empty", dir),
+            MigrateError::RowCountMismatch {
+                table,
+                expected,
+                found,
+            } => write!(
+                f,
+                "table {} has {} rows after migration, expected {}",
+                table, found, expected
+            ),
+            MigrateError::ChecksumMismatch(table) => {
+                write!(
+                    f,
+                    "table {} does not match its source after migration",
+                    table
+                )
+            }
+        }
+    }
+}
+
+impl Error for MigrateError {}
+
+impl From<std::io::Error> for MigrateError {
+    fn from(err: std::io::Error) -> MigrateError {
+        MigrateError::Io(err)
+    }
+}
+
+impl From<CatalogError> for MigrateError {
+    fn from(err: CatalogError) -> MigrateError {
+        MigrateError::Catalog(err)
+    }
+}
+
+impl From<DbError> for MigrateError {
+    fn from(err: DbError) -> MigrateError {
+        MigrateError::Catalog(CatalogError::Storage(err))
+    }
+}
+
+/// Row count and checksum of one migrated table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableSummary {
+    pub table_name: String,
+    pub rows: usize,
+    pub checksum: u64,
+}
+
+/// Copies every table, view and retention policy of the database in `source` into a new
+/// database in `target` that uses `target_block_size` byte blocks. Records are read and
+/// written through table scans, so the copy is repacked for the new block
+/// size. Each table is then scanned again in the target and its row count and
+/// checksum compared with the source before the summaries are returned.
+pub fn migrate(
+    source: &Path,
+    source_block_size: usize,
+    target: &Path,
+    target_block_size: usize,
+) -> Result<Vec<TableSummary>, MigrateError> {
+    std::fs::create_dir_all(target)?;
+    if std::fs::read_dir(target)?.next().is_some() {
+        return Err(MigrateError::TargetNotEmpty(
+            target.to_string_lossy().to_string(),
+        ));
+    }
+
+    let source_db = SimpleDB::with_params(source, source_block_size, SimpleDB::BUFFER_COUNT)?;
+    let source_tx = source_db.new_tx()?;
+    source_tx
+        .borrow_mut()
+        .set_buffer_ring(MAINTENANCE_RING_SIZE);
+    let source_metadata = source_db.metadata_mgr();
+    let target_db = SimpleDB::with_params(target, target_block_size, SimpleDB::BUFFER_COUNT)?;
+    let target_tx = target_db.new_tx()?;
+    let target_metadata = target_db.metadata_mgr();
+    let mut summaries = Vec::new();
+    for table_name in source_metadata.table_names(source_tx.clone()) {
+        if is_catalog_table(&table_name) {
+            continue;
+        }
+        let Some(layout) = source_metadata.get_layout(&table_name, source_tx.clone()) else {
+            continue;
+        };
+        let schema = layout.schema().clone();
+        target_metadata.create_table(&table_name, &schema, target_tx.clone())?;
+        // Partitions keep their bounds, so each heap file is copied to the
+        // heap file of the same name.
+        if let Some(partitioning) = source_metadata.get_partitioning(&table_name, source_tx.clone())
+        {
+            target_metadata.create_partitioning(
+                &table_name,
+                partitioning.field_name(),
+                partitioning.bounds(),
+                target_tx.clone(),
+            )?;
+        }
+        let heap_names = source_metadata.heap_names(&table_name, source_tx.clone());
+        let target_layout = target_metadata
+            .get_layout(&table_name, target_tx.clone())
+            .expect("table was just created");
+        // Indexes are declared again and rebuilt as the rows are copied.
+        let source_indexes = source_metadata.get_index_info(&table_name, source_tx.clone());
+        for info in source_indexes.values() {
+            target_metadata.create_index(
+                info.index_name(),
+                &table_name,
+                info.field_name(),
+                target_tx.clone(),
+            )?;
+        }
+        let mut target_indexes: Vec<_> = target_metadata
+            .get_index_info(&table_name, target_tx.clone())
+            .into_values()
+            .map(|info| {
+                let field_name = info.field_name().to_string();
+                Ok((field_name, info.open()?))
+            })
+            .collect::<Result<_, DbError>>()?;
+
+        let mut summary = TableSummary {
+            table_name: table_name.clone(),
+            rows: 0,
+            checksum: 0,
+        };
+        for heap_name in heap_names.iter() {
+            let mut source_scan = TableScan::new(source_tx.clone(), heap_name, layout.clone());
+            let mut target_scan =
+                TableScan::new(target_tx.clone(), heap_name, target_layout.clone());
+            while source_scan.next() {
+                let row = read_row(&source_scan, &schema);
+                target_scan.insert()?;
+                for (field_name, val) in schema.fields().iter().zip(&row) {
+                    target_scan.set_val(field_name, val.clone())?;
+                }
+                let rid = target_scan.get_rid().expect("scan is on a record");
+                for (field_name, index) in target_indexes.iter_mut() {
+                    let val = target_scan
+                        .get_val(field_name)
+                        .expect("indexed field is in the table's schema");
+                    index.insert(&val, rid)?;
+                }
+                summary.rows += 1;
+                summary.checksum = summary.checksum.wrapping_add(row_checksum(&row));
+            }
+            source_scan.close();
+            target_scan.close();
+        }
+        for (_, index) in target_indexes.iter_mut() {
+            index.close();
+        }
+        summaries.push(summary);
+    }
+    for view_name in source_metadata.view_names(source_tx.clone()) {
+        if let Some(view_def) = source_metadata.get_view_def(&view_name, source_tx.clone()) {
+            target_metadata.create_view(&view_name, &view_def, target_tx.clone())?;
+        }
+    }
+    for policy in source_metadata.retention_policies(source_tx.clone()) {
+        target_metadata.set_retention(
+            policy.table_name(),
+            policy.field_name(),
+            policy.days(),
+            target_tx.clone(),
+        )?;
+    }
+    source_tx.borrow_mut().commit()?;
+    target_tx.borrow_mut().commit()?;
+
+    let verify_tx = target_db.new_tx()?;
+    for summary in summaries.iter() {
+        let layout = target_metadata
+            .get_layout(&summary.table_name, verify_tx.clone())
+            .expect("migrated table is in the catalog");
+        let schema = layout.schema().clone();
+        let heap_names = target_metadata.heap_names(&summary.table_name, verify_tx.clone());
+        let mut scan = PartitionScan::new(verify_tx.clone(), heap_names, layout);
+        let mut rows = 0;
+        let mut checksum: u64 = 0;
+        while scan.next() {
+            rows += 1;
+            checksum = checksum.wrapping_add(row_checksum(&read_row(&scan, &schema)));
+        }
+        scan.close();
+        if rows != summary.rows {
+            verify_tx.borrow_mut().commit()?;
+            return Err(MigrateError::RowCountMismatch {
+                table: summary.table_name.clone(),
+                expected: summary.rows,
+                found: rows,
+            });
+        }
+        if checksum != summary.checksum {
+            verify_tx.borrow_mut().commit()?;
+            return Err(MigrateError::ChecksumMismatch(summary.table_name.clone()));
+        }
+    }
+    verify_tx.borrow_mut().commit()?;
+    Ok(summaries)
+}
+
+fn read_row(scan: &dyn Scan, schema: &Schema) -> Vec<Constant> {
+    schema
+        .fields()
+        .iter()
+        .map(|field_name| {
+            scan.get_val(field_name)
+                .expect("field is in the table's schema")
+        })
+        .collect()
+}
+
+// Rows are summed rather than chained so the checksum does not depend on
+// the order the scan returns them in.
+fn row_checksum(row: &[Constant]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_migrate() {
+        let source_dir = TempDir::new("test_migrate_source").expect("failed to create temp dir");
+        let target_dir = TempDir::new("test_migrate_target").expect("failed to create temp dir");
+        let target = target_dir.path().join("db");
+        {
+            let db = SimpleDB::with_params(source_dir.path(), 400, SimpleDB::BUFFER_COUNT).unwrap();
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            stmt.execute_update("create table t (a int, b varchar(12))")
+                .unwrap();
+            for i in 0..60 {
+                let sql = format!("insert into t (a, b) values ({}, 'row{}')", i, i);
+                stmt.execute_update(&sql).unwrap();
+            }
+            stmt.execute_update("create table empty (c int)").unwrap();
+            stmt.execute_update("create index t_a on t (a)").unwrap();
+            stmt.execute_update("create table parts (k int) partition by range (k) (10)")
+                .unwrap();
+            for k in [5, 15, 25] {
+                let sql = format!("insert into parts (k) values ({})", k);
+                stmt.execute_update(&sql).unwrap();
+            }
+            db.set_retention("t", "a", 30).unwrap();
+        }
+
+        let summaries = migrate(source_dir.path(), 400, &target, 1024).unwrap();
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].table_name, "t");
+        assert_eq!(summaries[0].rows, 60);
+        assert_eq!(summaries[1].table_name, "empty");
+        assert_eq!(summaries[1].rows, 0);
+        assert_eq!(summaries[2].table_name, "parts");
+        assert_eq!(summaries[2].rows, 3);
+
+        // 60 rows of 24 bytes fit in two 1024 byte blocks.
+        assert_eq!(
+            std::fs::metadata(target.join("t.tbl")).unwrap().len(),
+            2 * 1024
+        );
+        {
+            let db = SimpleDB::with_params(&target, 1024, SimpleDB::BUFFER_COUNT).unwrap();
+            let conn = db.connect();
+            let stmt = conn.create_statement();
+            let mut rs = stmt
+                .execute_query("select a, b from t where a = 0")
+                .unwrap();
+            assert!(rs.next());
+            assert_eq!(rs.get_string("b"), Some("row0".to_string()));
+            rs.close();
+            let mut rs = stmt
+                .execute_query("select k from parts where k = 25")
+                .unwrap();
+            assert!(rs.next());
+            assert!(!rs.next());
+            rs.close();
+
+            let tx = db.new_tx().unwrap();
+            let indexes = db.metadata_mgr().get_index_info("t", tx.clone());
+            let mut index = indexes["a"].open().unwrap();
+            index.before_first(&Constant::Int(42));
+            assert!(index.next());
+            assert!(!index.next());
+            index.close();
+            let policies = db.metadata_mgr().retention_policies(tx.clone());
+            assert_eq!(policies.len(), 1);
+            assert_eq!((policies[0].table_name(), policies[0].days()), ("t", 30));
+            tx.borrow_mut().commit().unwrap();
+        }
+
+        assert!(matches!(
+            migrate(source_dir.path(), 400, &target, 1024),
+            Err(MigrateError::TargetNotEmpty(_))
+        ));
+        source_dir.close().expect("failed to remove temp dir");
+        target_dir.close().expect("failed to remove temp dir");
+    }
+}
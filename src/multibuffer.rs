@@ -0,0 +1,467 @@
+use crate::error::DbError;
+use crate::filemanager::BlockId;
+use crate::materialize::{temp_table_blocks, TempTable};
+use crate::plan::{Plan, PlanNode};
+use crate::query::{Constant, Scan};
+use crate::recordmanager::{Layout, RecordPage, Schema};
+use crate::transaction::Transaction;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Decides how many buffers an operator may pin at once. Two of the
+/// available buffers are always left for the operator's other inputs.
+pub struct BufferNeeds {
+    available: usize,
+}
+
+impl BufferNeeds {
+    pub fn new(available: usize) -> BufferNeeds {
+        BufferNeeds { available }
+    }
+
+    /// The largest chunk of a `size` block file, `size` divided by some
+    /// whole number and rounded up, that fits in the buffers to spare.
+    pub fn best_factor(&self, size: usize) -> usize {
+        let available = self.available.saturating_sub(2);
+        if available <= 1 {
+            return 1;
+        }
+        let mut factor = size;
+        let mut i = 1;
+        while factor > available {
+            i += 1;
+            factor = size.div_ceil(i);
+        }
+        factor.max(1)
+    }
+}
+
+/// Reads the records of a run of consecutive blocks of a table, keeping
+/// every block of the run pinned until the scan is closed.
+pub struct ChunkScan {
+    tx: Rc<RefCell<Transaction>>,
+    layout: Layout,
+    blob_file_name: String,
+    pages: Vec<RecordPage>,
+    current_page: usize,
+    current_slot: Option<usize>,
+}
+
+impl ChunkScan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        table_name: &str,
+        layout: Layout,
+        start_block: usize,
+        end_block: usize,
+    ) -> ChunkScan {
+        let file_name = format!("{}.tbl", table_name);
+        let pages = (start_block..=end_block)
+            .map(|block_num| {
+                RecordPage::new(
+                    tx.clone(),
+                    BlockId::new(&file_name, block_num),
+                    layout.clone(),
+                )
+            })
+            .collect();
+        ChunkScan {
+            tx,
+            layout,
+            blob_file_name: format!("{}.blob", table_name),
+            pages,
+            current_page: 0,
+            current_slot: None,
+        }
+    }
+}
+
+impl Scan for ChunkScan {
+    fn before_first(&mut self) {
+        self.current_page = 0;
+        self.current_slot = None;
+    }
+
+    fn next(&mut self) -> bool {
+        while let Some(page) = self.pages.get(self.current_page) {
+            self.current_slot = page.next_after(self.current_slot);
+            if self.current_slot.is_some() {
+                return true;
+            }
+            self.current_page += 1;
+        }
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.pages
+            .get(self.current_page)?
+            .get_int(self.current_slot?, field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.pages
+            .get(self.current_page)?
+            .get_string(self.current_slot?, field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        match (self.pages.get(self.current_page), self.current_slot) {
+            (Some(page), Some(slot)) => page.with_str(slot, field_name, f),
+            _ => f(None),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.pages.get(self.current_page)?.get_val(
+            self.current_slot?,
+            field_name,
+            &self.blob_file_name,
+        )
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.layout.schema().has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        for page in self.pages.drain(..) {
+            self.tx.borrow_mut().unpin(page.block_id());
+        }
+    }
+}
+
+/// The product of two plans computed a chunk at a time: the right hand side
+/// is copied to a temporary table, and as many of its blocks as the free
+/// buffers allow are pinned together and joined with one pass over the left
+/// hand side. The left is read once per chunk instead of the right once per
+/// left hand record.
+pub struct MultibufferProductPlan {
+    tx: Rc<RefCell<Transaction>>,
+    lhs: Box<dyn Plan>,
+    rhs: Box<dyn Plan>,
+    schema: Schema,
+}
+
+impl MultibufferProductPlan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        lhs: Box<dyn Plan>,
+        rhs: Box<dyn Plan>,
+    ) -> MultibufferProductPlan {
+        let mut schema = Schema::new();
+        schema.add_all(lhs.schema());
+        schema.add_all(rhs.schema());
+        MultibufferProductPlan {
+            tx,
+            lhs,
+            rhs,
+            schema,
+        }
+    }
+
+    /// The blocks a multibuffer product of `lhs` and `rhs` would read, given
+    /// the buffers free right now. Copying the right hand side is not
+    /// counted, as for materialization.
+    pub fn cost(tx: &Rc<RefCell<Transaction>>, lhs: &dyn Plan, rhs: &dyn Plan) -> usize {
+        let size = temp_table_blocks(tx, rhs);
+        let available = tx.borrow().available_buffers();
+        let chunks = size.div_ceil(BufferNeeds::new(available).best_factor(size));
+        rhs.blocks_accessed() + lhs.blocks_accessed() * chunks.max(1)
+    }
+}
+
+impl Plan for MultibufferProductPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        let temp = TempTable::from_plan(self.tx.clone(), self.rhs.as_ref())?;
+        Ok(Box::new(MultibufferProductScan::new(
+            self.tx.clone(),
+            self.lhs.open()?,
+            temp,
+        )?))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        Self::cost(&self.tx, self.lhs.as_ref(), self.rhs.as_ref())
+    }
+
+    fn records_output(&self) -> usize {
+        self.lhs.records_output() * self.rhs.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        if self.lhs.schema().has_field(field_name) {
+            self.lhs.distinct_values(field_name)
+        } else {
+            self.rhs.distinct_values(field_name)
+        }
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new(
+            "multibuffer product",
+            self,
+            vec![self.lhs.explain(), self.rhs.explain()],
+        )
+    }
+}
+
+/// Joins each chunk of a temporary table with every record of the left hand
+/// scan before moving on to the next chunk.
+pub struct MultibufferProductScan {
+    tx: Rc<RefCell<Transaction>>,
+    lhs: Box<dyn Scan>,
+    rhs: TempTable,
+    chunk: Option<ChunkScan>,
+    lhs_valid: bool,
+    file_size: usize,
+    chunk_size: usize,
+    next_block: usize,
+}
+
+impl MultibufferProductScan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        lhs: Box<dyn Scan>,
+        rhs: TempTable,
+    ) -> Result<MultibufferProductScan, DbError> {
+        let file_size = tx.borrow_mut().size(&format!("{}.tbl", rhs.table_name()))?;
+        let available = tx.borrow().available_buffers();
+        let chunk_size = BufferNeeds::new(available).best_factor(file_size);
+        let mut scan = MultibufferProductScan {
+            tx,
+            lhs,
+            rhs,
+            chunk: None,
+            lhs_valid: false,
+            file_size,
+            chunk_size,
+            next_block: 0,
+        };
+        scan.before_first();
+        Ok(scan)
+    }
+
+    // Pins the next chunk of the right hand side and rewinds the left.
+    fn use_next_chunk(&mut self) -> bool {
+        if self.next_block >= self.file_size {
+            return false;
+        }
+        if let Some(chunk) = self.chunk.as_mut() {
+            chunk.close();
+        }
+        let end_block = (self.next_block + self.chunk_size - 1).min(self.file_size - 1);
+        self.chunk = Some(ChunkScan::new(
+            self.tx.clone(),
+            self.rhs.table_name(),
+            self.rhs.layout().clone(),
+            self.next_block,
+            end_block,
+        ));
+        self.next_block = end_block + 1;
+        self.lhs.before_first();
+        self.lhs_valid = false;
+        true
+    }
+}
+
+impl Scan for MultibufferProductScan {
+    fn before_first(&mut self) {
+        self.next_block = 0;
+        self.use_next_chunk();
+    }
+
+    fn next(&mut self) -> bool {
+        loop {
+            let Some(chunk) = self.chunk.as_mut() else {
+                return false;
+            };
+            if self.lhs_valid && chunk.next() {
+                return true;
+            }
+            self.lhs_valid = self.lhs.next();
+            if self.lhs_valid {
+                chunk.before_first();
+            } else if !self.use_next_chunk() {
+                return false;
+            }
+        }
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_int(field_name)
+        } else {
+            self.chunk.as_ref()?.get_int(field_name)
+        }
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_string(field_name)
+        } else {
+            self.chunk.as_ref()?.get_string(field_name)
+        }
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_str(field_name)
+        } else {
+            self.chunk.as_ref()?.get_str(field_name)
+        }
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        match &self.chunk {
+            _ if self.lhs.has_field(field_name) => self.lhs.with_str(field_name, f),
+            Some(chunk) => chunk.with_str(field_name, f),
+            None => f(None),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        if self.lhs.has_field(field_name) {
+            self.lhs.get_val(field_name)
+        } else {
+            self.chunk.as_ref()?.get_val(field_name)
+        }
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.lhs.has_field(field_name) || self.rhs.layout().schema().has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.lhs.close();
+        if let Some(chunk) = self.chunk.as_mut() {
+            chunk.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use crate::metadata::MetadataMgr;
+    use crate::plan::TablePlan;
+    use crate::query::UpdateScan;
+    use crate::recordmanager::TableScan;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_buffer_needs() {
+        let needs = BufferNeeds::new(8);
+        assert_eq!(needs.best_factor(4), 4);
+        assert_eq!(needs.best_factor(6), 6);
+        assert_eq!(needs.best_factor(10), 5);
+        assert_eq!(needs.best_factor(13), 5);
+        assert_eq!(BufferNeeds::new(2).best_factor(100), 1);
+    }
+
+    #[test]
+    fn test_multibuffer_product() {
+        let tmp_dir = TempDir::new("test_multibuffer_product").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = MetadataMgr::new(true, tx.clone()).unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        metadata_mgr
+            .create_table("t1", &schema, tx.clone())
+            .unwrap();
+        let mut schema = Schema::new();
+        schema.add_int_field("b");
+        schema.add_string_field("c", 20);
+        metadata_mgr
+            .create_table("t2", &schema, tx.clone())
+            .unwrap();
+        let layout = metadata_mgr.get_layout("t1", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t1", layout);
+        for a in 0..5 {
+            scan.insert().unwrap();
+            scan.set_int("a", a).unwrap();
+        }
+        scan.close();
+        // 120 records of 32 bytes take ten blocks, more than fit in a chunk.
+        let layout = metadata_mgr.get_layout("t2", tx.clone()).unwrap();
+        let mut scan = TableScan::new(tx.clone(), "t2", layout);
+        for b in 0..120 {
+            scan.insert().unwrap();
+            scan.set_int("b", b).unwrap();
+            scan.set_string("c", format!("c{}", b)).unwrap();
+        }
+        scan.close();
+
+        let read = |plan: &dyn Plan| {
+            let mut scan = plan.open().unwrap();
+            let mut rows = Vec::new();
+            while scan.next() {
+                rows.push((
+                    scan.get_int("a").unwrap(),
+                    scan.get_int("b").unwrap(),
+                    scan.get_string("c").unwrap(),
+                ));
+            }
+            scan.close();
+            rows.sort();
+            rows
+        };
+        let table = |table_name: &str| -> Box<dyn Plan> {
+            Box::new(TablePlan::new(tx.clone(), table_name, &metadata_mgr).unwrap())
+        };
+        let plan = MultibufferProductPlan::new(tx.clone(), table("t1"), table("t2"));
+        // Less than reading t2 once for every record of t1.
+        assert!(plan.blocks_accessed() < 1 + 5 * 10);
+        let rows = read(&plan);
+        let mut expected = Vec::new();
+        for a in 0..5 {
+            for b in 0..120 {
+                expected.push((a, b, format!("c{}", b)));
+            }
+        }
+        expected.sort();
+        assert_eq!(rows, expected);
+        assert_eq!(tx.borrow().available_buffers(), 8);
+
+        // An empty right hand side gives an empty product.
+        let mut schema = Schema::new();
+        schema.add_int_field("d");
+        metadata_mgr
+            .create_table("empty", &schema, tx.clone())
+            .unwrap();
+        let mut scan = MultibufferProductPlan::new(tx.clone(), table("t1"), table("empty"))
+            .open()
+            .unwrap();
+        assert!(!scan.next());
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
@@ -0,0 +1,1721 @@
+use crate::query::{parse_date, Constant, Expression, Predicate, Term};
+use crate::recordmanager::Schema;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+const KEYWORDS: [&str; 43] = [
+    "select",
+    "from",
+    "where",
+    "and",
+    "insert",
+    "into",
+    "values",
+    "delete",
+    "update",
+    "set",
+    "create",
+    "table",
+    "int",
+    "char",
+    "varchar",
+    "bigint",
+    "double",
+    "boolean",
+    "date",
+    "blob",
+    "true",
+    "false",
+    "view",
+    "as",
+    "index",
+    "on",
+    "group",
+    "by",
+    "explain",
+    "partition",
+    "range",
+    "temp",
+    "check",
+    "is",
+    "not",
+    "null",
+    "default",
+    "current_timestamp",
+    "references",
+    "cascade",
+    "grant",
+    "revoke",
+    "to",
+];
+
+#[derive(Debug)]
+pub struct BadSyntaxError {
+    message: String,
+}
+
+impl BadSyntaxError {
+    pub fn new(message: &str) -> BadSyntaxError {
+        BadSyntaxError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for BadSyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad syntax: {}", self.message)
+    }
+}
+
+impl Error for BadSyntaxError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Delim(char),
+    IntConstant(i32),
+    LongConstant(i64),
+    DoubleConstant(f64),
+    StringConstant(String),
+    BlobConstant(Vec<u8>),
+    Keyword(String),
+    Id(String),
+}
+
+/// Splits a statement into tokens. Keywords and identifiers are case
+/// insensitive and are lowercased; string constants keep their case.
+/// A whole number too large for an int is a bigint, and a number with a
+/// fraction or an exponent is a double. A blob is written in hex, as in
+/// `x'00ff'`.
+pub struct Lexer {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Lexer {
+    pub fn new(input: &str) -> Result<Lexer, BadSyntaxError> {
+        let mut tokens = Vec::new();
+        let mut chars = input.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c.is_ascii_digit() || (c == '-' && Self::starts_number(&chars)) {
+                let mut number = String::new();
+                number.push(c);
+                chars.next();
+                Self::push_digits(&mut chars, &mut number);
+                let mut fraction = false;
+                if chars.peek() == Some(&'.') {
+                    fraction = true;
+                    number.push('.');
+                    chars.next();
+                    Self::push_digits(&mut chars, &mut number);
+                }
+                if let Some(&e) = chars.peek().filter(|e| **e == 'e' || **e == 'E') {
+                    fraction = true;
+                    number.push(e);
+                    chars.next();
+                    if let Some(&sign) = chars.peek().filter(|c| **c == '-' || **c == '+') {
+                        number.push(sign);
+                        chars.next();
+                    }
+                    Self::push_digits(&mut chars, &mut number);
+                }
+                let bad_number = || BadSyntaxError::new(&format!("bad number {}", number));
+                let token = if fraction {
+                    Token::DoubleConstant(number.parse().map_err(|_| bad_number())?)
+                } else if let Ok(val) = number.parse() {
+                    Token::IntConstant(val)
+                } else {
+                    Token::LongConstant(number.parse().map_err(|_| bad_number())?)
+                };
+                tokens.push(token);
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let mut word = String::new();
+                while let Some(&d) = chars
+                    .peek()
+                    .filter(|d| d.is_ascii_alphanumeric() || **d == '_')
+                {
+                    word.push(d.to_ascii_lowercase());
+                    chars.next();
+                }
+                if word == "x" && chars.peek() == Some(&'\'') {
+                    chars.next();
+                    let mut hex = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(d) => hex.push(d),
+                            None => return Err(BadSyntaxError::new("unterminated blob constant")),
+                        }
+                    }
+                    tokens.push(Token::BlobConstant(Self::decode_hex(&hex)?));
+                } else if KEYWORDS.contains(&word.as_str()) {
+                    tokens.push(Token::Keyword(word));
+                } else {
+                    tokens.push(Token::Id(word));
+                }
+            } else if c == '\'' {
+                chars.next();
+                let mut val = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(d) => val.push(d),
+                        None => return Err(BadSyntaxError::new("unterminated string constant")),
+                    }
+                }
+                tokens.push(Token::StringConstant(val));
+            } else {
+                tokens.push(Token::Delim(c));
+                chars.next();
+            }
+        }
+        Ok(Lexer { tokens, pos: 0 })
+    }
+
+    pub fn match_delim(&self, delim: char) -> bool {
+        self.current() == Some(&Token::Delim(delim))
+    }
+
+    pub fn match_string_constant(&self) -> bool {
+        matches!(self.current(), Some(Token::StringConstant(_)))
+    }
+
+    pub fn match_keyword(&self, keyword: &str) -> bool {
+        matches!(self.current(), Some(Token::Keyword(word)) if word == keyword)
+    }
+
+    pub fn match_id(&self) -> bool {
+        matches!(self.current(), Some(Token::Id(_)))
+    }
+
+    /// Whether the next tokens are an identifier and an opening parenthesis,
+    /// as in a call to an aggregate function.
+    pub fn match_call(&self) -> bool {
+        self.match_id() && self.tokens.get(self.pos + 1) == Some(&Token::Delim('('))
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.current().is_none()
+    }
+
+    pub fn eat_delim(&mut self, delim: char) -> Result<(), BadSyntaxError> {
+        if !self.match_delim(delim) {
+            return Err(self.unexpected(&format!("'{}'", delim)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    pub fn eat_int_constant(&mut self) -> Result<i32, BadSyntaxError> {
+        match self.current() {
+            Some(Token::IntConstant(val)) => {
+                let val = *val;
+                self.pos += 1;
+                Ok(val)
+            }
+            _ => Err(self.unexpected("an integer")),
+        }
+    }
+
+    /// A number or a blob, as a constant of its type.
+    pub fn eat_constant(&mut self) -> Result<Constant, BadSyntaxError> {
+        let val = match self.current() {
+            Some(Token::IntConstant(val)) => Constant::Int(*val),
+            Some(Token::LongConstant(val)) => Constant::Long(*val),
+            Some(Token::DoubleConstant(val)) => Constant::Double(*val),
+            Some(Token::BlobConstant(val)) => Constant::Blob(val.clone()),
+            _ => return Err(self.unexpected("a constant")),
+        };
+        self.pos += 1;
+        Ok(val)
+    }
+
+    pub fn eat_string_constant(&mut self) -> Result<String, BadSyntaxError> {
+        match self.current() {
+            Some(Token::StringConstant(val)) => {
+                let val = val.clone();
+                self.pos += 1;
+                Ok(val)
+            }
+            _ => Err(self.unexpected("a string")),
+        }
+    }
+
+    pub fn eat_keyword(&mut self, keyword: &str) -> Result<(), BadSyntaxError> {
+        if !self.match_keyword(keyword) {
+            return Err(self.unexpected(keyword));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    pub fn eat_id(&mut self) -> Result<String, BadSyntaxError> {
+        match self.current() {
+            Some(Token::Id(id)) => {
+                let id = id.clone();
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    fn current(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn unexpected(&self, expected: &str) -> BadSyntaxError {
+        match self.current() {
+            Some(token) => {
+                BadSyntaxError::new(&format!("expected {} but found {:?}", expected, token))
+            }
+            None => BadSyntaxError::new(&format!("expected {} but found end of input", expected)),
+        }
+    }
+
+    fn push_digits(chars: &mut std::iter::Peekable<std::str::Chars>, number: &mut String) {
+        while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+            number.push(d);
+            chars.next();
+        }
+    }
+
+    fn decode_hex(hex: &str) -> Result<Vec<u8>, BadSyntaxError> {
+        let bad_blob = || BadSyntaxError::new(&format!("bad blob constant {}", hex));
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return Err(bad_blob());
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| bad_blob()))
+            .collect()
+    }
+
+    fn starts_number(chars: &std::iter::Peekable<std::str::Chars>) -> bool {
+        let mut rest = chars.clone();
+        rest.next();
+        rest.peek().is_some_and(|c| c.is_ascii_digit())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryData {
+    fields: Vec<String>,
+    tables: Vec<String>,
+    predicate: Predicate,
+    group_fields: Vec<String>,
+    aggregates: Vec<Aggregate>,
+}
+
+impl QueryData {
+    /// The output fields in select-list order. Aggregates appear under their
+    /// output names, such as `countofsid`.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn tables(&self) -> &[String] {
+        &self.tables
+    }
+
+    pub fn predicate(&self) -> &Predicate {
+        &self.predicate
+    }
+
+    pub fn group_fields(&self) -> &[String] {
+        &self.group_fields
+    }
+
+    pub fn aggregates(&self) -> &[Aggregate] {
+        &self.aggregates
+    }
+}
+
+impl Display for QueryData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let select_list: Vec<String> = self
+            .fields
+            .iter()
+            .map(|field_name| {
+                match self
+                    .aggregates
+                    .iter()
+                    .find(|aggregate| aggregate.output_name() == *field_name)
+                {
+                    Some(aggregate) => aggregate.to_string(),
+                    None => field_name.clone(),
+                }
+            })
+            .collect();
+        write!(
+            f,
+            "select {} from {}",
+            select_list.join(", "),
+            self.tables.join(", ")
+        )?;
+        if !self.predicate.is_empty() {
+            write!(f, " where {}", self.predicate)?;
+        }
+        if !self.group_fields.is_empty() {
+            write!(f, " group by {}", self.group_fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExplainFormat {
+    Dot,
+    Json,
+}
+
+impl Display for ExplainFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExplainFormat::Dot => write!(f, "dot"),
+            ExplainFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A statement whose plan `explain` can show.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Explained {
+    Query(QueryData),
+    /// An update, shown as the plan that finds the records it changes.
+    Modify(ModifyData),
+}
+
+impl Display for Explained {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Explained::Query(query) => write!(f, "{}", query),
+            Explained::Modify(modify) => write!(f, "{}", modify),
+        }
+    }
+}
+
+/// An `explain (format dot|json)` statement, which shows the plan of its
+/// query or update instead of running it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainData {
+    format: ExplainFormat,
+    statement: Explained,
+}
+
+impl ExplainData {
+    pub fn format(&self) -> ExplainFormat {
+        self.format
+    }
+
+    pub fn statement(&self) -> &Explained {
+        &self.statement
+    }
+}
+
+impl Display for ExplainData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "explain (format {}) {}", self.format, self.statement)
+    }
+}
+
+/// A `check table` statement, which reports where the table and its
+/// indexes disagree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckTableData {
+    table_name: String,
+}
+
+impl CheckTableData {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+}
+
+pub enum QueryCmd {
+    Select(QueryData),
+    Explain(ExplainData),
+    Check(CheckTableData),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateKind {
+    Count,
+    Max,
+    Min,
+    Sum,
+    Avg,
+    ApproxCountDistinct,
+}
+
+impl AggregateKind {
+    fn from_name(name: &str) -> Option<AggregateKind> {
+        match name {
+            "count" => Some(AggregateKind::Count),
+            "max" => Some(AggregateKind::Max),
+            "min" => Some(AggregateKind::Min),
+            "sum" => Some(AggregateKind::Sum),
+            "avg" => Some(AggregateKind::Avg),
+            "approx_count_distinct" => Some(AggregateKind::ApproxCountDistinct),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AggregateKind::Count => "count",
+            AggregateKind::Max => "max",
+            AggregateKind::Min => "min",
+            AggregateKind::Sum => "sum",
+            AggregateKind::Avg => "avg",
+            AggregateKind::ApproxCountDistinct => "approx_count_distinct",
+        }
+    }
+}
+
+/// An aggregate function applied to a field in a select list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    kind: AggregateKind,
+    field_name: String,
+}
+
+impl Aggregate {
+    pub fn new(kind: AggregateKind, field_name: &str) -> Aggregate {
+        Aggregate {
+            kind,
+            field_name: field_name.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> AggregateKind {
+        self.kind
+    }
+
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    /// The name the aggregate's value is read back under: `count(sid)` is
+    /// `countofsid`.
+    pub fn output_name(&self) -> String {
+        format!("{}of{}", self.kind.name(), self.field_name)
+    }
+}
+
+impl Display for Aggregate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.kind.name(), self.field_name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsertData {
+    table_name: String,
+    fields: Vec<String>,
+    values: Vec<Constant>,
+}
+
+impl InsertData {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn values(&self) -> &[Constant] {
+        &self.values
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeleteData {
+    table_name: String,
+    predicate: Predicate,
+}
+
+impl DeleteData {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn predicate(&self) -> &Predicate {
+        &self.predicate
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModifyData {
+    table_name: String,
+    field_name: String,
+    new_value: Expression,
+    predicate: Predicate,
+}
+
+impl ModifyData {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn target_field(&self) -> &str {
+        &self.field_name
+    }
+
+    pub fn new_value(&self) -> &Expression {
+        &self.new_value
+    }
+
+    pub fn predicate(&self) -> &Predicate {
+        &self.predicate
+    }
+}
+
+impl Display for ModifyData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "update {} set {} = {}",
+            self.table_name, self.field_name, self.new_value
+        )?;
+        if !self.predicate.is_empty() {
+            write!(f, " where {}", self.predicate)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a column is set to when an insert leaves it out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DefaultExpr {
+    Constant(Constant),
+    /// The time of the insert, in seconds since the Unix epoch.
+    CurrentTimestamp,
+    /// The next value of the named sequence.
+    NextVal(String),
+}
+
+impl Display for DefaultExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefaultExpr::Constant(val) => write!(f, "{}", val),
+            DefaultExpr::CurrentTimestamp => write!(f, "current_timestamp"),
+            DefaultExpr::NextVal(sequence_name) => write!(f, "nextval({})", sequence_name),
+        }
+    }
+}
+
+/// What deleting a referenced record does to the records referencing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteAction {
+    /// The referencing records are left as they are.
+    NoAction,
+    /// The referencing records are deleted too.
+    Cascade,
+    /// The referencing field is set to null.
+    SetNull,
+}
+
+impl DeleteAction {
+    pub fn from_name(name: &str) -> Option<DeleteAction> {
+        match name {
+            "no action" => Some(DeleteAction::NoAction),
+            "cascade" => Some(DeleteAction::Cascade),
+            "set null" => Some(DeleteAction::SetNull),
+            _ => None,
+        }
+    }
+}
+
+impl Display for DeleteAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteAction::NoAction => write!(f, "no action"),
+            DeleteAction::Cascade => write!(f, "cascade"),
+            DeleteAction::SetNull => write!(f, "set null"),
+        }
+    }
+}
+
+/// A field declared with `references`, whose values are those of a field
+/// of another table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForeignKey {
+    pub table_name: String,
+    pub field_name: String,
+    pub ref_table: String,
+    pub ref_field: String,
+    pub on_delete: DeleteAction,
+}
+
+#[derive(Clone)]
+pub struct CreateTableData {
+    table_name: String,
+    schema: Schema,
+    defaults: Vec<(String, DefaultExpr)>,
+    foreign_keys: Vec<ForeignKey>,
+    temporary: bool,
+    partition_field: Option<String>,
+    partition_bounds: Vec<i32>,
+}
+
+impl CreateTableData {
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The fields declared with a default, in declaration order.
+    pub fn defaults(&self) -> &[(String, DefaultExpr)] {
+        &self.defaults
+    }
+
+    /// The fields declared with `references`, in declaration order.
+    pub fn foreign_keys(&self) -> &[ForeignKey] {
+        &self.foreign_keys
+    }
+
+    /// Whether the table belongs to the connection that creates it.
+    pub fn is_temporary(&self) -> bool {
+        self.temporary
+    }
+
+    /// The field a range partitioned table is split on.
+    pub fn partition_field(&self) -> Option<&str> {
+        self.partition_field.as_deref()
+    }
+
+    /// The ascending values at which a new partition starts.
+    pub fn partition_bounds(&self) -> &[i32] {
+        &self.partition_bounds
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateViewData {
+    view_name: String,
+    query: QueryData,
+}
+
+impl CreateViewData {
+    pub fn view_name(&self) -> &str {
+        &self.view_name
+    }
+
+    /// The view's query as SQL text, which is what gets stored in the catalog.
+    pub fn view_def(&self) -> String {
+        self.query.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateIndexData {
+    index_name: String,
+    table_name: String,
+    field_name: String,
+}
+
+impl CreateIndexData {
+    pub fn index_name(&self) -> &str {
+        &self.index_name
+    }
+
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+}
+
+/// What a session user may do to a table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    Select,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Display for Privilege {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Privilege::Select => write!(f, "select"),
+            Privilege::Insert => write!(f, "insert"),
+            Privilege::Update => write!(f, "update"),
+            Privilege::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// What a `grant` gives or a `revoke` takes away: privileges on a table,
+/// or membership of a role, which carries every privilege the role has.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Grantable {
+    Privileges {
+        privileges: Vec<Privilege>,
+        table_name: String,
+    },
+    Role(String),
+}
+
+/// A `grant ... to grantee` or `revoke ... from grantee` statement. Users
+/// and roles are both just names; a role is a name others are granted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrantData {
+    granted: Grantable,
+    grantee: String,
+}
+
+impl GrantData {
+    pub fn granted(&self) -> &Grantable {
+        &self.granted
+    }
+
+    pub fn grantee(&self) -> &str {
+        &self.grantee
+    }
+}
+
+/// Every statement other than a query.
+pub enum UpdateData {
+    Insert(InsertData),
+    Delete(DeleteData),
+    Modify(ModifyData),
+    CreateTable(CreateTableData),
+    CreateView(CreateViewData),
+    CreateIndex(CreateIndexData),
+    Grant(GrantData),
+    Revoke(GrantData),
+}
+
+/// Recursive-descent parser for the SimpleDB dialect of SQL.
+pub struct Parser {
+    lexer: Lexer,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Result<Parser, BadSyntaxError> {
+        Ok(Parser {
+            lexer: Lexer::new(input)?,
+        })
+    }
+
+    pub fn field(&mut self) -> Result<String, BadSyntaxError> {
+        self.lexer.eat_id()
+    }
+
+    /// A date is written `date 'YYYY-MM-DD'`.
+    pub fn constant(&mut self) -> Result<Constant, BadSyntaxError> {
+        if self.lexer.match_string_constant() {
+            Ok(Constant::String(self.lexer.eat_string_constant()?))
+        } else if self.lexer.match_keyword("true") {
+            self.lexer.eat_keyword("true")?;
+            Ok(Constant::Bool(true))
+        } else if self.lexer.match_keyword("false") {
+            self.lexer.eat_keyword("false")?;
+            Ok(Constant::Bool(false))
+        } else if self.lexer.match_keyword("date") {
+            self.lexer.eat_keyword("date")?;
+            let text = self.lexer.eat_string_constant()?;
+            let days = parse_date(&text)
+                .ok_or_else(|| BadSyntaxError::new(&format!("bad date {}", text)))?;
+            Ok(Constant::Date(days))
+        } else {
+            self.lexer.eat_constant()
+        }
+    }
+
+    pub fn expression(&mut self) -> Result<Expression, BadSyntaxError> {
+        if self.lexer.match_id() {
+            Ok(Expression::FieldName(self.field()?))
+        } else {
+            Ok(Expression::Constant(self.constant()?))
+        }
+    }
+
+    pub fn term(&mut self) -> Result<Term, BadSyntaxError> {
+        let lhs = self.expression()?;
+        if self.lexer.match_keyword("is") {
+            self.lexer.eat_keyword("is")?;
+            let negated = self.lexer.match_keyword("not");
+            if negated {
+                self.lexer.eat_keyword("not")?;
+            }
+            self.lexer.eat_keyword("null")?;
+            return Ok(if negated {
+                Term::is_not_null(lhs)
+            } else {
+                Term::is_null(lhs)
+            });
+        }
+        self.lexer.eat_delim('=')?;
+        let rhs = self.expression()?;
+        Ok(Term::new(lhs, rhs))
+    }
+
+    pub fn predicate(&mut self) -> Result<Predicate, BadSyntaxError> {
+        let mut predicate = Predicate::from_term(self.term()?);
+        while self.lexer.match_keyword("and") {
+            self.lexer.eat_keyword("and")?;
+            predicate.conjoin_with(Predicate::from_term(self.term()?));
+        }
+        Ok(predicate)
+    }
+
+    /// Parses a whole `select` statement.
+    pub fn query(&mut self) -> Result<QueryData, BadSyntaxError> {
+        let query = self.select()?;
+        self.end()?;
+        Ok(query)
+    }
+
+    /// Parses a whole statement that reads the database: a query, a query
+    /// to explain, or a table to check.
+    pub fn query_cmd(&mut self) -> Result<QueryCmd, BadSyntaxError> {
+        let cmd = if self.lexer.match_keyword("explain") {
+            QueryCmd::Explain(self.explain()?)
+        } else if self.lexer.match_keyword("check") {
+            self.lexer.eat_keyword("check")?;
+            self.lexer.eat_keyword("table")?;
+            QueryCmd::Check(CheckTableData {
+                table_name: self.lexer.eat_id()?,
+            })
+        } else {
+            QueryCmd::Select(self.select()?)
+        };
+        self.end()?;
+        Ok(cmd)
+    }
+
+    /// Parses a whole statement that modifies the database.
+    pub fn update_cmd(&mut self) -> Result<UpdateData, BadSyntaxError> {
+        let update = if self.lexer.match_keyword("insert") {
+            UpdateData::Insert(self.insert()?)
+        } else if self.lexer.match_keyword("delete") {
+            UpdateData::Delete(self.delete()?)
+        } else if self.lexer.match_keyword("update") {
+            UpdateData::Modify(self.modify()?)
+        } else if self.lexer.match_keyword("grant") {
+            self.lexer.eat_keyword("grant")?;
+            let granted = self.grantable()?;
+            self.lexer.eat_keyword("to")?;
+            UpdateData::Grant(GrantData {
+                granted,
+                grantee: self.lexer.eat_id()?,
+            })
+        } else if self.lexer.match_keyword("revoke") {
+            self.lexer.eat_keyword("revoke")?;
+            let granted = self.grantable()?;
+            self.lexer.eat_keyword("from")?;
+            UpdateData::Revoke(GrantData {
+                granted,
+                grantee: self.lexer.eat_id()?,
+            })
+        } else {
+            self.create()?
+        };
+        self.end()?;
+        Ok(update)
+    }
+
+    fn select(&mut self) -> Result<QueryData, BadSyntaxError> {
+        self.lexer.eat_keyword("select")?;
+        let mut fields = Vec::new();
+        let mut aggregates = Vec::new();
+        loop {
+            if self.lexer.match_call() {
+                let aggregate = self.aggregate()?;
+                fields.push(aggregate.output_name());
+                aggregates.push(aggregate);
+            } else {
+                fields.push(self.field()?);
+            }
+            if !self.lexer.match_delim(',') {
+                break;
+            }
+            self.lexer.eat_delim(',')?;
+        }
+        self.lexer.eat_keyword("from")?;
+        let tables = self.table_list()?;
+        let predicate = self.optional_where()?;
+        let mut group_fields = Vec::new();
+        if self.lexer.match_keyword("group") {
+            self.lexer.eat_keyword("group")?;
+            self.lexer.eat_keyword("by")?;
+            group_fields = self.id_list()?;
+        }
+        Ok(QueryData {
+            fields,
+            tables,
+            predicate,
+            group_fields,
+            aggregates,
+        })
+    }
+
+    // The options are plain identifiers so that `format`, `dot` and `json`
+    // stay usable as field names.
+    fn explain(&mut self) -> Result<ExplainData, BadSyntaxError> {
+        self.lexer.eat_keyword("explain")?;
+        self.lexer.eat_delim('(')?;
+        let option = self.lexer.eat_id()?;
+        if option != "format" {
+            return Err(BadSyntaxError::new(&format!(
+                "unknown explain option {}",
+                option
+            )));
+        }
+        let format = match self.lexer.eat_id()?.as_str() {
+            "dot" => ExplainFormat::Dot,
+            "json" => ExplainFormat::Json,
+            other => {
+                return Err(BadSyntaxError::new(&format!(
+                    "unknown explain format {}",
+                    other
+                )))
+            }
+        };
+        self.lexer.eat_delim(')')?;
+        let statement = if self.lexer.match_keyword("update") {
+            Explained::Modify(self.modify()?)
+        } else {
+            Explained::Query(self.select()?)
+        };
+        Ok(ExplainData { format, statement })
+    }
+
+    fn aggregate(&mut self) -> Result<Aggregate, BadSyntaxError> {
+        let name = self.lexer.eat_id()?;
+        let kind = AggregateKind::from_name(&name)
+            .ok_or_else(|| BadSyntaxError::new(&format!("unknown function {}", name)))?;
+        self.lexer.eat_delim('(')?;
+        let field_name = self.field()?;
+        self.lexer.eat_delim(')')?;
+        Ok(Aggregate::new(kind, &field_name))
+    }
+
+    fn insert(&mut self) -> Result<InsertData, BadSyntaxError> {
+        self.lexer.eat_keyword("insert")?;
+        self.lexer.eat_keyword("into")?;
+        let table_name = self.lexer.eat_id()?;
+        self.lexer.eat_delim('(')?;
+        let fields = self.id_list()?;
+        self.lexer.eat_delim(')')?;
+        self.lexer.eat_keyword("values")?;
+        self.lexer.eat_delim('(')?;
+        let mut values = vec![self.constant()?];
+        while self.lexer.match_delim(',') {
+            self.lexer.eat_delim(',')?;
+            values.push(self.constant()?);
+        }
+        self.lexer.eat_delim(')')?;
+        if fields.len() != values.len() {
+            return Err(BadSyntaxError::new(&format!(
+                "{} fields but {} values",
+                fields.len(),
+                values.len()
+            )));
+        }
+        Ok(InsertData {
+            table_name,
+            fields,
+            values,
+        })
+    }
+
+    fn delete(&mut self) -> Result<DeleteData, BadSyntaxError> {
+        self.lexer.eat_keyword("delete")?;
+        self.lexer.eat_keyword("from")?;
+        let table_name = self.lexer.eat_id()?;
+        let predicate = self.optional_where()?;
+        Ok(DeleteData {
+            table_name,
+            predicate,
+        })
+    }
+
+    fn modify(&mut self) -> Result<ModifyData, BadSyntaxError> {
+        self.lexer.eat_keyword("update")?;
+        let table_name = self.lexer.eat_id()?;
+        self.lexer.eat_keyword("set")?;
+        let field_name = self.field()?;
+        self.lexer.eat_delim('=')?;
+        let new_value = self.expression()?;
+        let predicate = self.optional_where()?;
+        Ok(ModifyData {
+            table_name,
+            field_name,
+            new_value,
+            predicate,
+        })
+    }
+
+    // Either `privilege, ... on table` or the name of a role.
+    fn grantable(&mut self) -> Result<Grantable, BadSyntaxError> {
+        if self.lexer.match_id() {
+            return Ok(Grantable::Role(self.lexer.eat_id()?));
+        }
+        let mut privileges = vec![self.privilege()?];
+        while self.lexer.match_delim(',') {
+            self.lexer.eat_delim(',')?;
+            privileges.push(self.privilege()?);
+        }
+        self.lexer.eat_keyword("on")?;
+        Ok(Grantable::Privileges {
+            privileges,
+            table_name: self.lexer.eat_id()?,
+        })
+    }
+
+    fn privilege(&mut self) -> Result<Privilege, BadSyntaxError> {
+        for privilege in [
+            Privilege::Select,
+            Privilege::Insert,
+            Privilege::Update,
+            Privilege::Delete,
+        ] {
+            let name = privilege.to_string();
+            if self.lexer.match_keyword(&name) {
+                self.lexer.eat_keyword(&name)?;
+                return Ok(privilege);
+            }
+        }
+        Err(BadSyntaxError::new(
+            "expected select, insert, update or delete",
+        ))
+    }
+
+    fn create(&mut self) -> Result<UpdateData, BadSyntaxError> {
+        self.lexer.eat_keyword("create")?;
+        if self.lexer.match_keyword("temp") {
+            self.lexer.eat_keyword("temp")?;
+            let mut data = self.create_table()?;
+            data.temporary = true;
+            Ok(UpdateData::CreateTable(data))
+        } else if self.lexer.match_keyword("table") {
+            Ok(UpdateData::CreateTable(self.create_table()?))
+        } else if self.lexer.match_keyword("view") {
+            Ok(UpdateData::CreateView(self.create_view()?))
+        } else {
+            Ok(UpdateData::CreateIndex(self.create_index()?))
+        }
+    }
+
+    fn create_table(&mut self) -> Result<CreateTableData, BadSyntaxError> {
+        self.lexer.eat_keyword("table")?;
+        let table_name = self.lexer.eat_id()?;
+        self.lexer.eat_delim('(')?;
+        let mut schema = Schema::new();
+        let mut defaults = Vec::new();
+        let mut foreign_keys = Vec::new();
+        self.field_def(&table_name, &mut schema, &mut defaults, &mut foreign_keys)?;
+        while self.lexer.match_delim(',') {
+            self.lexer.eat_delim(',')?;
+            self.field_def(&table_name, &mut schema, &mut defaults, &mut foreign_keys)?;
+        }
+        self.lexer.eat_delim(')')?;
+        let mut partition_field = None;
+        let mut partition_bounds = Vec::new();
+        if self.lexer.match_keyword("partition") {
+            self.lexer.eat_keyword("partition")?;
+            self.lexer.eat_keyword("by")?;
+            self.lexer.eat_keyword("range")?;
+            self.lexer.eat_delim('(')?;
+            partition_field = Some(self.field()?);
+            self.lexer.eat_delim(')')?;
+            self.lexer.eat_delim('(')?;
+            partition_bounds.push(self.lexer.eat_int_constant()?);
+            while self.lexer.match_delim(',') {
+                self.lexer.eat_delim(',')?;
+                partition_bounds.push(self.lexer.eat_int_constant()?);
+            }
+            self.lexer.eat_delim(')')?;
+            if partition_bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(BadSyntaxError::new(
+                    "partition bounds must be in ascending order",
+                ));
+            }
+        }
+        Ok(CreateTableData {
+            table_name,
+            schema,
+            defaults,
+            foreign_keys,
+            temporary: false,
+            partition_field,
+            partition_bounds,
+        })
+    }
+
+    // The parenthesized length of a string type.
+    fn type_length(&mut self, type_name: &str) -> Result<usize, BadSyntaxError> {
+        self.lexer.eat_delim('(')?;
+        let length = self.lexer.eat_int_constant()?;
+        self.lexer.eat_delim(')')?;
+        usize::try_from(length)
+            .map_err(|_| BadSyntaxError::new(&format!("{} length must not be negative", type_name)))
+    }
+
+    fn field_def(
+        &mut self,
+        table_name: &str,
+        schema: &mut Schema,
+        defaults: &mut Vec<(String, DefaultExpr)>,
+        foreign_keys: &mut Vec<ForeignKey>,
+    ) -> Result<(), BadSyntaxError> {
+        let field_name = self.field()?;
+        if self.lexer.match_keyword("int") {
+            self.lexer.eat_keyword("int")?;
+            schema.add_int_field(&field_name);
+        } else if self.lexer.match_keyword("bigint") {
+            self.lexer.eat_keyword("bigint")?;
+            schema.add_long_field(&field_name);
+        } else if self.lexer.match_keyword("double") {
+            self.lexer.eat_keyword("double")?;
+            schema.add_double_field(&field_name);
+        } else if self.lexer.match_keyword("boolean") {
+            self.lexer.eat_keyword("boolean")?;
+            schema.add_bool_field(&field_name);
+        } else if self.lexer.match_keyword("date") {
+            self.lexer.eat_keyword("date")?;
+            schema.add_date_field(&field_name);
+        } else if self.lexer.match_keyword("blob") {
+            self.lexer.eat_keyword("blob")?;
+            schema.add_blob_field(&field_name);
+        } else if self.lexer.match_keyword("char") {
+            self.lexer.eat_keyword("char")?;
+            schema.add_char_field(&field_name, self.type_length("char")?);
+        } else {
+            self.lexer.eat_keyword("varchar")?;
+            schema.add_string_field(&field_name, self.type_length("varchar")?);
+        }
+        if self.lexer.match_keyword("default") {
+            self.lexer.eat_keyword("default")?;
+            defaults.push((field_name.clone(), self.default_expr()?));
+        }
+        if self.lexer.match_keyword("references") {
+            self.lexer.eat_keyword("references")?;
+            let ref_table = self.lexer.eat_id()?;
+            self.lexer.eat_delim('(')?;
+            let ref_field = self.field()?;
+            self.lexer.eat_delim(')')?;
+            let mut on_delete = DeleteAction::NoAction;
+            if self.lexer.match_keyword("on") {
+                self.lexer.eat_keyword("on")?;
+                self.lexer.eat_keyword("delete")?;
+                on_delete = if self.lexer.match_keyword("cascade") {
+                    self.lexer.eat_keyword("cascade")?;
+                    DeleteAction::Cascade
+                } else {
+                    self.lexer.eat_keyword("set")?;
+                    self.lexer.eat_keyword("null")?;
+                    DeleteAction::SetNull
+                };
+            }
+            foreign_keys.push(ForeignKey {
+                table_name: table_name.to_string(),
+                field_name,
+                ref_table,
+                ref_field,
+                on_delete,
+            });
+        }
+        Ok(())
+    }
+
+    /// A column default, as written after `default` in a field definition
+    /// and as stored in the catalog.
+    pub fn default_expr(&mut self) -> Result<DefaultExpr, BadSyntaxError> {
+        if self.lexer.match_keyword("current_timestamp") {
+            self.lexer.eat_keyword("current_timestamp")?;
+            return Ok(DefaultExpr::CurrentTimestamp);
+        }
+        if self.lexer.match_call() {
+            let name = self.lexer.eat_id()?;
+            if name != "nextval" {
+                return Err(BadSyntaxError::new(&format!("unknown function {}", name)));
+            }
+            self.lexer.eat_delim('(')?;
+            let sequence_name = self.lexer.eat_id()?;
+            self.lexer.eat_delim(')')?;
+            return Ok(DefaultExpr::NextVal(sequence_name));
+        }
+        Ok(DefaultExpr::Constant(self.constant()?))
+    }
+
+    fn create_view(&mut self) -> Result<CreateViewData, BadSyntaxError> {
+        self.lexer.eat_keyword("view")?;
+        let view_name = self.lexer.eat_id()?;
+        self.lexer.eat_keyword("as")?;
+        let query = self.select()?;
+        Ok(CreateViewData { view_name, query })
+    }
+
+    fn create_index(&mut self) -> Result<CreateIndexData, BadSyntaxError> {
+        self.lexer.eat_keyword("index")?;
+        let index_name = self.lexer.eat_id()?;
+        self.lexer.eat_keyword("on")?;
+        let table_name = self.lexer.eat_id()?;
+        self.lexer.eat_delim('(')?;
+        let field_name = self.field()?;
+        self.lexer.eat_delim(')')?;
+        Ok(CreateIndexData {
+            index_name,
+            table_name,
+            field_name,
+        })
+    }
+
+    fn id_list(&mut self) -> Result<Vec<String>, BadSyntaxError> {
+        let mut ids = vec![self.lexer.eat_id()?];
+        while self.lexer.match_delim(',') {
+            self.lexer.eat_delim(',')?;
+            ids.push(self.lexer.eat_id()?);
+        }
+        Ok(ids)
+    }
+
+    // Tables read by a query, each optionally qualified with the name of an
+    // attached database, as in `otherdb.table`.
+    fn table_list(&mut self) -> Result<Vec<String>, BadSyntaxError> {
+        let mut tables = vec![self.table_name()?];
+        while self.lexer.match_delim(',') {
+            self.lexer.eat_delim(',')?;
+            tables.push(self.table_name()?);
+        }
+        Ok(tables)
+    }
+
+    fn table_name(&mut self) -> Result<String, BadSyntaxError> {
+        let name = self.lexer.eat_id()?;
+        if !self.lexer.match_delim('.') {
+            return Ok(name);
+        }
+        self.lexer.eat_delim('.')?;
+        Ok(format!("{}.{}", name, self.lexer.eat_id()?))
+    }
+
+    fn optional_where(&mut self) -> Result<Predicate, BadSyntaxError> {
+        if self.lexer.match_keyword("where") {
+            self.lexer.eat_keyword("where")?;
+            self.predicate()
+        } else {
+            Ok(Predicate::new())
+        }
+    }
+
+    // A trailing semicolon is allowed; anything else left over is an error.
+    fn end(&mut self) -> Result<(), BadSyntaxError> {
+        if self.lexer.match_delim(';') {
+            self.lexer.eat_delim(';')?;
+        }
+        if !self.lexer.at_end() {
+            return Err(self.lexer.unexpected("end of statement"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recordmanager::{INTEGER, VARCHAR};
+
+    #[test]
+    fn test_lexer() {
+        let mut lexer = Lexer::new("SELECT a FROM T where b = -12 AND c='Hi there'").unwrap();
+        lexer.eat_keyword("select").unwrap();
+        assert_eq!(lexer.eat_id().unwrap(), "a");
+        lexer.eat_keyword("from").unwrap();
+        assert_eq!(lexer.eat_id().unwrap(), "t");
+        lexer.eat_keyword("where").unwrap();
+        assert!(lexer.match_id());
+        assert!(lexer.eat_int_constant().is_err());
+        assert_eq!(lexer.eat_id().unwrap(), "b");
+        lexer.eat_delim('=').unwrap();
+        assert_eq!(lexer.eat_int_constant().unwrap(), -12);
+        lexer.eat_keyword("and").unwrap();
+        assert_eq!(lexer.eat_id().unwrap(), "c");
+        lexer.eat_delim('=').unwrap();
+        assert_eq!(lexer.eat_string_constant().unwrap(), "Hi there");
+        assert!(lexer.at_end());
+
+        assert!(Lexer::new("select 'oops").is_err());
+
+        let mut lexer = Lexer::new("3000000000 -1.5 2e3 x'00fF' x").unwrap();
+        assert_eq!(lexer.eat_constant().unwrap(), Constant::Long(3_000_000_000));
+        assert_eq!(lexer.eat_constant().unwrap(), Constant::Double(-1.5));
+        assert_eq!(lexer.eat_constant().unwrap(), Constant::Double(2000.0));
+        assert_eq!(lexer.eat_constant().unwrap(), Constant::Blob(vec![0, 255]));
+        assert_eq!(lexer.eat_id().unwrap(), "x");
+        assert!(Lexer::new("x'0'").is_err());
+        assert!(Lexer::new("1e").is_err());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = Parser::new("select a, b from t1, t2 where a = c and b = 'x';")
+            .unwrap()
+            .query()
+            .unwrap();
+        assert_eq!(query.fields(), &["a".to_string(), "b".to_string()]);
+        assert_eq!(query.tables(), &["t1".to_string(), "t2".to_string()]);
+        assert_eq!(
+            query.to_string(),
+            "select a, b from t1, t2 where a = c and b = 'x'"
+        );
+
+        // The printed form of a query parses back to the same query.
+        let reparsed = Parser::new(&query.to_string()).unwrap().query().unwrap();
+        assert_eq!(reparsed, query);
+
+        let query = Parser::new("select a from t where b is null and c is not null")
+            .unwrap()
+            .query()
+            .unwrap();
+        assert_eq!(
+            query.to_string(),
+            "select a from t where b is null and c is not null"
+        );
+        assert!(Parser::new("select a from t where b is 1")
+            .unwrap()
+            .query()
+            .is_err());
+
+        assert!(Parser::new("select from t").unwrap().query().is_err());
+        assert!(Parser::new("select a from t b").unwrap().query().is_err());
+
+        let query = Parser::new("select a from otherdb.t1, t2")
+            .unwrap()
+            .query()
+            .unwrap();
+        assert_eq!(
+            query.tables(),
+            &["otherdb.t1".to_string(), "t2".to_string()]
+        );
+        assert!(Parser::new("select a from otherdb.")
+            .unwrap()
+            .query()
+            .is_err());
+
+        let query =
+            Parser::new("select dept, count(sid), MAX(grade) from t where x = 1 group by dept")
+                .unwrap()
+                .query()
+                .unwrap();
+        assert_eq!(
+            query.fields(),
+            &[
+                "dept".to_string(),
+                "countofsid".to_string(),
+                "maxofgrade".to_string()
+            ]
+        );
+        assert_eq!(query.group_fields(), &["dept".to_string()]);
+        assert_eq!(
+            query.aggregates()[1],
+            Aggregate::new(AggregateKind::Max, "grade")
+        );
+        assert_eq!(
+            query.to_string(),
+            "select dept, count(sid), max(grade) from t where x = 1 group by dept"
+        );
+        let reparsed = Parser::new(&query.to_string()).unwrap().query().unwrap();
+        assert_eq!(reparsed, query);
+        let query = Parser::new("select Approx_Count_Distinct(a) from t")
+            .unwrap()
+            .query()
+            .unwrap();
+        assert_eq!(query.fields(), &["approx_count_distinctofa".to_string()]);
+        assert_eq!(query.to_string(), "select approx_count_distinct(a) from t");
+        assert!(Parser::new("select median(a) from t")
+            .unwrap()
+            .query()
+            .is_err());
+        assert!(Parser::new("select a from t group a")
+            .unwrap()
+            .query()
+            .is_err());
+
+        let Ok(QueryCmd::Explain(explain)) =
+            Parser::new("EXPLAIN (FORMAT DOT) select a from t where a = 1")
+                .unwrap()
+                .query_cmd()
+        else {
+            panic!("expected an explain statement");
+        };
+        assert_eq!(explain.format(), ExplainFormat::Dot);
+        let Explained::Query(query) = explain.statement() else {
+            panic!("expected an explained query");
+        };
+        assert_eq!(query.tables(), &["t".to_string()]);
+        assert_eq!(
+            explain.to_string(),
+            "explain (format dot) select a from t where a = 1"
+        );
+        let Ok(QueryCmd::Explain(explain)) =
+            Parser::new("explain (format json) update t set b = 2 where a = 1")
+                .unwrap()
+                .query_cmd()
+        else {
+            panic!("expected an explain statement");
+        };
+        assert!(matches!(explain.statement(), Explained::Modify(_)));
+        assert_eq!(
+            explain.to_string(),
+            "explain (format json) update t set b = 2 where a = 1"
+        );
+        assert!(matches!(
+            Parser::new("select a from t").unwrap().query_cmd(),
+            Ok(QueryCmd::Select(_))
+        ));
+        let Ok(QueryCmd::Check(check)) = Parser::new("CHECK TABLE t;").unwrap().query_cmd() else {
+            panic!("expected a check statement");
+        };
+        assert_eq!(check.table_name(), "t");
+        for sql in [
+            "check t",
+            "check table",
+            "check table t, u",
+            "explain select a from t",
+            "explain (format xml) select a from t",
+            "explain (style json) select a from t",
+        ] {
+            assert!(Parser::new(sql).unwrap().query_cmd().is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_update_cmd() {
+        let cmd = Parser::new("insert into t (a, b) values (1, 'one')")
+            .unwrap()
+            .update_cmd()
+            .unwrap();
+        match cmd {
+            UpdateData::Insert(data) => {
+                assert_eq!(data.table_name(), "t");
+                assert_eq!(
+                    data.values(),
+                    &[Constant::Int(1), Constant::String("one".to_string())]
+                );
+            }
+            _ => panic!("expected an insert"),
+        }
+        assert!(Parser::new("insert into t (a, b) values (1)")
+            .unwrap()
+            .update_cmd()
+            .is_err());
+
+        match Parser::new("delete from t").unwrap().update_cmd().unwrap() {
+            UpdateData::Delete(data) => assert!(data.predicate().is_empty()),
+            _ => panic!("expected a delete"),
+        }
+
+        match Parser::new("update t set a = 5 where b = 'x'")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::Modify(data) => {
+                assert_eq!(data.target_field(), "a");
+                assert_eq!(data.new_value(), &Expression::Constant(Constant::Int(5)));
+                assert_eq!(data.predicate().to_string(), "b = 'x'");
+            }
+            _ => panic!("expected an update"),
+        }
+
+        match Parser::new("create table t (a int, b varchar(9))")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::CreateTable(data) => {
+                assert_eq!(data.table_name(), "t");
+                assert_eq!(data.schema().field_type("a"), Some(INTEGER));
+                assert_eq!(data.schema().field_type("b"), Some(VARCHAR));
+                assert_eq!(data.schema().length("b"), Some(9));
+                assert_eq!(data.partition_field(), None);
+                assert!(!data.is_temporary());
+            }
+            _ => panic!("expected a create table"),
+        }
+
+        match Parser::new("create temp table t (a int)")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::CreateTable(data) => {
+                assert_eq!(data.table_name(), "t");
+                assert!(data.is_temporary());
+            }
+            _ => panic!("expected a create table"),
+        }
+
+        match Parser::new("create table t (a int, b int) partition by range (a) (10, 20)")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::CreateTable(data) => {
+                assert_eq!(data.partition_field(), Some("a"));
+                assert_eq!(data.partition_bounds(), &[10, 20]);
+            }
+            _ => panic!("expected a create table"),
+        }
+        match Parser::new(
+            "create table t (a int default nextval(ids), b varchar(3) default 'x', \
+             c int default current_timestamp)",
+        )
+        .unwrap()
+        .update_cmd()
+        .unwrap()
+        {
+            UpdateData::CreateTable(data) => {
+                assert_eq!(
+                    data.defaults(),
+                    &[
+                        ("a".to_string(), DefaultExpr::NextVal("ids".to_string())),
+                        (
+                            "b".to_string(),
+                            DefaultExpr::Constant(Constant::String("x".to_string()))
+                        ),
+                        ("c".to_string(), DefaultExpr::CurrentTimestamp),
+                    ]
+                );
+                // The printed form is what the catalog stores and parses back.
+                let expr = data.defaults()[0].1.to_string();
+                assert_eq!(expr, "nextval(ids)");
+                assert_eq!(
+                    Parser::new(&expr).unwrap().default_expr().unwrap(),
+                    data.defaults()[0].1
+                );
+            }
+            _ => panic!("expected a create table"),
+        }
+        match Parser::new(
+            "create table t (a int references p (id) on delete cascade, \
+             b int default 0 references q (id) on delete set null, c int references p (c))",
+        )
+        .unwrap()
+        .update_cmd()
+        .unwrap()
+        {
+            UpdateData::CreateTable(data) => {
+                let actions: Vec<(&str, &str, &str, DeleteAction)> = data
+                    .foreign_keys()
+                    .iter()
+                    .map(|key| {
+                        assert_eq!(key.table_name, "t");
+                        (
+                            key.field_name.as_str(),
+                            key.ref_table.as_str(),
+                            key.ref_field.as_str(),
+                            key.on_delete,
+                        )
+                    })
+                    .collect();
+                assert_eq!(
+                    actions,
+                    vec![
+                        ("a", "p", "id", DeleteAction::Cascade),
+                        ("b", "q", "id", DeleteAction::SetNull),
+                        ("c", "p", "c", DeleteAction::NoAction),
+                    ]
+                );
+                assert_eq!(data.defaults().len(), 1);
+                for action in [DeleteAction::Cascade, DeleteAction::SetNull] {
+                    assert_eq!(DeleteAction::from_name(&action.to_string()), Some(action));
+                }
+            }
+            _ => panic!("expected a create table"),
+        }
+        assert!(
+            Parser::new("create table t (a int references p (id) on delete restrict)")
+                .unwrap()
+                .update_cmd()
+                .is_err()
+        );
+        assert!(Parser::new("create table t (a int default now())")
+            .unwrap()
+            .update_cmd()
+            .is_err());
+        assert!(
+            Parser::new("create table t (a int) partition by range (a) (20, 10)")
+                .unwrap()
+                .update_cmd()
+                .is_err()
+        );
+
+        match Parser::new("create view v as select a from t where b = 1")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::CreateView(data) => {
+                assert_eq!(data.view_name(), "v");
+                assert_eq!(data.view_def(), "select a from t where b = 1");
+            }
+            _ => panic!("expected a create view"),
+        }
+
+        match Parser::new("create index idx on t (a)")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::CreateIndex(data) => {
+                assert_eq!(data.index_name(), "idx");
+                assert_eq!(data.table_name(), "t");
+                assert_eq!(data.field_name(), "a");
+            }
+            _ => panic!("expected a create index"),
+        }
+
+        assert!(Parser::new("create sequence s")
+            .unwrap()
+            .update_cmd()
+            .is_err());
+
+        match Parser::new("grant select, insert on t to analyst")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::Grant(data) => {
+                assert_eq!(
+                    data.granted(),
+                    &Grantable::Privileges {
+                        privileges: vec![Privilege::Select, Privilege::Insert],
+                        table_name: "t".to_string(),
+                    }
+                );
+                assert_eq!(data.grantee(), "analyst");
+            }
+            _ => panic!("expected a grant"),
+        }
+        match Parser::new("revoke analyst from alice")
+            .unwrap()
+            .update_cmd()
+            .unwrap()
+        {
+            UpdateData::Revoke(data) => {
+                assert_eq!(data.granted(), &Grantable::Role("analyst".to_string()));
+                assert_eq!(data.grantee(), "alice");
+            }
+            _ => panic!("expected a revoke"),
+        }
+        assert!(Parser::new("grant select on t from alice")
+            .unwrap()
+            .update_cmd()
+            .is_err());
+    }
+}
@@ -0,0 +1,3435 @@
+use crate::attach::{split_qualified, Attachments};
+use crate::buffermanager::MAINTENANCE_RING_SIZE;
+use crate::changecapture::{ChangeCapture, ChangeOp};
+use crate::error::DbError;
+use crate::index::{BTreeIndex, Index, IndexJoinScan, IndexSelectScan};
+use crate::materialize::{is_sorted_on, GroupByPlan, MergeJoinPlan};
+use crate::metadata::{
+    IndexInfo, MetadataMgr, RetentionPolicy, StatInfo, MAINTENANCE_VIEW, MAX_DEFAULT, MAX_NAME,
+    MAX_VIEWDEF, STORAGE_VIEW,
+};
+use crate::multibuffer::MultibufferProductPlan;
+use crate::parse::{
+    BadSyntaxError, CheckTableData, CreateIndexData, CreateTableData, CreateViewData, DefaultExpr,
+    DeleteAction, DeleteData, ExplainFormat, Explained, ForeignKey, GrantData, Grantable,
+    InsertData, ModifyData, Parser, Privilege, QueryCmd, QueryData, UpdateData,
+};
+use crate::query::{Constant, Predicate, ProjectScan, Scan, SelectScan, UpdateScan};
+use crate::recordmanager::{
+    pad, Layout, PartitionScan, Rid, Schema, TableScan, BIGINT, BLOB, BOOLEAN, CHAR, DATE, DOUBLE,
+    INTEGER, VARCHAR,
+};
+use crate::transaction::Transaction;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum PlanError {
+    BadSyntax(BadSyntaxError),
+    UnknownTable(String),
+    UnknownField(String),
+    TableExists(String),
+    TypeMismatch(String),
+    ValueTooLong(String),
+    Unsupported(String),
+    /// A statement went past one of its connection's `ResourceLimits`.
+    LimitExceeded(Limit),
+    /// The storage layers failed the statement, such as when the disk filled
+    /// up under it. The statement was rolled back.
+    Storage(DbError),
+    /// The session user may not run the statement.
+    PermissionDenied {
+        user: String,
+        action: String,
+    },
+}
+
+/// The resource limit a statement went past.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    TempSpace,
+    PinnedBuffers,
+    ResultRows,
+    Runtime,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::TempSpace => write!(f, "temporary space"),
+            Limit::PinnedBuffers => write!(f, "pinned buffers"),
+            Limit::ResultRows => write!(f, "result rows"),
+            Limit::Runtime => write!(f, "statement runtime"),
+        }
+    }
+}
+
+impl Display for PlanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::BadSyntax(err) => write!(f, "{}", err),
+            PlanError::UnknownTable(table) => write!(f, "table {} does not exist", table),
+            PlanError::UnknownField(field) => write!(f, "field {} does not exist", field),
+            PlanError::TableExists(table) => write!(f, "table {} already exists", table),
+            PlanError::TypeMismatch(field) => write!(f, "wrong type of value for field {}", field),
+            PlanError::ValueTooLong(field) => write!(f, "value too long for field {}", field),
+            PlanError::Unsupported(what) => write!(f, "{} is not supported", what),
+            PlanError::LimitExceeded(limit) => write!(f, "{} limit exceeded", limit),
+            PlanError::Storage(err) => write!(f, "{}", err),
+            PlanError::PermissionDenied { user, action } => {
+                write!(f, "user {} may not {}", user, action)
+            }
+        }
+    }
+}
+
+impl Error for PlanError {}
+
+impl From<BadSyntaxError> for PlanError {
+    fn from(err: BadSyntaxError) -> PlanError {
+        PlanError::BadSyntax(err)
+    }
+}
+
+impl From<DbError> for PlanError {
+    fn from(err: DbError) -> PlanError {
+        PlanError::Storage(err)
+    }
+}
+
+/// A node of a query tree. It can estimate its own cost without touching the
+/// data and opens a `Scan` that produces its records.
+pub trait Plan {
+    /// Opens a scan over the plan's records. Operators that materialize their
+    /// input write it out to temporary tables here, which can fail.
+    fn open(&self) -> Result<Box<dyn Scan>, DbError>;
+    fn blocks_accessed(&self) -> usize;
+    fn records_output(&self) -> usize;
+    fn distinct_values(&self, field_name: &str) -> usize;
+    fn schema(&self) -> &Schema;
+    /// Describes the plan and its inputs for `explain`.
+    fn explain(&self) -> PlanNode;
+    /// The fields the plan's records come out sorted on, most significant
+    /// first, so that an operator needing that order can skip its sort.
+    fn sort_order(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// One operator of a plan tree with its estimated cost, as shown by
+/// `explain`. `details` holds the operator's arguments, such as the table it
+/// reads or the index it searches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanNode {
+    pub operator: String,
+    pub details: Vec<(String, String)>,
+    pub blocks_accessed: usize,
+    pub records_output: usize,
+    pub children: Vec<PlanNode>,
+}
+
+impl PlanNode {
+    pub fn new(operator: &str, plan: &dyn Plan, children: Vec<PlanNode>) -> PlanNode {
+        PlanNode {
+            operator: operator.to_string(),
+            details: Vec::new(),
+            blocks_accessed: plan.blocks_accessed(),
+            records_output: plan.records_output(),
+            children,
+        }
+    }
+
+    pub fn detail(mut self, name: &str, value: impl Display) -> PlanNode {
+        self.details.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The tree as a Graphviz digraph, with an edge from each operator to
+    /// each of its inputs.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph plan {\n");
+        self.write_dot(&mut out, &mut 0);
+        out.push('}');
+        out
+    }
+
+    // Writes this node and its subtree, numbering nodes in preorder, and
+    // returns this node's number.
+    fn write_dot(&self, out: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        let mut lines = vec![escape_label(&self.operator)];
+        for (name, value) in self.details.iter() {
+            lines.push(format!("{}: {}", escape_label(name), escape_label(value)));
+        }
+        lines.push(format!(
+            "blocks: {}, records: {}",
+            self.blocks_accessed, self.records_output
+        ));
+        out.push_str(&format!(
+            "  node{} [label=\"{}\"];\n",
+            id,
+            lines.join("\\n")
+        ));
+        for child in self.children.iter() {
+            let child_id = child.write_dot(out, next_id);
+            out.push_str(&format!("  node{} -> node{};\n", id, child_id));
+        }
+        id
+    }
+
+    pub fn to_json(&self) -> String {
+        let details: Vec<String> = self
+            .details
+            .iter()
+            .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+            .collect();
+        let children: Vec<String> = self.children.iter().map(PlanNode::to_json).collect();
+        format!(
+            "{{\"operator\":{},\"details\":{{{}}},\"blocks_accessed\":{},\"records_output\":{},\"children\":[{}]}}",
+            json_string(&self.operator),
+            details.join(","),
+            self.blocks_accessed,
+            self.records_output,
+            children.join(",")
+        )
+    }
+}
+
+fn escape_label(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn json_string(val: &str) -> String {
+    let mut out = String::from("\"");
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub struct TablePlan {
+    tx: Rc<RefCell<Transaction>>,
+    table_name: String,
+    layout: Layout,
+    schema: Schema,
+    stat_info: StatInfo,
+    // The heap files left to read: the partitions the predicate allows, or
+    // the one file of a table that is not partitioned.
+    heap_names: Vec<String>,
+    partitioned: bool,
+}
+
+impl TablePlan {
+    #[cfg(test)]
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        table_name: &str,
+        metadata_mgr: &MetadataMgr,
+    ) -> Result<TablePlan, PlanError> {
+        Self::with_predicate(tx, table_name, metadata_mgr, &Predicate::new())
+    }
+
+    /// A plan that leaves out the partitions of the table that cannot hold
+    /// records satisfying `predicate`.
+    pub fn with_predicate(
+        tx: Rc<RefCell<Transaction>>,
+        table_name: &str,
+        metadata_mgr: &MetadataMgr,
+        predicate: &Predicate,
+    ) -> Result<TablePlan, PlanError> {
+        let layout = metadata_mgr
+            .get_layout(table_name, tx.clone())
+            .ok_or_else(|| PlanError::UnknownTable(table_name.to_string()))?;
+        let partitioned = metadata_mgr
+            .get_partitioning(table_name, tx.clone())
+            .is_some();
+        let heap_names = metadata_mgr.scan_heaps(table_name, predicate, tx.clone());
+        let mut stat_info = StatInfo::default();
+        for heap_name in heap_names.iter() {
+            stat_info.merge(&metadata_mgr.get_stat_info(heap_name, &layout, tx.clone()));
+        }
+        Ok(TablePlan {
+            tx,
+            table_name: table_name.to_string(),
+            schema: Schema::of_table(table_name, &layout),
+            layout,
+            stat_info,
+            heap_names,
+            partitioned,
+        })
+    }
+}
+
+impl TablePlan {
+    fn table_scan(&self) -> TableScan {
+        TableScan::new(self.tx.clone(), &self.table_name, self.layout.clone())
+    }
+}
+
+impl Plan for TablePlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(match self.heap_names.as_slice() {
+            [heap_name] => Box::new(TableScan::new(
+                self.tx.clone(),
+                heap_name,
+                self.layout.clone(),
+            )),
+            heap_names => Box::new(PartitionScan::new(
+                self.tx.clone(),
+                heap_names.to_vec(),
+                self.layout.clone(),
+            )),
+        })
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        self.stat_info.blocks_accessed()
+    }
+
+    fn records_output(&self) -> usize {
+        self.stat_info.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        self.stat_info.distinct_values(field_name)
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        let node = PlanNode::new("table", self, Vec::new()).detail("table", &self.table_name);
+        if self.partitioned {
+            node.detail("partitions", self.heap_names.join(", "))
+        } else {
+            node
+        }
+    }
+}
+
+/// Output sizes that earlier selections actually produced, keyed by a
+/// description of the selection. The most recent complete run wins, so the
+/// figures follow the data as it changes.
+#[derive(Default)]
+pub struct CardinalityFeedback {
+    actual: HashMap<String, usize>,
+}
+
+impl CardinalityFeedback {
+    pub fn new() -> CardinalityFeedback {
+        CardinalityFeedback {
+            actual: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str, rows: usize) {
+        self.actual.insert(key.to_string(), rows);
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<usize> {
+        self.actual.get(key).copied()
+    }
+}
+
+// Counts the records a scan produces and reports the total to the feedback
+// cache once the scan has been read to the end.
+struct CountingScan<S: Scan> {
+    scan: S,
+    key: String,
+    feedback: Arc<Mutex<CardinalityFeedback>>,
+    count: usize,
+}
+
+impl<S: Scan> Scan for CountingScan<S> {
+    fn before_first(&mut self) {
+        self.count = 0;
+        self.scan.before_first();
+    }
+
+    fn next(&mut self) -> bool {
+        if self.scan.next() {
+            self.count += 1;
+            return true;
+        }
+        self.feedback.lock().unwrap().record(&self.key, self.count);
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.scan.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.scan.get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.scan.get_str(field_name)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.scan.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.scan.has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.scan.close();
+    }
+}
+
+pub struct SelectPlan {
+    plan: Box<dyn Plan>,
+    predicate: Predicate,
+    feedback: Option<(String, Arc<Mutex<CardinalityFeedback>>)>,
+}
+
+impl SelectPlan {
+    pub fn new(plan: Box<dyn Plan>, predicate: Predicate) -> SelectPlan {
+        SelectPlan {
+            predicate: predicate.padded(plan.schema()),
+            plan,
+            feedback: None,
+        }
+    }
+
+    /// A selection that estimates its output from the last time a selection
+    /// with the same `key` ran to completion, and records its own output
+    /// when it does.
+    pub fn with_feedback(
+        plan: Box<dyn Plan>,
+        predicate: Predicate,
+        key: String,
+        feedback: Arc<Mutex<CardinalityFeedback>>,
+    ) -> SelectPlan {
+        SelectPlan {
+            predicate: predicate.padded(plan.schema()),
+            plan,
+            feedback: Some((key, feedback)),
+        }
+    }
+}
+
+impl Plan for SelectPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        let scan = SelectScan::new(self.plan.open()?, self.predicate.clone());
+        Ok(match &self.feedback {
+            Some((key, feedback)) => Box::new(CountingScan {
+                scan,
+                key: key.clone(),
+                feedback: feedback.clone(),
+                count: 0,
+            }),
+            None => Box::new(scan),
+        })
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        self.plan.blocks_accessed()
+    }
+
+    fn records_output(&self) -> usize {
+        if let Some((key, feedback)) = &self.feedback {
+            if let Some(rows) = feedback.lock().unwrap().lookup(key) {
+                return rows;
+            }
+        }
+        self.plan.records_output() / self.predicate.reduction_factor(self.plan.as_ref())
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        if self.predicate.equates_with_constant(field_name).is_some() {
+            return 1;
+        }
+        let distinct = match self.predicate.equates_with_field(field_name) {
+            Some(other) => self
+                .plan
+                .distinct_values(field_name)
+                .min(self.plan.distinct_values(other)),
+            None => self.plan.distinct_values(field_name),
+        };
+        distinct.min(self.records_output())
+    }
+
+    fn schema(&self) -> &Schema {
+        self.plan.schema()
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("select", self, vec![self.plan.explain()])
+            .detail("predicate", &self.predicate)
+    }
+
+    fn sort_order(&self) -> Vec<String> {
+        self.plan.sort_order()
+    }
+}
+
+pub struct ProjectPlan {
+    plan: Box<dyn Plan>,
+    schema: Schema,
+}
+
+impl ProjectPlan {
+    pub fn new(plan: Box<dyn Plan>, fields: &[String]) -> Result<ProjectPlan, PlanError> {
+        let mut schema = Schema::new();
+        for field_name in fields {
+            if !plan.schema().has_field(field_name) {
+                return Err(PlanError::UnknownField(field_name.clone()));
+            }
+            schema.add(field_name, plan.schema());
+        }
+        Ok(ProjectPlan { plan, schema })
+    }
+}
+
+impl Plan for ProjectPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(ProjectScan::new(
+            self.plan.open()?,
+            self.schema.fields().to_vec(),
+        )))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        self.plan.blocks_accessed()
+    }
+
+    fn records_output(&self) -> usize {
+        self.plan.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        self.plan.distinct_values(field_name)
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("project", self, vec![self.plan.explain()])
+            .detail("fields", self.schema.fields().join(", "))
+    }
+
+    // The order survives up to the first field projected away.
+    fn sort_order(&self) -> Vec<String> {
+        self.plan
+            .sort_order()
+            .into_iter()
+            .take_while(|field_name| self.schema.has_field(field_name))
+            .collect()
+    }
+}
+
+/// Selects the records of a table whose indexed field equals a constant.
+pub struct IndexSelectPlan {
+    plan: TablePlan,
+    index_info: IndexInfo,
+    val: Constant,
+}
+
+impl IndexSelectPlan {
+    pub fn new(plan: TablePlan, index_info: IndexInfo, val: Constant) -> IndexSelectPlan {
+        IndexSelectPlan {
+            plan,
+            index_info,
+            val,
+        }
+    }
+}
+
+impl Plan for IndexSelectPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(IndexSelectScan::new(
+            self.plan.table_scan(),
+            self.index_info.open()?,
+            self.val.clone(),
+        )))
+    }
+
+    // One index lookup, then a block for each matching record.
+    fn blocks_accessed(&self) -> usize {
+        self.index_info.blocks_accessed() + self.records_output()
+    }
+
+    fn records_output(&self) -> usize {
+        self.index_info.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        self.index_info.distinct_values(field_name)
+    }
+
+    fn schema(&self) -> &Schema {
+        self.plan.schema()
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("index select", self, vec![self.plan.explain()])
+            .detail("index", self.index_info.index_name())
+            .detail("field", self.index_info.field_name())
+            .detail("value", &self.val)
+    }
+
+    // Every record has the same value for the indexed field.
+    fn sort_order(&self) -> Vec<String> {
+        vec![self.index_info.field_name().to_string()]
+    }
+}
+
+/// Joins each record on the left with the table records whose indexed field
+/// equals the left record's `join_field`.
+pub struct IndexJoinPlan {
+    lhs: Box<dyn Plan>,
+    rhs: TablePlan,
+    index_info: IndexInfo,
+    join_field: String,
+    schema: Schema,
+}
+
+impl IndexJoinPlan {
+    pub fn new(
+        lhs: Box<dyn Plan>,
+        rhs: TablePlan,
+        index_info: IndexInfo,
+        join_field: &str,
+    ) -> IndexJoinPlan {
+        let mut schema = Schema::new();
+        schema.add_all(lhs.schema());
+        schema.add_all(rhs.schema());
+        IndexJoinPlan {
+            lhs,
+            rhs,
+            index_info,
+            join_field: join_field.to_string(),
+            schema,
+        }
+    }
+}
+
+impl Plan for IndexJoinPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(IndexJoinScan::new(
+            self.lhs.open()?,
+            self.index_info.open()?,
+            &self.join_field,
+            self.rhs.table_scan(),
+        )))
+    }
+
+    // The index is searched once for every record on the left.
+    fn blocks_accessed(&self) -> usize {
+        self.lhs.blocks_accessed()
+            + self.lhs.records_output() * self.index_info.blocks_accessed()
+            + self.records_output()
+    }
+
+    fn records_output(&self) -> usize {
+        self.lhs.records_output() * self.index_info.records_output()
+    }
+
+    fn distinct_values(&self, field_name: &str) -> usize {
+        if self.lhs.schema().has_field(field_name) {
+            self.lhs.distinct_values(field_name)
+        } else {
+            self.rhs.distinct_values(field_name)
+        }
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new(
+            "index join",
+            self,
+            vec![self.lhs.explain(), self.rhs.explain()],
+        )
+        .detail("index", self.index_info.index_name())
+        .detail(
+            "condition",
+            format!("{} = {}", self.join_field, self.index_info.field_name()),
+        )
+    }
+
+    fn sort_order(&self) -> Vec<String> {
+        self.lhs.sort_order()
+    }
+}
+
+/// The result of `explain`: the plan tree rendered in the requested format,
+/// one line per record in a field named `plan`.
+pub struct ExplainPlan {
+    lines: Vec<String>,
+    schema: Schema,
+}
+
+impl ExplainPlan {
+    pub fn new(format: ExplainFormat, plan: &dyn Plan) -> ExplainPlan {
+        let text = match format {
+            ExplainFormat::Dot => plan.explain().to_dot(),
+            ExplainFormat::Json => plan.explain().to_json(),
+        };
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let mut schema = Schema::new();
+        let width = lines.iter().map(String::len).max().unwrap_or(0);
+        schema.add_string_field("plan", width);
+        ExplainPlan { lines, schema }
+    }
+}
+
+impl Plan for ExplainPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(ExplainScan {
+            lines: self.lines.clone(),
+            current: None,
+        }))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        0
+    }
+
+    fn records_output(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn distinct_values(&self, _field_name: &str) -> usize {
+        self.lines.len()
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("explain", self, Vec::new())
+    }
+}
+
+struct ExplainScan {
+    lines: Vec<String>,
+    current: Option<usize>,
+}
+
+impl Scan for ExplainScan {
+    fn before_first(&mut self) {
+        self.current = None;
+    }
+
+    fn next(&mut self) -> bool {
+        let next = self.current.map_or(0, |current| current + 1);
+        self.current = Some(next.min(self.lines.len()));
+        next < self.lines.len()
+    }
+
+    fn get_int(&self, _field_name: &str) -> Option<i32> {
+        None
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.get_str(field_name).map(Cow::into_owned)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        if field_name != "plan" {
+            return None;
+        }
+        self.lines
+            .get(self.current?)
+            .map(|line| Cow::Borrowed(line.as_str()))
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.get_string(field_name).map(Constant::String)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        field_name == "plan"
+    }
+
+    fn close(&mut self) {}
+}
+
+/// One disagreement between a table and one of its indexes.
+struct Discrepancy {
+    index_name: String,
+    rid: Rid,
+    problem: String,
+}
+
+/// The result of `check table`: one record per discrepancy between the
+/// table and its indexes, with the index, the record id the problem is at
+/// (`block`, `slot`) and what is wrong. A consistent table has none. Every
+/// row must be in each index exactly once, and every index entry must point
+/// to a row holding its value.
+pub struct CheckPlan {
+    discrepancies: Rc<Vec<Discrepancy>>,
+    schema: Schema,
+}
+
+impl CheckPlan {
+    pub fn new(
+        table_name: &str,
+        layout: &Layout,
+        indexes: &HashMap<String, IndexInfo>,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<CheckPlan, DbError> {
+        let mut indexes: Vec<&IndexInfo> = indexes.values().collect();
+        indexes.sort_by(|lhs, rhs| lhs.index_name().cmp(rhs.index_name()));
+        // The whole table and every index are read, which should not evict
+        // the pool.
+        let ring_size = tx.borrow().buffer_ring_size();
+        tx.borrow_mut().set_buffer_ring(MAINTENANCE_RING_SIZE);
+        let mut discrepancies = Vec::new();
+        for index_info in indexes {
+            let mut found = |rid: Rid, problem: String| {
+                discrepancies.push(Discrepancy {
+                    index_name: index_info.index_name().to_string(),
+                    rid,
+                    problem,
+                })
+            };
+            let mut rows = HashMap::new();
+            let mut scan = TableScan::new(tx.clone(), table_name, layout.clone());
+            while scan.next() {
+                let rid = scan.get_rid().expect("scan is on a record");
+                let val = scan
+                    .get_val(index_info.field_name())
+                    .expect("indexed field is in the table's schema");
+                rows.insert(rid, val);
+            }
+            scan.close();
+            let index_entries = match index_info.open().and_then(|index| index.entries()) {
+                Ok(index_entries) => index_entries,
+                Err(err) => {
+                    tx.borrow_mut().set_buffer_ring(ring_size);
+                    return Err(err);
+                }
+            };
+            let mut entries: HashMap<(Constant, Rid), usize> = HashMap::new();
+            for entry in index_entries {
+                *entries.entry(entry).or_default() += 1;
+            }
+
+            let mut rids: Vec<&Rid> = rows.keys().collect();
+            rids.sort();
+            for rid in rids {
+                match entries.get(&(rows[rid].clone(), *rid)) {
+                    None => found(*rid, "row is not in the index".to_string()),
+                    Some(1) => {}
+                    Some(count) => found(*rid, format!("row is in the index {} times", count)),
+                }
+            }
+            let mut entries: Vec<&(Constant, Rid)> = entries.keys().collect();
+            entries.sort_by(|lhs, rhs| (lhs.1, &lhs.0).cmp(&(rhs.1, &rhs.0)));
+            for (val, rid) in entries {
+                match rows.get(rid) {
+                    None => found(*rid, format!("entry {} points to no row", val)),
+                    Some(row_val) if row_val != val => found(
+                        *rid,
+                        format!("entry {} does not match the row's {}", val, row_val),
+                    ),
+                    Some(_) => {}
+                }
+            }
+        }
+        tx.borrow_mut().set_buffer_ring(ring_size);
+
+        let mut schema = Schema::new();
+        let width = |field: fn(&Discrepancy) -> &str| {
+            discrepancies
+                .iter()
+                .map(|discrepancy| field(discrepancy).len())
+                .max()
+                .unwrap_or(0)
+        };
+        schema.add_string_field("index_name", width(|d| &d.index_name));
+        schema.add_int_field("block");
+        schema.add_int_field("slot");
+        schema.add_string_field("problem", width(|d| &d.problem));
+        Ok(CheckPlan {
+            discrepancies: Rc::new(discrepancies),
+            schema,
+        })
+    }
+}
+
+impl Plan for CheckPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(CheckScan {
+            discrepancies: self.discrepancies.clone(),
+            current: None,
+        }))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        0
+    }
+
+    fn records_output(&self) -> usize {
+        self.discrepancies.len()
+    }
+
+    fn distinct_values(&self, _field_name: &str) -> usize {
+        self.discrepancies.len()
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("check", self, Vec::new())
+    }
+}
+
+struct CheckScan {
+    discrepancies: Rc<Vec<Discrepancy>>,
+    current: Option<usize>,
+}
+
+impl CheckScan {
+    fn current(&self) -> Option<&Discrepancy> {
+        self.discrepancies.get(self.current?)
+    }
+}
+
+impl Scan for CheckScan {
+    fn before_first(&mut self) {
+        self.current = None;
+    }
+
+    fn next(&mut self) -> bool {
+        let next = self.current.map_or(0, |current| current + 1);
+        self.current = Some(next.min(self.discrepancies.len()));
+        next < self.discrepancies.len()
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        let rid = self.current()?.rid;
+        match field_name {
+            "block" => Some(rid.block_num() as i32),
+            "slot" => Some(rid.slot() as i32),
+            _ => None,
+        }
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        let discrepancy = self.current()?;
+        match field_name {
+            "index_name" => Some(discrepancy.index_name.clone()),
+            "problem" => Some(discrepancy.problem.clone()),
+            _ => None,
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        match field_name {
+            "block" | "slot" => self.get_int(field_name).map(Constant::Int),
+            _ => self.get_string(field_name).map(Constant::String),
+        }
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        matches!(field_name, "index_name" | "block" | "slot" | "problem")
+    }
+
+    fn close(&mut self) {}
+}
+
+/// The rows of a system view, worked out from the catalog and the files when
+/// it is planned.
+///
+/// `sys_maintenance` has one row per recommendation of the maintenance
+/// advisor: the table, its dead slots, their share of the table as a
+/// percentage and the recommended `action`, `vacuum` or `reindex`.
+///
+/// `sys_storage` has one row per table and index with the blocks its files
+/// take, how many of them are in use or free, and how many overflow chains
+/// it holds.
+pub struct SystemViewPlan {
+    view_name: &'static str,
+    rows: Rc<Vec<Vec<Constant>>>,
+    schema: Schema,
+}
+
+impl SystemViewPlan {
+    pub fn maintenance(metadata_mgr: &MetadataMgr, tx: Rc<RefCell<Transaction>>) -> Self {
+        let rows = metadata_mgr
+            .maintenance_advice(tx)
+            .into_iter()
+            .map(|advice| {
+                vec![
+                    Constant::String(advice.table_name),
+                    Constant::Int(advice.dead_slots as i32),
+                    Constant::Int(advice.dead_percent as i32),
+                    Constant::String(advice.action),
+                ]
+            })
+            .collect();
+        Self::new(
+            MAINTENANCE_VIEW,
+            &[
+                ("table_name", VARCHAR),
+                ("dead_slots", INTEGER),
+                ("dead_percent", INTEGER),
+                ("action", VARCHAR),
+            ],
+            rows,
+        )
+    }
+
+    pub fn storage(metadata_mgr: &MetadataMgr, tx: Rc<RefCell<Transaction>>) -> Self {
+        let rows = metadata_mgr
+            .storage_report(tx)
+            .into_iter()
+            .map(|storage| {
+                vec![
+                    Constant::String(storage.relation),
+                    Constant::String(storage.kind),
+                    Constant::Int(storage.blocks as i32),
+                    Constant::Int(storage.used_blocks as i32),
+                    Constant::Int(storage.free_blocks as i32),
+                    Constant::Int(storage.overflow_chains as i32),
+                ]
+            })
+            .collect();
+        Self::new(
+            STORAGE_VIEW,
+            &[
+                ("relation", VARCHAR),
+                ("kind", VARCHAR),
+                ("blocks", INTEGER),
+                ("used_blocks", INTEGER),
+                ("free_blocks", INTEGER),
+                ("overflow_chains", INTEGER),
+            ],
+            rows,
+        )
+    }
+
+    // String fields are as wide as their longest value.
+    fn new(view_name: &'static str, fields: &[(&str, i32)], rows: Vec<Vec<Constant>>) -> Self {
+        let mut schema = Schema::new();
+        for (pos, (field_name, field_type)) in fields.iter().enumerate() {
+            if *field_type == VARCHAR {
+                let width = rows
+                    .iter()
+                    .filter_map(|row| row[pos].as_string().map(str::len))
+                    .max()
+                    .unwrap_or(0);
+                schema.add_string_field(field_name, width);
+            } else {
+                schema.add_int_field(field_name);
+            }
+        }
+        SystemViewPlan {
+            view_name,
+            rows: Rc::new(rows),
+            schema,
+        }
+    }
+}
+
+impl Plan for SystemViewPlan {
+    fn open(&self) -> Result<Box<dyn Scan>, DbError> {
+        Ok(Box::new(SystemViewScan {
+            fields: self.schema.fields().to_vec(),
+            rows: self.rows.clone(),
+            current: None,
+        }))
+    }
+
+    fn blocks_accessed(&self) -> usize {
+        0
+    }
+
+    fn records_output(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn distinct_values(&self, _field_name: &str) -> usize {
+        self.rows.len()
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn explain(&self) -> PlanNode {
+        PlanNode::new("system view", self, Vec::new()).detail("view", self.view_name)
+    }
+}
+
+struct SystemViewScan {
+    fields: Vec<String>,
+    rows: Rc<Vec<Vec<Constant>>>,
+    current: Option<usize>,
+}
+
+impl Scan for SystemViewScan {
+    fn before_first(&mut self) {
+        self.current = None;
+    }
+
+    fn next(&mut self) -> bool {
+        let next = self.current.map_or(0, |current| current + 1);
+        self.current = Some(next.min(self.rows.len()));
+        next < self.rows.len()
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.get_val(field_name)?.as_int()
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.get_str(field_name).map(Cow::into_owned)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        let row = self.rows.get(self.current?)?;
+        let pos = self.fields.iter().position(|field| field == field_name)?;
+        row[pos].as_string().map(Cow::Borrowed)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        let row = self.rows.get(self.current?)?;
+        let pos = self.fields.iter().position(|field| field == field_name)?;
+        Some(row[pos].clone())
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.fields.iter().any(|field| field == field_name)
+    }
+
+    fn close(&mut self) {}
+}
+
+pub trait QueryPlanner: Send + Sync {
+    fn create_plan(
+        &self,
+        data: &QueryData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError>;
+    fn create_check_plan(
+        &self,
+        data: &CheckTableData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError>;
+}
+
+/// Each `execute_*` method returns the number of records affected.
+pub trait UpdatePlanner: Send + Sync {
+    fn execute_insert(
+        &self,
+        data: &InsertData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_delete(
+        &self,
+        data: &DeleteData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_modify(
+        &self,
+        data: &ModifyData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    /// The plan `execute_modify` finds the records to change with, for
+    /// `explain`.
+    fn create_modify_plan(
+        &self,
+        data: &ModifyData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError>;
+    fn execute_create_table(
+        &self,
+        data: &CreateTableData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_create_view(
+        &self,
+        data: &CreateViewData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_create_index(
+        &self,
+        data: &CreateIndexData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_grant(
+        &self,
+        data: &GrantData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    fn execute_revoke(
+        &self,
+        data: &GrantData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+    /// Deletes at most `limit` rows whose timestamp under `policy` is before
+    /// `cutoff`, returning how many it deleted.
+    fn execute_expire(
+        &self,
+        policy: &RetentionPolicy,
+        cutoff: i32,
+        limit: usize,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError>;
+}
+
+/// Joins the tables of the query in the order they are listed, then applies
+/// the predicate and the projection on top of them. A table is read through
+/// an index when the predicate equates the indexed field with a constant, or
+/// with a field of the tables joined before it. Other equijoins are merge
+/// joined when the product would cost more; remaining tables are combined
+/// with a multibuffer product.
+pub struct BasicQueryPlanner {
+    metadata_mgr: Arc<MetadataMgr>,
+    feedback: Arc<Mutex<CardinalityFeedback>>,
+    attachments: Attachments,
+}
+
+impl BasicQueryPlanner {
+    #[cfg(test)]
+    pub fn new(metadata_mgr: Arc<MetadataMgr>) -> BasicQueryPlanner {
+        Self::with_attachments(metadata_mgr, Attachments::default())
+    }
+
+    /// A planner that also reads tables qualified with the name of one of
+    /// `attachments`, as in `otherdb.table`, through a read-only transaction
+    /// on that database which ends with the query's own.
+    pub fn with_attachments(
+        metadata_mgr: Arc<MetadataMgr>,
+        attachments: Attachments,
+    ) -> BasicQueryPlanner {
+        BasicQueryPlanner {
+            metadata_mgr,
+            feedback: Arc::new(Mutex::new(CardinalityFeedback::new())),
+            attachments,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn feedback(&self) -> Arc<Mutex<CardinalityFeedback>> {
+        self.feedback.clone()
+    }
+
+    fn plan_query(
+        &self,
+        data: &QueryData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError> {
+        let predicate = data.predicate();
+        let mut plan: Option<Box<dyn Plan>> = None;
+        for table_name in data.tables() {
+            if let Some((db_name, name)) = split_qualified(table_name) {
+                let attachments = self.attachments.read().unwrap();
+                let attachment = attachments
+                    .get(db_name)
+                    .ok_or_else(|| PlanError::UnknownTable(table_name.clone()))?;
+                let reader = attachment.begin();
+                tx.borrow_mut().add_reader(reader.clone());
+                let metadata_mgr = attachment.metadata_mgr();
+                let table_plan =
+                    TablePlan::with_predicate(reader.clone(), name, &metadata_mgr, predicate)
+                        .map_err(|_| PlanError::UnknownTable(table_name.clone()))?;
+                let indexes = metadata_mgr.get_index_info(name, reader);
+                plan = Some(match plan {
+                    Some(plan) => join(tx.clone(), plan, table_plan, indexes, predicate),
+                    None => index_select(table_plan, indexes, predicate),
+                });
+                continue;
+            }
+            // A system view is worked out now and joined like a view.
+            let system_view = match table_name.as_str() {
+                MAINTENANCE_VIEW => {
+                    Some(SystemViewPlan::maintenance(&self.metadata_mgr, tx.clone()))
+                }
+                STORAGE_VIEW => Some(SystemViewPlan::storage(&self.metadata_mgr, tx.clone())),
+                _ => None,
+            };
+            if let Some(system_view) = system_view {
+                let view_plan: Box<dyn Plan> = Box::new(system_view);
+                plan = Some(match plan {
+                    Some(plan) => merge_join_or_product(tx.clone(), plan, view_plan, predicate),
+                    None => view_plan,
+                });
+                continue;
+            }
+            // A view is planned from its stored query and joined like a
+            // table without indexes.
+            let view_def = self.metadata_mgr.get_view_def(table_name, tx.clone());
+            if let Some(view_def) = view_def {
+                let view_data = Parser::new(&view_def)?.query()?;
+                let view_plan = self.plan_query(&view_data, tx.clone())?;
+                plan = Some(match plan {
+                    Some(plan) => merge_join_or_product(tx.clone(), plan, view_plan, predicate),
+                    None => view_plan,
+                });
+                continue;
+            }
+            let metadata_mgr = &self.metadata_mgr;
+            let table_plan =
+                TablePlan::with_predicate(tx.clone(), table_name, metadata_mgr, predicate)?;
+            let indexes = metadata_mgr.get_index_info(table_name, tx.clone());
+            plan = Some(match plan {
+                Some(plan) => join(tx.clone(), plan, table_plan, indexes, predicate),
+                None => index_select(table_plan, indexes, predicate),
+            });
+        }
+        let plan = plan.ok_or_else(|| PlanError::UnknownTable(String::new()))?;
+        let key = format!("{} where {}", data.tables().join(", "), data.predicate());
+        let plan = Box::new(SelectPlan::with_feedback(
+            plan,
+            data.predicate().clone(),
+            key,
+            self.feedback.clone(),
+        ));
+        let plan: Box<dyn Plan> = if data.group_fields().is_empty() && data.aggregates().is_empty()
+        {
+            plan
+        } else {
+            Box::new(GroupByPlan::new(
+                tx,
+                plan,
+                data.group_fields().to_vec(),
+                data.aggregates().to_vec(),
+            )?)
+        };
+        Ok(Box::new(ProjectPlan::new(plan, data.fields())?))
+    }
+}
+
+impl QueryPlanner for BasicQueryPlanner {
+    fn create_plan(
+        &self,
+        data: &QueryData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError> {
+        // Views are read with the privileges of whoever defined them, so
+        // only the names the query itself lists are checked.
+        for table_name in data.tables() {
+            authorize(&self.metadata_mgr, table_name, Privilege::Select, &tx)?;
+        }
+        self.plan_query(data, tx)
+    }
+
+    fn create_check_plan(
+        &self,
+        data: &CheckTableData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError> {
+        let metadata_mgr = &self.metadata_mgr;
+        authorize(metadata_mgr, data.table_name(), Privilege::Select, &tx)?;
+        let layout = metadata_mgr
+            .get_layout(data.table_name(), tx.clone())
+            .ok_or_else(|| PlanError::UnknownTable(data.table_name().to_string()))?;
+        let indexes = metadata_mgr.get_index_info(data.table_name(), tx.clone());
+        Ok(Box::new(CheckPlan::new(
+            data.table_name(),
+            &layout,
+            &indexes,
+            tx,
+        )?))
+    }
+}
+
+// Fails unless the session user of `tx` may apply `privilege` to
+// `table_name`. A transaction without a session user may do anything.
+fn authorize(
+    metadata_mgr: &MetadataMgr,
+    table_name: &str,
+    privilege: Privilege,
+    tx: &Rc<RefCell<Transaction>>,
+) -> Result<(), PlanError> {
+    let Some(user) = tx.borrow().user().map(str::to_string) else {
+        return Ok(());
+    };
+    if metadata_mgr.has_privilege(&user, table_name, privilege, tx.clone()) {
+        return Ok(());
+    }
+    let action = match privilege {
+        Privilege::Select | Privilege::Delete => format!("{} from {}", privilege, table_name),
+        Privilege::Insert => format!("insert into {}", table_name),
+        Privilege::Update => format!("update {}", table_name),
+    };
+    Err(PlanError::PermissionDenied { user, action })
+}
+
+// Fails if `tx` has a session user: changes to the schema and to privileges
+// are left to sessions whose privileges are not checked.
+fn require_unrestricted(tx: &Rc<RefCell<Transaction>>, action: &str) -> Result<(), PlanError> {
+    match tx.borrow().user() {
+        Some(user) => Err(PlanError::PermissionDenied {
+            user: user.to_string(),
+            action: action.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Reads a table through the first index whose field the predicate equates
+// with a constant, or scans it whole when there is none.
+fn index_select(
+    table_plan: TablePlan,
+    mut indexes: HashMap<String, IndexInfo>,
+    predicate: &Predicate,
+) -> Box<dyn Plan> {
+    for field_name in table_plan.schema().fields().to_vec() {
+        if let Some(val) = predicate.equates_with_constant(&field_name) {
+            if let Some(index_info) = indexes.remove(&field_name) {
+                return Box::new(IndexSelectPlan::new(table_plan, index_info, val.clone()));
+            }
+        }
+    }
+    Box::new(table_plan)
+}
+
+// Joins a table to the plan through an index on a field the predicate
+// equates with a field of the plan. Without an index an equijoin becomes a
+// merge join when that is estimated to be cheaper, and anything else a
+// multibuffer product.
+fn join(
+    tx: Rc<RefCell<Transaction>>,
+    lhs: Box<dyn Plan>,
+    table_plan: TablePlan,
+    mut indexes: HashMap<String, IndexInfo>,
+    predicate: &Predicate,
+) -> Box<dyn Plan> {
+    for field_name in table_plan.schema().fields().to_vec() {
+        let Some(join_field) = predicate.equates_with_field(&field_name) else {
+            continue;
+        };
+        if !lhs.schema().has_field(join_field) || table_plan.schema().has_field(join_field) {
+            continue;
+        }
+        if let Some(index_info) = indexes.remove(&field_name) {
+            let join_field = join_field.to_string();
+            return Box::new(IndexJoinPlan::new(lhs, table_plan, index_info, &join_field));
+        }
+    }
+    let rhs = index_select(table_plan, indexes, predicate);
+    merge_join_or_product(tx, lhs, rhs, predicate)
+}
+
+fn merge_join_or_product(
+    tx: Rc<RefCell<Transaction>>,
+    lhs: Box<dyn Plan>,
+    rhs: Box<dyn Plan>,
+    predicate: &Predicate,
+) -> Box<dyn Plan> {
+    let equijoin = rhs.schema().fields().iter().find_map(|field_name| {
+        let join_field = predicate.equates_with_field(field_name)?;
+        (lhs.schema().has_field(join_field) && !rhs.schema().has_field(join_field))
+            .then(|| (join_field.to_string(), field_name.clone()))
+    });
+    let Some((lhs_field, rhs_field)) = equijoin else {
+        return Box::new(MultibufferProductPlan::new(tx, lhs, rhs));
+    };
+    // Sorting both sides pays off once rereading the left hand side for
+    // every chunk of the right costs more than the sorts. A left hand side
+    // already in join order is read as it is.
+    let product_cost = MultibufferProductPlan::cost(&tx, lhs.as_ref(), rhs.as_ref());
+    let lhs_cost = if is_sorted_on(lhs.as_ref(), std::slice::from_ref(&lhs_field)) {
+        lhs.blocks_accessed()
+    } else {
+        3 * lhs.blocks_accessed()
+    };
+    let merge_cost = lhs_cost + 3 * rhs.blocks_accessed();
+    if merge_cost < product_cost {
+        Box::new(MergeJoinPlan::new(tx, lhs, rhs, &lhs_field, &rhs_field))
+    } else {
+        Box::new(MultibufferProductPlan::new(tx, lhs, rhs))
+    }
+}
+
+pub struct BasicUpdatePlanner {
+    metadata_mgr: Arc<MetadataMgr>,
+    changes: Option<Arc<Mutex<ChangeCapture>>>,
+}
+
+impl BasicUpdatePlanner {
+    #[cfg(test)]
+    pub fn new(metadata_mgr: Arc<MetadataMgr>) -> BasicUpdatePlanner {
+        BasicUpdatePlanner {
+            metadata_mgr,
+            changes: None,
+        }
+    }
+
+    /// An update planner that reports every row it inserts, modifies or
+    /// deletes to `changes`.
+    pub fn with_change_capture(
+        metadata_mgr: Arc<MetadataMgr>,
+        changes: Arc<Mutex<ChangeCapture>>,
+    ) -> BasicUpdatePlanner {
+        BasicUpdatePlanner {
+            metadata_mgr,
+            changes: Some(changes),
+        }
+    }
+
+    fn capture_enabled(&self) -> bool {
+        self.changes.is_some()
+    }
+
+    fn capture(
+        &self,
+        tx: &Rc<RefCell<Transaction>>,
+        table_name: &str,
+        op: ChangeOp,
+        before: Vec<(String, Constant)>,
+        after: Vec<(String, Constant)>,
+    ) {
+        if let Some(changes) = &self.changes {
+            let tx_num = tx.borrow().transaction_n();
+            changes
+                .lock()
+                .unwrap()
+                .record(tx_num, table_name, op, before, after);
+        }
+    }
+
+    fn layout(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> Result<Layout, PlanError> {
+        self.metadata_mgr
+            .get_layout(table_name, tx)
+            .ok_or_else(|| PlanError::UnknownTable(table_name.to_string()))
+    }
+
+    // The values of the fields an insert leaves out but that have a default,
+    // evaluated afresh for each record.
+    fn default_values(
+        &self,
+        data: &InsertData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Vec<(String, Constant)>, PlanError> {
+        let defaults = self
+            .metadata_mgr
+            .get_defaults(data.table_name(), tx.clone());
+        let mut result = Vec::new();
+        for (field_name, expr) in defaults {
+            if data.fields().contains(&field_name) {
+                continue;
+            }
+            let val = match Parser::new(&expr)?.default_expr()? {
+                DefaultExpr::Constant(val) => val,
+                DefaultExpr::CurrentTimestamp => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_secs());
+                    Constant::Int(i32::try_from(now).unwrap_or(i32::MAX))
+                }
+                DefaultExpr::NextVal(sequence_name) => {
+                    Constant::Int(self.metadata_mgr.nextval(&sequence_name, tx.clone())?)
+                }
+            };
+            result.push((field_name, val));
+        }
+        Ok(result)
+    }
+
+    // An index through which an update can find the records it changes: one
+    // on a field the predicate equates with a constant. An index on the
+    // field being set is passed over, as setting it would move the entries
+    // the scan is reading.
+    fn modify_lookup(
+        &self,
+        data: &ModifyData,
+        schema: &Schema,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Option<(IndexInfo, Constant)> {
+        let mut indexes = self.metadata_mgr.get_index_info(data.table_name(), tx);
+        indexes.remove(data.target_field());
+        schema.fields().iter().find_map(|field_name| {
+            let val = data.predicate().equates_with_constant(field_name)?;
+            Some((indexes.remove(field_name)?, val.clone()))
+        })
+    }
+
+    // Whether deleting records of the table affects other records, through
+    // a foreign key that cascades or sets null.
+    fn has_delete_actions(&self, table_name: &str, tx: Rc<RefCell<Transaction>>) -> bool {
+        self.metadata_mgr
+            .get_referencing(table_name, tx)
+            .iter()
+            .any(|key| key.on_delete != DeleteAction::NoAction)
+    }
+
+    // Applies the delete actions of the foreign keys referencing the rows
+    // deleted from `table_name`, then those of the keys referencing the rows
+    // that deletes in turn, all within `tx`. Rows are deleted as soon as
+    // they are found, so a cycle of cascading keys ends once it comes back
+    // to rows that are already gone.
+    fn apply_delete_actions(
+        &self,
+        table_name: &str,
+        deleted: Vec<Vec<(String, Constant)>>,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<(), PlanError> {
+        let mut pending: VecDeque<(String, Vec<(String, Constant)>)> = deleted
+            .into_iter()
+            .map(|row| (table_name.to_string(), row))
+            .collect();
+        while let Some((table_name, row)) = pending.pop_front() {
+            let keys = self.metadata_mgr.get_referencing(&table_name, tx.clone());
+            for key in keys {
+                if key.on_delete == DeleteAction::NoAction {
+                    continue;
+                }
+                // A null key references nothing.
+                let Some((_, val)) = row
+                    .iter()
+                    .find(|(field_name, _)| *field_name == key.ref_field)
+                else {
+                    continue;
+                };
+                let layout = self.layout(&key.table_name, tx.clone())?;
+                let schema = layout.schema().clone();
+                let heap_name = self
+                    .metadata_mgr
+                    .heap_names(&key.table_name, tx.clone())
+                    .remove(0);
+                let rids =
+                    self.find_referencing(&key, val, &heap_name, layout.clone(), tx.clone())?;
+                let mut indexes = open_indexes(&self.metadata_mgr, &key.table_name, tx.clone())?;
+                let mut scan = TableScan::new(tx.clone(), &heap_name, layout);
+                for rid in rids {
+                    scan.move_to_rid(rid);
+                    let before = read_row(&scan, &schema);
+                    for (field_name, index) in indexes.iter_mut() {
+                        if key.on_delete == DeleteAction::Cascade || *field_name == key.field_name {
+                            if let Some(val) = scan.get_val(field_name) {
+                                index.delete(&val, rid)?;
+                            }
+                        }
+                    }
+                    if key.on_delete == DeleteAction::Cascade {
+                        scan.delete()?;
+                        self.metadata_mgr.record_deletes(&heap_name, 1);
+                        self.capture(
+                            &tx,
+                            &key.table_name,
+                            ChangeOp::Delete,
+                            before.clone(),
+                            Vec::new(),
+                        );
+                        pending.push_back((key.table_name.clone(), before));
+                    } else {
+                        scan.set_null(&key.field_name)?;
+                        if self.capture_enabled() {
+                            let after = read_row(&scan, &schema);
+                            self.capture(&tx, &key.table_name, ChangeOp::Update, before, after);
+                        }
+                    }
+                }
+                scan.close();
+                for (_, index) in indexes.iter_mut() {
+                    index.close();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The rows whose field of `key` holds `val`, found through an index on
+    // the field when there is one. They are gathered before any is changed,
+    // as changing them moves the index entries being read.
+    fn find_referencing(
+        &self,
+        key: &ForeignKey,
+        val: &Constant,
+        heap_name: &str,
+        layout: Layout,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Vec<Rid>, DbError> {
+        let index_info = self
+            .metadata_mgr
+            .get_index_info(&key.table_name, tx.clone())
+            .remove(&key.field_name);
+        let mut rids = Vec::new();
+        match index_info {
+            Some(index_info) => {
+                let mut index = index_info.open()?;
+                index.before_first(val);
+                while index.next() {
+                    rids.extend(index.get_data_rid());
+                }
+                index.close();
+            }
+            None => {
+                let mut scan = TableScan::new(tx, heap_name, layout);
+                while scan.next() {
+                    if scan.get_val(&key.field_name).as_ref() == Some(val) {
+                        rids.extend(scan.get_rid());
+                    }
+                }
+                scan.close();
+            }
+        }
+        Ok(rids)
+    }
+
+    // Reads the heap files of a table that can hold records satisfying
+    // `predicate`.
+    fn heap_scan(
+        &self,
+        table_name: &str,
+        predicate: &Predicate,
+        layout: Layout,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> PartitionScan {
+        let heap_names = self
+            .metadata_mgr
+            .scan_heaps(table_name, predicate, tx.clone());
+        PartitionScan::new(tx, heap_names, layout)
+    }
+}
+
+impl BasicUpdatePlanner {
+    // Privileges can be granted on stored tables, views and system views,
+    // to names the catalog can hold.
+    fn check_grant(&self, data: &GrantData, tx: Rc<RefCell<Transaction>>) -> Result<(), PlanError> {
+        let name = match data.granted() {
+            Grantable::Privileges { table_name, .. } => {
+                let metadata_mgr = &self.metadata_mgr;
+                let exists = [MAINTENANCE_VIEW, STORAGE_VIEW].contains(&table_name.as_str())
+                    || metadata_mgr.get_layout(table_name, tx.clone()).is_some()
+                    || metadata_mgr.get_view_def(table_name, tx.clone()).is_some();
+                if !exists || metadata_mgr.is_temp_table(table_name, Rc::clone(&tx)) {
+                    return Err(PlanError::UnknownTable(table_name.clone()));
+                }
+                table_name
+            }
+            Grantable::Role(role) => role,
+        };
+        for name in [name.as_str(), data.grantee()] {
+            if name.len() > MAX_NAME {
+                return Err(PlanError::ValueTooLong(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UpdatePlanner for BasicUpdatePlanner {
+    fn execute_insert(
+        &self,
+        data: &InsertData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        authorize(
+            &self.metadata_mgr,
+            data.table_name(),
+            Privilege::Insert,
+            &tx,
+        )?;
+        let layout = self.layout(data.table_name(), tx.clone())?;
+        for (field_name, val) in data.fields().iter().zip(data.values()) {
+            check_value(layout.schema(), field_name, val)?;
+        }
+        let mut values: Vec<(String, Constant)> = data
+            .fields()
+            .iter()
+            .cloned()
+            .zip(data.values().iter().cloned())
+            .collect();
+        values.extend(self.default_values(data, tx.clone())?);
+        let indexes = self
+            .metadata_mgr
+            .get_index_info(data.table_name(), tx.clone());
+        let schema = layout.schema().clone();
+        // A partitioned table takes the record into the partition of its
+        // key; a key left out with no default is stored as zero.
+        let heap_name = match self
+            .metadata_mgr
+            .get_partitioning(data.table_name(), tx.clone())
+        {
+            Some(partitioning) => {
+                let key = values
+                    .iter()
+                    .find_map(|(field_name, val)| match val {
+                        Constant::Int(key) if field_name == partitioning.field_name() => Some(*key),
+                        _ => None,
+                    })
+                    .unwrap_or(0);
+                partitioning.heap_name(partitioning.partition_for(key))
+            }
+            None => self
+                .metadata_mgr
+                .heap_names(data.table_name(), tx.clone())
+                .remove(0),
+        };
+        let mut scan = TableScan::new(tx.clone(), &heap_name, layout);
+        scan.insert()?;
+        for (field_name, val) in values {
+            scan.set_val(&field_name, val)?;
+        }
+        let rid = scan.get_rid().expect("scan is on the new record");
+        for (field_name, index_info) in indexes.iter() {
+            let val = scan
+                .get_val(field_name)
+                .expect("indexed field is in the table's schema");
+            let mut index = index_info.open()?;
+            index.insert(&val, rid)?;
+            index.close();
+        }
+        if self.capture_enabled() {
+            let after = read_row(&scan, &schema);
+            self.capture(&tx, data.table_name(), ChangeOp::Insert, Vec::new(), after);
+        }
+        scan.close();
+        self.metadata_mgr.record_inserts(&heap_name, 1);
+        Ok(1)
+    }
+
+    fn execute_delete(
+        &self,
+        data: &DeleteData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        authorize(
+            &self.metadata_mgr,
+            data.table_name(),
+            Privilege::Delete,
+            &tx,
+        )?;
+        let layout = self.layout(data.table_name(), tx.clone())?;
+        let schema = layout.schema().clone();
+        let mut indexes = open_indexes(&self.metadata_mgr, data.table_name(), tx.clone())?;
+        let table_scan = self.heap_scan(data.table_name(), data.predicate(), layout, tx.clone());
+        let mut scan = SelectScan::new(table_scan, data.predicate().padded(&schema));
+        let referenced = self.has_delete_actions(data.table_name(), tx.clone());
+        let mut deleted = Vec::new();
+        let mut count = 0;
+        while scan.next() {
+            if self.capture_enabled() || referenced {
+                let before = read_row(&scan, &schema);
+                if referenced {
+                    deleted.push(before.clone());
+                }
+                self.capture(&tx, data.table_name(), ChangeOp::Delete, before, Vec::new());
+            }
+            let rid = scan.get_rid().expect("scan is on a record");
+            for (field_name, index) in indexes.iter_mut() {
+                let val = scan
+                    .get_val(field_name)
+                    .expect("indexed field is in the table's schema");
+                index.delete(&val, rid)?;
+            }
+            scan.delete()?;
+            count += 1;
+        }
+        scan.close();
+        for (_, index) in indexes.iter_mut() {
+            index.close();
+        }
+        self.metadata_mgr.record_deletes(data.table_name(), count);
+        self.apply_delete_actions(data.table_name(), deleted, tx)?;
+        Ok(count)
+    }
+
+    fn execute_modify(
+        &self,
+        data: &ModifyData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        authorize(
+            &self.metadata_mgr,
+            data.table_name(),
+            Privilege::Update,
+            &tx,
+        )?;
+        let layout = self.layout(data.table_name(), tx.clone())?;
+        let schema = layout.schema().clone();
+        if !schema.has_field(data.target_field()) {
+            return Err(PlanError::UnknownField(data.target_field().to_string()));
+        }
+        if !data.new_value().applies_to(&schema) {
+            return Err(PlanError::UnknownField(data.new_value().to_string()));
+        }
+        let mut index = self
+            .metadata_mgr
+            .get_index_info(data.table_name(), tx.clone())
+            .remove(data.target_field())
+            .map(|index_info| index_info.open())
+            .transpose()?;
+        // Records whose new key belongs in another partition are taken out
+        // during the scan and put back once it is done, so that the scan
+        // does not meet them again.
+        let partitioning = self
+            .metadata_mgr
+            .get_partitioning(data.table_name(), tx.clone())
+            .filter(|partitioning| partitioning.field_name() == data.target_field());
+        let mut moved = Vec::new();
+        let table_scan: Box<dyn UpdateScan> = match self.modify_lookup(data, &schema, tx.clone()) {
+            Some((index_info, val)) => Box::new(IndexSelectScan::new(
+                TableScan::new(tx.clone(), data.table_name(), layout.clone()),
+                index_info.open()?,
+                val,
+            )),
+            None => Box::new(self.heap_scan(
+                data.table_name(),
+                data.predicate(),
+                layout.clone(),
+                tx.clone(),
+            )),
+        };
+        let mut scan = SelectScan::new(table_scan, data.predicate().padded(&schema));
+        let mut count = 0;
+        let mut result = Ok(());
+        while scan.next() {
+            let Some(val) = data.new_value().evaluate(&scan) else {
+                continue;
+            };
+            if let Err(err) = check_value(&schema, data.target_field(), &val) {
+                result = Err(err);
+                break;
+            }
+            if let Some(index) = index.as_mut() {
+                let rid = scan.get_rid().expect("scan is on a record");
+                let old_val = scan
+                    .get_val(data.target_field())
+                    .expect("target field is in the table's schema");
+                index.delete(&old_val, rid)?;
+                index.insert(&val, rid)?;
+            }
+            let before = self.capture_enabled().then(|| read_row(&scan, &schema));
+            let partitions = partitioning.as_ref().and_then(|partitioning| {
+                let old_key = scan.get_int(data.target_field())?;
+                let Constant::Int(new_key) = val else {
+                    return None;
+                };
+                Some((
+                    partitioning.partition_for(old_key),
+                    partitioning.partition_for(new_key),
+                ))
+            });
+            scan.set_val(data.target_field(), val)?;
+            if let Some(before) = before {
+                let after = read_row(&scan, &schema);
+                self.capture(&tx, data.table_name(), ChangeOp::Update, before, after);
+            }
+            if let Some((old_partition, new_partition)) = partitions {
+                if old_partition != new_partition {
+                    moved.push((new_partition, read_row(&scan, &schema)));
+                    scan.delete()?;
+                }
+            }
+            count += 1;
+        }
+        scan.close();
+        if let Some(partitioning) = partitioning.as_ref() {
+            for (partition, row) in moved {
+                let heap_name = partitioning.heap_name(partition);
+                let mut scan = TableScan::new(tx.clone(), &heap_name, layout.clone());
+                scan.insert()?;
+                for (field_name, val) in row {
+                    scan.set_val(&field_name, val)?;
+                }
+                scan.close();
+            }
+        }
+        if let Some(index) = index.as_mut() {
+            index.close();
+        }
+        result.map(|_| count)
+    }
+
+    fn create_modify_plan(
+        &self,
+        data: &ModifyData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError> {
+        authorize(
+            &self.metadata_mgr,
+            data.table_name(),
+            Privilege::Update,
+            &tx,
+        )?;
+        let table_plan = TablePlan::with_predicate(
+            tx.clone(),
+            data.table_name(),
+            &self.metadata_mgr,
+            data.predicate(),
+        )?;
+        if !table_plan.schema().has_field(data.target_field()) {
+            return Err(PlanError::UnknownField(data.target_field().to_string()));
+        }
+        let plan: Box<dyn Plan> = match self.modify_lookup(data, table_plan.schema(), tx) {
+            Some((index_info, val)) => Box::new(IndexSelectPlan::new(table_plan, index_info, val)),
+            None => Box::new(table_plan),
+        };
+        Ok(Box::new(SelectPlan::new(plan, data.predicate().clone())))
+    }
+
+    fn execute_create_table(
+        &self,
+        data: &CreateTableData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        if !data.is_temporary() {
+            require_unrestricted(&tx, "create tables")?;
+        }
+        let metadata_mgr = &self.metadata_mgr;
+        if [MAINTENANCE_VIEW, STORAGE_VIEW].contains(&data.table_name())
+            || metadata_mgr
+                .get_layout(data.table_name(), tx.clone())
+                .is_some()
+            || metadata_mgr
+                .get_view_def(data.table_name(), tx.clone())
+                .is_some()
+        {
+            return Err(PlanError::TableExists(data.table_name().to_string()));
+        }
+        if data.is_temporary() {
+            if data.partition_field().is_some() {
+                return Err(PlanError::Unsupported(
+                    "a partitioned temporary table".to_string(),
+                ));
+            }
+            if !data.defaults().is_empty() {
+                return Err(PlanError::Unsupported(
+                    "a default on a temporary table".to_string(),
+                ));
+            }
+            if !data.foreign_keys().is_empty() {
+                return Err(PlanError::Unsupported(
+                    "a foreign key on a temporary table".to_string(),
+                ));
+            }
+            metadata_mgr.create_temp_table(data.table_name(), data.schema(), tx);
+            return Ok(0);
+        }
+        if let Some(field_name) = data.partition_field() {
+            match data.schema().field_type(field_name) {
+                None => return Err(PlanError::UnknownField(field_name.to_string())),
+                Some(INTEGER) => {}
+                Some(_) => return Err(PlanError::TypeMismatch(field_name.to_string())),
+            }
+        }
+        for (field_name, expr) in data.defaults() {
+            match expr {
+                DefaultExpr::Constant(val) => check_value(data.schema(), field_name, val)?,
+                DefaultExpr::CurrentTimestamp | DefaultExpr::NextVal(_) => {
+                    if data.schema().field_type(field_name) != Some(INTEGER) {
+                        return Err(PlanError::TypeMismatch(field_name.to_string()));
+                    }
+                }
+            }
+            if expr.to_string().len() > MAX_DEFAULT {
+                return Err(PlanError::ValueTooLong(field_name.to_string()));
+            }
+        }
+        // Rows are found by record id when a referenced row is deleted,
+        // which does not say which partition a row is in.
+        if data.partition_field().is_some() && !data.foreign_keys().is_empty() {
+            return Err(PlanError::Unsupported(
+                "a foreign key on a partitioned table".to_string(),
+            ));
+        }
+        for key in data.foreign_keys() {
+            let ref_schema = if key.ref_table == data.table_name() {
+                data.schema().clone()
+            } else if metadata_mgr.is_temp_table(&key.ref_table, tx.clone()) {
+                return Err(PlanError::Unsupported(
+                    "a foreign key to a temporary table".to_string(),
+                ));
+            } else {
+                metadata_mgr
+                    .get_layout(&key.ref_table, tx.clone())
+                    .ok_or_else(|| PlanError::UnknownTable(key.ref_table.clone()))?
+                    .schema()
+                    .clone()
+            };
+            let Some(ref_type) = ref_schema.field_type(&key.ref_field) else {
+                return Err(PlanError::UnknownField(key.ref_field.clone()));
+            };
+            if data.schema().field_type(&key.field_name) != Some(ref_type) {
+                return Err(PlanError::TypeMismatch(key.field_name.clone()));
+            }
+        }
+        metadata_mgr.create_table(data.table_name(), data.schema(), tx.clone())?;
+        for (field_name, expr) in data.defaults() {
+            metadata_mgr.create_default(
+                data.table_name(),
+                field_name,
+                &expr.to_string(),
+                tx.clone(),
+            )?;
+        }
+        for key in data.foreign_keys() {
+            metadata_mgr.create_foreign_key(key, tx.clone())?;
+        }
+        if let Some(field_name) = data.partition_field() {
+            metadata_mgr.create_partitioning(
+                data.table_name(),
+                field_name,
+                data.partition_bounds(),
+                tx,
+            )?;
+        }
+        Ok(0)
+    }
+
+    fn execute_create_view(
+        &self,
+        data: &CreateViewData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        require_unrestricted(&tx, "create views")?;
+        let metadata_mgr = &self.metadata_mgr;
+        if [MAINTENANCE_VIEW, STORAGE_VIEW].contains(&data.view_name())
+            || metadata_mgr
+                .get_layout(data.view_name(), tx.clone())
+                .is_some()
+            || metadata_mgr
+                .get_view_def(data.view_name(), tx.clone())
+                .is_some()
+        {
+            return Err(PlanError::TableExists(data.view_name().to_string()));
+        }
+        let view_def = data.view_def();
+        if data.view_name().len() > MAX_NAME || view_def.len() > MAX_VIEWDEF {
+            return Err(PlanError::ValueTooLong(data.view_name().to_string()));
+        }
+        metadata_mgr.create_view(data.view_name(), &view_def, tx)?;
+        Ok(0)
+    }
+
+    // Records the index in the catalog and fills it from the rows already
+    // in the table.
+    fn execute_create_index(
+        &self,
+        data: &CreateIndexData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        require_unrestricted(&tx, "create indexes")?;
+        let metadata_mgr = &self.metadata_mgr;
+        let layout = metadata_mgr
+            .get_layout(data.table_name(), tx.clone())
+            .ok_or_else(|| PlanError::UnknownTable(data.table_name().to_string()))?;
+        match layout.schema().field_type(data.field_name()) {
+            None => return Err(PlanError::UnknownField(data.field_name().to_string())),
+            Some(BLOB) => return Err(PlanError::TypeMismatch(data.field_name().to_string())),
+            Some(_) => {}
+        }
+        // Index entries hold record ids, which do not say which partition
+        // a record is in.
+        if metadata_mgr
+            .get_partitioning(data.table_name(), tx.clone())
+            .is_some()
+        {
+            return Err(PlanError::Unsupported(
+                "an index on a partitioned table".to_string(),
+            ));
+        }
+        if metadata_mgr.is_temp_table(data.table_name(), tx.clone()) {
+            return Err(PlanError::Unsupported(
+                "an index on a temporary table".to_string(),
+            ));
+        }
+        metadata_mgr.create_index(
+            data.index_name(),
+            data.table_name(),
+            data.field_name(),
+            tx.clone(),
+        )?;
+
+        let indexes = metadata_mgr.get_index_info(data.table_name(), tx.clone());
+        let mut index = indexes[data.field_name()].open()?;
+        let mut scan = TableScan::new(tx, data.table_name(), layout);
+        while scan.next() {
+            let val = scan
+                .get_val(data.field_name())
+                .expect("field is in the table's schema");
+            index.insert(&val, scan.get_rid().expect("scan is on a record"))?;
+        }
+        scan.close();
+        index.close();
+        Ok(0)
+    }
+
+    // Comparisons other than equality cannot be written as a predicate, so
+    // the timestamps are checked here as the table is scanned.
+    fn execute_grant(
+        &self,
+        data: &GrantData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        require_unrestricted(&tx, "grant")?;
+        self.check_grant(data, tx.clone())?;
+        let metadata_mgr = &self.metadata_mgr;
+        match data.granted() {
+            Grantable::Privileges {
+                privileges,
+                table_name,
+            } => {
+                for privilege in privileges {
+                    metadata_mgr.grant_privilege(
+                        table_name,
+                        data.grantee(),
+                        *privilege,
+                        tx.clone(),
+                    )?;
+                }
+            }
+            Grantable::Role(role) => metadata_mgr.grant_role(role, data.grantee(), tx)?,
+        }
+        Ok(0)
+    }
+
+    fn execute_revoke(
+        &self,
+        data: &GrantData,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        require_unrestricted(&tx, "revoke")?;
+        self.check_grant(data, tx.clone())?;
+        let metadata_mgr = &self.metadata_mgr;
+        match data.granted() {
+            Grantable::Privileges {
+                privileges,
+                table_name,
+            } => {
+                for privilege in privileges {
+                    metadata_mgr.revoke_privilege(
+                        table_name,
+                        data.grantee(),
+                        *privilege,
+                        tx.clone(),
+                    )?;
+                }
+            }
+            Grantable::Role(role) => {
+                metadata_mgr.revoke_role(role, data.grantee(), tx)?;
+            }
+        }
+        Ok(0)
+    }
+
+    fn execute_expire(
+        &self,
+        policy: &RetentionPolicy,
+        cutoff: i32,
+        limit: usize,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        let layout = self.layout(policy.table_name(), tx.clone())?;
+        let schema = layout.schema().clone();
+        if !schema.has_field(policy.field_name()) {
+            return Err(PlanError::UnknownField(policy.field_name().to_string()));
+        }
+        let mut indexes = open_indexes(&self.metadata_mgr, policy.table_name(), tx.clone())?;
+        let mut scan = self.heap_scan(policy.table_name(), &Predicate::new(), layout, tx.clone());
+        let referenced = self.has_delete_actions(policy.table_name(), tx.clone());
+        let mut deleted = Vec::new();
+        let mut count = 0;
+        while count < limit && scan.next() {
+            if scan
+                .get_int(policy.field_name())
+                .is_none_or(|timestamp| timestamp >= cutoff)
+            {
+                continue;
+            }
+            if self.capture_enabled() || referenced {
+                let before = read_row(&scan, &schema);
+                if referenced {
+                    deleted.push(before.clone());
+                }
+                self.capture(
+                    &tx,
+                    policy.table_name(),
+                    ChangeOp::Delete,
+                    before,
+                    Vec::new(),
+                );
+            }
+            let rid = scan.get_rid().expect("scan is on a record");
+            for (field_name, index) in indexes.iter_mut() {
+                let val = scan
+                    .get_val(field_name)
+                    .expect("indexed field is in the table's schema");
+                index.delete(&val, rid)?;
+            }
+            scan.delete()?;
+            count += 1;
+        }
+        scan.close();
+        for (_, index) in indexes.iter_mut() {
+            index.close();
+        }
+        self.metadata_mgr.record_deletes(policy.table_name(), count);
+        self.apply_delete_actions(policy.table_name(), deleted, tx)?;
+        Ok(count)
+    }
+}
+
+fn read_row(scan: &dyn Scan, schema: &Schema) -> Vec<(String, Constant)> {
+    schema
+        .fields()
+        .iter()
+        .filter_map(|field_name| Some((field_name.clone(), scan.get_val(field_name)?)))
+        .collect()
+}
+
+// Opens every index on a table, paired with the field it indexes.
+fn open_indexes(
+    metadata_mgr: &MetadataMgr,
+    table_name: &str,
+    tx: Rc<RefCell<Transaction>>,
+) -> Result<Vec<(String, BTreeIndex)>, DbError> {
+    metadata_mgr
+        .get_index_info(table_name, tx)
+        .into_iter()
+        .map(|(field_name, index_info)| Ok((field_name, index_info.open()?)))
+        .collect()
+}
+
+// Rejects values that would not fit the field they are stored in.
+fn check_value(schema: &Schema, field_name: &str, val: &Constant) -> Result<(), PlanError> {
+    let field_type = schema
+        .field_type(field_name)
+        .ok_or_else(|| PlanError::UnknownField(field_name.to_string()))?;
+    match (field_type, val) {
+        (VARCHAR, Constant::String(val)) => {
+            if val.len() > schema.length(field_name).unwrap_or(0) {
+                return Err(PlanError::ValueTooLong(field_name.to_string()));
+            }
+            Ok(())
+        }
+        // Trailing spaces a char field has no room for are dropped.
+        (CHAR, Constant::String(val)) => {
+            let length = schema.length(field_name).unwrap_or(0);
+            if pad(val, length).len() > length {
+                return Err(PlanError::ValueTooLong(field_name.to_string()));
+            }
+            Ok(())
+        }
+        // Ints widen to the larger numeric types.
+        (INTEGER, Constant::Int(_))
+        | (BIGINT, Constant::Int(_) | Constant::Long(_))
+        | (DOUBLE, Constant::Int(_) | Constant::Long(_) | Constant::Double(_))
+        | (BOOLEAN, Constant::Bool(_))
+        | (DATE, Constant::Date(_))
+        | (BLOB, Constant::Blob(_)) => Ok(()),
+        _ => Err(PlanError::TypeMismatch(field_name.to_string())),
+    }
+}
+
+/// Parses SQL statements and hands them to the configured planners.
+pub struct Planner {
+    query_planner: Box<dyn QueryPlanner>,
+    update_planner: Box<dyn UpdatePlanner>,
+}
+
+impl Planner {
+    pub fn new(
+        query_planner: Box<dyn QueryPlanner>,
+        update_planner: Box<dyn UpdatePlanner>,
+    ) -> Planner {
+        Planner {
+            query_planner,
+            update_planner,
+        }
+    }
+
+    pub fn create_query_plan(
+        &self,
+        sql: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<Box<dyn Plan>, PlanError> {
+        match Parser::new(sql)?.query_cmd()? {
+            QueryCmd::Select(data) => self.query_planner.create_plan(&data, tx),
+            QueryCmd::Explain(data) => {
+                let plan = match data.statement() {
+                    Explained::Query(query) => self.query_planner.create_plan(query, tx)?,
+                    Explained::Modify(modify) => {
+                        self.update_planner.create_modify_plan(modify, tx)?
+                    }
+                };
+                Ok(Box::new(ExplainPlan::new(data.format(), plan.as_ref())))
+            }
+            QueryCmd::Check(data) => self.query_planner.create_check_plan(&data, tx),
+        }
+    }
+
+    pub fn execute_expire(
+        &self,
+        policy: &RetentionPolicy,
+        cutoff: i32,
+        limit: usize,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        self.update_planner
+            .execute_expire(policy, cutoff, limit, tx)
+    }
+
+    pub fn execute_update(
+        &self,
+        sql: &str,
+        tx: Rc<RefCell<Transaction>>,
+    ) -> Result<usize, PlanError> {
+        match Parser::new(sql)?.update_cmd()? {
+            UpdateData::Insert(data) => self.update_planner.execute_insert(&data, tx),
+            UpdateData::Delete(data) => self.update_planner.execute_delete(&data, tx),
+            UpdateData::Modify(data) => self.update_planner.execute_modify(&data, tx),
+            UpdateData::CreateTable(data) => self.update_planner.execute_create_table(&data, tx),
+            UpdateData::CreateView(data) => self.update_planner.execute_create_view(&data, tx),
+            UpdateData::CreateIndex(data) => self.update_planner.execute_create_index(&data, tx),
+            UpdateData::Grant(data) => self.update_planner.execute_grant(&data, tx),
+            UpdateData::Revoke(data) => self.update_planner.execute_revoke(&data, tx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_basic_planner() {
+        let tmp_dir = TempDir::new("test_basic_planner").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let mut planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr)),
+        );
+
+        let update = |planner: &mut Planner, sql: &str| planner.execute_update(sql, tx.clone());
+        update(
+            &mut planner,
+            "create table student (sid int, sname varchar(10), majorid int)",
+        )
+        .unwrap();
+        update(
+            &mut planner,
+            "create table dept (did int, dname varchar(8))",
+        )
+        .unwrap();
+        for (sid, sname, majorid) in [(1, "joe", 10), (2, "amy", 20), (3, "max", 10)] {
+            let sql = format!(
+                "insert into student (sid, sname, majorid) values ({}, '{}', {})",
+                sid, sname, majorid
+            );
+            assert_eq!(update(&mut planner, &sql).unwrap(), 1);
+        }
+        update(
+            &mut planner,
+            "insert into dept (did, dname) values (10, 'compsci')",
+        )
+        .unwrap();
+        update(
+            &mut planner,
+            "insert into dept (did, dname) values (20, 'math')",
+        )
+        .unwrap();
+
+        let plan = planner
+            .create_query_plan(
+                "select sname, dname from student, dept where majorid = did and dname = 'compsci'",
+                tx.clone(),
+            )
+            .unwrap();
+        assert!(plan.schema().has_field("sname"));
+        assert!(!plan.schema().has_field("sid"));
+        let mut scan = plan.open().unwrap();
+        let mut names = Vec::new();
+        while scan.next() {
+            assert_eq!(scan.get_string("dname"), Some("compsci".to_string()));
+            assert_eq!(scan.get_int("majorid"), None);
+            names.push(scan.get_string("sname").unwrap());
+        }
+        scan.close();
+        names.sort();
+        assert_eq!(names, vec!["joe".to_string(), "max".to_string()]);
+
+        let plan = planner
+            .create_query_plan(
+                "select majorid, count(sid), max(sname) from student group by majorid",
+                tx.clone(),
+            )
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut groups = Vec::new();
+        while scan.next() {
+            groups.push((
+                scan.get_int("majorid").unwrap(),
+                scan.get_int("countofsid").unwrap(),
+                scan.get_string("maxofsname").unwrap(),
+            ));
+        }
+        scan.close();
+        assert_eq!(
+            groups,
+            vec![(10, 2, "max".to_string()), (20, 1, "amy".to_string())]
+        );
+        let plan = planner
+            .create_query_plan(
+                "select majorid, approx_count_distinct(sname) from student group by majorid",
+                tx.clone(),
+            )
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut groups = Vec::new();
+        while scan.next() {
+            groups.push((
+                scan.get_int("majorid").unwrap(),
+                scan.get_int("approx_count_distinctofsname").unwrap(),
+            ));
+        }
+        scan.close();
+        assert_eq!(groups, vec![(10, 2), (20, 1)]);
+        assert!(matches!(
+            planner.create_query_plan("select sname, count(sid) from student", tx.clone()),
+            Err(PlanError::UnknownField(_))
+        ));
+
+        assert_eq!(
+            update(
+                &mut planner,
+                "create view compsci as select sname, majorid from student where majorid = 10"
+            )
+            .unwrap(),
+            0
+        );
+        let plan = planner
+            .create_query_plan(
+                "select sname, dname from compsci, dept where majorid = did",
+                tx.clone(),
+            )
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut rows = Vec::new();
+        while scan.next() {
+            rows.push((
+                scan.get_string("sname").unwrap(),
+                scan.get_string("dname").unwrap(),
+            ));
+        }
+        scan.close();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                ("joe".to_string(), "compsci".to_string()),
+                ("max".to_string(), "compsci".to_string())
+            ]
+        );
+        assert!(matches!(
+            update(&mut planner, "create view dept as select sid from student"),
+            Err(PlanError::TableExists(_))
+        ));
+        assert!(matches!(
+            update(&mut planner, "create table compsci (did int)"),
+            Err(PlanError::TableExists(_))
+        ));
+
+        assert_eq!(
+            update(
+                &mut planner,
+                "update student set majorid = 20 where sname = 'joe'"
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(
+            update(&mut planner, "delete from student where majorid = 20").unwrap(),
+            2
+        );
+        let plan = planner
+            .create_query_plan("select sid from student", tx.clone())
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut sids = Vec::new();
+        while scan.next() {
+            sids.push(scan.get_int("sid").unwrap());
+        }
+        scan.close();
+        assert_eq!(sids, vec![3]);
+
+        assert!(matches!(
+            planner.create_query_plan("select sid from nosuch", tx.clone()),
+            Err(PlanError::UnknownTable(_))
+        ));
+        assert!(matches!(
+            planner.create_query_plan("select nosuch from student", tx.clone()),
+            Err(PlanError::UnknownField(_))
+        ));
+        assert!(matches!(
+            update(&mut planner, "create table dept (did int)"),
+            Err(PlanError::TableExists(_))
+        ));
+        assert!(matches!(
+            update(
+                &mut planner,
+                "insert into dept (did, dname) values ('x', 'y')"
+            ),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            update(
+                &mut planner,
+                "insert into dept (did, dname) values (1, 'philosophy')"
+            ),
+            Err(PlanError::ValueTooLong(_))
+        ));
+        assert!(matches!(
+            planner.create_query_plan("select from", tx.clone()),
+            Err(PlanError::BadSyntax(_))
+        ));
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_indexes() {
+        let tmp_dir = TempDir::new("test_create_index").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let mut planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr.clone())),
+        );
+
+        planner
+            .execute_update("create table t (a int, b varchar(5))", tx.clone())
+            .unwrap();
+        for i in 0..50 {
+            let sql = format!("insert into t (a, b) values ({}, 'b{}')", i % 5, i);
+            planner.execute_update(&sql, tx.clone()).unwrap();
+        }
+        // Rows inserted before the index exists are indexed when it is created.
+        planner
+            .execute_update("create index t_a on t (a)", tx.clone())
+            .unwrap();
+        let indexes = metadata_mgr.get_index_info("t", tx.clone());
+        let mut index = indexes["a"].open().unwrap();
+        index.before_first(&Constant::Int(3));
+        let mut found = 0;
+        while index.next() {
+            found += 1;
+        }
+        index.close();
+        assert_eq!(found, 10);
+
+        assert!(matches!(
+            planner.execute_update("create index bad on nosuch (a)", tx.clone()),
+            Err(PlanError::UnknownTable(_))
+        ));
+        assert!(matches!(
+            planner.execute_update("create index bad on t (c)", tx.clone()),
+            Err(PlanError::UnknownField(_))
+        ));
+
+        // Later updates keep the index current, and queries read through it.
+        let query = |planner: &mut Planner, sql: &str| {
+            let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+            let mut scan = plan.open().unwrap();
+            let mut rows = Vec::new();
+            while scan.next() {
+                rows.push(scan.get_string("b").unwrap());
+            }
+            scan.close();
+            rows.sort();
+            rows
+        };
+        planner
+            .execute_update("insert into t (a, b) values (100, 'new')", tx.clone())
+            .unwrap();
+        assert_eq!(
+            query(&mut planner, "select b from t where a = 100"),
+            vec!["new"]
+        );
+        planner
+            .execute_update("update t set a = 100 where b = 'b3'", tx.clone())
+            .unwrap();
+        assert_eq!(
+            query(&mut planner, "select b from t where a = 100"),
+            vec!["b3", "new"]
+        );
+        assert_eq!(query(&mut planner, "select b from t where a = 3").len(), 9);
+        assert_eq!(
+            planner
+                .execute_update("delete from t where a = 100", tx.clone())
+                .unwrap(),
+            2
+        );
+        assert!(query(&mut planner, "select b from t where a = 100").is_empty());
+
+        planner
+            .execute_update("create table u (c int, d varchar(5))", tx.clone())
+            .unwrap();
+        for (c, d) in [(3, "x"), (4, "y"), (99, "z")] {
+            let sql = format!("insert into u (c, d) values ({}, '{}')", c, d);
+            planner.execute_update(&sql, tx.clone()).unwrap();
+        }
+        let plan = planner
+            .create_query_plan("select b, d from u, t where c = a", tx.clone())
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut joined = 0;
+        while scan.next() {
+            let d = scan.get_string("d").unwrap();
+            let b: i32 = scan.get_string("b").unwrap()[1..].parse().unwrap();
+            assert_eq!(d, if b % 5 == 3 { "x" } else { "y" });
+            joined += 1;
+        }
+        scan.close();
+        assert_eq!(joined, 19);
+
+        let explain = |planner: &mut Planner, sql: &str| {
+            let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+            assert!(plan.schema().has_field("plan"));
+            let mut scan = plan.open().unwrap();
+            let mut lines = Vec::new();
+            while scan.next() {
+                lines.push(scan.get_string("plan").unwrap());
+            }
+            scan.close();
+            lines
+        };
+        let lines = explain(
+            &mut planner,
+            "explain (format json) select b from t where a = 3",
+        );
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("{\"operator\":\"project\",\"details\":{\"fields\":\"b\"}"));
+        assert!(lines[0].contains(
+            "\"operator\":\"index select\",\"details\":{\"index\":\"t_a\",\"field\":\"a\",\"value\":\"3\"}"
+        ));
+        let lines = explain(
+            &mut planner,
+            "explain (format dot) select b, d from u, t where c = a and d = 'x'",
+        );
+        assert_eq!(lines.first().map(String::as_str), Some("digraph plan {"));
+        assert_eq!(lines.last().map(String::as_str), Some("}"));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("  node0 [label=\"project\\nfields: b, d\\nblocks: ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("predicate: c = a and d = 'x'")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("index join\\nindex: t_a\\ncondition: c = a")));
+        assert!(lines.contains(&"  node0 -> node1;".to_string()));
+
+        // An update finds its records through an index on another field, but
+        // not through one on the field it sets.
+        let lines = explain(
+            &mut planner,
+            "explain (format json) update t set b = 'two' where a = 2",
+        );
+        assert!(lines[0].starts_with("{\"operator\":\"select\""));
+        assert!(lines[0].contains("\"operator\":\"index select\",\"details\":{\"index\":\"t_a\""));
+        let lines = explain(
+            &mut planner,
+            "explain (format json) update t set a = 1 where a = 2",
+        );
+        assert!(!lines[0].contains("index select"));
+        assert!(matches!(
+            planner.create_query_plan("explain (format json) update t set c = 1", tx.clone()),
+            Err(PlanError::UnknownField(_))
+        ));
+        assert_eq!(
+            planner
+                .execute_update(
+                    "update t set b = 'seven' where a = 2 and b = 'b7'",
+                    tx.clone()
+                )
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            planner
+                .execute_update("update t set b = 'two' where a = 2", tx.clone())
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            query(&mut planner, "select b from t where a = 2"),
+            vec!["two"; 10]
+        );
+
+        let check = |planner: &mut Planner| {
+            let plan = planner
+                .create_query_plan("check table t", tx.clone())
+                .unwrap();
+            let mut scan = plan.open().unwrap();
+            let mut discrepancies = Vec::new();
+            while scan.next() {
+                assert_eq!(scan.get_string("index_name").unwrap(), "t_a");
+                let rid = Rid::new(
+                    scan.get_int("block").unwrap() as usize,
+                    scan.get_int("slot").unwrap() as usize,
+                );
+                discrepancies.push((rid, scan.get_string("problem").unwrap()));
+            }
+            scan.close();
+            discrepancies
+        };
+        assert!(check(&mut planner).is_empty());
+        let first_rid = |val: i32| {
+            let mut index = indexes["a"].open().unwrap();
+            index.before_first(&Constant::Int(val));
+            assert!(index.next());
+            let rid = index.get_data_rid().unwrap();
+            index.close();
+            rid
+        };
+        let (missing, mismatched) = (first_rid(3), first_rid(4));
+        let mut index = indexes["a"].open().unwrap();
+        index.delete(&Constant::Int(3), missing).unwrap();
+        index.insert(&Constant::Int(2), mismatched).unwrap();
+        index.insert(&Constant::Int(4), Rid::new(500, 0)).unwrap();
+        index.close();
+        assert_eq!(
+            check(&mut planner),
+            vec![
+                (missing, "row is not in the index".to_string()),
+                (mismatched, "entry 2 does not match the row's 4".to_string()),
+                (Rid::new(500, 0), "entry 4 points to no row".to_string()),
+            ]
+        );
+        assert!(matches!(
+            planner.create_query_plan("check table nosuch", tx.clone()),
+            Err(PlanError::UnknownTable(_))
+        ));
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_partitioned_table() {
+        let tmp_dir = TempDir::new("test_partitioned_table").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let mut planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr)),
+        );
+
+        planner
+            .execute_update(
+                "create table readings (ts int, v varchar(5)) partition by range (ts) (100, 200)",
+                tx.clone(),
+            )
+            .unwrap();
+        for (ts, v) in [(50, "a"), (150, "b"), (250, "c"), (120, "d")] {
+            let sql = format!("insert into readings (ts, v) values ({}, '{}')", ts, v);
+            planner.execute_update(&sql, tx.clone()).unwrap();
+        }
+        let query = |planner: &mut Planner, sql: &str| {
+            let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+            let mut scan = plan.open().unwrap();
+            let mut rows = Vec::new();
+            while scan.next() {
+                rows.push(scan.get_string("v").unwrap());
+            }
+            scan.close();
+            rows
+        };
+        assert_eq!(
+            query(&mut planner, "select v from readings"),
+            vec!["a", "b", "d", "c"]
+        );
+        assert_eq!(
+            query(&mut planner, "select v from readings where ts = 150"),
+            vec!["b"]
+        );
+        let plan = planner
+            .create_query_plan(
+                "explain (format json) select v from readings where ts = 150",
+                tx.clone(),
+            )
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        assert!(scan.next());
+        let json = scan.get_string("plan").unwrap();
+        scan.close();
+        assert!(json.contains("\"partitions\":\"readings.p1\""));
+
+        // Moving a record's key past a bound moves the record.
+        assert_eq!(
+            planner
+                .execute_update("update readings set ts = 300 where ts = 120", tx.clone())
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            query(&mut planner, "select v from readings where ts = 300"),
+            vec!["d"]
+        );
+        assert!(query(&mut planner, "select v from readings where ts = 120").is_empty());
+        assert_eq!(
+            planner
+                .execute_update("delete from readings where ts = 50", tx.clone())
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            query(&mut planner, "select v from readings"),
+            vec!["b", "c", "d"]
+        );
+
+        assert!(matches!(
+            planner.execute_update("create index readings_ts on readings (ts)", tx.clone()),
+            Err(PlanError::Unsupported(_))
+        ));
+        assert!(matches!(
+            planner.execute_update(
+                "create table logs (v varchar(5)) partition by range (v) (1)",
+                tx.clone()
+            ),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_column_defaults() {
+        let tmp_dir = TempDir::new("test_column_defaults").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr)),
+        );
+
+        planner
+            .execute_update(
+                "create table t (id int default nextval(t_ids), v varchar(5) default 'none', \
+                 ts int default current_timestamp)",
+                tx.clone(),
+            )
+            .unwrap();
+        planner
+            .execute_update("insert into t (v) values ('a')", tx.clone())
+            .unwrap();
+        planner
+            .execute_update("insert into t (id) values (10)", tx.clone())
+            .unwrap();
+        planner
+            .execute_update("insert into t (ts) values (0)", tx.clone())
+            .unwrap();
+
+        let plan = planner
+            .create_query_plan("select id, v, ts from t", tx.clone())
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut rows = Vec::new();
+        while scan.next() {
+            rows.push((
+                scan.get_int("id").unwrap(),
+                scan.get_string("v").unwrap(),
+                scan.get_int("ts").unwrap(),
+            ));
+        }
+        scan.close();
+        // Each record that leaves a field out evaluates its default again;
+        // a value given by the insert draws nothing from the sequence.
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[1].0, 10);
+        assert_eq!(rows[2].0, 2);
+        assert_eq!(rows[0].1, "a");
+        assert_eq!(rows[1].1, "none");
+        assert!(rows[0].2 > 1_600_000_000);
+        assert_eq!(rows[2].2, 0);
+
+        assert!(matches!(
+            planner.execute_update(
+                "create table u (v varchar(5) default current_timestamp)",
+                tx.clone()
+            ),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            planner.execute_update("create table u (a int default 'x')", tx.clone()),
+            Err(PlanError::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            planner.execute_update("create temp table u (a int default 1)", tx.clone()),
+            Err(PlanError::Unsupported(_))
+        ));
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_foreign_keys() {
+        let tmp_dir = TempDir::new("test_foreign_keys").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let mut planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr)),
+        );
+        let update = |sql: &str| planner.execute_update(sql, tx.clone());
+
+        for sql in [
+            "create table dept (id int)",
+            "create table emp (id int, dept int references dept (id) on delete cascade)",
+            "create index emp_dept on emp (dept)",
+            "create table badge (emp int references emp (id) on delete set null, n int)",
+            "create table memo (dept int references dept (id), n int)",
+            "create table node (id int, next int references node (id) on delete cascade)",
+        ] {
+            update(sql).unwrap();
+        }
+        for sql in [
+            "insert into dept (id) values (1)",
+            "insert into dept (id) values (2)",
+            "insert into emp (id, dept) values (10, 1)",
+            "insert into emp (id, dept) values (11, 1)",
+            "insert into emp (id, dept) values (20, 2)",
+            "insert into badge (emp, n) values (10, 100)",
+            "insert into badge (emp, n) values (20, 200)",
+            "insert into memo (dept, n) values (1, 1)",
+            "insert into node (id, next) values (1, 2)",
+            "insert into node (id, next) values (2, 1)",
+            "insert into node (id, next) values (3, 3)",
+        ] {
+            update(sql).unwrap();
+        }
+
+        let rows = |planner: &mut Planner, sql: &str, fields: [&str; 2]| {
+            let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+            let mut scan = plan.open().unwrap();
+            let mut rows = Vec::new();
+            while scan.next() {
+                rows.push((scan.get_int(fields[0]), scan.get_int(fields[1])));
+            }
+            scan.close();
+            rows.sort();
+            rows
+        };
+
+        // Deleting a department deletes its employees through the index on
+        // emp.dept, which clears their badges; a key without an action
+        // leaves its rows alone.
+        assert_eq!(
+            planner
+                .execute_update("delete from dept where id = 1", tx.clone())
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            rows(&mut planner, "select id, dept from emp", ["id", "dept"]),
+            vec![(Some(20), Some(2))]
+        );
+        assert_eq!(
+            rows(&mut planner, "select emp, n from badge", ["emp", "n"]),
+            vec![(None, Some(100)), (Some(20), Some(200))]
+        );
+        assert_eq!(
+            rows(&mut planner, "select dept, n from memo", ["dept", "n"]),
+            vec![(Some(1), Some(1))]
+        );
+        assert_eq!(
+            rows(
+                &mut planner,
+                "select id, dept from emp where dept = 1",
+                ["id", "dept"]
+            ),
+            Vec::new()
+        );
+
+        // A cycle of cascading rows stops once it is back at a deleted row.
+        assert_eq!(
+            planner
+                .execute_update("delete from node where id = 1", tx.clone())
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            rows(&mut planner, "select id, next from node", ["id", "next"]),
+            vec![(Some(3), Some(3))]
+        );
+        planner
+            .execute_update("delete from node where id = 3", tx.clone())
+            .unwrap();
+        assert!(rows(&mut planner, "select id, next from node", ["id", "next"]).is_empty());
+
+        for (sql, unsupported) in [
+            ("create table x (a int references nosuch (id))", false),
+            ("create table x (a int references dept (nosuch))", false),
+            ("create table x (a varchar(3) references dept (id))", false),
+            ("create temp table x (a int references dept (id))", true),
+            (
+                "create table x (a int references dept (id)) partition by range (a) (10)",
+                true,
+            ),
+        ] {
+            let result = planner.execute_update(sql, tx.clone());
+            assert_eq!(
+                matches!(result, Err(PlanError::Unsupported(_))),
+                unsupported,
+                "{}",
+                sql
+            );
+            assert!(result.is_err());
+        }
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_maintenance_advice() {
+        let tmp_dir = TempDir::new("test_maintenance_advice").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let mut planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr.clone())),
+        );
+
+        let update = |planner: &mut Planner, sql: &str| planner.execute_update(sql, tx.clone());
+        for sql in [
+            "create table t (a int)",
+            "create table u (a int)",
+            "create index ta on t (a)",
+        ] {
+            update(&mut planner, sql).unwrap();
+        }
+        // Each table fills its first block exactly.
+        let layout = metadata_mgr.get_layout("t", tx.clone()).unwrap();
+        let slots = TEST_BLOCK_SIZE / layout.slot_size();
+        for a in 0..slots {
+            update(&mut planner, &format!("insert into t (a) values ({})", a)).unwrap();
+            update(&mut planner, &format!("insert into u (a) values ({})", a)).unwrap();
+        }
+        let advice = |planner: &mut Planner| {
+            let plan = planner
+                .create_query_plan(
+                    "select table_name, dead_percent, action from sys_maintenance",
+                    tx.clone(),
+                )
+                .unwrap();
+            let mut scan = plan.open().unwrap();
+            let mut rows = Vec::new();
+            while scan.next() {
+                rows.push((
+                    scan.get_string("table_name").unwrap(),
+                    scan.get_int("dead_percent").unwrap(),
+                    scan.get_string("action").unwrap(),
+                ));
+            }
+            scan.close();
+            rows
+        };
+        assert!(advice(&mut planner).is_empty());
+
+        // The deletes are counted against the cached statistics.
+        let half = slots as i32 / 2;
+        update(&mut planner, "delete from u where a = 0").unwrap();
+        for a in 0..half {
+            update(&mut planner, &format!("delete from t where a = {}", a)).unwrap();
+        }
+        let dead = half * 100 / slots as i32;
+        assert_eq!(
+            advice(&mut planner),
+            vec![
+                ("t".to_string(), dead, "vacuum".to_string()),
+                ("t".to_string(), dead, "reindex".to_string()),
+            ]
+        );
+
+        // Without indexes there is nothing to rebuild.
+        for a in 1..half {
+            update(&mut planner, &format!("delete from u where a = {}", a)).unwrap();
+        }
+        assert_eq!(advice(&mut planner).len(), 3);
+        assert_eq!(
+            advice(&mut planner)[2],
+            ("u".to_string(), dead, "vacuum".to_string())
+        );
+        assert!(matches!(
+            update(&mut planner, "create table sys_maintenance (a int)"),
+            Err(PlanError::TableExists(_))
+        ));
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_storage_report() {
+        let tmp_dir = TempDir::new("test_storage_report").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let planner = Planner::new(
+            Box::new(BasicQueryPlanner::new(metadata_mgr.clone())),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr.clone())),
+        );
+
+        // Blobs cannot be written through SQL, so the table is filled
+        // directly: a value of three overflow blocks, an empty one of one
+        // block and a record without one.
+        let mut schema = Schema::new();
+        schema.add_int_field("id");
+        schema.add_blob_field("body");
+        metadata_mgr
+            .create_table("docs", &schema, tx.clone())
+            .unwrap();
+        let layout = metadata_mgr.get_layout("docs", tx.clone()).unwrap();
+        let big: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut scan = TableScan::new(tx.clone(), "docs", layout.clone());
+        for (id, body) in [(1, Some(big.as_slice())), (2, Some(&[][..])), (3, None)] {
+            scan.insert().unwrap();
+            scan.set_int("id", id).unwrap();
+            if let Some(body) = body {
+                scan.set_blob("body", body).unwrap();
+            }
+        }
+        scan.close();
+        for sql in [
+            "create table t (a int)",
+            "create index ta on t (a)",
+            "insert into t (a) values (1)",
+        ] {
+            planner.execute_update(sql, tx.clone()).unwrap();
+        }
+        tx.borrow_mut().commit().unwrap();
+        // The replaced chain is freed when the replacement commits.
+        let mut scan = TableScan::new(tx.clone(), "docs", layout);
+        assert!(scan.next());
+        scan.set_blob("body", b"short").unwrap();
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+
+        let plan = planner
+            .create_query_plan(
+                "select relation, kind, blocks, used_blocks, free_blocks, overflow_chains \
+                 from sys_storage",
+                tx.clone(),
+            )
+            .unwrap();
+        let mut scan = plan.open().unwrap();
+        let mut rows = Vec::new();
+        while scan.next() {
+            rows.push((
+                scan.get_string("relation").unwrap(),
+                scan.get_string("kind").unwrap(),
+                ["blocks", "used_blocks", "free_blocks", "overflow_chains"]
+                    .map(|field_name| scan.get_int(field_name).unwrap()),
+            ));
+        }
+        scan.close();
+        assert_eq!(
+            rows,
+            vec![
+                ("docs".to_string(), "table".to_string(), [6, 3, 3, 2]),
+                ("t".to_string(), "table".to_string(), [1, 1, 0, 0]),
+                ("ta".to_string(), "index".to_string(), [2, 2, 0, 0]),
+            ]
+        );
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_select_plan_uses_feedback() {
+        let tmp_dir = TempDir::new("test_feedback").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let metadata_mgr = Arc::new(MetadataMgr::new(true, tx.clone()).unwrap());
+        let query_planner = BasicQueryPlanner::new(metadata_mgr.clone());
+        let feedback = query_planner.feedback();
+        let planner = Planner::new(
+            Box::new(query_planner),
+            Box::new(BasicUpdatePlanner::new(metadata_mgr)),
+        );
+        planner
+            .execute_update("create table t (a int, b int)", tx.clone())
+            .unwrap();
+        // Half of the rows share one value of b, which the statistics
+        // cannot tell from a uniform spread.
+        for i in 0..100 {
+            let b = if i < 50 { 7 } else { i };
+            let sql = format!("insert into t (a, b) values ({}, {})", i, b);
+            planner.execute_update(&sql, tx.clone()).unwrap();
+        }
+
+        let sql = "select a from t where b = 7";
+        let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+        let estimate = plan.records_output();
+        assert_ne!(estimate, 50);
+
+        // A scan that is abandoned early reports nothing.
+        let mut scan = plan.open().unwrap();
+        assert!(scan.next());
+        scan.close();
+        assert!(feedback.lock().unwrap().lookup("t where b = 7").is_none());
+
+        let mut scan = plan.open().unwrap();
+        while scan.next() {}
+        scan.close();
+        assert_eq!(feedback.lock().unwrap().lookup("t where b = 7"), Some(50));
+
+        let plan = planner.create_query_plan(sql, tx.clone()).unwrap();
+        assert_eq!(plan.records_output(), 50);
+        let plan = planner
+            .create_query_plan("select a from t where b = 8", tx.clone())
+            .unwrap();
+        assert_eq!(plan.records_output(), estimate);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
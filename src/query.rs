@@ -0,0 +1,860 @@
+use crate::error::DbError;
+use crate::plan::Plan;
+use crate::recordmanager::{Rid, Schema, CHAR};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+/// Iterates over the output records of a relational operator.
+pub trait Scan {
+    fn before_first(&mut self);
+    fn next(&mut self) -> bool;
+    fn get_int(&self, field_name: &str) -> Option<i32>;
+    fn get_string(&self, field_name: &str) -> Option<String>;
+    /// Like `get_string`, but borrowed from the scan when it holds the value
+    /// in memory. A scan that reads values out of a buffer's page, which it
+    /// cannot lend past the lock on the page, returns an owned copy.
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.get_string(field_name).map(Cow::Owned)
+    }
+    /// Calls `f` with the value of the field, borrowed for the length of the
+    /// call. A scan that reads values out of a buffer's page lends them
+    /// straight from it, as the page stays locked until `f` returns.
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        f(self.get_str(field_name).as_deref())
+    }
+    fn get_val(&self, field_name: &str) -> Option<Constant>;
+    fn has_field(&self, field_name: &str) -> bool;
+    fn close(&mut self);
+}
+
+/// A scan whose current record maps back to a single record of a stored
+/// table, so it can be changed in place. The changes fail if the storage
+/// layers do, such as when the disk is full, and leave the transaction for
+/// the caller to roll back.
+pub trait UpdateScan: Scan {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError>;
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError>;
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError>;
+    fn insert(&mut self) -> Result<(), DbError>;
+    fn delete(&mut self) -> Result<(), DbError>;
+    fn get_rid(&self) -> Option<Rid>;
+    fn move_to_rid(&mut self, rid: Rid);
+}
+
+impl Scan for Box<dyn Scan> {
+    fn before_first(&mut self) {
+        self.as_mut().before_first()
+    }
+
+    fn next(&mut self) -> bool {
+        self.as_mut().next()
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.as_ref().get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.as_ref().get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.as_ref().get_str(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        self.as_ref().with_str(field_name, f)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.as_ref().get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.as_ref().has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.as_mut().close()
+    }
+}
+
+impl Scan for Box<dyn UpdateScan> {
+    fn before_first(&mut self) {
+        self.as_mut().before_first()
+    }
+
+    fn next(&mut self) -> bool {
+        self.as_mut().next()
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.as_ref().get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.as_ref().get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.as_ref().get_str(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        self.as_ref().with_str(field_name, f)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.as_ref().get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.as_ref().has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.as_mut().close()
+    }
+}
+
+impl UpdateScan for Box<dyn UpdateScan> {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError> {
+        self.as_mut().set_int(field_name, val)
+    }
+
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError> {
+        self.as_mut().set_string(field_name, val)
+    }
+
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError> {
+        self.as_mut().set_val(field_name, val)
+    }
+
+    fn insert(&mut self) -> Result<(), DbError> {
+        self.as_mut().insert()
+    }
+
+    fn delete(&mut self) -> Result<(), DbError> {
+        self.as_mut().delete()
+    }
+
+    fn get_rid(&self) -> Option<Rid> {
+        self.as_ref().get_rid()
+    }
+
+    fn move_to_rid(&mut self, rid: Rid) {
+        self.as_mut().move_to_rid(rid)
+    }
+}
+
+/// A value stored in a field, of one of the field types. Values of
+/// different types are never equal; they order by type, then by value, with
+/// doubles in IEEE 754 total order.
+#[derive(Clone, Debug)]
+pub enum Constant {
+    Int(i32),
+    String(String),
+    Long(i64),
+    Double(f64),
+    Bool(bool),
+    /// Days since 1970-01-01.
+    Date(i32),
+    Blob(Vec<u8>),
+}
+
+impl Constant {
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Constant::Int(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            Constant::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// The value of a bigint, or of an int widened to one.
+    pub fn as_long(&self) -> Option<i64> {
+        match self {
+            Constant::Int(val) => Some(i64::from(*val)),
+            Constant::Long(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    /// The value of a double, or of an int or bigint converted to one.
+    pub fn as_double(&self) -> Option<f64> {
+        match self {
+            Constant::Int(val) => Some(f64::from(*val)),
+            Constant::Long(val) => Some(*val as f64),
+            Constant::Double(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Constant::Bool(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_date(&self) -> Option<i32> {
+        match self {
+            Constant::Date(days) => Some(*days),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            Constant::Blob(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Constant::Int(_) => 0,
+            Constant::String(_) => 1,
+            Constant::Long(_) => 2,
+            Constant::Double(_) => 3,
+            Constant::Bool(_) => 4,
+            Constant::Date(_) => 5,
+            Constant::Blob(_) => 6,
+        }
+    }
+}
+
+impl PartialEq for Constant {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Constant {}
+
+impl PartialOrd for Constant {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Constant {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Constant::Int(lhs), Constant::Int(rhs)) => lhs.cmp(rhs),
+            (Constant::String(lhs), Constant::String(rhs)) => lhs.cmp(rhs),
+            (Constant::Long(lhs), Constant::Long(rhs)) => lhs.cmp(rhs),
+            (Constant::Double(lhs), Constant::Double(rhs)) => lhs.total_cmp(rhs),
+            (Constant::Bool(lhs), Constant::Bool(rhs)) => lhs.cmp(rhs),
+            (Constant::Date(lhs), Constant::Date(rhs)) => lhs.cmp(rhs),
+            (Constant::Blob(lhs), Constant::Blob(rhs)) => lhs.cmp(rhs),
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+}
+
+// Doubles hash by their bits, which are equal exactly when they are equal
+// in total order.
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_rank().hash(state);
+        match self {
+            Constant::Int(val) | Constant::Date(val) => val.hash(state),
+            Constant::String(val) => val.hash(state),
+            Constant::Long(val) => val.hash(state),
+            Constant::Double(val) => val.to_bits().hash(state),
+            Constant::Bool(val) => val.hash(state),
+            Constant::Blob(val) => val.hash(state),
+        }
+    }
+}
+
+// Written as the parser reads them back, which is how defaults are kept in
+// the catalog.
+impl Display for Constant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Int(val) => write!(f, "{}", val),
+            Constant::String(val) => write!(f, "'{}'", val),
+            Constant::Long(val) => write!(f, "{}", val),
+            Constant::Double(val) => write!(f, "{:?}", val),
+            Constant::Bool(val) => write!(f, "{}", val),
+            Constant::Date(days) => write!(f, "date '{}'", format_date(*days)),
+            Constant::Blob(val) => {
+                write!(f, "x'")?;
+                for byte in val {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+/// The number of days from 1970-01-01 to a date written `YYYY-MM-DD`, or
+/// `None` if it is not a valid date.
+pub fn parse_date(text: &str) -> Option<i32> {
+    let mut parts = text.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+    if !(1..=12).contains(&month) || !(1..=month_days[month as usize - 1]).contains(&day) {
+        return None;
+    }
+    // Counts from March, so that a leap day ends its year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    i32::try_from(era * 146_097 + day_of_era - 719_468).ok()
+}
+
+/// `days` after 1970-01-01, written `YYYY-MM-DD`.
+pub fn format_date(days: i32) -> String {
+    let days = i64::from(days) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// One side of a term: a constant or the name of a field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Constant(Constant),
+    FieldName(String),
+}
+
+impl Expression {
+    pub fn evaluate(&self, scan: &dyn Scan) -> Option<Constant> {
+        match self {
+            Expression::Constant(val) => Some(val.clone()),
+            Expression::FieldName(name) => scan.get_val(name),
+        }
+    }
+
+    pub fn applies_to(&self, schema: &Schema) -> bool {
+        match self {
+            Expression::Constant(_) => true,
+            Expression::FieldName(name) => schema.has_field(name),
+        }
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Constant(val) => write!(f, "{}", val),
+            Expression::FieldName(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// What a term checks its left-hand expression against.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Equals(Expression),
+    IsNull,
+    IsNotNull,
+}
+
+/// An equality between two expressions, or a test of whether an expression
+/// is null. A null is equal to nothing, not even another null.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Term {
+    lhs: Expression,
+    comparison: Comparison,
+    // Whether strings are compared as if the shorter were padded with
+    // spaces, as they are when a char field is one side.
+    padded: bool,
+}
+
+impl Term {
+    pub fn new(lhs: Expression, rhs: Expression) -> Term {
+        Term {
+            lhs,
+            comparison: Comparison::Equals(rhs),
+            padded: false,
+        }
+    }
+
+    pub fn is_null(lhs: Expression) -> Term {
+        Term {
+            lhs,
+            comparison: Comparison::IsNull,
+            padded: false,
+        }
+    }
+
+    pub fn is_not_null(lhs: Expression) -> Term {
+        Term {
+            lhs,
+            comparison: Comparison::IsNotNull,
+            padded: false,
+        }
+    }
+
+    /// The right-hand side of an equality; `None` for a null test.
+    pub fn rhs(&self) -> Option<&Expression> {
+        match &self.comparison {
+            Comparison::Equals(rhs) => Some(rhs),
+            Comparison::IsNull | Comparison::IsNotNull => None,
+        }
+    }
+
+    /// The constant `field_name` is compared with, if this term has the form
+    /// `F = c` or `c = F`.
+    pub fn equates_with_constant(&self, field_name: &str) -> Option<&Constant> {
+        match (&self.lhs, self.rhs()?) {
+            (Expression::FieldName(name), Expression::Constant(val))
+            | (Expression::Constant(val), Expression::FieldName(name))
+                if name == field_name =>
+            {
+                Some(val)
+            }
+            _ => None,
+        }
+    }
+
+    /// The other field `field_name` is compared with, if this term has the
+    /// form `F1 = F2`.
+    pub fn equates_with_field(&self, field_name: &str) -> Option<&str> {
+        match (&self.lhs, self.rhs()?) {
+            (Expression::FieldName(lhs), Expression::FieldName(rhs)) if lhs == field_name => {
+                Some(rhs)
+            }
+            (Expression::FieldName(lhs), Expression::FieldName(rhs)) if rhs == field_name => {
+                Some(lhs)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_satisfied(&self, scan: &dyn Scan) -> bool {
+        let lhs = self.lhs.evaluate(scan);
+        match &self.comparison {
+            Comparison::Equals(rhs) => match (lhs, rhs.evaluate(scan)) {
+                (Some(Constant::String(lhs)), Some(Constant::String(rhs))) if self.padded => {
+                    lhs.trim_end_matches(' ') == rhs.trim_end_matches(' ')
+                }
+                (Some(lhs), Some(rhs)) => lhs == rhs,
+                _ => false,
+            },
+            Comparison::IsNull => lhs.is_none(),
+            Comparison::IsNotNull => lhs.is_some(),
+        }
+    }
+
+    /// This term as checked against records of `schema`. A string compared
+    /// with a char field is padded to the field's length, like the field's
+    /// values are, and a char field compared with another field ignores
+    /// trailing spaces on both sides.
+    pub fn padded(&self, schema: &Schema) -> Term {
+        let mut term = self.clone();
+        let is_char = |name: &str| schema.field_type(name) == Some(CHAR);
+        if let Comparison::Equals(rhs) = &mut term.comparison {
+            match (&mut term.lhs, rhs) {
+                (Expression::FieldName(name), Expression::Constant(val))
+                | (Expression::Constant(val), Expression::FieldName(name)) => {
+                    *val = schema.padded(name, val);
+                }
+                (Expression::FieldName(lhs), Expression::FieldName(rhs)) => {
+                    term.padded = is_char(lhs) || is_char(rhs);
+                }
+                _ => {}
+            }
+        }
+        term
+    }
+
+    /// How many times smaller than `plan`'s output this term makes it.
+    /// Null is estimated as one more distinct value of the field.
+    pub fn reduction_factor(&self, plan: &dyn Plan) -> usize {
+        let rhs = match &self.comparison {
+            Comparison::Equals(rhs) => rhs,
+            Comparison::IsNull => {
+                return match &self.lhs {
+                    Expression::FieldName(name) => plan.distinct_values(name),
+                    Expression::Constant(_) => usize::MAX,
+                };
+            }
+            Comparison::IsNotNull => return 1,
+        };
+        match (&self.lhs, rhs) {
+            (Expression::FieldName(lhs), Expression::FieldName(rhs)) => {
+                plan.distinct_values(lhs).max(plan.distinct_values(rhs))
+            }
+            (Expression::FieldName(name), _) | (_, Expression::FieldName(name)) => {
+                plan.distinct_values(name)
+            }
+            (Expression::Constant(lhs), Expression::Constant(rhs)) => {
+                if lhs == rhs {
+                    1
+                } else {
+                    usize::MAX
+                }
+            }
+        }
+    }
+}
+
+impl Display for Term {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.comparison {
+            Comparison::Equals(rhs) => write!(f, "{} = {}", self.lhs, rhs),
+            Comparison::IsNull => write!(f, "{} is null", self.lhs),
+            Comparison::IsNotNull => write!(f, "{} is not null", self.lhs),
+        }
+    }
+}
+
+/// A conjunction of terms. The empty predicate is always true.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Predicate {
+    terms: Vec<Term>,
+}
+
+impl Predicate {
+    pub fn new() -> Predicate {
+        Predicate { terms: Vec::new() }
+    }
+
+    pub fn from_term(term: Term) -> Predicate {
+        Predicate { terms: vec![term] }
+    }
+
+    pub fn conjoin_with(&mut self, predicate: Predicate) {
+        self.terms.extend(predicate.terms);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn is_satisfied(&self, scan: &dyn Scan) -> bool {
+        self.terms.iter().all(|term| term.is_satisfied(scan))
+    }
+
+    pub fn reduction_factor(&self, plan: &dyn Plan) -> usize {
+        self.terms.iter().fold(1, |factor, term| {
+            factor.saturating_mul(term.reduction_factor(plan).max(1))
+        })
+    }
+
+    /// This predicate as checked against records of `schema`; see
+    /// `Term::padded`.
+    pub fn padded(&self, schema: &Schema) -> Predicate {
+        Predicate {
+            terms: self.terms.iter().map(|term| term.padded(schema)).collect(),
+        }
+    }
+
+    pub fn equates_with_constant(&self, field_name: &str) -> Option<&Constant> {
+        self.terms
+            .iter()
+            .find_map(|term| term.equates_with_constant(field_name))
+    }
+
+    pub fn equates_with_field(&self, field_name: &str) -> Option<&str> {
+        self.terms
+            .iter()
+            .find_map(|term| term.equates_with_field(field_name))
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let terms: Vec<String> = self.terms.iter().map(|term| term.to_string()).collect();
+        write!(f, "{}", terms.join(" and "))
+    }
+}
+
+/// Passes through the records of `scan` that satisfy a predicate.
+pub struct SelectScan<S: Scan> {
+    scan: S,
+    predicate: Predicate,
+}
+
+impl<S: Scan> SelectScan<S> {
+    pub fn new(scan: S, predicate: Predicate) -> SelectScan<S> {
+        SelectScan { scan, predicate }
+    }
+}
+
+impl<S: Scan> Scan for SelectScan<S> {
+    fn before_first(&mut self) {
+        self.scan.before_first();
+    }
+
+    fn next(&mut self) -> bool {
+        while self.scan.next() {
+            if self.predicate.is_satisfied(&self.scan) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.scan.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.scan.get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.scan.get_str(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        self.scan.with_str(field_name, f)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.scan.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.scan.has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        self.scan.close();
+    }
+}
+
+impl<S: UpdateScan> UpdateScan for SelectScan<S> {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError> {
+        self.scan.set_int(field_name, val)
+    }
+
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError> {
+        self.scan.set_string(field_name, val)
+    }
+
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError> {
+        self.scan.set_val(field_name, val)
+    }
+
+    fn insert(&mut self) -> Result<(), DbError> {
+        self.scan.insert()
+    }
+
+    fn delete(&mut self) -> Result<(), DbError> {
+        self.scan.delete()
+    }
+
+    fn get_rid(&self) -> Option<Rid> {
+        self.scan.get_rid()
+    }
+
+    fn move_to_rid(&mut self, rid: Rid) {
+        self.scan.move_to_rid(rid);
+    }
+}
+
+/// Hides every field of `scan` other than the listed ones.
+pub struct ProjectScan<S: Scan> {
+    scan: S,
+    fields: Vec<String>,
+}
+
+impl<S: Scan> ProjectScan<S> {
+    pub fn new(scan: S, fields: Vec<String>) -> ProjectScan<S> {
+        ProjectScan { scan, fields }
+    }
+}
+
+impl<S: Scan> Scan for ProjectScan<S> {
+    fn before_first(&mut self) {
+        self.scan.before_first();
+    }
+
+    fn next(&mut self) -> bool {
+        self.scan.next()
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        if !self.has_field(field_name) {
+            return None;
+        }
+        self.scan.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        if !self.has_field(field_name) {
+            return None;
+        }
+        self.scan.get_string(field_name)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        if !self.has_field(field_name) {
+            return None;
+        }
+        self.scan.get_str(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        if !self.has_field(field_name) {
+            return f(None);
+        }
+        self.scan.with_str(field_name, f)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        if !self.has_field(field_name) {
+            return None;
+        }
+        self.scan.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.fields.iter().any(|field| field == field_name)
+    }
+
+    fn close(&mut self) {
+        self.scan.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use crate::parse::Parser;
+    use crate::recordmanager::{Layout, TableScan};
+    use crate::transaction::Transaction;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    fn predicate(sql: &str) -> Predicate {
+        Parser::new(sql).unwrap().predicate().unwrap()
+    }
+
+    #[test]
+    fn test_predicate_analysis() {
+        let pred = predicate("a = 1 and 2 = b and b = c and c = 3");
+        assert_eq!(pred.equates_with_constant("a"), Some(&Constant::Int(1)));
+        assert_eq!(pred.equates_with_constant("b"), Some(&Constant::Int(2)));
+        assert_eq!(pred.equates_with_constant("d"), None);
+        assert_eq!(pred.equates_with_field("c"), Some("b"));
+        assert_eq!(pred.equates_with_field("a"), None);
+    }
+
+    #[test]
+    fn test_scans() {
+        let tmp_dir = TempDir::new("test_scans").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+
+        let mut schema1 = Schema::new();
+        schema1.add_int_field("a");
+        schema1.add_string_field("b", 9);
+        let layout1 = Layout::new(schema1);
+        let mut scan1 = TableScan::new(tx.clone(), "t1", layout1.clone());
+        for i in 0..30 {
+            scan1.insert().unwrap();
+            scan1.set_val("a", Constant::Int(i)).unwrap();
+            scan1
+                .set_val("b", Constant::String(format!("b{}", i % 3)))
+                .unwrap();
+        }
+        scan1.close();
+
+        // select b from t1 where b = 'b1' and a = 4
+        let select = SelectScan::new(
+            TableScan::new(tx.clone(), "t1", layout1.clone()),
+            predicate("b = 'b1' and a = 4"),
+        );
+        let mut project = ProjectScan::new(select, vec!["b".to_string()]);
+        let mut rows = Vec::new();
+        while project.next() {
+            assert!(!project.has_field("a"));
+            assert_eq!(project.get_int("a"), None);
+            rows.push(project.get_val("b"));
+        }
+        assert_eq!(rows, vec![Some(Constant::String("b1".to_string()))]);
+        project.close();
+
+        // Updates through a select scan change only the selected records.
+        let mut select = SelectScan::new(
+            TableScan::new(tx.clone(), "t1", layout1.clone()),
+            predicate("b = 'b2'"),
+        );
+        while select.next() {
+            select
+                .set_val("b", Constant::String("changed".to_string()))
+                .unwrap();
+        }
+        select.before_first();
+        assert!(!select.next());
+        select.close();
+
+        let mut select = SelectScan::new(
+            TableScan::new(tx.clone(), "t1", layout1),
+            predicate("b = 'changed'"),
+        );
+        let mut count = 0;
+        while select.next() {
+            assert_eq!(select.get_int("a").unwrap() % 3, 2);
+            count += 1;
+        }
+        select.close();
+        assert_eq!(count, 10);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
@@ -0,0 +1,1420 @@
+use crate::error::DbError;
+use crate::filemanager::BlockId;
+use crate::query::{Constant, Scan, UpdateScan};
+use crate::transaction::Transaction;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Type codes follow java.sql.Types, as SimpleDB's always have.
+pub const INTEGER: i32 = 4;
+pub const CHAR: i32 = 1;
+pub const VARCHAR: i32 = 12;
+pub const BIGINT: i32 = -5;
+pub const DOUBLE: i32 = 8;
+pub const BOOLEAN: i32 = 16;
+pub const DATE: i32 = 91;
+pub const BLOB: i32 = 2004;
+
+// Stored in a blob field that holds no value.
+const NO_BLOB: i32 = -1;
+
+#[derive(Clone)]
+struct FieldInfo {
+    field_type: i32,
+    length: usize,
+    // Where the values of a query's field come from, when it is a field of
+    // a stored table, and whether they can be null. Neither is kept in the
+    // catalog.
+    table_name: Option<String>,
+    nullable: bool,
+}
+
+/// Names, types and declared lengths of the fields in a table's records.
+#[derive(Clone)]
+pub struct Schema {
+    fields: Vec<String>,
+    info: HashMap<String, FieldInfo>,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema {
+            fields: Vec::new(),
+            info: HashMap::new(),
+        }
+    }
+
+    pub fn add_field(&mut self, field_name: &str, field_type: i32, length: usize) {
+        if !self.info.contains_key(field_name) {
+            self.fields.push(field_name.to_string());
+        }
+        self.info.insert(
+            field_name.to_string(),
+            FieldInfo {
+                field_type,
+                length,
+                table_name: None,
+                nullable: true,
+            },
+        );
+    }
+
+    pub fn add_int_field(&mut self, field_name: &str) {
+        self.add_field(field_name, INTEGER, 0);
+    }
+
+    pub fn add_string_field(&mut self, field_name: &str, length: usize) {
+        self.add_field(field_name, VARCHAR, length);
+    }
+
+    /// A char field holds strings of exactly `length` bytes: shorter values
+    /// are padded with spaces when stored, and read back padded.
+    pub fn add_char_field(&mut self, field_name: &str, length: usize) {
+        self.add_field(field_name, CHAR, length);
+    }
+
+    pub fn add_long_field(&mut self, field_name: &str) {
+        self.add_field(field_name, BIGINT, 0);
+    }
+
+    pub fn add_double_field(&mut self, field_name: &str) {
+        self.add_field(field_name, DOUBLE, 0);
+    }
+
+    pub fn add_bool_field(&mut self, field_name: &str) {
+        self.add_field(field_name, BOOLEAN, 0);
+    }
+
+    /// A date field holds days since 1970-01-01.
+    pub fn add_date_field(&mut self, field_name: &str) {
+        self.add_field(field_name, DATE, 0);
+    }
+
+    /// A blob field holds a value of any size. The record keeps only the
+    /// number of the first block of a chain of overflow blocks in the
+    /// table's `.blob` file.
+    pub fn add_blob_field(&mut self, field_name: &str) {
+        self.add_field(field_name, BLOB, 0);
+    }
+
+    /// Adds `field_name` as `schema` has it, with the table it comes from.
+    pub fn add(&mut self, field_name: &str, schema: &Schema) {
+        if let Some(info) = schema.info.get(field_name) {
+            self.add_field(field_name, info.field_type, info.length);
+            self.info.insert(field_name.to_string(), info.clone());
+        }
+    }
+
+    pub fn add_all(&mut self, schema: &Schema) {
+        for field_name in schema.fields() {
+            self.add(field_name, schema);
+        }
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn has_field(&self, field_name: &str) -> bool {
+        self.info.contains_key(field_name)
+    }
+
+    pub fn field_type(&self, field_name: &str) -> Option<i32> {
+        self.info.get(field_name).map(|info| info.field_type)
+    }
+
+    pub fn length(&self, field_name: &str) -> Option<usize> {
+        self.info.get(field_name).map(|info| info.length)
+    }
+
+    /// The stored table the field's values are read from, if they are not
+    /// computed by the query.
+    pub fn table_name(&self, field_name: &str) -> Option<&str> {
+        self.info.get(field_name)?.table_name.as_deref()
+    }
+
+    /// Whether the field can be null. A field is taken to be nullable unless
+    /// known otherwise.
+    pub fn nullable(&self, field_name: &str) -> Option<bool> {
+        self.info.get(field_name).map(|info| info.nullable)
+    }
+
+    pub fn set_nullable(&mut self, field_name: &str, nullable: bool) {
+        if let Some(info) = self.info.get_mut(field_name) {
+            info.nullable = nullable;
+        }
+    }
+
+    /// `val` as it compares with the values of `field_name`: a string is
+    /// padded with spaces to the length of a char field, or loses trailing
+    /// spaces past that length, and an int is widened to a bigint or double
+    /// field's type. Other values are returned as they are.
+    pub fn padded(&self, field_name: &str, val: &Constant) -> Constant {
+        match (self.field_type(field_name), val) {
+            (Some(CHAR), Constant::String(val)) => {
+                Constant::String(pad(val, self.length(field_name).unwrap_or(0)))
+            }
+            (Some(BIGINT), Constant::Int(_)) => Constant::Long(val.as_long().unwrap_or(0)),
+            (Some(DOUBLE), Constant::Int(_) | Constant::Long(_)) => {
+                Constant::Double(val.as_double().unwrap_or(0.0))
+            }
+            _ => val.clone(),
+        }
+    }
+
+    /// The fields of `layout`, read from `table_name`; only fields with a
+    /// null bit can be null.
+    pub fn of_table(table_name: &str, layout: &Layout) -> Schema {
+        let mut schema = layout.schema().clone();
+        for info in schema.info.values_mut() {
+            info.table_name = Some(table_name.to_string());
+        }
+        for field_name in layout.schema().fields() {
+            schema.set_nullable(field_name, layout.null_bit(field_name).is_some());
+        }
+        schema
+    }
+}
+
+/// Physical layout of a record: each slot starts with an in-use flag followed
+/// by the fields at fixed offsets.
+///
+/// The flag is an int whose lowest bit marks the slot in use. Its other 31
+/// bits are the null bitmap of the first 31 fields, in offset order; a record
+/// with more fields has one more int of null bits for every 32 of the rest,
+/// between the flag and the first field.
+#[derive(Clone)]
+pub struct Layout {
+    schema: Schema,
+    offsets: HashMap<String, usize>,
+    slot_size: usize,
+    null_bits: HashMap<String, (usize, i32)>,
+}
+
+impl Layout {
+    pub fn new(schema: Schema) -> Layout {
+        let mut offsets = HashMap::new();
+        let extra_null_words = schema.fields().len().saturating_sub(31).div_ceil(32);
+        let mut pos = size_of::<i32>() * (1 + extra_null_words);
+        for field_name in schema.fields() {
+            offsets.insert(field_name.clone(), pos);
+            pos += Self::length_in_bytes(&schema, field_name);
+        }
+        Self::from_parts(schema, offsets, pos)
+    }
+
+    /// A layout read back from the catalog. Null bits go by offset order,
+    /// so they do not depend on the order the fields were read in; fields
+    /// with no room left for a bit before the first field cannot be null.
+    pub fn from_parts(schema: Schema, offsets: HashMap<String, usize>, slot_size: usize) -> Layout {
+        let mut by_offset: Vec<(&String, &usize)> = offsets.iter().collect();
+        by_offset.sort_by_key(|(_, offset)| **offset);
+        let first_offset = by_offset
+            .first()
+            .map_or(size_of::<i32>(), |(_, offset)| **offset);
+        let nullable = 31 + 32 * (first_offset / size_of::<i32>()).saturating_sub(1);
+        let null_bits = by_offset
+            .iter()
+            .take(nullable)
+            .enumerate()
+            .map(|(idx, (field_name, _))| {
+                let bit = if idx < 31 {
+                    (0, 1 << (idx + 1))
+                } else {
+                    let idx = idx - 31;
+                    (size_of::<i32>() * (1 + idx / 32), 1 << (idx % 32))
+                };
+                (field_name.to_string(), bit)
+            })
+            .collect();
+        Layout {
+            schema,
+            offsets,
+            slot_size,
+            null_bits,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn offset(&self, field_name: &str) -> Option<usize> {
+        self.offsets.get(field_name).copied()
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// The offset within the slot of the int holding `field_name`'s null
+    /// bit, and the bit itself.
+    pub fn null_bit(&self, field_name: &str) -> Option<(usize, i32)> {
+        self.null_bits.get(field_name).copied()
+    }
+
+    // Every null bit, grouped by the int holding them.
+    fn null_words(&self) -> Vec<(usize, i32)> {
+        let mut words: Vec<(usize, i32)> = Vec::new();
+        for (offset, bit) in self.null_bits.values() {
+            match words.iter_mut().find(|(word, _)| word == offset) {
+                Some((_, bits)) => *bits |= bit,
+                None => words.push((*offset, *bit)),
+            }
+        }
+        words.sort();
+        words
+    }
+
+    fn length_in_bytes(schema: &Schema, field_name: &str) -> usize {
+        match schema.field_type(field_name) {
+            Some(VARCHAR) | Some(CHAR) => size_of::<i32>() + schema.length(field_name).unwrap_or(0),
+            Some(BIGINT) | Some(DOUBLE) => size_of::<i64>(),
+            _ => size_of::<i32>(),
+        }
+    }
+}
+
+/// The blob whose overflow chain in `blob_file_name` starts at
+/// `first_block`, or `None` for a field that holds no blob. A read that
+/// fails is kept for the statement to fail with, like any other field's.
+pub fn read_blob(tx: &mut Transaction, blob_file_name: &str, first_block: i32) -> Option<Vec<u8>> {
+    if first_block == NO_BLOB {
+        return None;
+    }
+    tx.scan_read(|tx| tx.read_blob(blob_file_name, first_block).map(Some))
+}
+
+/// `val` padded with spaces to `length` bytes. Spaces past `length` are
+/// dropped; other bytes past it are kept, since they make `val` too long for
+/// a char field of that length.
+pub fn pad(val: &str, length: usize) -> String {
+    let trimmed = val.trim_end_matches(' ');
+    if trimmed.len() >= length {
+        return trimmed.to_string();
+    }
+    trimmed.to_string() + &" ".repeat(length - trimmed.len())
+}
+
+pub const EMPTY: i32 = 0;
+pub const USED: i32 = 1;
+
+/// Fixed-length records stored in consecutive slots of one block. Slot
+/// positions are `Option<usize>` where `None` means "before the first slot".
+/// Every field of a newly inserted record is null until it is set, and the
+/// getters return `None` for a null field.
+pub struct RecordPage {
+    tx: Rc<RefCell<Transaction>>,
+    block_id: BlockId,
+    layout: Layout,
+}
+
+impl RecordPage {
+    pub fn new(tx: Rc<RefCell<Transaction>>, block_id: BlockId, layout: Layout) -> RecordPage {
+        tx.borrow_mut()
+            .pin(&block_id)
+            .expect("failed to pin record block");
+        RecordPage {
+            tx,
+            block_id,
+            layout,
+        }
+    }
+
+    pub fn get_int(&self, slot: usize, field_name: &str) -> Option<i32> {
+        self.read(slot, field_name, Transaction::get_int)
+    }
+
+    pub fn get_string(&self, slot: usize, field_name: &str) -> Option<String> {
+        self.read(slot, field_name, Transaction::get_string)
+    }
+
+    /// Calls `f` with the string field borrowed straight from the block's
+    /// page, which stays read-locked until `f` returns.
+    pub fn with_str<R>(
+        &self,
+        slot: usize,
+        field_name: &str,
+        f: impl FnOnce(Option<&str>) -> R,
+    ) -> R {
+        let page = match self.layout.offset(field_name) {
+            Some(offset) if !self.is_null(slot, field_name) => {
+                let page = self
+                    .tx
+                    .borrow_mut()
+                    .scan_read(|tx| tx.read_page(&self.block_id));
+                page.map(|page| (page, self.offset(slot) + offset))
+            }
+            _ => None,
+        };
+        let Some((page, pos)) = page else {
+            return f(None);
+        };
+        let page = page.read().unwrap();
+        f(page.get_str(pos).as_deref())
+    }
+
+    pub fn get_long(&self, slot: usize, field_name: &str) -> Option<i64> {
+        self.read(slot, field_name, Transaction::get_long)
+    }
+
+    pub fn get_double(&self, slot: usize, field_name: &str) -> Option<f64> {
+        self.read(slot, field_name, Transaction::get_double)
+    }
+
+    // Fields are read through `Transaction::scan_read`, so a read that
+    // fails, as on a lock that cannot be granted, reads as `None` and is
+    // kept for the statement to fail with.
+    fn read<T>(
+        &self,
+        slot: usize,
+        field_name: &str,
+        read: impl FnOnce(&mut Transaction, &BlockId, usize) -> Result<Option<T>, DbError>,
+    ) -> Option<T> {
+        let pos = self.offset(slot) + self.layout.offset(field_name)?;
+        if self.is_null(slot, field_name) {
+            return None;
+        }
+        self.tx
+            .borrow_mut()
+            .scan_read(|tx| read(tx, &self.block_id, pos))
+    }
+
+    pub fn get_bool(&self, slot: usize, field_name: &str) -> Option<bool> {
+        self.get_int(slot, field_name).map(|val| val != 0)
+    }
+
+    pub fn get_date(&self, slot: usize, field_name: &str) -> Option<i32> {
+        self.get_int(slot, field_name)
+    }
+
+    /// The value of a field of any type. A blob is read from the overflow
+    /// chain in `blob_file_name` that the field points to.
+    pub fn get_val(&self, slot: usize, field_name: &str, blob_file_name: &str) -> Option<Constant> {
+        match self.layout.schema().field_type(field_name)? {
+            VARCHAR | CHAR => self.get_string(slot, field_name).map(Constant::String),
+            BIGINT => self.get_long(slot, field_name).map(Constant::Long),
+            DOUBLE => self.get_double(slot, field_name).map(Constant::Double),
+            BOOLEAN => self.get_bool(slot, field_name).map(Constant::Bool),
+            DATE => self.get_date(slot, field_name).map(Constant::Date),
+            BLOB => {
+                let first_block = self.get_int(slot, field_name)?;
+                read_blob(&mut self.tx.borrow_mut(), blob_file_name, first_block)
+                    .map(Constant::Blob)
+            }
+            _ => self.get_int(slot, field_name).map(Constant::Int),
+        }
+    }
+
+    pub fn set_int(&self, slot: usize, field_name: &str, val: i32) -> Result<(), DbError> {
+        let pos = self.offset(slot) + self.field_offset(field_name);
+        self.set_null_bit(slot, field_name, false)?;
+        self.tx
+            .borrow_mut()
+            .set_int(&self.block_id, pos as i32, Some(val), true)
+    }
+
+    pub fn set_long(&self, slot: usize, field_name: &str, val: i64) -> Result<(), DbError> {
+        let pos = self.offset(slot) + self.field_offset(field_name);
+        self.set_null_bit(slot, field_name, false)?;
+        self.tx
+            .borrow_mut()
+            .set_long(&self.block_id, pos as i32, Some(val), true)
+    }
+
+    pub fn set_double(&self, slot: usize, field_name: &str, val: f64) -> Result<(), DbError> {
+        let pos = self.offset(slot) + self.field_offset(field_name);
+        self.set_null_bit(slot, field_name, false)?;
+        self.tx
+            .borrow_mut()
+            .set_double(&self.block_id, pos as i32, Some(val), true)
+    }
+
+    pub fn set_bool(&self, slot: usize, field_name: &str, val: bool) -> Result<(), DbError> {
+        self.set_int(slot, field_name, i32::from(val))
+    }
+
+    pub fn set_date(&self, slot: usize, field_name: &str, days: i32) -> Result<(), DbError> {
+        self.set_int(slot, field_name, days)
+    }
+
+    /// A char field's value is padded to the field's length.
+    pub fn set_string(&self, slot: usize, field_name: &str, val: String) -> Result<(), DbError> {
+        let pos = self.offset(slot) + self.field_offset(field_name);
+        let schema = self.layout.schema();
+        let val = match schema.field_type(field_name) {
+            Some(CHAR) => pad(&val, schema.length(field_name).unwrap_or(0)),
+            _ => val,
+        };
+        self.set_null_bit(slot, field_name, false)?;
+        self.tx
+            .borrow_mut()
+            .set_string(&self.block_id, pos as i32, Some(val), true)
+    }
+
+    pub fn is_null(&self, slot: usize, field_name: &str) -> bool {
+        let Some((word, bit)) = self.layout.null_bit(field_name) else {
+            return false;
+        };
+        let pos = self.offset(slot) + word;
+        let bits = self
+            .tx
+            .borrow_mut()
+            .scan_read(|tx| tx.get_int(&self.block_id, pos));
+        bits.is_some_and(|bits| bits & bit != 0)
+    }
+
+    pub fn set_null(&self, slot: usize, field_name: &str) -> Result<(), DbError> {
+        if self.layout.null_bit(field_name).is_none() {
+            panic!("field {} cannot be null", field_name);
+        }
+        self.set_null_bit(slot, field_name, true)
+    }
+
+    // Logged like any other change, and only written when it changes.
+    fn set_null_bit(&self, slot: usize, field_name: &str, null: bool) -> Result<(), DbError> {
+        let Some((word, bit)) = self.layout.null_bit(field_name) else {
+            return Ok(());
+        };
+        let pos = self.offset(slot) + word;
+        let mut tx = self.tx.borrow_mut();
+        let bits = tx.get_int(&self.block_id, pos)?.unwrap_or(0);
+        let new_bits = if null { bits | bit } else { bits & !bit };
+        if new_bits != bits {
+            tx.set_int(&self.block_id, pos as i32, Some(new_bits), true)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, slot: usize) -> Result<(), DbError> {
+        self.set_flag(slot, EMPTY)
+    }
+
+    /// Marks every slot of a fresh block empty and zeroes its fields. The
+    /// block is new, so none of this needs to be logged.
+    pub fn format(&self) -> Result<(), DbError> {
+        let mut slot = 0;
+        while self.is_valid_slot(slot) {
+            let mut tx = self.tx.borrow_mut();
+            let offset = self.offset(slot);
+            tx.set_int(&self.block_id, offset as i32, Some(EMPTY), false)?;
+            let schema = self.layout.schema();
+            for field_name in schema.fields() {
+                let pos = (offset + self.field_offset(field_name)) as i32;
+                match schema.field_type(field_name) {
+                    Some(VARCHAR) => tx.set_string(&self.block_id, pos, Some(String::new()), false),
+                    Some(CHAR) => {
+                        let blank = pad("", schema.length(field_name).unwrap_or(0));
+                        tx.set_string(&self.block_id, pos, Some(blank), false)
+                    }
+                    Some(BIGINT) | Some(DOUBLE) => tx.set_long(&self.block_id, pos, Some(0), false),
+                    Some(BLOB) => tx.set_int(&self.block_id, pos, Some(NO_BLOB), false),
+                    _ => tx.set_int(&self.block_id, pos, Some(0), false),
+                }?;
+            }
+            slot += 1;
+        }
+        Ok(())
+    }
+
+    pub fn next_after(&self, slot: Option<usize>) -> Option<usize> {
+        self.tx
+            .borrow_mut()
+            .scan_read(|tx| self.search_after(tx, slot, USED))
+    }
+
+    pub fn insert_after(&self, slot: Option<usize>) -> Result<Option<usize>, DbError> {
+        let found = self.search_after(&mut self.tx.borrow_mut(), slot, EMPTY)?;
+        let Some(new_slot) = found else {
+            return Ok(None);
+        };
+        let mut flag = USED;
+        for (word, bits) in self.layout.null_words() {
+            if word == 0 {
+                flag |= bits;
+            } else {
+                let pos = (self.offset(new_slot) + word) as i32;
+                self.tx
+                    .borrow_mut()
+                    .set_int(&self.block_id, pos, Some(bits), true)?;
+            }
+        }
+        self.set_flag(new_slot, flag)?;
+        Ok(Some(new_slot))
+    }
+
+    pub fn block_id(&self) -> &BlockId {
+        &self.block_id
+    }
+
+    fn set_flag(&self, slot: usize, flag: i32) -> Result<(), DbError> {
+        self.tx
+            .borrow_mut()
+            .set_int(&self.block_id, self.offset(slot) as i32, Some(flag), true)
+    }
+
+    fn search_after(
+        &self,
+        tx: &mut Transaction,
+        slot: Option<usize>,
+        flag: i32,
+    ) -> Result<Option<usize>, DbError> {
+        let mut slot = slot.map_or(0, |slot| slot + 1);
+        while self.offset(slot + 1) <= tx.block_size() {
+            let found = tx.get_int(&self.block_id, self.offset(slot))?;
+            if found.map(|found| found & USED) == Some(flag) {
+                return Ok(Some(slot));
+            }
+            slot += 1;
+        }
+        Ok(None)
+    }
+
+    fn is_valid_slot(&self, slot: usize) -> bool {
+        self.offset(slot + 1) <= self.tx.borrow().block_size()
+    }
+
+    fn offset(&self, slot: usize) -> usize {
+        slot * self.layout.slot_size()
+    }
+
+    fn field_offset(&self, field_name: &str) -> usize {
+        self.layout
+            .offset(field_name)
+            .unwrap_or_else(|| panic!("field {} is not in the layout", field_name))
+    }
+}
+
+/// Identifies a record by the block it lives in and its slot in that block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Rid {
+    block_num: usize,
+    slot: usize,
+}
+
+impl Rid {
+    pub fn new(block_num: usize, slot: usize) -> Rid {
+        Rid { block_num, slot }
+    }
+
+    pub fn block_num(&self) -> usize {
+        self.block_num
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+/// Iterates over every record of a table, whose records are stored in
+/// `<table>.tbl` one `RecordPage` per block. Values of blob fields live in
+/// `<table>.blob`.
+pub struct TableScan {
+    tx: Rc<RefCell<Transaction>>,
+    layout: Layout,
+    record_page: Option<RecordPage>,
+    file_name: String,
+    blob_file_name: String,
+    current_slot: Option<usize>,
+}
+
+impl TableScan {
+    pub fn new(tx: Rc<RefCell<Transaction>>, table_name: &str, layout: Layout) -> TableScan {
+        let file_name = format!("{}.tbl", table_name);
+        let mut scan = TableScan {
+            tx,
+            layout,
+            record_page: None,
+            file_name,
+            blob_file_name: format!("{}.blob", table_name),
+            current_slot: None,
+        };
+        scan.before_first();
+        scan
+    }
+
+    // Typed updates of the fields that `Scan` only reaches through
+    // `set_val`.
+
+    pub fn set_long(&mut self, field_name: &str, val: i64) -> Result<(), DbError> {
+        self.record_page().set_long(self.slot(), field_name, val)
+    }
+
+    pub fn set_double(&mut self, field_name: &str, val: f64) -> Result<(), DbError> {
+        self.record_page().set_double(self.slot(), field_name, val)
+    }
+
+    pub fn set_bool(&mut self, field_name: &str, val: bool) -> Result<(), DbError> {
+        self.record_page().set_bool(self.slot(), field_name, val)
+    }
+
+    pub fn set_date(&mut self, field_name: &str, days: i32) -> Result<(), DbError> {
+        self.record_page().set_date(self.slot(), field_name, days)
+    }
+
+    pub fn set_null(&mut self, field_name: &str) -> Result<(), DbError> {
+        if self.layout.schema().field_type(field_name) == Some(BLOB) {
+            self.drop_blob(field_name)?;
+        }
+        self.record_page().set_null(self.slot(), field_name)
+    }
+
+    /// Whether a blob field points to an overflow chain, without reading it.
+    pub fn has_blob(&self, field_name: &str) -> bool {
+        self.get_int(field_name)
+            .is_some_and(|first_block| first_block != NO_BLOB)
+    }
+
+    /// Writes `val` to a new overflow chain and points the field at it. The
+    /// chain it replaces is freed when the transaction commits.
+    pub fn set_blob(&mut self, field_name: &str, val: &[u8]) -> Result<(), DbError> {
+        self.drop_blob(field_name)?;
+        let first_block = self.tx.borrow_mut().write_blob(&self.blob_file_name, val)?;
+        self.set_int(field_name, first_block)
+    }
+
+    fn drop_blob(&mut self, field_name: &str) -> Result<(), DbError> {
+        if let Some(first_block) = self.get_int(field_name).filter(|block| *block != NO_BLOB) {
+            self.tx
+                .borrow_mut()
+                .drop_blob(&self.blob_file_name, first_block)?;
+        }
+        Ok(())
+    }
+
+    fn move_to_block(&mut self, block_num: usize) {
+        self.close();
+        let block_id = BlockId::new(&self.file_name, block_num);
+        self.record_page = Some(RecordPage::new(
+            self.tx.clone(),
+            block_id,
+            self.layout.clone(),
+        ));
+        self.current_slot = None;
+    }
+
+    // Asks for the block after `block_num` while this one is read, so that
+    // a sequential scan does not stall at every block boundary.
+    fn prefetch_after(&self, block_num: usize) {
+        let mut tx = self.tx.borrow_mut();
+        if tx.prefetching() && block_num + 1 < Self::size(&mut tx, &self.file_name) {
+            tx.prefetch(&[BlockId::new(&self.file_name, block_num + 1)]);
+        }
+    }
+
+    fn move_to_new_block(&mut self) -> Result<(), DbError> {
+        self.close();
+        self.current_slot = None;
+        let block_id = self.tx.borrow_mut().append(&self.file_name)?;
+        let record_page = RecordPage::new(self.tx.clone(), block_id, self.layout.clone());
+        self.record_page = Some(record_page);
+        self.record_page().format()
+    }
+
+    fn at_last_block(&self) -> bool {
+        let size = Self::size(&mut self.tx.borrow_mut(), &self.file_name);
+        self.record_page().block_id().block_num() + 1 >= size
+    }
+
+    // A size that cannot be read, as on a lock that cannot be granted, is
+    // kept for the statement to fail with, and the scan ends.
+    fn size(tx: &mut Transaction, file_name: &str) -> usize {
+        tx.scan_read(|tx| tx.size(file_name).map(Some)).unwrap_or(0)
+    }
+
+    fn record_page(&self) -> &RecordPage {
+        self.record_page.as_ref().expect("table scan is closed")
+    }
+
+    fn slot(&self) -> usize {
+        self.current_slot
+            .expect("table scan is not positioned on a record")
+    }
+}
+
+impl Scan for TableScan {
+    // An empty table gets its first block from the first insert, so that
+    // reading it takes no space on the disk.
+    fn before_first(&mut self) {
+        if Self::size(&mut self.tx.borrow_mut(), &self.file_name) == 0 {
+            self.close();
+            self.current_slot = None;
+        } else {
+            self.move_to_block(0);
+            self.prefetch_after(0);
+        }
+    }
+
+    // Moves to the next used record, crossing into later blocks as needed.
+    fn next(&mut self) -> bool {
+        if self.record_page.is_none() {
+            return false;
+        }
+        loop {
+            self.current_slot = self.record_page().next_after(self.current_slot);
+            if self.current_slot.is_some() {
+                return true;
+            }
+            if self.at_last_block() {
+                return false;
+            }
+            let next_block = self.record_page().block_id().block_num() + 1;
+            self.move_to_block(next_block);
+            self.prefetch_after(next_block);
+        }
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.record_page().get_int(self.current_slot?, field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.record_page()
+            .get_string(self.current_slot?, field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        match self.current_slot {
+            Some(slot) => self.record_page().with_str(slot, field_name, f),
+            None => f(None),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.record_page()
+            .get_val(self.current_slot?, field_name, &self.blob_file_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.layout.schema().has_field(field_name)
+    }
+
+    // Unpins the current block. The scan must be repositioned before use.
+    fn close(&mut self) {
+        if let Some(record_page) = self.record_page.take() {
+            self.tx.borrow_mut().unpin(record_page.block_id());
+        }
+    }
+}
+
+impl UpdateScan for TableScan {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError> {
+        self.record_page().set_int(self.slot(), field_name, val)
+    }
+
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError> {
+        self.record_page().set_string(self.slot(), field_name, val)
+    }
+
+    // An int is stored in a bigint or double field as that type.
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError> {
+        match self.layout.schema().padded(field_name, &val) {
+            Constant::Int(val) => self.set_int(field_name, val),
+            Constant::String(val) => self.set_string(field_name, val),
+            Constant::Long(val) => self.set_long(field_name, val),
+            Constant::Double(val) => self.set_double(field_name, val),
+            Constant::Bool(val) => self.set_bool(field_name, val),
+            Constant::Date(days) => self.set_date(field_name, days),
+            Constant::Blob(val) => self.set_blob(field_name, &val),
+        }
+    }
+
+    // Claims an empty slot after the current one, appending a new block to
+    // the table when every later slot is in use or it has none yet.
+    fn insert(&mut self) -> Result<(), DbError> {
+        if self.record_page.is_none() {
+            self.before_first();
+        }
+        if self.record_page.is_none() {
+            self.move_to_new_block()?;
+        }
+        loop {
+            self.current_slot = self.record_page().insert_after(self.current_slot)?;
+            if self.current_slot.is_some() {
+                return Ok(());
+            }
+            if self.at_last_block() {
+                self.move_to_new_block()?;
+            } else {
+                let next_block = self.record_page().block_id().block_num() + 1;
+                self.move_to_block(next_block);
+            }
+        }
+    }
+
+    fn delete(&mut self) -> Result<(), DbError> {
+        let schema = self.layout.schema().clone();
+        for field_name in schema.fields() {
+            if schema.field_type(field_name) == Some(BLOB) {
+                self.drop_blob(field_name)?;
+            }
+        }
+        self.record_page().delete(self.slot())
+    }
+
+    fn get_rid(&self) -> Option<Rid> {
+        let block_num = self.record_page().block_id().block_num();
+        self.current_slot.map(|slot| Rid::new(block_num, slot))
+    }
+
+    fn move_to_rid(&mut self, rid: Rid) {
+        self.close();
+        let block_id = BlockId::new(&self.file_name, rid.block_num());
+        self.record_page = Some(RecordPage::new(
+            self.tx.clone(),
+            block_id,
+            self.layout.clone(),
+        ));
+        self.current_slot = Some(rid.slot());
+    }
+}
+
+/// Iterates over the records of several heap files with the same layout, one
+/// file after the other, as the partitions of a table are read. Only one
+/// file is open at a time. Record ids are relative to the current file.
+pub struct PartitionScan {
+    tx: Rc<RefCell<Transaction>>,
+    layout: Layout,
+    heap_names: Vec<String>,
+    current: usize,
+    scan: Option<TableScan>,
+}
+
+impl PartitionScan {
+    pub fn new(
+        tx: Rc<RefCell<Transaction>>,
+        heap_names: Vec<String>,
+        layout: Layout,
+    ) -> PartitionScan {
+        let mut scan = PartitionScan {
+            tx,
+            layout,
+            heap_names,
+            current: 0,
+            scan: None,
+        };
+        scan.before_first();
+        scan
+    }
+
+    fn open_current(&mut self) {
+        self.scan = self
+            .heap_names
+            .get(self.current)
+            .map(|heap_name| TableScan::new(self.tx.clone(), heap_name, self.layout.clone()));
+    }
+
+    fn table_scan(&self) -> &TableScan {
+        self.scan.as_ref().expect("partition scan is closed")
+    }
+
+    fn table_scan_mut(&mut self) -> &mut TableScan {
+        self.scan.as_mut().expect("partition scan is closed")
+    }
+}
+
+impl Scan for PartitionScan {
+    fn before_first(&mut self) {
+        self.close();
+        self.current = 0;
+        self.open_current();
+    }
+
+    fn next(&mut self) -> bool {
+        while let Some(scan) = self.scan.as_mut() {
+            if scan.next() {
+                return true;
+            }
+            if self.current + 1 == self.heap_names.len() {
+                return false;
+            }
+            scan.close();
+            self.current += 1;
+            self.open_current();
+        }
+        false
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.scan.as_ref()?.get_int(field_name)
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.scan.as_ref()?.get_string(field_name)
+    }
+
+    fn with_str(&self, field_name: &str, f: &mut dyn FnMut(Option<&str>)) {
+        match &self.scan {
+            Some(scan) => scan.with_str(field_name, f),
+            None => f(None),
+        }
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.scan.as_ref()?.get_val(field_name)
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.layout.schema().has_field(field_name)
+    }
+
+    fn close(&mut self) {
+        if let Some(mut scan) = self.scan.take() {
+            scan.close();
+        }
+    }
+}
+
+impl UpdateScan for PartitionScan {
+    fn set_int(&mut self, field_name: &str, val: i32) -> Result<(), DbError> {
+        self.table_scan_mut().set_int(field_name, val)
+    }
+
+    fn set_string(&mut self, field_name: &str, val: String) -> Result<(), DbError> {
+        self.table_scan_mut().set_string(field_name, val)
+    }
+
+    fn set_val(&mut self, field_name: &str, val: Constant) -> Result<(), DbError> {
+        self.table_scan_mut().set_val(field_name, val)
+    }
+
+    // New records go into the current file; callers that route records by
+    // key open a scan over the one file they belong in.
+    fn insert(&mut self) -> Result<(), DbError> {
+        self.table_scan_mut().insert()
+    }
+
+    fn delete(&mut self) -> Result<(), DbError> {
+        self.table_scan_mut().delete()
+    }
+
+    fn get_rid(&self) -> Option<Rid> {
+        self.table_scan().get_rid()
+    }
+
+    fn move_to_rid(&mut self, rid: Rid) {
+        self.table_scan_mut().move_to_rid(rid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffermanager::BufferManager;
+    use crate::concurrencymanager::LockTable;
+    use crate::filemanager::FileManager;
+    use crate::logmanager::LogManager;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_schema_and_layout() {
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_string_field("B", 9);
+        assert_eq!(schema.fields(), &["A".to_string(), "B".to_string()]);
+        assert_eq!(schema.field_type("B"), Some(VARCHAR));
+        assert_eq!(schema.length("B"), Some(9));
+        assert!(!schema.has_field("C"));
+
+        let mut other = Schema::new();
+        other.add("B", &schema);
+        other.add_int_field("C");
+        assert_eq!(other.fields(), &["B".to_string(), "C".to_string()]);
+
+        let layout = Layout::new(schema);
+        assert_eq!(layout.offset("A"), Some(4));
+        assert_eq!(layout.offset("B"), Some(8));
+        assert_eq!(layout.offset("C"), None);
+        assert_eq!(layout.slot_size(), 21);
+    }
+
+    #[test]
+    fn test_record_page() {
+        let tmp_dir = TempDir::new("test_record_page").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_string_field("B", 9);
+        let layout = Layout::new(schema);
+        let blid = tx.borrow_mut().append("testfile").unwrap();
+        let record_page = RecordPage::new(tx.clone(), blid.clone(), layout);
+        record_page.format().unwrap();
+
+        // 400 byte blocks hold 19 slots of 21 bytes.
+        let mut slot = record_page.insert_after(None).unwrap();
+        let mut inserted = 0;
+        while let Some(s) = slot {
+            record_page.set_int(s, "A", s as i32).unwrap();
+            record_page.set_string(s, "B", format!("rec{}", s)).unwrap();
+            inserted += 1;
+            slot = record_page.insert_after(slot).unwrap();
+        }
+        assert_eq!(inserted, 19);
+
+        let mut slot = record_page.next_after(None);
+        while let Some(s) = slot {
+            if record_page.get_int(s, "A").unwrap() % 2 == 0 {
+                record_page.delete(s).unwrap();
+            }
+            slot = record_page.next_after(slot);
+        }
+
+        let mut remaining = Vec::new();
+        let mut slot = record_page.next_after(None);
+        while let Some(s) = slot {
+            assert_eq!(record_page.get_string(s, "B"), Some(format!("rec{}", s)));
+            remaining.push(record_page.get_int(s, "A").unwrap());
+            slot = record_page.next_after(slot);
+        }
+        assert_eq!(remaining, (1..19).step_by(2).collect::<Vec<i32>>());
+
+        // Deleted slots are reused by the next insert.
+        assert_eq!(record_page.insert_after(None).unwrap(), Some(0));
+        tx.borrow_mut().unpin(&blid);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_table_scan() {
+        let tmp_dir = TempDir::new("test_table_scan").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            )
+            .unwrap(),
+        ));
+
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_string_field("B", 9);
+        let layout = Layout::new(schema);
+
+        // 50 records of 21 bytes need three 400 byte blocks.
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        let mut rids = Vec::new();
+        for i in 0..50 {
+            scan.insert().unwrap();
+            scan.set_int("A", i).unwrap();
+            scan.set_string("B", format!("rec{}", i)).unwrap();
+            rids.push(scan.get_rid().unwrap());
+        }
+        assert_eq!(tx.borrow_mut().size("T.tbl").unwrap(), 3);
+
+        scan.before_first();
+        while scan.next() {
+            if scan.get_int("A").unwrap() % 3 == 0 {
+                scan.delete().unwrap();
+            }
+        }
+
+        scan.before_first();
+        let mut remaining = Vec::new();
+        while scan.next() {
+            let a = scan.get_int("A").unwrap();
+            assert_eq!(scan.get_string("B"), Some(format!("rec{}", a)));
+            remaining.push(a);
+        }
+        let expected: Vec<i32> = (0..50).filter(|i| i % 3 != 0).collect();
+        assert_eq!(remaining, expected);
+
+        scan.move_to_rid(rids[40]);
+        assert_eq!(scan.get_int("A"), Some(40));
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+
+        // Committed records are visible to a later transaction.
+        let tx = Rc::new(RefCell::new(
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap(),
+        ));
+        let mut scan = TableScan::new(tx.clone(), "T", layout);
+        let mut count = 0;
+        while scan.next() {
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_typed_fields() {
+        let tmp_dir = TempDir::new("test_typed_fields").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = || {
+            Rc::new(RefCell::new(
+                Transaction::new(
+                    file_manager.clone(),
+                    log_manager.clone(),
+                    buffer_manager.clone(),
+                    lock_table.clone(),
+                )
+                .unwrap(),
+            ))
+        };
+
+        let mut schema = Schema::new();
+        schema.add_long_field("L");
+        schema.add_double_field("D");
+        schema.add_bool_field("B");
+        schema.add_date_field("DT");
+        let layout = Layout::new(schema);
+        assert_eq!(layout.offset("D"), Some(12));
+        assert_eq!(layout.offset("B"), Some(20));
+        assert_eq!(layout.slot_size(), 28);
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        scan.insert().unwrap();
+        scan.set_long("L", 1 << 40).unwrap();
+        scan.set_double("D", 0.25).unwrap();
+        scan.set_bool("B", true).unwrap();
+        scan.set_date("DT", 19_000).unwrap();
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+
+        // Rolling back undoes long and double updates from their log records.
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        assert!(scan.next());
+        scan.set_long("L", -7).unwrap();
+        scan.set_double("D", 1e100).unwrap();
+        scan.set_bool("B", false).unwrap();
+        assert_eq!(scan.get_val("L"), Some(Constant::Long(-7)));
+        assert_eq!(scan.get_val("D"), Some(Constant::Double(1e100)));
+        scan.close();
+        tx.borrow_mut().rollback().unwrap();
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout);
+        assert!(scan.next());
+        assert_eq!(scan.get_val("L"), Some(Constant::Long(1 << 40)));
+        assert_eq!(scan.get_val("D"), Some(Constant::Double(0.25)));
+        assert_eq!(scan.get_val("B"), Some(Constant::Bool(true)));
+        assert_eq!(scan.get_val("DT"), Some(Constant::Date(19_000)));
+        assert!(!scan.next());
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_null_fields() {
+        let tmp_dir = TempDir::new("test_null_fields").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = || {
+            Rc::new(RefCell::new(
+                Transaction::new(
+                    file_manager.clone(),
+                    log_manager.clone(),
+                    buffer_manager.clone(),
+                    lock_table.clone(),
+                )
+                .unwrap(),
+            ))
+        };
+
+        // Past 31 fields the null bits need a second int before the fields.
+        let mut wide = Schema::new();
+        for idx in 0..40 {
+            wide.add_int_field(&format!("F{}", idx));
+        }
+        let wide = Layout::new(wide);
+        assert_eq!(wide.offset("F0"), Some(8));
+        assert_eq!(wide.null_bit("F0"), Some((0, 2)));
+        assert_eq!(wide.null_bit("F31"), Some((4, 1)));
+
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_string_field("B", 5);
+        let layout = Layout::new(schema);
+        assert_eq!(layout.offset("A"), Some(4));
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        scan.insert().unwrap();
+        assert_eq!(scan.get_val("A"), None);
+        assert_eq!(scan.get_int("A"), None);
+        scan.set_int("A", 0).unwrap();
+        scan.set_string("B", "x".to_string()).unwrap();
+        assert_eq!(scan.get_int("A"), Some(0));
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+
+        // Setting a field to null is undone by a rollback.
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        assert!(scan.next());
+        scan.set_null("B").unwrap();
+        assert_eq!(scan.get_string("B"), None);
+        assert_eq!(scan.get_val("B"), None);
+        scan.close();
+        tx.borrow_mut().rollback().unwrap();
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout);
+        assert!(scan.next());
+        assert_eq!(scan.get_string("B"), Some("x".to_string()));
+        assert!(!scan.next());
+        scan.close();
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_blob_fields() {
+        let tmp_dir = TempDir::new("test_blob_fields").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = || {
+            Rc::new(RefCell::new(
+                Transaction::new(
+                    file_manager.clone(),
+                    log_manager.clone(),
+                    buffer_manager.clone(),
+                    lock_table.clone(),
+                )
+                .unwrap(),
+            ))
+        };
+
+        let mut schema = Schema::new();
+        schema.add_int_field("A");
+        schema.add_blob_field("B");
+        let layout = Layout::new(schema);
+        // Each 400 byte overflow block holds 358 bytes of the value, so that
+        // its log record fits in a log page.
+        let big: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        scan.insert().unwrap();
+        scan.set_int("A", 1).unwrap();
+        assert_eq!(scan.get_val("B"), None);
+        scan.set_blob("B", &big).unwrap();
+        scan.insert().unwrap();
+        scan.set_int("A", 2).unwrap();
+        scan.set_blob("B", b"").unwrap();
+        scan.close();
+        assert_eq!(tx.borrow_mut().size("T.blob").unwrap(), 4);
+        tx.borrow_mut().commit().unwrap();
+
+        // A rolled back replacement leaves the old value in place.
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        assert!(scan.next());
+        assert_eq!(scan.get_val("B"), Some(Constant::Blob(big.clone())));
+        scan.set_blob("B", b"short").unwrap();
+        assert_eq!(scan.get_val("B"), Some(Constant::Blob(b"short".to_vec())));
+        scan.close();
+        tx.borrow_mut().rollback().unwrap();
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout.clone());
+        let mut blobs = Vec::new();
+        while scan.next() {
+            blobs.push(scan.get_val("B").unwrap());
+        }
+        assert_eq!(
+            blobs,
+            vec![Constant::Blob(big.clone()), Constant::Blob(Vec::new())]
+        );
+
+        // Committing a replacement frees the old chain for reuse.
+        scan.before_first();
+        assert!(scan.next());
+        scan.set_blob("B", b"short").unwrap();
+        scan.close();
+        let blob_blocks = tx.borrow_mut().size("T.blob").unwrap();
+        tx.borrow_mut().commit().unwrap();
+
+        let tx = new_tx();
+        let mut scan = TableScan::new(tx.clone(), "T", layout);
+        assert!(scan.next());
+        assert_eq!(scan.get_val("B"), Some(Constant::Blob(b"short".to_vec())));
+        scan.delete().unwrap();
+        assert!(scan.next());
+        scan.set_blob("B", &big).unwrap();
+        assert_eq!(scan.get_val("B"), Some(Constant::Blob(big)));
+        scan.close();
+        assert_eq!(tx.borrow_mut().size("T.blob").unwrap(), blob_blocks);
+        tx.borrow_mut().commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+}
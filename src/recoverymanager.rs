@@ -1,130 +1,417 @@
 use crate::buffermanager::{Buffer, BufferManager};
-use crate::filemanager::Page;
-use crate::logmanager::{LogIterator, LogManager};
+use crate::error::DbError;
+use crate::filemanager::BlockId;
+use crate::logmanager::LogManager;
 use crate::logrecord::{
-    CommitLogRecord, LogRecordFactory, SetIntLogRecord, SetStringLogRecord, CHECKPOINT, COMMIT,
-    ROLLBACK, START,
+    is_update, CheckpointLogRecord, CommitLogRecord, LogRecordFactory, RollbackLogRecord,
+    SetBytesLogRecord, SetDoubleLogRecord, SetFreeLogRecord, SetIntLogRecord, SetLongLogRecord,
+    SetStringLogRecord, StartLogRecord, CHECKPOINT, COMMIT, ROLLBACK, START,
 };
 use crate::transaction::Transaction;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
-pub struct RecoveryManager<'a> {
-    log_manager: Rc<RefCell<LogManager>>,
-    buffer_manager: Rc<RefCell<BufferManager>>,
-    transaction: Transaction<'a>,
+#[derive(Clone)]
+pub struct RecoveryManager {
+    log_manager: Arc<Mutex<LogManager>>,
+    buffer_manager: Arc<BufferManager>,
     transaction_n: i32,
+    // Update records this transaction has logged, so that a savepoint can
+    // say how many come after it.
+    updates: usize,
+    read_only: bool,
 }
 
 impl RecoveryManager {
     pub fn new(
-        tx: Transaction,
         tx_n: i32,
-        log_manager: Rc<RefCell<LogManager>>,
-        buffer_manager: Rc<RefCell<BufferManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
+        buffer_manager: Arc<BufferManager>,
+    ) -> Result<RecoveryManager, DbError> {
+        // The start record only tells a rollback where to stop reading, so
+        // without it a transaction can still read while the disk is full, and
+        // a rollback reads to the start of the log.
+        match StartLogRecord::write_to_log_record(log_manager.clone(), tx_n) {
+            Ok(_) | Err(DbError::DiskFull) => {}
+            Err(err) => return Err(err),
+        }
+        Ok(RecoveryManager {
+            log_manager,
+            buffer_manager,
+            transaction_n: tx_n,
+            updates: 0,
+            read_only: false,
+        })
+    }
+
+    /// A recovery manager for a transaction that only reads. It writes
+    /// nothing to the log, not even the start and commit records, so it can
+    /// run against a database whose files must not change; any update fails
+    /// with `DbError::ReadOnly`.
+    pub fn read_only(
+        tx_n: i32,
+        log_manager: Arc<Mutex<LogManager>>,
+        buffer_manager: Arc<BufferManager>,
     ) -> RecoveryManager {
         RecoveryManager {
             log_manager,
             buffer_manager,
-            transaction: tx,
             transaction_n: tx_n,
+            updates: 0,
+            read_only: true,
         }
     }
 
-    pub fn commit(&self) {
-        self.buffer_manager
-            .borrow_mut()
-            .flush_all_buffers(self.transaction_n);
-        let lsn =
-            CommitLogRecord::write_to_log_record(self.log_manager.clone(), self.transaction_n);
-        self.log_manager.clone().borrow_mut().flush();
+    pub fn updates(&self) -> usize {
+        self.updates
     }
 
-    pub fn rollback(&mut self) {
-        self.do_rollback();
-        self.buffer_manager
-            .borrow_mut()
-            .flush_all_buffers(self.transaction_n);
-        let lsn =
-            CommitLogRecord::write_to_log_record(self.log_manager.clone(), self.transaction_n);
-        self.log_manager.clone().borrow_mut().flush();
+    /// Undoes the transaction's updates after the first `updates`, newest
+    /// first, leaving it running. The undo is logged like any other change.
+    pub fn rollback_to(&mut self, tx: &mut Transaction, updates: usize) -> Result<(), DbError> {
+        let mut remaining = self.updates.saturating_sub(updates);
+        let lit = self.log_manager.lock().unwrap().iterator()?;
+        for b in lit {
+            if remaining == 0 {
+                break;
+            }
+            if let Some(rec) = LogRecordFactory::create_log_record(b?.to_vec()) {
+                if rec.tx_number() != self.transaction_n {
+                    continue;
+                }
+                if rec.operation() == START {
+                    break;
+                }
+                if is_update(rec.operation()) {
+                    rec.compensate(tx)?;
+                    remaining -= 1;
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn recover(&mut self) {
-        self.do_recover();
-        self.buffer_manager
-            .borrow_mut()
-            .flush_all_buffers(self.transaction_n);
-        let lsn =
-            CommitLogRecord::write_to_log_record(self.log_manager.clone(), self.transaction_n);
-        self.log_manager.clone().borrow_mut().flush();
+    /// Writes and flushes the commit record, returning its LSN. The
+    /// transaction's buffers are left for the buffer manager to write when it
+    /// needs them; recovery redoes whatever had not reached the disk.
+    ///
+    /// A transaction that logged no updates has nothing for recovery to undo,
+    /// so it commits even when the disk is too full for its commit record.
+    pub fn commit(&self) -> Result<i64, DbError> {
+        if self.read_only {
+            return Ok(self.log_manager.lock().unwrap().latest_lsn());
+        }
+        let lsn = match CommitLogRecord::write_to_log_record(
+            self.log_manager.clone(),
+            self.transaction_n,
+        ) {
+            Err(DbError::DiskFull) if self.updates == 0 => {
+                self.log_manager.lock().unwrap().latest_lsn()
+            }
+            result => result?,
+        };
+        self.log_manager.clone().lock().unwrap().flush()?;
+        self.finish()?;
+        Ok(lsn)
+    }
+
+    pub fn rollback(&mut self, tx: &mut Transaction) -> Result<(), DbError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.do_rollback(tx)?;
+        self.buffer_manager.flush_all_buffers(self.transaction_n)?;
+        // Undoing is idempotent: without the rollback record, recovery takes
+        // the transaction for unfinished and undoes it again.
+        match RollbackLogRecord::write_to_log_record(self.log_manager.clone(), self.transaction_n) {
+            Ok(_) => {}
+            Err(DbError::DiskFull) => {
+                log::warn!(
+                    "no space to log the rollback of transaction {}",
+                    self.transaction_n
+                );
+            }
+            Err(err) => return Err(err),
+        }
+        self.log_manager.clone().lock().unwrap().flush()?;
+        self.finish()
+    }
+
+    /// Undoes the changes of every transaction that did not finish and redoes
+    /// those of every transaction that committed, then writes a checkpoint so
+    /// that the next recovery stops there.
+    pub fn recover(&mut self, tx: &mut Transaction) -> Result<(), DbError> {
+        self.do_recover(tx)?;
+        self.buffer_manager.flush_all()?;
+        CheckpointLogRecord::write_to_log_record(self.log_manager.clone())?;
+        self.log_manager.clone().lock().unwrap().flush()?;
+        self.finish()
+    }
+
+    /// Requests a quiescent checkpoint. It runs at once if no transaction
+    /// has uncommitted updates, and otherwise when the last of them commits
+    /// or rolls back. Returns whether it ran.
+    pub fn checkpoint(
+        log_manager: &Arc<Mutex<LogManager>>,
+        buffer_manager: &Arc<BufferManager>,
+    ) -> Result<bool, DbError> {
+        let due = {
+            let mut log_manager = log_manager.lock().unwrap();
+            log_manager.request_checkpoint();
+            log_manager.checkpoint_due()
+        };
+        if due {
+            Self::run_checkpoint(log_manager, buffer_manager)?;
+        }
+        Ok(due)
     }
 
-    pub fn set_int(&mut self, buf: Buffer, offset: i32, new_val: i32) -> i32 {
+    pub fn set_int(&mut self, buf: &Buffer, offset: i32, new_val: i32) -> Result<i64, DbError> {
+        self.check_writable()?;
         let old_value = buf
             .contents()
-            .borrow_mut()
+            .write()
+            .unwrap()
             .get_int(offset as usize)
             .expect("no old value");
         if let Some(blid) = buf.block_id() {
+            self.log_manager
+                .lock()
+                .unwrap()
+                .mark_active(self.transaction_n);
+            self.updates += 1;
             SetIntLogRecord::write_to_log_record(
                 self.log_manager.clone(),
-                buf.modifying_txn().unwrap(),
+                self.transaction_n,
                 blid,
                 offset,
                 old_value,
+                new_val,
             )
         } else {
             panic!("no old value")
         }
     }
 
-    pub fn set_string(&mut self, buf: Buffer, offset: i32, new_val: String) -> i32 {
+    pub fn set_string(
+        &mut self,
+        buf: &Buffer,
+        offset: i32,
+        new_val: String,
+    ) -> Result<i64, DbError> {
+        self.check_writable()?;
         let old_value = buf
             .contents()
-            .borrow_mut()
+            .write()
+            .unwrap()
             .get_string(offset as usize)
             .expect("no old value");
         if let Some(blid) = buf.block_id() {
+            self.log_manager
+                .lock()
+                .unwrap()
+                .mark_active(self.transaction_n);
+            self.updates += 1;
             SetStringLogRecord::write_to_log_record(
                 self.log_manager.clone(),
-                buf.modifying_txn().unwrap(),
+                self.transaction_n,
                 blid,
                 offset,
                 old_value,
+                new_val,
             )
         } else {
             panic!("no old value")
         }
     }
 
-    fn do_rollback(&mut self) {
-        let mut lit = self.log_manager.borrow_mut().iterator();
-        while let Some(b) = lit.next() {
-            if let Some(rec) = LogRecordFactory::create_log_record(b.to_vec()) {
+    pub fn set_long(&mut self, buf: &Buffer, offset: i32, new_val: i64) -> Result<i64, DbError> {
+        self.check_writable()?;
+        let old_value = buf
+            .contents()
+            .write()
+            .unwrap()
+            .get_long(offset as usize)
+            .expect("no old value");
+        let Some(blid) = buf.block_id() else {
+            panic!("no old value")
+        };
+        self.log_manager
+            .lock()
+            .unwrap()
+            .mark_active(self.transaction_n);
+        self.updates += 1;
+        SetLongLogRecord::write_to_log_record(
+            self.log_manager.clone(),
+            self.transaction_n,
+            blid,
+            offset,
+            old_value,
+            new_val,
+        )
+    }
+
+    pub fn set_double(&mut self, buf: &Buffer, offset: i32, new_val: f64) -> Result<i64, DbError> {
+        self.check_writable()?;
+        let old_value = buf
+            .contents()
+            .write()
+            .unwrap()
+            .get_double(offset as usize)
+            .expect("no old value");
+        let Some(blid) = buf.block_id() else {
+            panic!("no old value")
+        };
+        self.log_manager
+            .lock()
+            .unwrap()
+            .mark_active(self.transaction_n);
+        self.updates += 1;
+        SetDoubleLogRecord::write_to_log_record(
+            self.log_manager.clone(),
+            self.transaction_n,
+            blid,
+            offset,
+            old_value,
+            new_val,
+        )
+    }
+
+    pub fn set_bytes(&mut self, buf: &Buffer, offset: i32, new_val: &[u8]) -> Result<i64, DbError> {
+        self.check_writable()?;
+        let old_value = buf
+            .contents()
+            .write()
+            .unwrap()
+            .get_bytes(offset as usize)
+            .expect("no old value");
+        let Some(blid) = buf.block_id() else {
+            panic!("no old value")
+        };
+        self.log_manager
+            .lock()
+            .unwrap()
+            .mark_active(self.transaction_n);
+        self.updates += 1;
+        SetBytesLogRecord::write_to_log_record(
+            self.log_manager.clone(),
+            self.transaction_n,
+            blid,
+            offset,
+            &old_value,
+            new_val,
+        )
+    }
+
+    /// Logs `block_id` going onto or coming off its free block map. The map
+    /// is written straight to disk rather than through a buffer, so the
+    /// record is flushed before this returns.
+    pub fn set_free(
+        &mut self,
+        block_id: &BlockId,
+        was_free: bool,
+        free: bool,
+    ) -> Result<i64, DbError> {
+        self.check_writable()?;
+        self.log_manager
+            .lock()
+            .unwrap()
+            .mark_active(self.transaction_n);
+        self.updates += 1;
+        let lsn = SetFreeLogRecord::write_to_log_record(
+            self.log_manager.clone(),
+            self.transaction_n,
+            block_id,
+            was_free,
+            free,
+        )?;
+        self.log_manager.lock().unwrap().flush_lsn(lsn)?;
+        Ok(lsn)
+    }
+
+    fn check_writable(&self) -> Result<(), DbError> {
+        match self.read_only {
+            true => Err(DbError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // Runs a checkpoint the log manager scheduled once this was the last
+    // transaction still holding log records.
+    fn finish(&self) -> Result<(), DbError> {
+        let due = {
+            let mut log_manager = self.log_manager.lock().unwrap();
+            log_manager.mark_finished(self.transaction_n);
+            log_manager.checkpoint_due()
+        };
+        if due {
+            Self::run_checkpoint(&self.log_manager, &self.buffer_manager)?;
+        }
+        Ok(())
+    }
+
+    // With every change on disk and none uncommitted, no record before this
+    // point is needed again. A log past its maximum size is emptied first.
+    fn run_checkpoint(
+        log_manager: &Arc<Mutex<LogManager>>,
+        buffer_manager: &Arc<BufferManager>,
+    ) -> Result<(), DbError> {
+        buffer_manager.flush_all()?;
+        if log_manager.lock().unwrap().over_max_size() {
+            log_manager.lock().unwrap().truncate()?;
+        }
+        CheckpointLogRecord::write_to_log_record(log_manager.clone())?;
+        let mut log_manager = log_manager.lock().unwrap();
+        log_manager.flush()?;
+        log_manager.checkpoint_done();
+        Ok(())
+    }
+
+    fn do_rollback(&mut self, tx: &mut Transaction) -> Result<(), DbError> {
+        let lit = self.log_manager.lock().unwrap().iterator()?;
+        for b in lit {
+            if let Some(rec) = LogRecordFactory::create_log_record(b?.to_vec()) {
+                if rec.tx_number() != self.transaction_n {
+                    continue;
+                }
                 if rec.operation() == START {
-                    return;
+                    return Ok(());
                 } else {
-                    rec.undo(&mut self.transaction)
+                    rec.undo(tx)?
                 }
             }
         }
+        Ok(())
     }
 
-    fn do_recover(&mut self) {
+    // The undo pass reads the log backwards to the last checkpoint, keeping
+    // the records of committed transactions for the redo pass, which applies
+    // them in the order they were written. A rolled back transaction has
+    // already undone and flushed its changes, so it is left alone.
+    fn do_recover(&mut self, tx: &mut Transaction) -> Result<(), DbError> {
         let mut finished_txns = Vec::new();
-        let mut lit = self.log_manager.borrow_mut().iterator();
-        while let Some(b) = lit.next() {
-            if let Some(rec) = LogRecordFactory::create_log_record(b.to_vec()) {
+        let mut committed_txns = Vec::new();
+        let mut redo_records = Vec::new();
+        let lit = self.log_manager.lock().unwrap().iterator()?;
+        for b in lit {
+            if let Some(rec) = LogRecordFactory::create_log_record(b?.to_vec()) {
                 if rec.operation() == CHECKPOINT {
-                    return;
+                    break;
                 }
-                if rec.operation() == COMMIT || rec.operation() == ROLLBACK {
-                    finished_txns.push(rec);
-                } else if !finished_txns.contains(&rec) {
-                    rec.undo(&mut self.transaction);
+                if rec.operation() == COMMIT {
+                    committed_txns.push(rec.tx_number());
+                    finished_txns.push(rec.tx_number());
+                } else if rec.operation() == ROLLBACK {
+                    finished_txns.push(rec.tx_number());
+                } else if !finished_txns.contains(&rec.tx_number()) {
+                    rec.undo(tx)?;
+                } else if committed_txns.contains(&rec.tx_number()) {
+                    redo_records.push(rec);
                 }
             }
         }
+        for rec in redo_records.iter().rev() {
+            rec.redo(tx)?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,197 @@
+use crate::changecapture::ChangeStream;
+use crate::query::{Constant, Scan};
+use crate::recordmanager::Schema;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+type Rows = Arc<Vec<Vec<Option<Constant>>>>;
+
+struct CachedResult {
+    sql: String,
+    schema: Schema,
+    tables: Vec<String>,
+    rows: Rows,
+}
+
+/// The results of recent read-only queries, keyed by their SQL text. The
+/// cache subscribes to committed row changes and drops every result that
+/// read a table before it answers again, so it never serves rows older than
+/// the last commit. When full, the oldest result makes room.
+pub struct ResultCache {
+    capacity: usize,
+    results: VecDeque<CachedResult>,
+    changes: ChangeStream,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize, changes: ChangeStream) -> ResultCache {
+        ResultCache {
+            capacity,
+            results: VecDeque::new(),
+            changes,
+        }
+    }
+
+    /// A scan of the cached result of `sql`, if there is one.
+    pub fn get(&mut self, sql: &str) -> Option<CachedScan> {
+        self.catch_up();
+        let result = self.results.iter().find(|result| result.sql == sql)?;
+        Some(CachedScan::new(result.schema.clone(), result.rows.clone()))
+    }
+
+    /// Caches the rows of `sql`, whose fields are those of `schema` and
+    /// which read `tables`, and returns a scan of them.
+    pub fn insert(
+        &mut self,
+        sql: &str,
+        schema: Schema,
+        tables: Vec<String>,
+        rows: Vec<Vec<Option<Constant>>>,
+    ) -> CachedScan {
+        self.catch_up();
+        self.results.retain(|result| result.sql != sql);
+        while self.results.len() >= self.capacity.max(1) {
+            self.results.pop_front();
+        }
+        let rows = Arc::new(rows);
+        self.results.push_back(CachedResult {
+            sql: sql.to_string(),
+            schema: schema.clone(),
+            tables,
+            rows: rows.clone(),
+        });
+        CachedScan::new(schema, rows)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    fn catch_up(&mut self) {
+        for change in self.changes.by_ref() {
+            self.results
+                .retain(|result| !result.tables.contains(&change.table_name));
+        }
+    }
+}
+
+/// Reads the rows of a cached result.
+pub struct CachedScan {
+    schema: Schema,
+    rows: Rows,
+    // One past the current row, so that zero is before the first.
+    current: usize,
+}
+
+impl CachedScan {
+    pub fn new(schema: Schema, rows: Rows) -> CachedScan {
+        CachedScan {
+            schema,
+            rows,
+            current: 0,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    // The current row's value of `field_name`, or `None` if it is null.
+    fn value(&self, field_name: &str) -> Option<&Constant> {
+        let row = self.rows.get(self.current.checked_sub(1)?)?;
+        let pos = self
+            .schema
+            .fields()
+            .iter()
+            .position(|field| field == field_name)?;
+        row[pos].as_ref()
+    }
+}
+
+impl Scan for CachedScan {
+    fn before_first(&mut self) {
+        self.current = 0;
+    }
+
+    fn next(&mut self) -> bool {
+        if self.current < self.rows.len() {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn get_int(&self, field_name: &str) -> Option<i32> {
+        self.get_val(field_name)?.as_int()
+    }
+
+    fn get_string(&self, field_name: &str) -> Option<String> {
+        self.get_str(field_name).map(Cow::into_owned)
+    }
+
+    fn get_str(&self, field_name: &str) -> Option<Cow<'_, str>> {
+        self.value(field_name)?.as_string().map(Cow::Borrowed)
+    }
+
+    fn get_val(&self, field_name: &str) -> Option<Constant> {
+        self.value(field_name).cloned()
+    }
+
+    fn has_field(&self, field_name: &str) -> bool {
+        self.schema.has_field(field_name)
+    }
+
+    fn close(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changecapture::{ChangeCapture, ChangeOp};
+
+    #[test]
+    fn test_result_cache() {
+        let mut capture = ChangeCapture::new();
+        let mut cache = ResultCache::new(2, capture.subscribe());
+        let mut schema = Schema::new();
+        schema.add_int_field("a");
+        let rows = |vals: &[i32]| {
+            vals.iter()
+                .map(|val| vec![Some(Constant::Int(*val))])
+                .collect()
+        };
+
+        let mut scan = cache.insert("q1", schema.clone(), vec!["t".to_string()], rows(&[1, 2]));
+        assert!(scan.has_field("a"));
+        assert_eq!(scan.get_int("a"), None);
+        assert!(scan.next());
+        assert_eq!(scan.get_int("a"), Some(1));
+        assert!(scan.next());
+        assert_eq!(scan.get_val("a"), Some(Constant::Int(2)));
+        assert_eq!(scan.get_string("a"), None);
+        assert!(!scan.next());
+        scan.before_first();
+        assert!(scan.next());
+        assert_eq!(scan.get_int("a"), Some(1));
+
+        let mut scan = cache.get("q1").unwrap();
+        assert_eq!(scan.schema().fields(), &["a".to_string()]);
+        assert!(scan.next());
+        assert!(cache.get("q2").is_none());
+
+        cache.insert("q2", schema.clone(), vec!["u".to_string()], rows(&[3]));
+        cache.insert("q3", schema.clone(), vec!["u".to_string()], rows(&[4]));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("q1").is_none());
+
+        // A change is seen only once its transaction commits.
+        capture.record(1, "u", ChangeOp::Insert, Vec::new(), Vec::new());
+        assert!(cache.get("q2").is_some());
+        capture.commit(1, 10);
+        assert!(cache.get("q2").is_none());
+        assert!(cache.get("q3").is_none());
+    }
+}
@@ -1,101 +1,572 @@
-//public Transaction(FileMgr fm, LogMgr lm, BufferMgr bm);
-
 use crate::buffermanager::{Buffer, BufferManager};
-use crate::filemanager::{BlockId, FileManager};
+use crate::concurrencymanager::{ConcurrencyManager, HeldLocks, LockTable};
+use crate::error::DbError;
+use crate::filemanager::{BlockId, FileManager, Page};
 use crate::logmanager::LogManager;
+use crate::metadata::TempCatalog;
 use crate::recoverymanager::RecoveryManager;
-use std::cell::{Ref, RefCell};
-use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+static NEXT_TX_NUM: AtomicI32 = AtomicI32::new(0);
+
+// Stands in for the end of a file when locking against appends, so that
+// `size` and `append` conflict with each other the way reading and writing a
+// block does.
+const END_OF_FILE: usize = usize::MAX;
+
+// Where an overflow block keeps the number of the next block of its chain,
+// and the chunk of the value it holds.
+const BLOB_NEXT_POS: usize = 0;
+const BLOB_CHUNK_POS: usize = BLOB_NEXT_POS + size_of::<i32>();
+// Everything in a log page holding one SETBYTES record with no old value
+// besides the file name and the new bytes: the page's boundary, the record's
+// length and its seven length and int fields.
+const BLOB_LOG_OVERHEAD: usize = 9 * size_of::<i32>();
+
+// Temporary files are removed when the database starts, so changes to them
+// are never logged: recovery has nothing to undo in them.
+fn is_temp_file(block_id: &BlockId) -> bool {
+    block_id.file_name().starts_with("temp")
+}
+
+/// A point in a transaction that it can roll back to without giving up
+/// the work done before it.
+pub struct Savepoint {
+    updates: usize,
+    locks: HeldLocks,
+}
 
-struct BufferList<'a> {
-    buffers: HashMap<&'a BlockId, Rc<RefCell<Buffer>>>,
-    pins: Vec<&'a BlockId>,
-    buffer_manager: Rc<RefCell<BufferManager>>,
+struct BufferList {
+    buffers: HashMap<BlockId, Arc<RwLock<Buffer>>>,
+    pins: Vec<BlockId>,
+    buffer_manager: Arc<BufferManager>,
+    transaction_n: i32,
 }
 
 impl BufferList {
-    pub fn new(buffer_manager: Rc<RefCell<BufferManager>>) -> BufferList {
+    pub fn new(buffer_manager: Arc<BufferManager>, transaction_n: i32) -> BufferList {
         BufferList {
             buffer_manager,
             pins: Vec::new(),
             buffers: HashMap::new(),
+            transaction_n,
         }
     }
 
-    pub fn get_buffer(&self, block_id: &BlockId) -> Option<&Rc<RefCell<Buffer>>> {
+    pub fn get_buffer(&self, block_id: &BlockId) -> Option<&Arc<RwLock<Buffer>>> {
         self.buffers.get(block_id)
     }
 
-    pub fn pin(&mut self, block_id: &BlockId) {
-        if let Some(buffer) = self.buffer_manager.borrow_mut().pin(block_id) {
-            self.buffers.insert(block_id, buffer);
-            self.pins.push(block_id);
+    // A block this transaction already holds is pinned again through its
+    // cached buffer without going back to the buffer manager.
+    pub fn pin(&mut self, block_id: &BlockId) -> Result<(), DbError> {
+        if let Some(buffer) = self.buffers.get(block_id) {
+            buffer.read().unwrap().pin();
+            self.buffer_manager.note_repin(block_id, self.transaction_n);
+            self.pins.push(block_id.clone());
+            return Ok(());
         }
+        let buffer = self.buffer_manager.pin(block_id, self.transaction_n)?;
+        self.buffers.insert(block_id.clone(), buffer);
+        self.pins.push(block_id.clone());
+        Ok(())
     }
 
     pub fn unpin(&mut self, block_id: &BlockId) {
-        if let Some(mut buffer) = self.buffers.get(block_id) {
-            let buf_mut = buffer.get_mut();
-            self.buffer_manager.borrow_mut().unpin(buf_mut);
+        if let Some(buffer) = self.buffers.get(block_id) {
+            self.buffer_manager.unpin(buffer, self.transaction_n);
             for (idx, val) in self.pins.iter().enumerate() {
-                if *val == block_id {
+                if val == block_id {
                     self.pins.remove(idx);
                     break;
                 }
             }
-            if !self.pins.contains(&block_id) {
-                self.buffers.remove(&block_id);
+            if !self.pins.contains(block_id) {
+                self.buffers.remove(block_id);
             }
         }
     }
 
+    // A block read into a ring buffer moves into the pool before it is
+    // changed, keeping every pin this transaction holds on it, so that ring
+    // buffers are never dirty.
+    pub fn promote(&mut self, block_id: &BlockId) -> Result<(), DbError> {
+        let Some(ring_buffer) = self.buffers.get(block_id).cloned() else {
+            return Ok(());
+        };
+        let buffer_manager = &self.buffer_manager;
+        if !buffer_manager.in_buffer_ring(&ring_buffer, self.transaction_n) {
+            return Ok(());
+        }
+        let buffer = buffer_manager.pin_in_pool(block_id, self.transaction_n)?;
+        let pins = self.pins.iter().filter(|pin| *pin == block_id).count();
+        for _ in 1..pins {
+            buffer.read().unwrap().pin();
+        }
+        for _ in 0..pins {
+            buffer_manager.unpin(&ring_buffer, self.transaction_n);
+        }
+        self.buffers.insert(block_id.clone(), buffer);
+        Ok(())
+    }
+
     pub fn unpin_all(&mut self) {
+        for block_id in self.pins.iter() {
+            if let Some(buffer) = self.buffers.get(block_id) {
+                self.buffer_manager.unpin(buffer, self.transaction_n);
+            }
+        }
         self.pins.clear();
         self.buffers.clear();
     }
 }
 
-pub struct Transaction<'a> {
-    buffer_manager: Rc<RefCell<BufferManager>>,
-    file_manager: Rc<RefCell<FileManager>>,
-    recovery_manager: RecoveryManager<'a>,
-    buffer_list: BufferList<'a>,
+pub struct Transaction {
+    buffer_manager: Arc<BufferManager>,
+    file_manager: Arc<Mutex<FileManager>>,
+    recovery_manager: RecoveryManager,
+    concurrency_manager: ConcurrencyManager,
+    buffer_list: BufferList,
     transaction_n: i32,
+    temp_catalog: Rc<RefCell<TempCatalog>>,
+    user: Option<String>,
+    sort_workers: usize,
+    temp_blocks: usize,
+    peak_pinned: usize,
+    // The first error a scan's read ran into. Scans read through getters
+    // that cannot fail, so they stop there and leave the error for the
+    // statement running them to fail with.
+    read_error: Option<DbError>,
+    // Overflow blocks to give back to the file manager once the transaction
+    // ends: those of replaced blobs if it commits, and those of blobs it
+    // wrote if it rolls back.
+    freed_on_commit: Vec<BlockId>,
+    freed_on_rollback: Vec<BlockId>,
+    // Read-only transactions on attached databases that this one's queries
+    // read through. They finish when it does.
+    readers: Vec<Rc<RefCell<Transaction>>>,
 }
 
 impl Transaction {
     pub fn new(
-        file_manager: Rc<RefCell<FileManager>>,
-        log_manager: Rc<RefCell<LogManager>>,
-        buffer_manager: Rc<RefCell<BufferManager>>,
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
+        buffer_manager: Arc<BufferManager>,
+        lock_table: Arc<LockTable>,
+    ) -> Result<Self, DbError> {
+        let transaction_n = NEXT_TX_NUM.fetch_add(1, Ordering::Relaxed) + 1;
+        let recovery_manager =
+            RecoveryManager::new(transaction_n, log_manager, buffer_manager.clone())?;
+        Ok(Self::with_recovery_manager(
+            transaction_n,
+            recovery_manager,
+            file_manager,
+            buffer_manager,
+            lock_table,
+        ))
+    }
+
+    /// A transaction that reads without writing anything to the database
+    /// or its log. Its updates fail with `DbError::ReadOnly`.
+    pub fn read_only(
+        file_manager: Arc<Mutex<FileManager>>,
+        log_manager: Arc<Mutex<LogManager>>,
+        buffer_manager: Arc<BufferManager>,
+        lock_table: Arc<LockTable>,
+    ) -> Self {
+        let transaction_n = NEXT_TX_NUM.fetch_add(1, Ordering::Relaxed) + 1;
+        let recovery_manager =
+            RecoveryManager::read_only(transaction_n, log_manager, buffer_manager.clone());
+        Self::with_recovery_manager(
+            transaction_n,
+            recovery_manager,
+            file_manager,
+            buffer_manager,
+            lock_table,
+        )
+    }
+
+    fn with_recovery_manager(
+        transaction_n: i32,
+        recovery_manager: RecoveryManager,
+        file_manager: Arc<Mutex<FileManager>>,
+        buffer_manager: Arc<BufferManager>,
+        lock_table: Arc<LockTable>,
     ) -> Self {
-        let recovery_manager = RecoveryManager::new(Self, 0, log_manager, buffer_manager.clone());
-        let buffer_list = BufferList::new(buffer_manager.clone());
+        let concurrency_manager = ConcurrencyManager::new(transaction_n, lock_table);
+        let buffer_list = BufferList::new(buffer_manager.clone(), transaction_n);
         Transaction {
             buffer_manager,
             file_manager,
             recovery_manager,
+            concurrency_manager,
             buffer_list,
-            transaction_n: 0,
+            transaction_n,
+            temp_catalog: Rc::new(RefCell::new(TempCatalog::new())),
+            user: None,
+            sort_workers: 1,
+            temp_blocks: 0,
+            peak_pinned: 0,
+            read_error: None,
+            freed_on_commit: Vec::new(),
+            freed_on_rollback: Vec::new(),
+            readers: Vec::new(),
         }
     }
 
-    pub fn commit(&mut self) {}
+    /// Ends `reader`, a read-only transaction on another database, together
+    /// with this one, releasing its locks and buffers.
+    pub fn add_reader(&mut self, reader: Rc<RefCell<Transaction>>) {
+        self.readers.push(reader);
+    }
+
+    pub fn transaction_n(&self) -> i32 {
+        self.transaction_n
+    }
+
+    /// The temporary tables this transaction can see. A connection hands
+    /// its own catalog to each transaction it starts.
+    pub fn temp_catalog(&self) -> Rc<RefCell<TempCatalog>> {
+        self.temp_catalog.clone()
+    }
+
+    pub fn set_temp_catalog(&mut self, temp_catalog: Rc<RefCell<TempCatalog>>) {
+        self.temp_catalog = temp_catalog;
+    }
+
+    /// The session user whose privileges the planner checks statements of
+    /// this transaction against, or `None` if they are not checked.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.user = user;
+    }
+
+    /// Threads a sort run by this transaction may use to sort its runs.
+    pub fn sort_workers(&self) -> usize {
+        self.sort_workers
+    }
 
-    pub fn rollback(&mut self) {}
+    pub fn set_sort_workers(&mut self, sort_workers: usize) {
+        self.sort_workers = sort_workers.max(1);
+    }
 
-    pub fn recover(&mut self) {}
+    /// Reads blocks that are not already in the buffer pool through `size`
+    /// private buffers, so that a long scan does not push everything else
+    /// out of the pool. A size of zero reads through the pool again. The
+    /// ring is dropped when the transaction finishes.
+    pub fn set_buffer_ring(&mut self, size: usize) {
+        self.buffer_manager
+            .set_buffer_ring(self.transaction_n, size);
+    }
 
-    pub fn pin(&mut self, block_id: &BlockId) {}
+    pub fn buffer_ring_size(&self) -> usize {
+        self.buffer_manager.buffer_ring_size(self.transaction_n)
+    }
 
-    pub fn unpin(&mut self, block_id: &BlockId) {}
+    /// Whether scans should ask for blocks ahead of reading them: only while
+    /// the buffer manager reads prefetches in the background, and not into
+    /// the pool a buffer ring is meant to leave alone.
+    pub fn prefetching(&self) -> bool {
+        self.buffer_manager.prefetching() && self.buffer_ring_size() == 0
+    }
 
-    pub fn get_int(&self, offset: i32) -> Option<i32> {}
+    /// See `BufferManager::prefetch`. No locks are taken: the blocks are
+    /// only read, and pinning them later locks them as usual.
+    pub fn prefetch(&self, block_ids: &[BlockId]) {
+        self.buffer_manager.prefetch(block_ids);
+    }
+
+    /// Commits the transaction and returns the LSN of its commit record. A
+    /// commit record that cannot be written, as on a full disk, fails it and
+    /// leaves the transaction for the caller to roll back, and so does an
+    /// error `scan_read` kept.
+    pub fn commit(&mut self) -> Result<i64, DbError> {
+        if let Some(err) = self.read_error.take() {
+            return Err(err);
+        }
+        // Freed ahead of the commit record, so that recovery puts them back
+        // if the transaction never gets to write it.
+        for block_id in std::mem::take(&mut self.freed_on_commit) {
+            self.set_free(&block_id, true, true)?;
+        }
+        let lsn = self.recovery_manager.commit()?;
+        self.freed_on_rollback.clear();
+        self.concurrency_manager.release();
+        self.buffer_list.unpin_all();
+        self.set_buffer_ring(0);
+        self.finish_readers()?;
+        Ok(lsn)
+    }
+
+    /// Undoes the transaction's changes and ends it. A rollback that fails
+    /// part way leaves the transaction holding its locks, since its changes
+    /// may be only partly undone; recovery undoes the rest when the database
+    /// is next opened.
+    pub fn rollback(&mut self) -> Result<(), DbError> {
+        let mut recovery_manager = self.recovery_manager.clone();
+        recovery_manager.rollback(self)?;
+        self.read_error = None;
+        // Past the rollback record nothing undoes these, so they are not
+        // logged. A crash before they reach the map leaks the blocks.
+        for block_id in std::mem::take(&mut self.freed_on_rollback) {
+            if let Err(err) = self.set_free(&block_id, true, false) {
+                log::warn!("failed to free overflow block {}: {}", block_id, err);
+            }
+        }
+        self.freed_on_commit.clear();
+        self.concurrency_manager.release();
+        self.buffer_list.unpin_all();
+        self.set_buffer_ring(0);
+        self.finish_readers()
+    }
 
-    pub fn get_string(&self, block_id: &BlockId, offset: usize) -> Option<String> {}
+    fn finish_readers(&mut self) -> Result<(), DbError> {
+        for reader in std::mem::take(&mut self.readers) {
+            reader.borrow_mut().commit()?;
+        }
+        Ok(())
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            updates: self.recovery_manager.updates(),
+            locks: self.concurrency_manager.held_locks(),
+        }
+    }
+
+    /// Undoes every change made since `savepoint` and then releases the
+    /// locks taken since, so that a failed step of a long transaction
+    /// stops blocking others on blocks it no longer needs. Locks that were
+    /// shared at the savepoint and exclusive now become shared again.
+    pub fn rollback_to_savepoint(&mut self, savepoint: &Savepoint) -> Result<(), DbError> {
+        let mut recovery_manager = self.recovery_manager.clone();
+        recovery_manager.rollback_to(self, savepoint.updates)?;
+        self.concurrency_manager.release_since(&savepoint.locks);
+        Ok(())
+    }
+
+    pub fn recover(&mut self) -> Result<(), DbError> {
+        self.buffer_manager.flush_all_buffers(self.transaction_n)?;
+        let mut recovery_manager = self.recovery_manager.clone();
+        recovery_manager.recover(self)
+    }
+
+    pub fn pin(&mut self, block_id: &BlockId) -> Result<(), DbError> {
+        self.buffer_list.pin(block_id)?;
+        self.peak_pinned = self.peak_pinned.max(self.buffer_list.buffers.len());
+        Ok(())
+    }
+
+    /// The most buffers this transaction has held pinned at once.
+    pub fn peak_pinned(&self) -> usize {
+        self.peak_pinned
+    }
+
+    /// Blocks this transaction has appended to temporary tables.
+    pub fn temp_blocks(&self) -> usize {
+        self.temp_blocks
+    }
+
+    /// Runs `read` for a scan, whose getters cannot fail. An error, such as
+    /// a lock that cannot be granted, is kept for `take_read_error` and read
+    /// as `None`, and so is every read after it, so that the scan ends
+    /// without waiting on the lock again.
+    pub fn scan_read<T>(
+        &mut self,
+        read: impl FnOnce(&mut Transaction) -> Result<Option<T>, DbError>,
+    ) -> Option<T> {
+        if self.read_error.is_some() {
+            return None;
+        }
+        read(self).unwrap_or_else(|err| {
+            self.read_error = Some(err);
+            None
+        })
+    }
+
+    /// The error `scan_read` kept, if a read failed since it was last taken.
+    pub fn take_read_error(&mut self) -> Option<DbError> {
+        self.read_error.take()
+    }
+
+    pub fn unpin(&mut self, block_id: &BlockId) {
+        self.buffer_list.unpin(block_id);
+    }
+
+    pub fn get_int(&mut self, block_id: &BlockId, offset: usize) -> Result<Option<i32>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        let val = contents.read().unwrap().get_int(offset);
+        Ok(val)
+    }
+
+    pub fn get_string(
+        &mut self,
+        block_id: &BlockId,
+        offset: usize,
+    ) -> Result<Option<String>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        let val = contents.read().unwrap().get_string(offset);
+        Ok(val)
+    }
+
+    /// The page of a pinned block, for reads that borrow from it, once the
+    /// block is locked for reading.
+    pub fn read_page(&mut self, block_id: &BlockId) -> Result<Option<Arc<RwLock<Page>>>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        Ok(Some(contents))
+    }
+
+    pub fn get_bytes(
+        &mut self,
+        block_id: &BlockId,
+        offset: usize,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        let val = contents.read().unwrap().get_bytes(offset);
+        Ok(val.map(Vec::from))
+    }
+
+    pub fn get_long(&mut self, block_id: &BlockId, offset: usize) -> Result<Option<i64>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        let val = contents.read().unwrap().get_long(offset);
+        Ok(val)
+    }
+
+    pub fn get_double(
+        &mut self,
+        block_id: &BlockId,
+        offset: usize,
+    ) -> Result<Option<f64>, DbError> {
+        self.concurrency_manager.acquire_s_lock(block_id)?;
+        self.concurrency_manager.audit_read(block_id);
+        let Some(buffer) = self.buffer_list.get_buffer(block_id) else {
+            return Ok(None);
+        };
+        let contents = buffer.read().unwrap().contents();
+        let val = contents.read().unwrap().get_double(offset);
+        Ok(val)
+    }
+
+    pub fn set_int(
+        &mut self,
+        block_id: &BlockId,
+        offset: i32,
+        val: Option<i32>,
+        should_log: bool,
+    ) -> Result<(), DbError> {
+        self.concurrency_manager.acquire_x_lock(block_id)?;
+        self.concurrency_manager.audit_write(block_id);
+        self.buffer_list.promote(block_id)?;
+        let buffer = self
+            .buffer_list
+            .get_buffer(block_id)
+            .expect("block is not pinned")
+            .clone();
+        let mut lsn = -1;
+        if let (true, Some(new_val)) = (should_log && !is_temp_file(block_id), val) {
+            lsn = self
+                .recovery_manager
+                .set_int(&buffer.read().unwrap(), offset, new_val)?;
+        }
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_int(offset as usize, val)?;
+        buffer
+            .write()
+            .unwrap()
+            .set_modified(self.transaction_n, lsn);
+        Ok(())
+    }
+
+    pub fn set_long(
+        &mut self,
+        block_id: &BlockId,
+        offset: i32,
+        val: Option<i64>,
+        should_log: bool,
+    ) -> Result<(), DbError> {
+        self.concurrency_manager.acquire_x_lock(block_id)?;
+        self.concurrency_manager.audit_write(block_id);
+        self.buffer_list.promote(block_id)?;
+        let buffer = self
+            .buffer_list
+            .get_buffer(block_id)
+            .expect("block is not pinned")
+            .clone();
+        let mut lsn = -1;
+        if let (true, Some(new_val)) = (should_log && !is_temp_file(block_id), val) {
+            lsn = self
+                .recovery_manager
+                .set_long(&buffer.read().unwrap(), offset, new_val)?;
+        }
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_long(offset as usize, val)?;
+        buffer
+            .write()
+            .unwrap()
+            .set_modified(self.transaction_n, lsn);
+        Ok(())
+    }
 
-    pub fn set_int(&mut self, block_id: &BlockId, offset: i32, val: Option<i32>, should_log: bool) {
+    pub fn set_double(
+        &mut self,
+        block_id: &BlockId,
+        offset: i32,
+        val: Option<f64>,
+        should_log: bool,
+    ) -> Result<(), DbError> {
+        self.concurrency_manager.acquire_x_lock(block_id)?;
+        self.concurrency_manager.audit_write(block_id);
+        self.buffer_list.promote(block_id)?;
+        let buffer = self
+            .buffer_list
+            .get_buffer(block_id)
+            .expect("block is not pinned")
+            .clone();
+        let mut lsn = -1;
+        if let (true, Some(new_val)) = (should_log && !is_temp_file(block_id), val) {
+            lsn = self
+                .recovery_manager
+                .set_double(&buffer.read().unwrap(), offset, new_val)?;
+        }
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_double(offset as usize, val)?;
+        buffer
+            .write()
+            .unwrap()
+            .set_modified(self.transaction_n, lsn);
+        Ok(())
     }
 
     pub fn set_string(
@@ -104,14 +575,833 @@ impl Transaction {
         offset: i32,
         val: Option<String>,
         should_log: bool,
-    ) {
+    ) -> Result<(), DbError> {
+        self.concurrency_manager.acquire_x_lock(block_id)?;
+        self.concurrency_manager.audit_write(block_id);
+        self.buffer_list.promote(block_id)?;
+        let buffer = self
+            .buffer_list
+            .get_buffer(block_id)
+            .expect("block is not pinned")
+            .clone();
+        let mut lsn = -1;
+        if let (true, Some(new_val)) = (should_log && !is_temp_file(block_id), val.clone()) {
+            lsn = self
+                .recovery_manager
+                .set_string(&buffer.read().unwrap(), offset, new_val)?;
+        }
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_string(offset as usize, val)?;
+        buffer
+            .write()
+            .unwrap()
+            .set_modified(self.transaction_n, lsn);
+        Ok(())
     }
 
-    pub fn available_buffers(&self) -> Option<usize> {}
+    pub fn set_bytes(
+        &mut self,
+        block_id: &BlockId,
+        offset: i32,
+        val: Option<&[u8]>,
+        should_log: bool,
+    ) -> Result<(), DbError> {
+        self.concurrency_manager.acquire_x_lock(block_id)?;
+        self.concurrency_manager.audit_write(block_id);
+        self.buffer_list.promote(block_id)?;
+        let buffer = self
+            .buffer_list
+            .get_buffer(block_id)
+            .expect("block is not pinned")
+            .clone();
+        let mut lsn = -1;
+        if let (true, Some(new_val)) = (should_log && !is_temp_file(block_id), val) {
+            lsn = self
+                .recovery_manager
+                .set_bytes(&buffer.read().unwrap(), offset, new_val)?;
+        }
+        let contents = buffer.read().unwrap().contents();
+        contents.write().unwrap().set_bytes(offset as usize, val)?;
+        buffer
+            .write()
+            .unwrap()
+            .set_modified(self.transaction_n, lsn);
+        Ok(())
+    }
 
-    pub fn size() -> usize {}
+    /// Writes `bytes` to a new chain of overflow blocks appended to
+    /// `file_name` and returns the number of the chain's first block. Each
+    /// block starts with the number of the next one, or -1 in the last,
+    /// followed by a length prefixed chunk of the value. The chain is freed
+    /// again if the transaction rolls back.
+    ///
+    /// Chains are never changed in place, so whatever a block held before is
+    /// of no use to an undo. It is cleared without logging, which keeps the
+    /// old value out of the chunk's log record, and chunks are sized for the
+    /// record to fit in a log page.
+    pub fn write_blob(&mut self, file_name: &str, bytes: &[u8]) -> Result<i32, DbError> {
+        let chunk_size = self
+            .block_size()
+            .saturating_sub(BLOB_LOG_OVERHEAD + file_name.len())
+            .max(1);
+        let chunks: Vec<&[u8]> = if bytes.is_empty() {
+            vec![bytes]
+        } else {
+            bytes.chunks(chunk_size).collect()
+        };
+        let mut blocks = Vec::new();
+        for _ in 0..chunks.len() {
+            blocks.push(self.append(file_name)?);
+        }
+        for (idx, (block_id, chunk)) in blocks.iter().zip(chunks).enumerate() {
+            let next = blocks
+                .get(idx + 1)
+                .map_or(-1, |next| next.block_num() as i32);
+            self.pin(block_id)?;
+            let written = self
+                .set_int(block_id, BLOB_NEXT_POS as i32, Some(next), true)
+                .and_then(|()| self.set_bytes(block_id, BLOB_CHUNK_POS as i32, Some(&[]), false))
+                .and_then(|()| self.set_bytes(block_id, BLOB_CHUNK_POS as i32, Some(chunk), true));
+            self.unpin(block_id);
+            written?;
+        }
+        let first = blocks[0].block_num() as i32;
+        self.freed_on_rollback.extend(blocks);
+        Ok(first)
+    }
+
+    /// The value stored in the chain of overflow blocks starting at
+    /// `first_block`.
+    pub fn read_blob(&mut self, file_name: &str, first_block: i32) -> Result<Vec<u8>, DbError> {
+        let mut bytes = Vec::new();
+        for block_id in self.blob_blocks(file_name, first_block)? {
+            self.pin(&block_id)?;
+            let chunk = self.get_bytes(&block_id, BLOB_CHUNK_POS);
+            self.unpin(&block_id);
+            let chunk = chunk?.ok_or_else(|| {
+                DbError::Corruption(format!("overflow block {} holds no chunk", block_id))
+            })?;
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// Frees the chain of overflow blocks starting at `first_block` once the
+    /// transaction commits, for a blob that has been replaced or deleted.
+    pub fn drop_blob(&mut self, file_name: &str, first_block: i32) -> Result<(), DbError> {
+        let blocks = self.blob_blocks(file_name, first_block)?;
+        self.freed_on_commit.extend(blocks);
+        Ok(())
+    }
+
+    fn blob_blocks(&mut self, file_name: &str, first_block: i32) -> Result<Vec<BlockId>, DbError> {
+        let mut blocks = Vec::new();
+        let mut next = first_block;
+        while next >= 0 {
+            let block_id = BlockId::new(file_name, next as usize);
+            // A cycle can only come from a corrupted chain.
+            if blocks.contains(&block_id) {
+                return Err(DbError::Corruption(format!(
+                    "overflow chain in {} loops back to {}",
+                    file_name, block_id
+                )));
+            }
+            self.pin(&block_id)?;
+            let read = self.get_int(&block_id, BLOB_NEXT_POS);
+            self.unpin(&block_id);
+            next = read?.unwrap_or(-1);
+            blocks.push(block_id);
+        }
+        Ok(blocks)
+    }
+
+    /// Puts `block_id` on its file's free block map for `append` to hand
+    /// out again, or takes it off. Like `append`, this locks the end of the
+    /// file, so no other transaction takes a freed block before this one
+    /// finishes.
+    pub fn set_free(
+        &mut self,
+        block_id: &BlockId,
+        free: bool,
+        should_log: bool,
+    ) -> Result<(), DbError> {
+        let dummy = BlockId::new(&block_id.file_name(), END_OF_FILE);
+        self.concurrency_manager.acquire_x_lock(&dummy)?;
+        let was_free = self.file_manager.lock().unwrap().is_free(block_id);
+        if should_log && !is_temp_file(block_id) {
+            if was_free == free {
+                return Ok(());
+            }
+            self.recovery_manager.set_free(block_id, was_free, free)?;
+        }
+        self.file_manager.lock().unwrap().set_free(block_id, free)
+    }
+
+    pub fn available_buffers(&self) -> usize {
+        self.buffer_manager.available_buffers() as usize
+    }
+
+    pub fn size(&mut self, file_name: &str) -> Result<usize, DbError> {
+        let dummy = BlockId::new(file_name, END_OF_FILE);
+        self.concurrency_manager.acquire_s_lock(&dummy)?;
+        let mut file_manager = self.file_manager.lock().unwrap();
+        Ok(file_manager.length(file_name)? / file_manager.block_size())
+    }
+
+    /// The number of freed blocks of `file_name` that `append` will reuse.
+    pub fn free_block_count(&mut self, file_name: &str) -> Result<usize, DbError> {
+        let dummy = BlockId::new(file_name, END_OF_FILE);
+        self.concurrency_manager.acquire_s_lock(&dummy)?;
+        Ok(self
+            .file_manager
+            .lock()
+            .unwrap()
+            .free_block_count(file_name))
+    }
+
+    pub fn append(&mut self, file_name: &str) -> Result<BlockId, DbError> {
+        let dummy = BlockId::new(file_name, END_OF_FILE);
+        self.concurrency_manager.acquire_x_lock(&dummy)?;
+        let reused = self.file_manager.lock().unwrap().first_free(file_name);
+        let block_id = match reused {
+            Some(block_id) => {
+                self.set_free(&block_id, false, true)?;
+                self.file_manager.lock().unwrap().clear(&block_id)?;
+                block_id
+            }
+            None => self.file_manager.lock().unwrap().append(file_name)?,
+        };
+        if is_temp_file(&block_id) {
+            self.temp_blocks += 1;
+        }
+        Ok(block_id)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.file_manager.lock().unwrap().block_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filemanager::{Page, SyncMode};
+    use crate::logrecord::{LogRecordFactory, SetIntLogRecord, CHECKPOINT};
+    use tempdir::TempDir;
+    const TEST_BLOCK_SIZE: usize = 400;
+
+    #[test]
+    fn test_transaction_releases_locks() {
+        let tmp_dir = TempDir::new("test_transaction").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let mut other = ConcurrencyManager::new(-1, lock_table.clone());
+        let blid = file_manager.lock().unwrap().append("test").unwrap();
+
+        let mut tx = Transaction::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            lock_table.clone(),
+        )
+        .unwrap();
+        tx.pin(&blid).unwrap();
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(0));
+        assert_eq!(tx.available_buffers(), 2);
+        assert!(other.acquire_x_lock(&blid).is_err());
+        tx.commit().unwrap();
+        assert_eq!(tx.available_buffers(), 3);
+        assert!(other.acquire_x_lock(&blid).is_ok());
+        other.release();
+
+        let mut tx =
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap();
+        tx.pin(&blid).unwrap();
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(0));
+        assert!(other.acquire_x_lock(&blid).is_err());
+        tx.rollback().unwrap();
+        assert_eq!(tx.available_buffers(), 3);
+        assert!(other.acquire_x_lock(&blid).is_ok());
+        other.release();
+    }
 
-    pub fn append() -> BlockId {}
+    #[test]
+    fn test_transactions_on_separate_threads() {
+        fn shareable<T: Send + Sync>(_: &T) {}
 
-    pub fn block_size() -> usize {}
+        let tmp_dir = TempDir::new("test_transaction").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        shareable(&file_manager);
+        shareable(&log_manager);
+        shareable(&buffer_manager);
+        shareable(&lock_table);
+        let blid = file_manager.lock().unwrap().append("test").unwrap();
+
+        let mut tx = Transaction::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            lock_table.clone(),
+        )
+        .unwrap();
+        tx.pin(&blid).unwrap();
+        tx.set_int(&blid, 0, Some(7), true).unwrap();
+
+        // The reader blocks on the writer's exclusive lock until it commits.
+        let reader = {
+            let (file_manager, log_manager, buffer_manager, lock_table) = (
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            );
+            let blid = blid.clone();
+            std::thread::spawn(move || {
+                let mut tx =
+                    Transaction::new(file_manager, log_manager, buffer_manager, lock_table)
+                        .unwrap();
+                tx.pin(&blid).unwrap();
+                let val = tx.get_int(&blid, 0).unwrap();
+                tx.commit().unwrap();
+                val
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        tx.commit().unwrap();
+        assert_eq!(reader.join().unwrap(), Some(7));
+        assert_eq!(buffer_manager.available_buffers(), 3);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_transaction_size_and_append() {
+        let tmp_dir = TempDir::new("test_transaction").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+
+        let mut tx =
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap();
+        assert_eq!(tx.block_size(), TEST_BLOCK_SIZE);
+        assert_eq!(tx.size("test").unwrap(), 0);
+        assert_eq!(tx.append("test").unwrap().block_num(), 0);
+        assert_eq!(tx.append("test").unwrap().block_num(), 1);
+        assert_eq!(tx.size("test").unwrap(), 2);
+        tx.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_transaction_repins_cached_block() {
+        let tmp_dir = TempDir::new("test_transaction").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let mut tx = Transaction::new(
+            file_manager,
+            log_manager,
+            buffer_manager.clone(),
+            lock_table,
+        )
+        .unwrap();
+        let blid = tx.append("testfile").unwrap();
+        tx.pin(&blid).unwrap();
+        tx.set_int(&blid, 0, Some(7), true).unwrap();
+
+        // Holding a borrow of the buffer manager proves the second pin and
+        // the reads never touch it.
+        {
+            let _buffer_manager = buffer_manager;
+            tx.pin(&blid).unwrap();
+            assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+            assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+        }
+        assert_eq!(tx.available_buffers(), 2);
+
+        tx.unpin(&blid);
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+        tx.unpin(&blid);
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), None);
+        assert_eq!(tx.available_buffers(), 3);
+        tx.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_transaction_buffer_ring() {
+        let tmp_dir = TempDir::new("test_transaction").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let mut tx = Transaction::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            buffer_manager.clone(),
+            lock_table.clone(),
+        )
+        .unwrap();
+        tx.set_buffer_ring(2);
+        assert_eq!(tx.buffer_ring_size(), 2);
+        let blid = tx.append("testfile").unwrap();
+        tx.pin(&blid).unwrap();
+        tx.pin(&blid).unwrap();
+        assert_eq!(tx.available_buffers(), 3);
+
+        // Changing the block moves it and both pins into the pool.
+        tx.set_int(&blid, 0, Some(7), true).unwrap();
+        assert_eq!(tx.available_buffers(), 2);
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+        tx.unpin(&blid);
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+        tx.commit().unwrap();
+        assert_eq!(tx.buffer_ring_size(), 0);
+        assert_eq!(tx.available_buffers(), 3);
+
+        let mut tx =
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap();
+        tx.pin(&blid).unwrap();
+        assert_eq!(tx.get_int(&blid, 0).unwrap(), Some(7));
+        tx.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_automatic_checkpoint() {
+        let tmp_dir = TempDir::new("test_checkpoint").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .max_wal_size(2 * TEST_BLOCK_SIZE)
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = || {
+            Transaction::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            )
+            .unwrap()
+        };
+        let log_blocks =
+            || file_manager.lock().unwrap().length("log.wal").unwrap() / TEST_BLOCK_SIZE;
+
+        let mut setup = new_tx();
+        let blid1 = setup.append("testfile").unwrap();
+        let blid2 = setup.append("testfile").unwrap();
+        setup.commit().unwrap();
+
+        // A transaction with an uncommitted update holds the checkpoint back.
+        let mut writer = new_tx();
+        writer.pin(&blid1).unwrap();
+        writer.set_int(&blid1, 0, Some(1), true).unwrap();
+        let mut tx = new_tx();
+        tx.pin(&blid2).unwrap();
+        for i in 0..60 {
+            tx.set_int(&blid2, 4 * i, Some(i), true).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(log_blocks() > 2);
+
+        writer.commit().unwrap();
+        assert_eq!(log_blocks(), 1);
+        let mut page = Page::builder()
+            .block_size(TEST_BLOCK_SIZE)
+            .with_buffer()
+            .build();
+        file_manager
+            .lock()
+            .unwrap()
+            .read(&blid2, &mut page)
+            .unwrap();
+        assert_eq!(page.get_int(4 * 59), Some(59));
+
+        // Without a limit the log keeps growing.
+        log_manager.lock().unwrap().set_max_wal_size(None);
+        let mut tx = new_tx();
+        tx.pin(&blid2).unwrap();
+        for i in 0..60 {
+            tx.set_int(&blid2, 4 * i, Some(0), true).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(log_blocks() > 2);
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let tmp_dir = TempDir::new("test_checkpoint").expect("failed to create temp dir");
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), file_manager.clone())
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            8,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let new_tx = |lock_table: &Arc<LockTable>| {
+            Transaction::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            )
+            .unwrap()
+        };
+
+        let mut setup = new_tx(&lock_table);
+        let blid = setup.append("testfile").unwrap();
+        setup.commit().unwrap();
+
+        // The checkpoint waits for the transaction with an uncommitted update.
+        let mut writer = new_tx(&lock_table);
+        writer.pin(&blid).unwrap();
+        writer.set_int(&blid, 0, Some(1), true).unwrap();
+        // Recovery would put 42 back if it read past the checkpoint.
+        SetIntLogRecord::write_to_log_record(log_manager.clone(), 9999, &blid, 4, 42, 0).unwrap();
+        assert!(!RecoveryManager::checkpoint(&log_manager, &buffer_manager).unwrap());
+        writer.commit().unwrap();
+        let newest = log_manager
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let record = LogRecordFactory::create_log_record(newest.to_vec()).unwrap();
+        assert_eq!(record.operation(), CHECKPOINT);
+
+        // An update after the checkpoint is undone when its transaction
+        // never finishes. Recovery runs with fresh locks, as after a restart.
+        let mut crashed = new_tx(&lock_table);
+        crashed.pin(&blid).unwrap();
+        crashed.set_int(&blid, 0, Some(2), true).unwrap();
+        let mut recovery = new_tx(&Arc::new(LockTable::new()));
+        recovery.recover().unwrap();
+        recovery.pin(&blid).unwrap();
+        assert_eq!(recovery.get_int(&blid, 0).unwrap(), Some(1));
+        assert_eq!(recovery.get_int(&blid, 4).unwrap(), Some(0));
+        recovery.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_recovery_redoes_committed_updates() {
+        let tmp_dir = TempDir::new("test_redo").expect("failed to create temp dir");
+        let open = || {
+            let file_manager = Arc::new(Mutex::new(
+                FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+            ));
+            let log_manager = Arc::new(Mutex::new(
+                LogManager::builder("log.wal".to_string(), file_manager.clone())
+                    .build()
+                    .unwrap(),
+            ));
+            let buffer_manager = Arc::new(BufferManager::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                8,
+            ));
+            let lock_table = Arc::new(LockTable::new());
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap()
+        };
+        let on_disk = |tx: &Transaction, blid: &BlockId| {
+            let mut page = Page::builder()
+                .block_size(TEST_BLOCK_SIZE)
+                .with_buffer()
+                .build();
+            tx.file_manager
+                .lock()
+                .unwrap()
+                .read(blid, &mut page)
+                .unwrap();
+            (page.get_int(0), page.get_string(8))
+        };
+
+        let mut tx = open();
+        let blid = tx.append("testfile").unwrap();
+        tx.pin(&blid).unwrap();
+        tx.set_int(&blid, 0, Some(7), true).unwrap();
+        tx.set_string(&blid, 8, Some("redo".to_string()), true)
+            .unwrap();
+        tx.commit().unwrap();
+        // Committing wrote the log but left the block in its buffer.
+        assert_eq!(on_disk(&tx, &blid), (Some(0), Some(String::new())));
+
+        // The buffer is lost in the crash; recovery puts the update back.
+        let mut recovery = open();
+        recovery.recover().unwrap();
+        assert_eq!(
+            on_disk(&recovery, &blid),
+            (Some(7), Some("redo".to_string()))
+        );
+        recovery.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_free_block_map_is_logged() {
+        let tmp_dir = TempDir::new("test_free_block_log").expect("failed to create temp dir");
+        let open = || {
+            let file_manager = Arc::new(Mutex::new(
+                FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+            ));
+            let log_manager = Arc::new(Mutex::new(
+                LogManager::builder("log.wal".to_string(), file_manager.clone())
+                    .build()
+                    .unwrap(),
+            ));
+            let buffer_manager = Arc::new(BufferManager::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                8,
+            ));
+            let lock_table = Arc::new(LockTable::new());
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap()
+        };
+
+        let mut tx = open();
+        let first = tx.append("testfile").unwrap();
+        let second = tx.append("testfile").unwrap();
+        tx.commit().unwrap();
+
+        // Freeing a block is undone by a rollback.
+        let mut tx = open();
+        tx.set_free(&first, true, true).unwrap();
+        assert_eq!(tx.free_block_count("testfile").unwrap(), 1);
+        tx.rollback().unwrap();
+        let mut tx = open();
+        assert_eq!(tx.free_block_count("testfile").unwrap(), 0);
+
+        // So is handing a freed block out again.
+        tx.set_free(&second, true, true).unwrap();
+        tx.commit().unwrap();
+        let mut tx = open();
+        assert_eq!(tx.append("testfile").unwrap(), second);
+        assert_eq!(tx.free_block_count("testfile").unwrap(), 0);
+        tx.rollback().unwrap();
+        let mut tx = open();
+        assert_eq!(tx.free_block_count("testfile").unwrap(), 1);
+        tx.commit().unwrap();
+
+        // Recovery puts the map back for a transaction that never finished.
+        let mut crashed = open();
+        crashed.set_free(&first, true, true).unwrap();
+        assert_eq!(crashed.append("testfile").unwrap(), first);
+        crashed.set_free(&first, true, true).unwrap();
+        let mut recovery = open();
+        recovery.recover().unwrap();
+        assert!(!recovery.file_manager.lock().unwrap().is_free(&first));
+        assert!(recovery.file_manager.lock().unwrap().is_free(&second));
+        recovery.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_rollback_to_savepoint() {
+        let tmp_dir = TempDir::new("test_savepoint").expect("failed to create temp dir");
+        let lock_table = Arc::new(LockTable::new());
+        let open = || {
+            let file_manager = Arc::new(Mutex::new(
+                FileManager::new(tmp_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+            ));
+            let log_manager = Arc::new(Mutex::new(
+                LogManager::builder("log.wal".to_string(), file_manager.clone())
+                    .build()
+                    .unwrap(),
+            ));
+            let buffer_manager = Arc::new(BufferManager::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                8,
+            ));
+            (file_manager, log_manager, buffer_manager)
+        };
+        let (file_manager, log_manager, buffer_manager) = open();
+        let new_tx = || {
+            Transaction::new(
+                file_manager.clone(),
+                log_manager.clone(),
+                buffer_manager.clone(),
+                lock_table.clone(),
+            )
+            .unwrap()
+        };
+        let mut tx = new_tx();
+        let kept = tx.append("testfile").unwrap();
+        let undone = tx.append("testfile").unwrap();
+        let read = tx.append("testfile").unwrap();
+        let shared = tx.append("testfile").unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = new_tx();
+        for blid in [&kept, &undone, &read, &shared] {
+            tx.pin(blid).unwrap();
+        }
+        tx.set_int(&kept, 0, Some(1), true).unwrap();
+        assert_eq!(tx.get_int(&shared, 0).unwrap(), Some(0));
+        let savepoint = tx.savepoint();
+        tx.set_int(&kept, 0, Some(2), true).unwrap();
+        tx.set_int(&undone, 0, Some(3), true).unwrap();
+        tx.set_string(&undone, 8, Some("gone".to_string()), true)
+            .unwrap();
+        assert_eq!(tx.get_int(&read, 0).unwrap(), Some(0));
+        tx.set_int(&shared, 0, Some(4), true).unwrap();
+        tx.rollback_to_savepoint(&savepoint).unwrap();
+        let tx_n = tx.transaction_n();
+        assert!(lock_table.holds_x_lock(tx_n, &kept));
+        assert!(!lock_table.holds_lock(tx_n, &undone));
+        assert!(!lock_table.holds_lock(tx_n, &read));
+        assert!(lock_table.holds_lock(tx_n, &shared));
+        assert!(!lock_table.holds_x_lock(tx_n, &shared));
+        assert_eq!(tx.get_int(&kept, 0).unwrap(), Some(1));
+        assert_eq!(tx.get_int(&shared, 0).unwrap(), Some(0));
+
+        // Others can now take the released locks, and see the undo.
+        let mut other = new_tx();
+        other.pin(&undone).unwrap();
+        assert_eq!(other.get_int(&undone, 0).unwrap(), Some(0));
+        assert_eq!(other.get_string(&undone, 8).unwrap(), Some(String::new()));
+        other.set_int(&undone, 0, Some(5), true).unwrap();
+        other.commit().unwrap();
+
+        tx.pin(&undone).unwrap();
+        tx.set_int(&undone, 4, Some(6), true).unwrap();
+        tx.commit().unwrap();
+
+        // The buffers are lost in a crash, and recovery redoes the undo
+        // along with the change.
+        let (file_manager, log_manager, buffer_manager) = open();
+        let mut recovery = Transaction::new(
+            file_manager,
+            log_manager,
+            buffer_manager,
+            lock_table.clone(),
+        )
+        .unwrap();
+        recovery.recover().unwrap();
+        for (blid, offset, expected) in [
+            (&kept, 0, 1),
+            (&undone, 0, 5),
+            (&undone, 4, 6),
+            (&shared, 0, 0),
+        ] {
+            recovery.pin(blid).unwrap();
+            assert_eq!(recovery.get_int(blid, offset).unwrap(), Some(expected));
+            recovery.unpin(blid);
+        }
+        recovery.commit().unwrap();
+        tmp_dir.close().expect("failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_transaction_with_separate_wal_directory() {
+        let wal_dir = TempDir::new("test_transaction_wal").expect("failed to create temp dir");
+        let data_dir = TempDir::new("test_transaction_data").expect("failed to create temp dir");
+        let mut wal_file_manager =
+            FileManager::new(wal_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap();
+        wal_file_manager.with_sync_mode(SyncMode::Full);
+        let wal_file_manager = Arc::new(Mutex::new(wal_file_manager));
+        let file_manager = Arc::new(Mutex::new(
+            FileManager::new(data_dir.path().to_owned(), TEST_BLOCK_SIZE).unwrap(),
+        ));
+        let log_manager = Arc::new(Mutex::new(
+            LogManager::builder("log.wal".to_string(), wal_file_manager)
+                .build()
+                .unwrap(),
+        ));
+        let buffer_manager = Arc::new(BufferManager::new(
+            file_manager.clone(),
+            log_manager.clone(),
+            3,
+        ));
+        let lock_table = Arc::new(LockTable::new());
+        let mut tx =
+            Transaction::new(file_manager, log_manager, buffer_manager, lock_table).unwrap();
+        let blid = tx.append("testfile").unwrap();
+        tx.pin(&blid).unwrap();
+        tx.set_int(&blid, 0, Some(42), true).unwrap();
+        tx.commit().unwrap();
+
+        assert!(wal_dir.path().join("log.wal").exists());
+        assert!(!wal_dir.path().join("testfile").exists());
+        assert!(data_dir.path().join("testfile").exists());
+        assert!(!data_dir.path().join("log.wal").exists());
+        wal_dir.close().expect("failed to remove temp dir");
+        data_dir.close().expect("failed to remove temp dir");
+    }
 }